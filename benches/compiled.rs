@@ -0,0 +1,137 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use mail_parser::Message;
+use sieve::{Compiler, Context, Input, Runtime, Sieve};
+
+const MESSAGE: &str = concat!(
+    "Return-Path: <bounces@lists.example.org>\r\n",
+    "Received: from mx.example.org (mx.example.org [192.0.2.1])\r\n",
+    "\tby mail.example.com with ESMTPS id 4f2a1b\r\n",
+    "\tfor <jane@example.com>; Mon, 21 Nov 2022 10:00:00 +0000\r\n",
+    "From: John Doe <john@lists.example.org>\r\n",
+    "Sender: Mailing List <bounces@lists.example.org>\r\n",
+    "To: Jane Doe <jane@example.com>, Bill Foobar <bill@example.com>\r\n",
+    "Cc: Team <team@example.com>\r\n",
+    "Subject: Re: Quarterly report and meeting notes\r\n",
+    "List-Id: Team discussion <team.lists.example.org>\r\n",
+    "Message-ID: <1234@lists.example.org>\r\n",
+    "X-Priority: 3\r\n",
+    "Content-Type: text/plain; charset=utf-8\r\n",
+    "\r\n",
+    "See you at the meeting.\r\n",
+);
+
+// A global script made of the header, address and exists tests that
+// are usually found in site-wide spam and routing rules.
+fn global_script(with_regex: bool) -> String {
+    let mut script = String::from(concat!(
+        "require [\"fileinto\", \"variables\", \"imap4flags\", \"regex\", \"relational\", ",
+        "\"comparator-i;ascii-numeric\", \"comparator-i;unicode-casemap\"];\r\n",
+    ));
+    for domain in [
+        "example.net",
+        "example.info",
+        "example.biz",
+        "spam.example",
+        "junk.example",
+    ] {
+        script.push_str(&format!(
+            concat!(
+                "if address :domain :is [\"from\", \"sender\", \"reply-to\"] \"{domain}\" {{\r\n",
+                "  fileinto \"Junk\";\r\n",
+                "}}\r\n",
+            ),
+            domain = domain
+        ));
+    }
+    for word in ["lottery", "winner", "prize", "viagra", "invoice overdue"] {
+        script.push_str(&format!(
+            concat!(
+                "if header :comparator \"i;unicode-casemap\" :matches ",
+                "[\"subject\", \"comments\", \"keywords\"] \"*{word}*\" {{\r\n",
+                "  addflag \"$Junk\";\r\n",
+                "}}\r\n",
+            ),
+            word = word
+        ));
+    }
+    script.push_str(concat!(
+        "if anyof (exists \"X-Spam-Flag\", exists [\"X-Virus\", \"X-Malware\"]) {\r\n",
+        "  fileinto \"Quarantine\";\r\n",
+        "  stop;\r\n",
+        "}\r\n",
+        "if allof (address :localpart :is \"to\" [\"jane\", \"bill\"], ",
+        "header :value \"lt\" :comparator \"i;ascii-numeric\" \"x-priority\" \"2\") {\r\n",
+        "  addflag \"\\\\Flagged\";\r\n",
+        "}\r\n",
+    ));
+    if with_regex {
+        script.push_str(concat!(
+            "if header :regex \"list-id\" \"<([a-z]+)\\\\.lists\\\\.example\\\\.org>\" {\r\n",
+            "  fileinto \"Lists/${1}\";\r\n",
+            "}\r\n",
+        ));
+    }
+    script
+}
+
+fn run(mut instance: Context<'_>, script: &Input) {
+    let mut input = script.clone();
+    while let Some(event) = instance.run(input) {
+        black_box(event.unwrap());
+        input = true.into();
+    }
+}
+
+fn compiled(c: &mut Criterion) {
+    let runtime = Runtime::new();
+    let message = Message::parse(MESSAGE.as_bytes()).unwrap();
+
+    for (name, with_regex) in [("global_script", false), ("global_script_regex", true)] {
+        let script = Arc::new(
+            Compiler::new()
+                .compile(global_script(with_regex).as_bytes())
+                .unwrap(),
+        );
+        let compiled = Arc::new(Sieve::clone(&script));
+        compiled.compile_closures();
+        let interpreted = Input::script("global", script);
+        let compiled = Input::script("global", compiled);
+
+        let mut group = c.benchmark_group(name);
+        group.bench_function("interpreter", |b| {
+            b.iter(|| run(runtime.filter_shared(&message), &interpreted))
+        });
+        group.bench_function("compiled", |b| {
+            b.iter(|| run(runtime.filter_shared(&message), &compiled))
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, compiled);
+criterion_main!(benches);
@@ -0,0 +1,110 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use sieve::{Compiler, Context, Input, Runtime};
+
+fn build_message(attachment_size: usize) -> Vec<u8> {
+    let mut message = concat!(
+        "From: John Doe <john@example.org>\r\n",
+        "To: Jane Doe <jane@example.org>\r\n",
+        "Subject: Quarterly report\r\n",
+        "Content-Type: multipart/mixed; boundary=\"boundary\"\r\n",
+        "\r\n",
+        "--boundary\r\n",
+        "Content-Type: text/plain\r\n",
+        "\r\n",
+        "Please find the report attached.\r\n",
+        "--boundary\r\n",
+        "Content-Type: application/octet-stream\r\n",
+        "Content-Transfer-Encoding: base64\r\n",
+        "\r\n",
+    )
+    .as_bytes()
+    .to_vec();
+    for _ in 0..attachment_size / 76 {
+        message.extend_from_slice(
+            b"QUJDREVGR0hJSktMTU5PUFFSU1RVVldYWVphYmNkZWZnaGlqa2xtbm9wcXJzdHV2d3h5ejAxMjM0\r\n",
+        );
+    }
+    message.extend_from_slice(b"--boundary--\r\n");
+    message
+}
+
+fn run(mut instance: Context<'_>, script: &Input) {
+    let mut input = script.clone();
+    while let Some(event) = instance.run(input) {
+        black_box(event.unwrap());
+        input = true.into();
+    }
+}
+
+fn lazy_parsing(c: &mut Criterion) {
+    let runtime = Runtime::new();
+    let compiler = Compiler::new();
+    let header_script = Input::script(
+        "header",
+        compiler
+            .compile(
+                br#"require ["fileinto"];
+                if header :contains "subject" "report" {
+                    fileinto "Reports";
+                }"#,
+            )
+            .unwrap(),
+    );
+    let body_script = Input::script(
+        "body",
+        compiler
+            .compile(
+                br#"require ["fileinto", "body"];
+                if body :text :contains "report" {
+                    fileinto "Reports";
+                }"#,
+            )
+            .unwrap(),
+    );
+
+    let messages = [16 * 1024, 1024 * 1024]
+        .into_iter()
+        .map(|size| (size, build_message(size)))
+        .collect::<Vec<_>>();
+
+    for (name, script) in [("header_test", &header_script), ("body_test", &body_script)] {
+        let mut group = c.benchmark_group(name);
+        for (size, message) in &messages {
+            group.bench_with_input(BenchmarkId::new("filter", size), message, |b, message| {
+                b.iter(|| run(runtime.filter(message), script))
+            });
+            group.bench_with_input(
+                BenchmarkId::new("filter_lazy", size),
+                message,
+                |b, message| b.iter(|| run(runtime.filter_lazy(message), script)),
+            );
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, lazy_parsing);
+criterion_main!(benches);
@@ -83,12 +83,42 @@ So if you could go ahead and try to remember to do that from now on, that'd be g
                     // Set to true if the ID is duplicate
                     input = false.into();
                 }
-                Event::Execute { command, arguments } => {
+                Event::Execute {
+                    command,
+                    arguments,
+                    ..
+                } => {
                     println!(
                         "Script executed command {:?} with parameters {:?}",
                         command, arguments
                     );
-                    // Set to true if the script succeeded
+                    // Set to true if the script succeeded, the program output or the filtered
+                    // message are returned with Input::output or Input::message
+                    input = false.into();
+                }
+                Event::Extension {
+                    name,
+                    tags,
+                    arguments,
+                } => {
+                    println!(
+                        "Script called extension {:?} with tags {:?} and arguments {:?}",
+                        name, tags, arguments
+                    );
+                    // Set to true if the extension test succeeded, values are assigned
+                    // to the variable arguments in order
+                    input = Input::extension(false, Vec::<String>::new());
+                }
+                Event::Filter {
+                    program, message, ..
+                } => {
+                    println!(
+                        "Script filtered the message through {:?} ({} bytes)",
+                        program,
+                        message.len()
+                    );
+                    // Return the filtered message with Input::message or set
+                    // to false if the program failed
                     input = false.into();
                 }
 
@@ -156,6 +186,26 @@ So if you could go ahead and try to remember to do that from now on, that'd be g
                     messages.push(String::from_utf8(message).unwrap());
                     input = true.into();
                 }
+                Event::CreatedPatch { patch, .. } => {
+                    messages.push(String::from_utf8(patch.to_vec(raw_message.as_bytes())).unwrap());
+                    input = true.into();
+                }
+                Event::Log {
+                    script,
+                    level,
+                    message,
+                    line_num,
+                    ..
+                } => {
+                    println!(
+                        "Script {:?} logged {:?} at line {}: {}",
+                        script.as_str(),
+                        level,
+                        line_num,
+                        message
+                    );
+                    input = true.into();
+                }
 
                 #[cfg(test)]
                 _ => unreachable!(),
@@ -165,6 +215,9 @@ So if you could go ahead and try to remember to do that from now on, that'd be g
                     RuntimeError::TooManyIncludes => {
                         eprintln!("Too many included scripts.");
                     }
+                    RuntimeError::TooManyNestedCalls => {
+                        eprintln!("Too many nested procedure calls.");
+                    }
                     RuntimeError::InvalidInstruction(instruction) => {
                         eprintln!(
                             "Invalid instruction {:?} found at {}:{}.",
@@ -188,6 +241,12 @@ So if you could go ahead and try to remember to do that from now on, that'd be g
                     RuntimeError::CPULimitReached => {
                         eprintln!("Script exceeded the configured CPU limit.");
                     }
+                    RuntimeError::MessageRequired => {
+                        eprintln!("Script needs the message contents.");
+                    }
+                    RuntimeError::InvalidNotificationUri(uri) => {
+                        eprintln!("Invalid notification URI {:?}.", uri);
+                    }
                 }
                 input = true.into();
             }
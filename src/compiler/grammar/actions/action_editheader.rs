@@ -76,7 +76,7 @@ impl<'x> CompilerState<'x> {
                     let string = self.parse_string_token(token_info)?;
                     if field_name.is_none() {
                         if let StringItem::Text(header_name) = &string {
-                            if HeaderName::parse(header_name).is_none() {
+                            if HeaderName::parse(header_name.as_ref()).is_none() {
                                 return Err(self
                                     .tokens
                                     .unwrap_next()?
@@ -101,11 +101,12 @@ impl<'x> CompilerState<'x> {
                 }
             }
         }
-        self.instructions.push(Instruction::AddHeader(AddHeader {
-            last,
-            field_name: field_name.unwrap(),
-            value,
-        }));
+        self.instructions
+            .push(Instruction::AddHeader(Box::new(AddHeader {
+                last,
+                field_name: field_name.unwrap(),
+                value,
+            })));
         Ok(())
     }
 
@@ -175,7 +176,7 @@ impl<'x> CompilerState<'x> {
                 _ => {
                     field_name = self.parse_string_token(token_info)?;
                     if let StringItem::Text(header_name) = &field_name {
-                        if HeaderName::parse(header_name).is_none() {
+                        if HeaderName::parse(header_name.as_ref()).is_none() {
                             return Err(self
                                 .tokens
                                 .unwrap_next()?
@@ -191,23 +192,25 @@ impl<'x> CompilerState<'x> {
             return Err(self.tokens.unwrap_next()?.missing_tag(":mime"));
         }
 
-        let cmd = Instruction::DeleteHeader(DeleteHeader {
+        let value_patterns = if let Some(Ok(
+            Token::StringConstant(_) | Token::StringVariable(_) | Token::BracketOpen,
+        )) = self.tokens.peek().map(|r| r.map(|t| &t.token))
+        {
+            let key_list = self.parse_strings()?;
+            self.validate_match(&match_type, &comparator, &key_list)?;
+            key_list
+        } else {
+            Vec::new()
+        };
+
+        let cmd = Instruction::DeleteHeader(Box::new(DeleteHeader {
             index: if index_last { index.map(|i| -i) } else { index },
             comparator,
             match_type,
             field_name,
-            value_patterns: if let Some(Ok(
-                Token::StringConstant(_) | Token::StringVariable(_) | Token::BracketOpen,
-            )) = self.tokens.peek().map(|r| r.map(|t| &t.token))
-            {
-                let key_list = self.parse_strings()?;
-                self.validate_match(&match_type, &key_list)?;
-                key_list
-            } else {
-                Vec::new()
-            },
+            value_patterns,
             mime_anychild,
-        });
+        }));
         self.instructions.push(cmd);
         Ok(())
     }
@@ -106,14 +106,15 @@ impl<'x> CompilerState<'x> {
             }
         }
 
-        self.instructions.push(Instruction::FileInto(FileInto {
-            folder,
-            copy,
-            create,
-            flags,
-            mailbox_id,
-            special_use,
-        }));
+        self.instructions
+            .push(Instruction::FileInto(Box::new(FileInto {
+                folder,
+                copy,
+                create,
+                flags,
+                mailbox_id,
+                special_use,
+            })));
         Ok(())
     }
 }
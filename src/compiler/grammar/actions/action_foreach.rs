@@ -0,0 +1,117 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use serde::{Deserialize, Serialize};
+
+use crate::compiler::{
+    grammar::{instruction::CompilerState, AddressPart, Capability},
+    lexer::{string::StringItem, word::Word, Token},
+    CompileError, ErrorType,
+};
+
+use super::action_set::Variable;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum ForEachSource {
+    List(Vec<StringItem>),
+    Split {
+        separator: StringItem,
+        values: Vec<StringItem>,
+    },
+    Header(Vec<StringItem>),
+    Address {
+        header_list: Vec<StringItem>,
+        address_part: AddressPart,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ForEach {
+    pub var_name: Variable,
+    pub jz_pos: u32,
+}
+
+impl<'x> CompilerState<'x> {
+    pub(crate) fn parse_foreach(&mut self) -> Result<(ForEachSource, Variable), CompileError> {
+        let mut source = Word::List;
+        let mut separator = None;
+        let mut address_part = None;
+        let values;
+
+        loop {
+            let token_info = self.tokens.unwrap_next()?;
+            match token_info.token {
+                Token::Tag(word @ (Word::Header | Word::Address)) => {
+                    self.validate_argument(1, None, token_info.line_num, token_info.line_pos)?;
+                    source = word;
+                }
+                Token::Tag(Word::Split) => {
+                    self.validate_argument(1, None, token_info.line_num, token_info.line_pos)?;
+                    separator = self.parse_string()?.into();
+                    source = Word::Split;
+                }
+                Token::Tag(
+                    word @ (Word::LocalPart | Word::Domain | Word::All | Word::User | Word::Detail),
+                ) => {
+                    self.validate_argument(
+                        2,
+                        if matches!(word, Word::User | Word::Detail) {
+                            Capability::SubAddress.into()
+                        } else {
+                            None
+                        },
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    address_part = AddressPart::from(word).into();
+                }
+                _ => {
+                    values = self.parse_strings_token(token_info)?;
+                    break;
+                }
+            }
+        }
+
+        let token_info = self.tokens.unwrap_next()?;
+        if address_part.is_some() && source != Word::Address {
+            return Err(token_info.custom(ErrorType::InvalidArguments));
+        }
+        let var_name = self.parse_variable_name(token_info)?;
+
+        Ok((
+            match source {
+                Word::Header => ForEachSource::Header(values),
+                Word::Address => ForEachSource::Address {
+                    header_list: values,
+                    address_part: address_part.unwrap_or(AddressPart::All),
+                },
+                Word::Split => ForEachSource::Split {
+                    separator: separator.unwrap(),
+                    values,
+                },
+                _ => ForEachSource::List(values),
+            },
+            var_name,
+        ))
+    }
+}
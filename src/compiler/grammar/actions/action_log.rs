@@ -0,0 +1,82 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compiler::{
+        grammar::instruction::{CompilerState, Instruction},
+        lexer::{string::StringItem, word::Word, Token},
+        CompileError,
+    },
+    LogLevel,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Log {
+    pub level: LogLevel,
+    pub message: StringItem,
+    pub line_num: usize,
+    pub line_pos: usize,
+}
+
+impl<'x> CompilerState<'x> {
+    pub(crate) fn parse_log(
+        &mut self,
+        line_num: usize,
+        line_pos: usize,
+    ) -> Result<(), CompileError> {
+        let mut level = LogLevel::Debug;
+        let message;
+
+        loop {
+            let token_info = self.tokens.unwrap_next()?;
+            match token_info.token {
+                Token::Tag(
+                    word @ (Word::Error | Word::Warn | Word::Info | Word::Debug | Word::Trace),
+                ) => {
+                    self.validate_argument(1, None, token_info.line_num, token_info.line_pos)?;
+                    level = match word {
+                        Word::Error => LogLevel::Error,
+                        Word::Warn => LogLevel::Warn,
+                        Word::Info => LogLevel::Info,
+                        Word::Debug => LogLevel::Debug,
+                        _ => LogLevel::Trace,
+                    };
+                }
+                _ => {
+                    message = self.parse_string_token(token_info)?;
+                    break;
+                }
+            }
+        }
+
+        self.instructions.push(Instruction::Log(Log {
+            level,
+            message,
+            line_num,
+            line_pos,
+        }));
+        Ok(())
+    }
+}
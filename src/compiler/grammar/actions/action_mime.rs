@@ -33,7 +33,7 @@ use super::action_set::{Modifier, Variable};
 
 #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub(crate) struct ForEveryPart {
-    pub jz_pos: usize,
+    pub jz_pos: u32,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
@@ -96,12 +96,13 @@ impl<'x> CompilerState<'x> {
             }
         }
 
-        self.instructions.push(Instruction::Replace(Replace {
-            subject,
-            from,
-            replacement,
-            mime,
-        }));
+        self.instructions
+            .push(Instruction::Replace(Box::new(Replace {
+                subject,
+                from,
+                replacement,
+                mime,
+            })));
         Ok(())
     }
 
@@ -128,11 +129,12 @@ impl<'x> CompilerState<'x> {
             }
         }
 
-        self.instructions.push(Instruction::Enclose(Enclose {
-            subject,
-            headers,
-            value,
-        }));
+        self.instructions
+            .push(Instruction::Enclose(Box::new(Enclose {
+                subject,
+                headers,
+                value,
+            })));
         Ok(())
     }
 
@@ -157,26 +159,32 @@ impl<'x> CompilerState<'x> {
                     | Word::QuoteRegex
                     | Word::Length),
                 ) => {
-                    let modifier = word.into();
+                    let modifier = Modifier::from(word);
                     if !modifiers.contains(&modifier) {
                         modifiers.push(modifier);
                     }
                 }
                 _ => {
-                    name = self.parse_variable_name(token_info)?;
-                    break;
+                    if let Some(modifier) = self.parse_vendor_modifier(&token_info)? {
+                        if !modifiers.contains(&modifier) {
+                            modifiers.push(modifier);
+                        }
+                    } else {
+                        name = self.parse_variable_name(token_info)?;
+                        break;
+                    }
                 }
             }
         }
 
-        modifiers.sort_unstable_by(|a: &Modifier, b: &Modifier| b.cmp(a));
+        self.sort_modifiers(&mut modifiers);
 
         self.instructions
-            .push(Instruction::ExtractText(ExtractText {
+            .push(Instruction::ExtractText(Box::new(ExtractText {
                 modifiers,
                 first,
                 name,
-            }));
+            })));
         Ok(())
     }
 
@@ -32,7 +32,7 @@ use crate::{
         lexer::{string::StringItem, word::Word, Token},
         CompileError, ErrorType,
     },
-    runtime::actions::action_notify::{validate_from, validate_uri},
+    runtime::actions::action_notify::validate_from,
     FileCarbonCopy,
 };
 
@@ -141,7 +141,12 @@ impl<'x> CompilerState<'x> {
                 }
                 _ => {
                     if let Token::StringConstant(uri) = &token_info.token {
-                        if validate_uri(std::str::from_utf8(uri).unwrap_or("")).is_none() {
+                        if self
+                            .compiler
+                            .notification_methods
+                            .validate_uri(std::str::from_utf8(uri).unwrap_or(""))
+                            .is_none()
+                        {
                             return Err(token_info.custom(ErrorType::InvalidURI));
                         }
                     }
@@ -158,7 +163,7 @@ impl<'x> CompilerState<'x> {
             return Err(self.tokens.unwrap_next()?.missing_tag(":fcc"));
         }
 
-        self.instructions.push(Instruction::Notify(Notify {
+        self.instructions.push(Instruction::Notify(Box::new(Notify {
             method,
             from,
             importance,
@@ -176,7 +181,7 @@ impl<'x> CompilerState<'x> {
             } else {
                 None
             },
-        }));
+        })));
         Ok(())
     }
 }
@@ -0,0 +1,99 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use serde::{Deserialize, Serialize};
+
+use crate::compiler::{
+    grammar::instruction::{CompilerState, Instruction},
+    lexer::{string::StringItem, Token},
+    CompileError, ErrorType,
+};
+
+use super::action_set::Variable;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ProcedureCall {
+    pub pos: usize,
+    pub params: Vec<Variable>,
+    pub args: Vec<StringItem>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Procedure {
+    pub pos: usize,
+    pub params: Vec<Variable>,
+}
+
+impl<'x> CompilerState<'x> {
+    pub(crate) fn parse_procedure(&mut self) -> Result<(String, Vec<String>), CompileError> {
+        let token_info = self.tokens.unwrap_next()?;
+        let name = if let Token::StringConstant(name) = &token_info.token {
+            String::from_utf8_lossy(name).to_lowercase()
+        } else {
+            return Err(token_info.custom(ErrorType::ExpectedConstantString));
+        };
+        if self.procedures.contains_key(&name) {
+            return Err(token_info.custom(ErrorType::ProcedureAlreadyDefined(name)));
+        }
+
+        let params =
+            if let Some(Ok(Token::CurlyOpen)) = self.tokens.peek().map(|r| r.map(|t| &t.token)) {
+                Vec::new()
+            } else {
+                self.parse_static_strings()?
+            };
+
+        Ok((name, params))
+    }
+
+    pub(crate) fn parse_call(&mut self) -> Result<(), CompileError> {
+        let token_info = self.tokens.unwrap_next()?;
+        let procedure = if let Token::StringConstant(name) = &token_info.token {
+            let name = String::from_utf8_lossy(name).to_lowercase();
+            if let Some(procedure) = self.procedures.get(&name) {
+                procedure.clone()
+            } else {
+                return Err(token_info.custom(ErrorType::ProcedureUndefined(name)));
+            }
+        } else {
+            return Err(token_info.custom(ErrorType::ExpectedConstantString));
+        };
+
+        let args =
+            if let Some(Ok(Token::Semicolon)) = self.tokens.peek().map(|r| r.map(|t| &t.token)) {
+                Vec::new()
+            } else {
+                self.parse_strings()?
+            };
+        if args.len() != procedure.params.len() {
+            return Err(token_info.custom(ErrorType::InvalidArguments));
+        }
+
+        self.instructions.push(Instruction::Call(ProcedureCall {
+            pos: procedure.pos,
+            params: procedure.params,
+            args,
+        }));
+        Ok(())
+    }
+}
@@ -224,28 +224,29 @@ impl<'x> CompilerState<'x> {
             }
         }
 
-        self.instructions.push(Instruction::Redirect(Redirect {
-            address,
-            copy,
-            notify,
-            return_of_content: ret,
-            by_time: if let Some(alimit) = by_alimit {
-                ByTime::Absolute {
-                    alimit,
-                    mode: by_mode,
-                    trace: by_trace,
-                }
-            } else if let Some(rlimit) = by_rlimit {
-                ByTime::Relative {
-                    rlimit,
-                    mode: by_mode,
-                    trace: by_trace,
-                }
-            } else {
-                ByTime::None
-            },
-            list,
-        }));
+        self.instructions
+            .push(Instruction::Redirect(Box::new(Redirect {
+                address,
+                copy,
+                notify,
+                return_of_content: ret,
+                by_time: if let Some(alimit) = by_alimit {
+                    ByTime::Absolute {
+                        alimit,
+                        mode: by_mode,
+                        trace: by_trace,
+                    }
+                } else if let Some(rlimit) = by_rlimit {
+                    ByTime::Relative {
+                        rlimit,
+                        mode: by_mode,
+                        trace: by_trace,
+                    }
+                } else {
+                    ByTime::None
+                },
+                list,
+            })));
         Ok(())
     }
 }
@@ -91,11 +91,12 @@ impl<'x> CompilerState<'x> {
         if !capabilities.is_empty() {
             if self.block.require_pos == usize::MAX {
                 self.block.require_pos = self.instructions.len();
-                self.instructions.push(Instruction::Require(capabilities));
+                self.instructions
+                    .push(Instruction::Require(capabilities.into()));
             } else if let Some(Instruction::Require(capabilties)) =
                 self.instructions.get_mut(self.block.require_pos)
             {
-                capabilties.extend(capabilities)
+                *capabilties = capabilties.iter().cloned().chain(capabilities).collect();
             } else {
                 #[cfg(test)]
                 panic!(
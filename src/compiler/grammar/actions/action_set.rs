@@ -21,34 +21,64 @@
  * for more details.
 */
 
-use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
 
 use crate::{
     compiler::{
-        grammar::instruction::{CompilerState, Instruction},
+        grammar::{
+            expr::ExpressionItem,
+            instruction::{CompilerState, Instruction},
+            Capability,
+        },
         lexer::{string::StringItem, tokenizer::TokenInfo, word::Word, Token},
         CompileError, ErrorType,
     },
     runtime::string::IntoString,
 };
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) enum Modifier {
-    Lower = 41,
-    Upper = 40,
-    LowerFirst = 31,
-    UpperFirst = 30,
-    QuoteWildcard = 20,
-    QuoteRegex = 21,
-    EncodeUrl = 15,
-    Length = 10,
+    Lower,
+    Upper,
+    LowerFirst,
+    UpperFirst,
+    QuoteWildcard,
+    QuoteRegex,
+    EncodeUrl,
+    Length,
+
+    // Vendor modifiers
+    Trim,
+    Replace { find: String, replace: String },
+    Substring { offset: usize, length: usize },
+    Split(String),
+    Join(String),
+    DecodeMime,
+    DecodeBase64,
+    EncodeBase64,
+    HtmlToText,
+    Punycode,
+    Hash(HashAlgorithm),
+    Custom(String),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum HashAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
 pub(crate) struct Set {
     pub modifiers: Vec<Modifier>,
     pub name: Variable,
     pub value: StringItem,
+    #[serde(default)]
+    pub expression: Option<Vec<ExpressionItem>>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -57,10 +87,30 @@ pub(crate) enum Variable {
     Global(String),
 }
 
+// Same as `#[serde(skip_serializing_if = "Option::is_none")]` on `expression`,
+// except for binary formats such as bincode which need every field.
+impl Serialize for Set {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let skip_expression = self.expression.is_none() && serializer.is_human_readable();
+        let mut state = serializer.serialize_struct("Set", if skip_expression { 3 } else { 4 })?;
+        state.serialize_field("modifiers", &self.modifiers)?;
+        state.serialize_field("name", &self.name)?;
+        state.serialize_field("value", &self.value)?;
+        if skip_expression {
+            state.skip_field("expression")?;
+        } else {
+            state.serialize_field("expression", &self.expression)?;
+        }
+        state.end()
+    }
+}
+
 impl<'x> CompilerState<'x> {
     pub(crate) fn parse_set(&mut self) -> Result<(), CompileError> {
         let mut modifiers = Vec::new();
         let mut name = None;
+        let mut is_eval = false;
+        let mut expression = None;
         let value;
 
         loop {
@@ -76,14 +126,31 @@ impl<'x> CompilerState<'x> {
                     | Word::Length
                     | Word::EncodeUrl),
                 ) => {
-                    let modifier = word.into();
+                    let modifier = Modifier::from(word);
                     if !modifiers.contains(&modifier) {
                         modifiers.push(modifier);
                     }
                 }
+                Token::Tag(Word::Eval) => {
+                    self.validate_argument(
+                        1,
+                        Capability::Expressions.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    is_eval = true;
+                }
                 _ => {
-                    if name.is_none() {
+                    if let Some(modifier) = self.parse_vendor_modifier(&token_info)? {
+                        if !modifiers.contains(&modifier) {
+                            modifiers.push(modifier);
+                        }
+                    } else if name.is_none() {
                         name = self.parse_variable_name(token_info)?.into();
+                    } else if is_eval {
+                        expression = self.parse_expression_token(token_info)?.into();
+                        value = StringItem::Text("".into());
+                        break;
                     } else {
                         value = self.parse_string_token(token_info)?;
                         break;
@@ -92,13 +159,14 @@ impl<'x> CompilerState<'x> {
             }
         }
 
-        modifiers.sort_unstable_by(|a: &Modifier, b: &Modifier| b.cmp(a));
+        self.sort_modifiers(&mut modifiers);
 
-        self.instructions.push(Instruction::Set(Set {
+        self.instructions.push(Instruction::Set(Box::new(Set {
             modifiers,
             name: name.unwrap(),
             value,
-        }));
+            expression,
+        })));
         Ok(())
     }
 
@@ -119,6 +187,79 @@ impl<'x> CompilerState<'x> {
         }
     }
 
+    pub(crate) fn parse_vendor_modifier(
+        &mut self,
+        token_info: &TokenInfo,
+    ) -> Result<Option<Modifier>, CompileError> {
+        let modifier = match &token_info.token {
+            Token::Tag(Word::Trim) => Modifier::Trim,
+            Token::Tag(Word::Replace) => Modifier::Replace {
+                find: self.tokens.expect_static_string()?.into_string(),
+                replace: self.tokens.expect_static_string()?.into_string(),
+            },
+            Token::Tag(Word::Substring) => Modifier::Substring {
+                offset: self.tokens.expect_number(usize::MAX)?,
+                length: self.tokens.expect_number(usize::MAX)?,
+            },
+            Token::Tag(Word::Split) => {
+                Modifier::Split(self.tokens.expect_static_string()?.into_string())
+            }
+            Token::Tag(Word::Join) => {
+                Modifier::Join(self.tokens.expect_static_string()?.into_string())
+            }
+            Token::Tag(Word::DecodeMime) => Modifier::DecodeMime,
+            Token::Tag(Word::DecodeBase64) => Modifier::DecodeBase64,
+            Token::Tag(Word::EncodeBase64) => Modifier::EncodeBase64,
+            Token::Tag(Word::HtmlToText) => Modifier::HtmlToText,
+            Token::Tag(Word::Punycode) => Modifier::Punycode,
+            Token::Tag(Word::Hash) => {
+                let algorithm = self.tokens.unwrap_next()?;
+                let hash = if let Token::StringConstant(value) = &algorithm.token {
+                    match value.as_slice() {
+                        b"md5" => HashAlgorithm::Md5.into(),
+                        b"sha1" => HashAlgorithm::Sha1.into(),
+                        b"sha256" => HashAlgorithm::Sha256.into(),
+                        b"sha512" => HashAlgorithm::Sha512.into(),
+                        _ => None,
+                    }
+                } else {
+                    None
+                };
+                Modifier::Hash(hash.ok_or_else(|| algorithm.custom(ErrorType::InvalidArguments))?)
+            }
+            Token::Tag(word) if self.compiler.modifiers.contains(&word.to_string()) => {
+                Modifier::Custom(word.to_string())
+            }
+            Token::CustomTag(name) if self.compiler.modifiers.contains(name) => {
+                Modifier::Custom(name.to_string())
+            }
+            _ => return Ok(None),
+        };
+
+        self.validate_argument(
+            0,
+            Capability::Modifiers.into(),
+            token_info.line_num,
+            token_info.line_pos,
+        )?;
+
+        Ok(Some(modifier))
+    }
+
+    pub(crate) fn sort_modifiers(&self, modifiers: &mut [Modifier]) {
+        // Stable sort, modifiers sharing a precedence are applied in script order
+        modifiers.sort_by_key(|modifier| {
+            Reverse(match modifier {
+                Modifier::Custom(name) => self
+                    .compiler
+                    .modifiers
+                    .get(name)
+                    .map_or(0, |modifier| modifier.precedence()),
+                modifier => modifier.precedence(),
+            })
+        });
+    }
+
     pub(crate) fn register_variable(&mut self, name: String) -> Result<Variable, ErrorType> {
         let name = name.to_lowercase();
         if let Some((namespace, name)) = name.split_once('.') {
@@ -137,6 +278,32 @@ impl<'x> CompilerState<'x> {
     }
 }
 
+impl Modifier {
+    pub(crate) fn precedence(&self) -> u32 {
+        match self {
+            Modifier::DecodeBase64 | Modifier::DecodeMime => 70,
+            Modifier::HtmlToText => 65,
+            Modifier::Trim => 60,
+            Modifier::Replace { .. } => 55,
+            Modifier::Substring { .. } => 50,
+            Modifier::Split(_) => 46,
+            Modifier::Join(_) => 45,
+            Modifier::Lower => 41,
+            Modifier::Upper => 40,
+            Modifier::LowerFirst => 31,
+            Modifier::UpperFirst => 30,
+            Modifier::QuoteRegex => 21,
+            Modifier::QuoteWildcard => 20,
+            Modifier::Punycode => 18,
+            Modifier::EncodeUrl => 15,
+            Modifier::EncodeBase64 => 13,
+            Modifier::Hash(_) => 12,
+            Modifier::Length => 10,
+            Modifier::Custom(_) => 25,
+        }
+    }
+}
+
 impl From<Word> for Modifier {
     fn from(word: Word) -> Self {
         match word {
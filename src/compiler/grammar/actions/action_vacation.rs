@@ -201,34 +201,35 @@ impl<'x> CompilerState<'x> {
         }
 
         self.instructions
-            .push(Instruction::Test(Test::Vacation(TestVacation {
+            .push(Instruction::Test(Box::new(Test::Vacation(TestVacation {
                 period,
                 handle,
                 reason: reason.clone(),
                 addresses,
-            })));
+            }))));
 
         self.instructions
-            .push(Instruction::Jz(self.instructions.len() + 2));
-
-        self.instructions.push(Instruction::Vacation(Vacation {
-            reason,
-            subject,
-            from,
-            mime,
-            fcc: if let Some(fcc) = fcc {
-                FileCarbonCopy {
-                    mailbox: fcc,
-                    create,
-                    flags,
-                    special_use,
-                    mailbox_id,
-                }
-                .into()
-            } else {
-                None
-            },
-        }));
+            .push(Instruction::Jz(self.instructions.len() as u32 + 2));
+
+        self.instructions
+            .push(Instruction::Vacation(Box::new(Vacation {
+                reason,
+                subject,
+                from,
+                mime,
+                fcc: if let Some(fcc) = fcc {
+                    FileCarbonCopy {
+                        mailbox: fcc,
+                        create,
+                        flags,
+                        special_use,
+                        mailbox_id,
+                    }
+                    .into()
+                } else {
+                    None
+                },
+            })));
 
         Ok(())
     }
@@ -25,10 +25,13 @@ pub mod action_convert;
 pub mod action_editheader;
 pub mod action_fileinto;
 pub mod action_flags;
+pub mod action_foreach;
 pub mod action_include;
 pub mod action_keep;
+pub mod action_log;
 pub mod action_mime;
 pub mod action_notify;
+pub mod action_procedure;
 pub mod action_redirect;
 pub mod action_reject;
 pub mod action_require;
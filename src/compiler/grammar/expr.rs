@@ -0,0 +1,589 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{iter::Peekable, str::Chars};
+
+use serde::{Deserialize, Serialize};
+
+use crate::compiler::lexer::string::StringItem;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum ExpressionItem {
+    Variable(StringItem),
+    Constant(Constant),
+    UnaryOperator(UnaryOperator),
+    BinaryOperator(BinaryOperator),
+    Function { id: Function, num_args: usize },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) enum Constant {
+    Integer(i64),
+    Float(f64),
+    String(String),
+}
+
+impl Eq for Constant {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum UnaryOperator {
+    Not,
+    Minus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum BinaryOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    And,
+    Or,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Function {
+    Len,
+    Lower,
+    Upper,
+    Trim,
+    Contains,
+    StartsWith,
+    EndsWith,
+    Substring,
+    Abs,
+    Round,
+    Min,
+    Max,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Operand(ExpressionItem),
+    Function(Function),
+    BinaryOperator(BinaryOperator),
+    Not,
+    ParenOpen,
+    ParenClose,
+    Comma,
+}
+
+enum StackItem {
+    UnaryOperator(UnaryOperator),
+    BinaryOperator(BinaryOperator),
+    Function(Function),
+    ParenOpen,
+}
+
+/// Compiles an expression to postfix notation, variables are the ones
+/// already resolved by the string tokenizer. Variables inside quoted
+/// literals are concatenated with the surrounding text.
+pub(crate) fn parse_expression(expr: &StringItem) -> Result<Vec<ExpressionItem>, String> {
+    let mut tokenizer = Tokenizer::default();
+    match expr {
+        StringItem::Text(text) => tokenizer.tokenize(text)?,
+        StringItem::List(items) => {
+            for item in items {
+                if let StringItem::Text(text) = item {
+                    tokenizer.tokenize(text)?;
+                } else {
+                    tokenizer.push_variable(item);
+                }
+            }
+        }
+        variable => tokenizer.push_variable(variable),
+    }
+    let tokens = tokenizer.finish()?;
+
+    let mut output = Vec::with_capacity(tokens.len());
+    let mut stack: Vec<StackItem> = Vec::new();
+    let mut arg_count: Vec<usize> = Vec::new();
+    let mut expect_operand = true;
+
+    for token in tokens {
+        match token {
+            Token::Operand(item) => {
+                if !expect_operand {
+                    return Err("missing operator".to_string());
+                }
+                output.push(item);
+                expect_operand = false;
+            }
+            Token::Function(function) => {
+                if !expect_operand {
+                    return Err("missing operator".to_string());
+                }
+                stack.push(StackItem::Function(function));
+                arg_count.push(1);
+            }
+            Token::ParenOpen => {
+                if !expect_operand {
+                    return Err("missing operator".to_string());
+                }
+                stack.push(StackItem::ParenOpen);
+            }
+            Token::Comma | Token::ParenClose => {
+                if expect_operand {
+                    return Err("missing operand".to_string());
+                }
+                loop {
+                    match stack.pop() {
+                        Some(StackItem::UnaryOperator(op)) => {
+                            output.push(ExpressionItem::UnaryOperator(op));
+                        }
+                        Some(StackItem::BinaryOperator(op)) => {
+                            output.push(ExpressionItem::BinaryOperator(op));
+                        }
+                        Some(StackItem::ParenOpen) if token == Token::ParenClose => break,
+                        Some(StackItem::Function(function)) if token == Token::Comma => {
+                            stack.push(StackItem::Function(function));
+                            if let Some(count) = arg_count.last_mut() {
+                                *count += 1;
+                            }
+                            expect_operand = true;
+                            break;
+                        }
+                        Some(StackItem::Function(function)) => {
+                            let num_args = arg_count.pop().unwrap_or(1);
+                            let (min_args, max_args) = function.num_args();
+                            if num_args < min_args || num_args > max_args {
+                                return Err(format!(
+                                    "invalid number of arguments for function {:?}",
+                                    function.name()
+                                ));
+                            }
+                            output.push(ExpressionItem::Function {
+                                id: function,
+                                num_args,
+                            });
+                            break;
+                        }
+                        _ => return Err("unbalanced parentheses".to_string()),
+                    }
+                }
+            }
+            Token::Not => {
+                if !expect_operand {
+                    return Err("missing operator".to_string());
+                }
+                stack.push(StackItem::UnaryOperator(UnaryOperator::Not));
+            }
+            Token::BinaryOperator(op) => {
+                if expect_operand {
+                    match op {
+                        BinaryOperator::Subtract => {
+                            stack.push(StackItem::UnaryOperator(UnaryOperator::Minus));
+                        }
+                        BinaryOperator::Add => (),
+                        _ => return Err("missing operand".to_string()),
+                    }
+                    continue;
+                }
+
+                while let Some(item) = stack.last() {
+                    match item {
+                        StackItem::UnaryOperator(prev_op) => {
+                            output.push(ExpressionItem::UnaryOperator(*prev_op));
+                        }
+                        StackItem::BinaryOperator(prev_op)
+                            if prev_op.precedence() >= op.precedence() =>
+                        {
+                            output.push(ExpressionItem::BinaryOperator(*prev_op));
+                        }
+                        _ => break,
+                    }
+                    stack.pop();
+                }
+                stack.push(StackItem::BinaryOperator(op));
+                expect_operand = true;
+            }
+        }
+    }
+
+    if expect_operand {
+        return Err("missing operand".to_string());
+    }
+
+    while let Some(item) = stack.pop() {
+        match item {
+            StackItem::UnaryOperator(op) => output.push(ExpressionItem::UnaryOperator(op)),
+            StackItem::BinaryOperator(op) => output.push(ExpressionItem::BinaryOperator(op)),
+            StackItem::Function(_) | StackItem::ParenOpen => {
+                return Err("unbalanced parentheses".to_string())
+            }
+        }
+    }
+
+    Ok(output)
+}
+
+#[derive(Default)]
+struct Tokenizer {
+    tokens: Vec<Token>,
+    quote: Option<Quote>,
+}
+
+struct Quote {
+    end_ch: char,
+    items: Vec<StringItem>,
+    text: String,
+}
+
+impl Tokenizer {
+    fn tokenize(&mut self, text: &str) -> Result<(), String> {
+        let mut chars = text.chars().peekable();
+        if self.quote.is_some() {
+            self.tokenize_quoted(&mut chars);
+        }
+
+        while let Some(ch) = chars.next() {
+            let token = match ch {
+                ' ' | '\t' | '\r' | '\n' => continue,
+                '0'..='9' | '.' => {
+                    let mut number = String::new();
+                    number.push(ch);
+                    while let Some(&ch) = chars.peek() {
+                        if ch.is_ascii_digit() || ch == '.' {
+                            number.push(ch);
+                            chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                    Token::Operand(ExpressionItem::Constant(
+                        if let Ok(number) = number.parse::<i64>() {
+                            Constant::Integer(number)
+                        } else if let Ok(number) = number.parse::<f64>() {
+                            Constant::Float(number)
+                        } else {
+                            return Err(format!("invalid number {:?}", number));
+                        },
+                    ))
+                }
+                '\'' | '"' => {
+                    self.quote = Some(Quote {
+                        end_ch: ch,
+                        items: Vec::new(),
+                        text: String::new(),
+                    });
+                    self.tokenize_quoted(&mut chars);
+                    continue;
+                }
+                'a'..='z' | 'A'..='Z' | '_' => {
+                    let mut name = String::new();
+                    name.push(ch.to_ascii_lowercase());
+                    while let Some(&ch) = chars.peek() {
+                        if ch.is_ascii_alphanumeric() || ch == '_' {
+                            name.push(ch.to_ascii_lowercase());
+                            chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                    match name.as_str() {
+                        "true" => Token::Operand(ExpressionItem::Constant(Constant::Integer(1))),
+                        "false" => Token::Operand(ExpressionItem::Constant(Constant::Integer(0))),
+                        _ => {
+                            while chars.next_if(|ch| ch.is_whitespace()).is_some() {}
+                            match (Function::parse(&name), chars.next()) {
+                                (Some(function), Some('(')) => Token::Function(function),
+                                _ => return Err(format!("unknown function {:?}", name)),
+                            }
+                        }
+                    }
+                }
+                '(' => Token::ParenOpen,
+                ')' => Token::ParenClose,
+                ',' => Token::Comma,
+                '+' => Token::BinaryOperator(BinaryOperator::Add),
+                '-' => Token::BinaryOperator(BinaryOperator::Subtract),
+                '*' => Token::BinaryOperator(BinaryOperator::Multiply),
+                '/' => Token::BinaryOperator(BinaryOperator::Divide),
+                '%' => Token::BinaryOperator(BinaryOperator::Modulo),
+                '&' if chars.next_if_eq(&'&').is_some() => {
+                    Token::BinaryOperator(BinaryOperator::And)
+                }
+                '|' if chars.next_if_eq(&'|').is_some() => {
+                    Token::BinaryOperator(BinaryOperator::Or)
+                }
+                '=' if chars.next_if_eq(&'=').is_some() => {
+                    Token::BinaryOperator(BinaryOperator::Eq)
+                }
+                '!' if chars.next_if_eq(&'=').is_some() => {
+                    Token::BinaryOperator(BinaryOperator::Ne)
+                }
+                '!' => Token::Not,
+                '<' if chars.next_if_eq(&'=').is_some() => {
+                    Token::BinaryOperator(BinaryOperator::Le)
+                }
+                '<' => Token::BinaryOperator(BinaryOperator::Lt),
+                '>' if chars.next_if_eq(&'=').is_some() => {
+                    Token::BinaryOperator(BinaryOperator::Ge)
+                }
+                '>' => Token::BinaryOperator(BinaryOperator::Gt),
+                _ => return Err(format!("invalid character {:?}", ch)),
+            };
+            self.tokens.push(token);
+        }
+
+        Ok(())
+    }
+
+    // Reads a quoted literal until its closing quote or the end of the text
+    fn tokenize_quoted(&mut self, chars: &mut Peekable<Chars>) {
+        let quote = self.quote.as_mut().unwrap();
+        loop {
+            match chars.next() {
+                Some('\\') => {
+                    if let Some(ch) = chars.next() {
+                        quote.text.push(ch);
+                    }
+                }
+                Some(ch) if ch == quote.end_ch => break,
+                Some(ch) => quote.text.push(ch),
+                None => return,
+            }
+        }
+
+        let mut quote = self.quote.take().unwrap();
+        let item = if quote.items.is_empty() {
+            ExpressionItem::Constant(Constant::String(quote.text))
+        } else {
+            if !quote.text.is_empty() {
+                quote.items.push(StringItem::Text(quote.text.into()));
+            }
+            ExpressionItem::Variable(StringItem::List(quote.items))
+        };
+        self.tokens.push(Token::Operand(item));
+    }
+
+    fn push_variable(&mut self, variable: &StringItem) {
+        if let Some(quote) = &mut self.quote {
+            if !quote.text.is_empty() {
+                quote
+                    .items
+                    .push(StringItem::Text(std::mem::take(&mut quote.text).into()));
+            }
+            quote.items.push(variable.clone());
+        } else {
+            self.tokens
+                .push(Token::Operand(ExpressionItem::Variable(variable.clone())));
+        }
+    }
+
+    fn finish(self) -> Result<Vec<Token>, String> {
+        if self.quote.is_none() {
+            Ok(self.tokens)
+        } else {
+            Err("unterminated string".to_string())
+        }
+    }
+}
+
+impl BinaryOperator {
+    fn precedence(&self) -> u32 {
+        match self {
+            BinaryOperator::Multiply | BinaryOperator::Divide | BinaryOperator::Modulo => 6,
+            BinaryOperator::Add | BinaryOperator::Subtract => 5,
+            BinaryOperator::Lt | BinaryOperator::Le | BinaryOperator::Gt | BinaryOperator::Ge => 4,
+            BinaryOperator::Eq | BinaryOperator::Ne => 3,
+            BinaryOperator::And => 2,
+            BinaryOperator::Or => 1,
+        }
+    }
+}
+
+impl Function {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "len" => Function::Len,
+            "lower" => Function::Lower,
+            "upper" => Function::Upper,
+            "trim" => Function::Trim,
+            "contains" => Function::Contains,
+            "starts_with" => Function::StartsWith,
+            "ends_with" => Function::EndsWith,
+            "substring" => Function::Substring,
+            "abs" => Function::Abs,
+            "round" => Function::Round,
+            "min" => Function::Min,
+            "max" => Function::Max,
+            _ => return None,
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Function::Len => "len",
+            Function::Lower => "lower",
+            Function::Upper => "upper",
+            Function::Trim => "trim",
+            Function::Contains => "contains",
+            Function::StartsWith => "starts_with",
+            Function::EndsWith => "ends_with",
+            Function::Substring => "substring",
+            Function::Abs => "abs",
+            Function::Round => "round",
+            Function::Min => "min",
+            Function::Max => "max",
+        }
+    }
+
+    fn num_args(&self) -> (usize, usize) {
+        match self {
+            Function::Len
+            | Function::Lower
+            | Function::Upper
+            | Function::Trim
+            | Function::Abs
+            | Function::Round => (1, 1),
+            Function::Contains | Function::StartsWith | Function::EndsWith => (2, 2),
+            Function::Substring => (2, 3),
+            Function::Min | Function::Max => (1, usize::MAX),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse_expression, BinaryOperator, Constant, ExpressionItem, Function, UnaryOperator,
+    };
+    use crate::compiler::lexer::string::StringItem;
+
+    #[test]
+    fn expression_parser() {
+        let c = |value| ExpressionItem::Constant(Constant::Integer(value));
+
+        for (expr, expected) in [
+            (
+                "1 + 2 * 3",
+                vec![
+                    c(1),
+                    c(2),
+                    c(3),
+                    ExpressionItem::BinaryOperator(BinaryOperator::Multiply),
+                    ExpressionItem::BinaryOperator(BinaryOperator::Add),
+                ],
+            ),
+            (
+                "-(1 - 2) >= 3",
+                vec![
+                    c(1),
+                    c(2),
+                    ExpressionItem::BinaryOperator(BinaryOperator::Subtract),
+                    ExpressionItem::UnaryOperator(UnaryOperator::Minus),
+                    c(3),
+                    ExpressionItem::BinaryOperator(BinaryOperator::Ge),
+                ],
+            ),
+            (
+                "max(1, 2 || 3, len('abc'))",
+                vec![
+                    c(1),
+                    c(2),
+                    c(3),
+                    ExpressionItem::BinaryOperator(BinaryOperator::Or),
+                    ExpressionItem::Constant(Constant::String("abc".to_string())),
+                    ExpressionItem::Function {
+                        id: Function::Len,
+                        num_args: 1,
+                    },
+                    ExpressionItem::Function {
+                        id: Function::Max,
+                        num_args: 3,
+                    },
+                ],
+            ),
+        ] {
+            assert_eq!(
+                parse_expression(&StringItem::Text(expr.into())).unwrap(),
+                expected,
+                "{}",
+                expr
+            );
+        }
+
+        assert_eq!(
+            parse_expression(&StringItem::List(vec![
+                StringItem::LocalVariable(0),
+                StringItem::Text(" * 2".into()),
+            ]))
+            .unwrap(),
+            vec![
+                ExpressionItem::Variable(StringItem::LocalVariable(0)),
+                c(2),
+                ExpressionItem::BinaryOperator(BinaryOperator::Multiply),
+            ]
+        );
+
+        // Variables inside quoted literals
+        assert_eq!(
+            parse_expression(&StringItem::List(vec![
+                StringItem::Text("'Re: ".into()),
+                StringItem::LocalVariable(0),
+                StringItem::Text("' == 'x'".into()),
+            ]))
+            .unwrap(),
+            vec![
+                ExpressionItem::Variable(StringItem::List(vec![
+                    StringItem::Text("Re: ".into()),
+                    StringItem::LocalVariable(0),
+                ])),
+                ExpressionItem::Constant(Constant::String("x".to_string())),
+                ExpressionItem::BinaryOperator(BinaryOperator::Eq),
+            ]
+        );
+        assert!(parse_expression(&StringItem::List(vec![
+            StringItem::Text("'".into()),
+            StringItem::LocalVariable(0),
+        ]))
+        .is_err());
+
+        for expr in [
+            "",
+            "1 +",
+            "(1",
+            "1)",
+            "1 2",
+            "len()",
+            "contains('a')",
+            "foo(1)",
+            "'abc",
+            "1 = 2",
+        ] {
+            assert!(
+                parse_expression(&StringItem::Text(expr.into())).is_err(),
+                "{}",
+                expr
+            );
+        }
+    }
+}
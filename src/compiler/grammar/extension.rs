@@ -0,0 +1,152 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compiler::{
+        lexer::{string::StringItem, Token},
+        CompileError, ErrorType,
+    },
+    ArgumentType, ExtensionSignature,
+};
+
+use super::{actions::action_set::Variable, instruction::CompilerState, Capability};
+
+#[derive(Debug, Clone)]
+pub(crate) struct Extension {
+    pub capability: Capability,
+    pub signature: ExtensionSignature,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ExtensionCall {
+    pub name: String,
+    pub tags: Vec<(String, Option<ExtensionArgument>)>,
+    pub arguments: Vec<ExtensionArgument>,
+    pub is_not: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum ExtensionArgument {
+    Number(usize),
+    String(StringItem),
+    StringList(Vec<StringItem>),
+    Variable(Variable),
+}
+
+impl<'x> CompilerState<'x> {
+    pub(crate) fn parse_extension(
+        &mut self,
+        name: String,
+        extension: &Extension,
+        line_num: usize,
+        line_pos: usize,
+    ) -> Result<ExtensionCall, CompileError> {
+        self.validate_argument(0, extension.capability.clone().into(), line_num, line_pos)?;
+
+        let mut tags: Vec<(String, Option<ExtensionArgument>)> = Vec::new();
+        loop {
+            let tag = match self.tokens.peek().map(|r| r.map(|t| &t.token)) {
+                Some(Ok(Token::Tag(word))) => word.to_string(),
+                Some(Ok(Token::CustomTag(tag))) => tag.to_string(),
+                _ => break,
+            };
+            let tag_info = self.tokens.unwrap_next()?;
+
+            if let Some((tag, value_type)) = extension
+                .signature
+                .tags
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(&tag))
+            {
+                if tags.iter().any(|(name, _)| name == tag) {
+                    return Err(tag_info.custom(ErrorType::DuplicatedParameter));
+                }
+                let value = if let Some(value_type) = value_type {
+                    Some(self.parse_extension_argument(*value_type)?)
+                } else {
+                    None
+                };
+                tags.push((tag.clone(), value));
+            } else {
+                return Err(tag_info.custom(ErrorType::InvalidArguments));
+            }
+        }
+
+        let mut arguments = Vec::with_capacity(extension.signature.arguments.len());
+        for argument_type in &extension.signature.arguments {
+            arguments.push(self.parse_extension_argument(*argument_type)?);
+        }
+
+        Ok(ExtensionCall {
+            name,
+            tags,
+            arguments,
+            is_not: false,
+        })
+    }
+
+    fn parse_extension_argument(
+        &mut self,
+        argument_type: ArgumentType,
+    ) -> Result<ExtensionArgument, CompileError> {
+        let token_info = self.tokens.unwrap_next()?;
+        match argument_type {
+            ArgumentType::Number => {
+                if let Token::Number(number) = token_info.token {
+                    Ok(ExtensionArgument::Number(number))
+                } else {
+                    Err(token_info.expected("number"))
+                }
+            }
+            ArgumentType::String => self
+                .parse_string_token(token_info)
+                .map(ExtensionArgument::String),
+            ArgumentType::StringList => self
+                .parse_strings_token(token_info)
+                .map(ExtensionArgument::StringList),
+            ArgumentType::Variable => self
+                .parse_variable_name(token_info)
+                .map(ExtensionArgument::Variable),
+        }
+    }
+}
+
+impl ExtensionSignature {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an optional tag, followed by a value when `value` is set.
+    pub fn with_tag(mut self, name: impl Into<String>, value: Option<ArgumentType>) -> Self {
+        self.tags.push((name.into(), value));
+        self
+    }
+
+    /// Adds a positional argument, all of them are required.
+    pub fn with_argument(mut self, argument: ArgumentType) -> Self {
+        self.arguments.push(argument);
+        self
+    }
+}
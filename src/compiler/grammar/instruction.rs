@@ -21,6 +21,8 @@
  * for more details.
 */
 
+use std::sync::Arc;
+
 use ahash::{AHashMap, AHashSet};
 use serde::{Deserialize, Serialize};
 
@@ -30,7 +32,10 @@ use crate::{
         lexer::{tokenizer::Tokenizer, word::Word, Token},
         CompileError, ErrorType,
     },
-    runtime::string::IntoString,
+    runtime::{
+        memory::{deserialize_shared_list, serialize_shared},
+        string::IntoString,
+    },
     Compiler, Sieve,
 };
 
@@ -40,62 +45,76 @@ use super::{
         action_editheader::{AddHeader, DeleteHeader},
         action_fileinto::FileInto,
         action_flags::EditFlags,
+        action_foreach::{ForEach, ForEachSource},
         action_include::Include,
         action_keep::Keep,
+        action_log::Log,
         action_mime::{Enclose, ExtractText, ForEveryPart, Replace},
         action_notify::Notify,
+        action_procedure::{Procedure, ProcedureCall},
         action_redirect::Redirect,
         action_reject::Reject,
         action_set::Set,
         action_vacation::Vacation,
     },
-    tests::test_execute::Execute,
+    extension::ExtensionCall,
+    tests::{
+        test_duplicate::DupMatch,
+        test_execute::{Execute, ExecuteInput, Filter},
+    },
     Capability, Clear, Invalid,
 };
 
 use super::tests::test_ihave::Error;
 
+// Large payloads are boxed to keep instructions small
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub(crate) enum Instruction {
-    Require(Vec<Capability>),
+    Require(
+        #[serde(
+            serialize_with = "serialize_shared",
+            deserialize_with = "deserialize_shared_list"
+        )]
+        Arc<[Capability]>,
+    ),
     Keep(Keep),
-    FileInto(FileInto),
-    Redirect(Redirect),
+    FileInto(Box<FileInto>),
+    Redirect(Box<Redirect>),
     Discard,
     Stop,
     Invalid(Invalid),
-    Test(Test),
-    Jmp(usize),
-    Jz(usize),
-    Jnz(usize),
+    Test(Box<Test>),
+    Jmp(u32),
+    Jz(u32),
+    Jnz(u32),
 
     // RFC 5703
     ForEveryPartPush,
     ForEveryPart(ForEveryPart),
     ForEveryPartPop(usize),
-    Replace(Replace),
-    Enclose(Enclose),
-    ExtractText(ExtractText),
+    Replace(Box<Replace>),
+    Enclose(Box<Enclose>),
+    ExtractText(Box<ExtractText>),
 
     // RFC 6558
-    Convert(Convert),
+    Convert(Box<Convert>),
 
     // RFC 5293
-    AddHeader(AddHeader),
-    DeleteHeader(DeleteHeader),
+    AddHeader(Box<AddHeader>),
+    DeleteHeader(Box<DeleteHeader>),
 
     // RFC 5229
-    Set(Set),
+    Set(Box<Set>),
     Clear(Clear),
 
     // RFC 5435
-    Notify(Notify),
+    Notify(Box<Notify>),
 
     // RFC 5429
     Reject(Reject),
 
     // RFC 5230
-    Vacation(Vacation),
+    Vacation(Box<Vacation>),
 
     // RFC 5463
     Error(Error),
@@ -108,7 +127,25 @@ pub(crate) enum Instruction {
     Return,
 
     // Execute extension
-    Execute(Execute),
+    Execute(Box<Execute>),
+
+    // Vendor extensions
+    Extension(Box<ExtensionCall>),
+
+    // Loops extension
+    ForEachPush(ForEachSource),
+    ForEach(ForEach),
+    ForEachPop(usize),
+
+    // Procedures extension
+    Call(ProcedureCall),
+    ProcedureReturn,
+
+    // vnd.dovecot.debug
+    Log(Log),
+
+    // vnd.dovecot.filter
+    Filter(Box<Filter>),
 
     // Testing
     #[cfg(test)]
@@ -125,6 +162,7 @@ pub(crate) struct Block {
     pub(crate) last_block_start: usize,
     pub(crate) if_jmps: Vec<usize>,
     pub(crate) break_jmps: Vec<usize>,
+    pub(crate) loop_start: usize,
     pub(crate) match_test_pos: Vec<usize>,
     pub(crate) match_test_vars: u64,
     pub(crate) vars_local: AHashMap<String, usize>,
@@ -145,6 +183,7 @@ pub(crate) struct CompilerState<'x> {
     pub(crate) vars_match_max: usize,
     pub(crate) param_check: [bool; MAX_PARAMS],
     pub(crate) includes_num: usize,
+    pub(crate) procedures: AHashMap<String, Procedure>,
 }
 
 impl Compiler {
@@ -170,6 +209,7 @@ impl Compiler {
             vars_match_max: 0,
             param_check: [false; MAX_PARAMS],
             includes_num: 0,
+            procedures: AHashMap::new(),
         };
 
         while let Some(token_info) = state.tokens.next() {
@@ -247,89 +287,80 @@ impl Compiler {
                                 );
                             }
 
-                            is_new_block = if let Some(Ok(Token::Tag(Word::Name))) =
-                                state.tokens.peek().map(|r| r.map(|t| &t.token))
-                            {
-                                let tag = state.tokens.next().unwrap().unwrap();
-                                let label = state.tokens.expect_static_string()?;
-                                for block in &state.block_stack {
-                                    if block.label.as_ref().map_or(false, |n| n.eq(&label)) {
-                                        return Err(tag.custom(ErrorType::LabelAlreadyDefined(
-                                            label.into_string(),
-                                        )));
-                                    }
-                                }
-                                Block::new(Word::ForEveryPart).with_label(label)
-                            } else {
-                                Block::new(Word::ForEveryPart)
-                            }
-                            .into();
+                            is_new_block = state.parse_loop_block(Word::ForEveryPart)?.into();
 
                             state.instructions.push(Instruction::ForEveryPartPush);
                             state
                                 .instructions
-                                .push(Instruction::ForEveryPart(ForEveryPart {
-                                    jz_pos: usize::MAX,
-                                }));
+                                .push(Instruction::ForEveryPart(ForEveryPart { jz_pos: u32::MAX }));
                         }
                         Word::Break => {
-                            state.validate_argument(
-                                0,
-                                Capability::ForEveryPart.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
-                            if let Some(Ok(Token::Tag(Word::Name))) =
+                            if !state.has_capability(&Capability::Loops) {
+                                state.validate_argument(
+                                    0,
+                                    Capability::ForEveryPart.into(),
+                                    token_info.line_num,
+                                    token_info.line_pos,
+                                )?;
+                            }
+                            let label = if let Some(Ok(Token::Tag(Word::Name))) =
                                 state.tokens.peek().map(|r| r.map(|t| &t.token))
                             {
                                 let tag = state.tokens.next().unwrap().unwrap();
-                                let label = state.tokens.expect_static_string()?;
-                                let mut label_found = false;
-                                let mut num_pops = 0;
-
-                                for block in [&mut state.block]
-                                    .into_iter()
-                                    .chain(state.block_stack.iter_mut().rev())
-                                {
-                                    if let Word::ForEveryPart = &block.btype {
-                                        num_pops += 1;
-                                        if block.label.as_ref().map_or(false, |n| n.eq(&label)) {
-                                            state
-                                                .instructions
-                                                .push(Instruction::ForEveryPartPop(num_pops));
-                                            block.break_jmps.push(state.instructions.len());
-                                            label_found = true;
-                                            break;
-                                        }
-                                    }
-                                }
-
-                                if !label_found {
-                                    return Err(
-                                        tag.custom(ErrorType::LabelUndefined(label.into_string()))
-                                    );
-                                }
+                                Some((tag, state.tokens.expect_static_string()?))
                             } else {
-                                let mut label_found = false;
-                                state.instructions.push(Instruction::ForEveryPartPop(1));
-                                if let Word::ForEveryPart = &state.block.btype {
-                                    state.block.break_jmps.push(state.instructions.len());
-                                    label_found = true;
-                                } else {
-                                    for block in state.block_stack.iter_mut().rev() {
-                                        if let Word::ForEveryPart = &block.btype {
-                                            block.break_jmps.push(state.instructions.len());
-                                            label_found = true;
-                                            break;
-                                        }
-                                    }
+                                None
+                            };
+
+                            // Find the loop to exit and count the iterators to discard
+                            let mut num_part_pops = 0;
+                            let mut num_foreach_pops = 0;
+                            let mut target = None;
+                            for (depth, block) in [&state.block]
+                                .into_iter()
+                                .chain(state.block_stack.iter().rev())
+                                .enumerate()
+                            {
+                                match &block.btype {
+                                    Word::ForEveryPart => num_part_pops += 1,
+                                    Word::ForEach => num_foreach_pops += 1,
+                                    Word::While => (),
+                                    _ => continue,
                                 }
-                                if !label_found {
-                                    return Err(token_info.custom(ErrorType::BreakOutsideLoop));
+                                if label.as_ref().map_or(true, |(_, label)| {
+                                    block.label.as_ref().map_or(false, |n| n.eq(label))
+                                }) {
+                                    target = Some(depth);
+                                    break;
                                 }
                             }
 
-                            state.instructions.push(Instruction::Jmp(usize::MAX));
+                            let block = match target {
+                                Some(0) => &mut state.block,
+                                Some(depth) => {
+                                    let pos = state.block_stack.len() - depth;
+                                    &mut state.block_stack[pos]
+                                }
+                                None => {
+                                    return Err(if let Some((tag, label)) = label {
+                                        tag.custom(ErrorType::LabelUndefined(label.into_string()))
+                                    } else {
+                                        token_info.custom(ErrorType::BreakOutsideLoop)
+                                    });
+                                }
+                            };
+                            if num_part_pops > 0 {
+                                state
+                                    .instructions
+                                    .push(Instruction::ForEveryPartPop(num_part_pops));
+                            }
+                            if num_foreach_pops > 0 {
+                                state
+                                    .instructions
+                                    .push(Instruction::ForEachPop(num_foreach_pops));
+                            }
+                            block.break_jmps.push(state.instructions.len());
+                            state.instructions.push(Instruction::Jmp(u32::MAX));
                         }
                         Word::Replace => {
                             state.validate_argument(
@@ -481,30 +512,57 @@ impl Compiler {
                             }
                         }
                         Word::Return => {
-                            state.validate_argument(
-                                0,
-                                Capability::Include.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
                             let mut num_pops = 0;
+                            let mut num_foreach_pops = 0;
+                            let mut in_procedure = false;
 
                             for block in [&state.block]
                                 .into_iter()
                                 .chain(state.block_stack.iter().rev())
                             {
-                                if let Word::ForEveryPart = &block.btype {
-                                    num_pops += 1;
+                                match &block.btype {
+                                    Word::ForEveryPart => num_pops += 1,
+                                    Word::ForEach => num_foreach_pops += 1,
+                                    Word::Procedure => {
+                                        in_procedure = true;
+                                        break;
+                                    }
+                                    _ => (),
                                 }
                             }
 
+                            if !in_procedure {
+                                state.validate_argument(
+                                    0,
+                                    Capability::Include.into(),
+                                    token_info.line_num,
+                                    token_info.line_pos,
+                                )?;
+                            }
+
                             if num_pops > 0 {
                                 state
                                     .instructions
                                     .push(Instruction::ForEveryPartPop(num_pops));
                             }
+                            if num_foreach_pops > 0 {
+                                state
+                                    .instructions
+                                    .push(Instruction::ForEachPop(num_foreach_pops));
+                            }
 
-                            state.instructions.push(Instruction::Return);
+                            if in_procedure {
+                                // Jump to the end of the procedure, patched when its block closes
+                                let block = if state.block_stack.len() > 1 {
+                                    &mut state.block_stack[1]
+                                } else {
+                                    &mut state.block
+                                };
+                                block.break_jmps.push(state.instructions.len());
+                                state.instructions.push(Instruction::Jmp(u32::MAX));
+                            } else {
+                                state.instructions.push(Instruction::Return);
+                            }
                         }
                         Word::Global => {
                             state.validate_argument(
@@ -538,6 +596,119 @@ impl Compiler {
                             }
                         }
 
+                        // Loops extension
+                        Word::ForEach | Word::While => {
+                            state.validate_argument(
+                                0,
+                                Capability::Loops.into(),
+                                token_info.line_num,
+                                token_info.line_pos,
+                            )?;
+
+                            if [&state.block]
+                                .into_iter()
+                                .chain(state.block_stack.iter())
+                                .filter(|b| matches!(&b.btype, Word::ForEach | Word::While))
+                                .count()
+                                >= self.max_nested_loops
+                            {
+                                return Err(token_info.custom(ErrorType::TooManyNestedLoops));
+                            }
+
+                            let mut block = state.parse_loop_block(instruction)?;
+                            if let Word::ForEach = instruction {
+                                let (source, var_name) = state.parse_foreach()?;
+                                state.instructions.push(Instruction::ForEachPush(source));
+                                state.instructions.push(Instruction::ForEach(ForEach {
+                                    var_name,
+                                    jz_pos: u32::MAX,
+                                }));
+                            } else {
+                                block.loop_start = state.instructions.len();
+                                state.parse_test()?;
+                            }
+                            is_new_block = block.into();
+                        }
+
+                        // Procedures extension
+                        Word::Procedure => {
+                            state.validate_argument(
+                                0,
+                                Capability::Procedures.into(),
+                                token_info.line_num,
+                                token_info.line_pos,
+                            )?;
+                            if !state.block_stack.is_empty() {
+                                return Err(token_info.expected("procedure outside of a block"));
+                            }
+
+                            let (name, params) = state.parse_procedure()?;
+                            let mut new_block = Block::new(Word::Procedure);
+                            new_block.line_num = state.tokens.line_num;
+                            new_block.line_pos = state.tokens.pos - state.tokens.line_start;
+                            state.tokens.expect_token(Token::CurlyOpen)?;
+
+                            // Skip over the procedure body during normal execution
+                            state.instructions.push(Instruction::Jmp(u32::MAX));
+                            state.block.last_block_start = state.instructions.len() - 1;
+                            state.block_stack.push(state.block);
+                            state.block = new_block;
+
+                            let mut param_vars = Vec::with_capacity(params.len());
+                            for param in params {
+                                if param.len() < self.max_variable_name_size {
+                                    param_vars.push(state.register_variable(param).map_err(
+                                        |error_type| CompileError {
+                                            line_num: token_info.line_num,
+                                            line_pos: token_info.line_pos,
+                                            error_type,
+                                        },
+                                    )?);
+                                } else {
+                                    return Err(token_info.custom(ErrorType::VariableTooLong));
+                                }
+                            }
+                            state.procedures.insert(
+                                name,
+                                Procedure {
+                                    pos: state.instructions.len(),
+                                    params: param_vars,
+                                },
+                            );
+                            continue;
+                        }
+                        Word::Call => {
+                            state.validate_argument(
+                                0,
+                                Capability::Procedures.into(),
+                                token_info.line_num,
+                                token_info.line_pos,
+                            )?;
+                            state.parse_call()?;
+                        }
+
+                        // vnd.dovecot.debug
+                        Word::DebugLog => {
+                            state.validate_argument(
+                                0,
+                                Capability::Debug.into(),
+                                token_info.line_num,
+                                token_info.line_pos,
+                            )?;
+                            state.parse_log(token_info.line_num, token_info.line_pos)?;
+                        }
+
+                        // vnd.dovecot.filter
+                        Word::Filter => {
+                            state.validate_argument(
+                                0,
+                                Capability::Filter.into(),
+                                token_info.line_num,
+                                token_info.line_pos,
+                            )?;
+                            state.parse_filter()?;
+                        }
+
                         Word::Execute => {
                             state.validate_argument(
                                 0,
@@ -548,6 +719,21 @@ impl Compiler {
                             state.parse_execute()?;
                         }
                         _ => {
+                            let name = instruction.to_string();
+                            if let Some(extension) = self.extension_commands.get(&name) {
+                                let call = state.parse_extension(
+                                    name,
+                                    extension,
+                                    token_info.line_num,
+                                    token_info.line_pos,
+                                )?;
+                                state
+                                    .instructions
+                                    .push(Instruction::Extension(Box::new(call)));
+                                state.expect_instruction_end()?;
+                                continue;
+                            }
+
                             state.ignore_instruction()?;
                             state.instructions.push(Instruction::Invalid(Invalid {
                                 name: instruction.to_string(),
@@ -582,21 +768,48 @@ impl Compiler {
                     state.block_end();
                     let mut prev_block = state.block_stack.pop().unwrap();
                     match &state.block.btype {
-                        Word::ForEveryPart => {
-                            state
-                                .instructions
-                                .push(Instruction::Jmp(prev_block.last_block_start));
-                            let cur_pos = state.instructions.len();
-                            if let Instruction::ForEveryPart(fep) =
+                        Word::ForEveryPart | Word::ForEach | Word::While => {
+                            state.instructions.push(Instruction::Jmp(
+                                if let Word::While = &state.block.btype {
+                                    state.block.loop_start as u32
+                                } else {
+                                    prev_block.last_block_start as u32
+                                },
+                            ));
+                            let cur_pos = state.instructions.len() as u32;
+                            match &mut state.instructions[prev_block.last_block_start] {
+                                Instruction::ForEveryPart(ForEveryPart { jz_pos })
+                                | Instruction::ForEach(ForEach { jz_pos, .. })
+                                | Instruction::Jz(jz_pos) => {
+                                    *jz_pos = cur_pos;
+                                }
+                                _ => {
+                                    debug_assert!(false, "This should not have happened.");
+                                }
+                            }
+                            for pos in state.block.break_jmps {
+                                if let Instruction::Jmp(jmp_pos) = &mut state.instructions[pos] {
+                                    *jmp_pos = cur_pos;
+                                } else {
+                                    debug_assert!(false, "This should not have happened.");
+                                }
+                            }
+                            state.last_block_type = Word::Not;
+                        }
+                        Word::Procedure => {
+                            let return_pos = state.instructions.len() as u32;
+                            state.instructions.push(Instruction::ProcedureReturn);
+                            let cur_pos = state.instructions.len() as u32;
+                            if let Instruction::Jmp(jmp_pos) =
                                 &mut state.instructions[prev_block.last_block_start]
                             {
-                                fep.jz_pos = cur_pos;
+                                *jmp_pos = cur_pos;
                             } else {
                                 debug_assert!(false, "This should not have happened.");
                             }
                             for pos in state.block.break_jmps {
                                 if let Instruction::Jmp(jmp_pos) = &mut state.instructions[pos] {
-                                    *jmp_pos = cur_pos;
+                                    *jmp_pos = return_pos;
                                 } else {
                                     debug_assert!(false, "This should not have happened.");
                                 }
@@ -610,9 +823,9 @@ impl Compiler {
                             );
                             if next_is_block {
                                 prev_block.if_jmps.push(state.instructions.len());
-                                state.instructions.push(Instruction::Jmp(usize::MAX));
+                                state.instructions.push(Instruction::Jmp(u32::MAX));
                             }
-                            let cur_pos = state.instructions.len();
+                            let cur_pos = state.instructions.len() as u32;
                             if let Instruction::Jz(jmp_pos) =
                                 &mut state.instructions[prev_block.last_block_start]
                             {
@@ -635,7 +848,7 @@ impl Compiler {
                             }
                         }
                         Word::Else => {
-                            let cur_pos = state.instructions.len();
+                            let cur_pos = state.instructions.len() as u32;
                             for pos in prev_block.if_jmps.drain(..) {
                                 if let Instruction::Jmp(jmp_pos) = &mut state.instructions[pos] {
                                     *jmp_pos = cur_pos;
@@ -673,7 +886,9 @@ impl Compiler {
                         let mut params = Vec::new();
                         loop {
                             params.push(match state.tokens.unwrap_next()?.token {
-                                Token::StringConstant(s) => StringItem::Text(s.into_string()),
+                                Token::StringConstant(s) => {
+                                    StringItem::Text(s.into_string().into())
+                                }
                                 Token::StringVariable(s) => state
                                     .tokenize_string(&s, true)
                                     .map_err(|error_type| CompileError {
@@ -681,10 +896,11 @@ impl Compiler {
                                         line_pos: 0,
                                         error_type,
                                     })?,
-                                Token::Number(n) => StringItem::Text(n.to_string()),
-                                Token::Identifier(s) => StringItem::Text(s.to_string()),
-                                Token::Tag(s) => StringItem::Text(format!(":{}", s)),
-                                Token::Invalid(s) => StringItem::Text(s),
+                                Token::Number(n) => StringItem::Text(n.to_string().into()),
+                                Token::Identifier(s) => StringItem::Text(s.to_string().into()),
+                                Token::Tag(s) => StringItem::Text(format!(":{}", s).into()),
+                                Token::CustomTag(s) => StringItem::Text(format!(":{}", s).into()),
+                                Token::Invalid(s) => StringItem::Text(s.into()),
                                 Token::Semicolon => break,
                                 other => panic!("Invalid test param {:?}", other),
                             });
@@ -696,6 +912,21 @@ impl Compiler {
                 }
 
                 Token::Invalid(instruction) => {
+                    let name = instruction.to_ascii_lowercase();
+                    if let Some(extension) = self.extension_commands.get(&name) {
+                        let call = state.parse_extension(
+                            name,
+                            extension,
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        state
+                            .instructions
+                            .push(Instruction::Extension(Box::new(call)));
+                        state.expect_instruction_end()?;
+                        continue;
+                    }
+
                     state.ignore_instruction()?;
                     state.instructions.push(Instruction::Invalid(Invalid {
                         name: instruction,
@@ -711,9 +942,10 @@ impl Compiler {
 
         if state.block_stack.is_empty() {
             Ok(Sieve {
-                instructions: state.instructions,
+                instructions: state.instructions.into_boxed_slice(),
                 num_vars: std::cmp::max(state.vars_num_max, state.vars_num),
                 num_match_vars: state.vars_match_max,
+                compiled: Default::default(),
             })
         } else {
             Err(CompileError {
@@ -791,7 +1023,7 @@ impl<'x> CompilerState<'x> {
 
             for pos in &block.match_test_pos {
                 if let Instruction::Test(test) = &mut self.instructions[*pos] {
-                    let match_type = match test {
+                    let match_type = match test.as_mut() {
                         Test::Address(t) => &mut t.match_type,
                         Test::Body(t) => &mut t.match_type,
                         Test::Date(t) => &mut t.match_type,
@@ -848,6 +1080,83 @@ impl<'x> CompilerState<'x> {
             }));
         }
     }
+
+    pub(crate) fn parse_loop_block(&mut self, btype: Word) -> Result<Block, CompileError> {
+        if let Some(Ok(Token::Tag(Word::Name))) = self.tokens.peek().map(|r| r.map(|t| &t.token)) {
+            let tag = self.tokens.next().unwrap().unwrap();
+            let label = self.tokens.expect_static_string()?;
+            for block in [&self.block].into_iter().chain(self.block_stack.iter()) {
+                if block.label.as_ref().map_or(false, |n| n.eq(&label)) {
+                    return Err(tag.custom(ErrorType::LabelAlreadyDefined(label.into_string())));
+                }
+            }
+            Ok(Block::new(btype).with_label(label))
+        } else {
+            Ok(Block::new(btype))
+        }
+    }
+}
+
+impl Instruction {
+    pub(crate) fn needs_message_body(&self) -> bool {
+        match self {
+            Instruction::ForEveryPartPush
+            | Instruction::ForEveryPart(_)
+            | Instruction::Replace(_)
+            | Instruction::Enclose(_)
+            | Instruction::ExtractText(_)
+            | Instruction::Convert(_)
+            | Instruction::AddHeader(_)
+            | Instruction::DeleteHeader(_) => true,
+            Instruction::Execute(execute) => execute.input == ExecuteInput::Pipe,
+            Instruction::Filter(_) => true,
+            Instruction::Test(test) => match test.as_ref() {
+                Test::Body(_) | Test::Convert(_) => true,
+                Test::Execute(execute) => execute.input == ExecuteInput::Pipe,
+                Test::Filter(_) => true,
+                Test::Header(test) => test.mime_anychild,
+                Test::Address(test) => test.mime_anychild,
+                Test::Exists(test) => test.mime_anychild,
+                Test::Date(test) => test.mime_anychild,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+impl Instruction {
+    pub(crate) fn needs_message(&self) -> bool {
+        match self {
+            Instruction::Notify(_) | Instruction::Vacation(_) => true,
+            Instruction::ForEachPush(ForEachSource::Header(_) | ForEachSource::Address { .. }) => {
+                true
+            }
+            Instruction::Test(test) => match test.as_ref() {
+                Test::Address(_)
+                | Test::Exists(_)
+                | Test::Header(_)
+                | Test::Size(_)
+                | Test::Date(_)
+                | Test::SpamTest(_)
+                | Test::VirusTest(_)
+                | Test::Vacation(_) => true,
+                Test::Duplicate(test) => !matches!(test.dup_match, DupMatch::UniqueId(_)),
+                _ => self.needs_message_body(),
+            },
+            _ => self.needs_message_body(),
+        }
+    }
+}
+
+impl Sieve {
+    /// Returns `true` if this script may access the message body or
+    /// modify the message. Scripts loaded through `include` are not inspected.
+    pub fn needs_message_body(&self) -> bool {
+        self.instructions
+            .iter()
+            .any(|instruction| instruction.needs_message_body())
+    }
 }
 
 impl Block {
@@ -862,6 +1171,7 @@ impl Block {
             match_test_vars: 0,
             if_jmps: vec![],
             break_jmps: vec![],
+            loop_start: 0,
             vars_local: AHashMap::new(),
             capabilities: AHashSet::new(),
             require_pos: usize::MAX,
@@ -21,8 +21,9 @@
  * for more details.
 */
 
-use std::fmt::Display;
+use std::{fmt::Display, sync::OnceLock};
 
+use aho_corasick::AhoCorasick;
 use phf::phf_map;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
@@ -37,6 +38,8 @@ use super::{
 };
 
 pub mod actions;
+pub mod expr;
+pub mod extension;
 pub mod instruction;
 pub mod test;
 pub mod tests;
@@ -91,6 +94,12 @@ pub enum Capability {
 
     // Extensions
     Execute,
+    Expressions,
+    Loops,
+    Modifiers,
+    Procedures,
+    Debug,
+    Filter,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -129,9 +138,23 @@ pub enum Comparator {
     Octet,
     AsciiCaseMap,
     AsciiNumeric,
+    UnicodeCaseMap,
     Other(String),
 }
 
+// Constant keys folded at compile time, matched with a single lookup
+#[derive(Debug, Clone)]
+pub(crate) struct KeySet {
+    pub(crate) keys: Vec<String>,
+    pub(crate) is_contains: bool,
+    pub(crate) automaton: OnceLock<Box<AhoCorasick>>,
+}
+
+// Folded on compilation and again on first use after deserializing,
+// only the key list is serialized
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LazyKeySet(pub(crate) OnceLock<Option<KeySet>>);
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Clear {
     pub(crate) local_vars_idx: u32,
@@ -242,12 +265,22 @@ impl<'x> CompilerState<'x> {
     }
 
     pub(crate) fn parse_comparator(&mut self) -> Result<Comparator, CompileError> {
+        let (line_num, line_pos) = match self.tokens.peek() {
+            Some(Ok(token_info)) => (token_info.line_num, token_info.line_pos),
+            _ => (0, 0),
+        };
         let comparator = self.tokens.expect_static_string()?.into_string();
-        Ok(if let Some(comparator) = COMPARATOR.get(&comparator) {
-            comparator.clone()
+        if let Some(comparator) = COMPARATOR.get(&comparator) {
+            Ok(comparator.clone())
+        } else if self.compiler.comparators.contains(&comparator) {
+            Ok(Comparator::Other(comparator))
         } else {
-            Comparator::Other(comparator)
-        })
+            Err(CompileError {
+                line_num,
+                line_pos,
+                error_type: ErrorType::UnsupportedComparator(comparator),
+            })
+        }
     }
 
     pub(crate) fn parse_static_strings(&mut self) -> Result<Vec<String>, CompileError> {
@@ -292,7 +325,7 @@ impl<'x> CompilerState<'x> {
     pub fn parse_string(&mut self) -> Result<StringItem, CompileError> {
         let next_token = self.tokens.unwrap_next()?;
         match next_token.token {
-            Token::StringConstant(s) => Ok(StringItem::Text(s.into_string())),
+            Token::StringConstant(s) => Ok(StringItem::Text(s.into_string().into())),
             Token::StringVariable(s) => {
                 self.tokenize_string(&s, true)
                     .map_err(|error_type| CompileError {
@@ -316,7 +349,7 @@ impl<'x> CompilerState<'x> {
         let token_info = self.tokens.unwrap_next()?;
         match token_info.token {
             Token::BracketOpen => self.parse_string_list(),
-            Token::StringConstant(s) => Ok(vec![StringItem::Text(s.into_string())]),
+            Token::StringConstant(s) => Ok(vec![StringItem::Text(s.into_string().into())]),
             Token::StringVariable(s) => {
                 self.tokenize_string(&s, true)
                     .map(|s| vec![s])
@@ -335,7 +368,7 @@ impl<'x> CompilerState<'x> {
         token_info: TokenInfo,
     ) -> Result<StringItem, CompileError> {
         match token_info.token {
-            Token::StringConstant(s) => Ok(StringItem::Text(s.into_string())),
+            Token::StringConstant(s) => Ok(StringItem::Text(s.into_string().into())),
             Token::StringVariable(s) => {
                 self.tokenize_string(&s, true)
                     .map_err(|error_type| CompileError {
@@ -353,7 +386,7 @@ impl<'x> CompilerState<'x> {
         token_info: TokenInfo,
     ) -> Result<Vec<StringItem>, CompileError> {
         match token_info.token {
-            Token::StringConstant(s) => Ok(vec![StringItem::Text(s.into_string())]),
+            Token::StringConstant(s) => Ok(vec![StringItem::Text(s.into_string().into())]),
             Token::StringVariable(s) => {
                 self.tokenize_string(&s, true)
                     .map(|s| vec![s])
@@ -374,7 +407,7 @@ impl<'x> CompilerState<'x> {
             let token_info = self.tokens.unwrap_next()?;
             match token_info.token {
                 Token::StringConstant(s) => {
-                    strings.push(StringItem::Text(s.into_string()));
+                    strings.push(StringItem::Text(s.into_string().into()));
                 }
                 Token::StringVariable(s) => {
                     strings.push(self.tokenize_string(&s, true).map_err(|error_type| {
@@ -446,9 +479,15 @@ impl<'x> CompilerState<'x> {
     pub(crate) fn validate_match(
         &mut self,
         match_type: &MatchType,
+        comparator: &Comparator,
         key_list: &[StringItem],
     ) -> Result<(), CompileError> {
         if matches!(match_type, MatchType::Regex(_)) {
+            if let Comparator::Other(comparator) = comparator {
+                return Err(self.tokens.unwrap_next()?.custom(
+                    ErrorType::UnsupportedRegexComparator(comparator.to_string()),
+                ));
+            }
             for key in key_list {
                 if let StringItem::Text(regex) = key {
                     if Regex::new(regex).is_err() {
@@ -464,6 +503,68 @@ impl<'x> CompilerState<'x> {
     }
 }
 
+impl KeySet {
+    pub(crate) fn new(
+        match_type: &MatchType,
+        comparator: &Comparator,
+        key_list: &[StringItem],
+    ) -> Option<KeySet> {
+        let is_contains = match match_type {
+            MatchType::Is => false,
+            MatchType::Contains => true,
+            _ => return None,
+        };
+        let mut keys = Vec::with_capacity(key_list.len());
+        for key in key_list {
+            if let StringItem::Text(key) = key {
+                keys.push(comparator.fold(key)?.into_owned());
+            } else {
+                return None;
+            }
+        }
+        keys.sort_unstable();
+        keys.dedup();
+
+        Some(KeySet {
+            keys,
+            is_contains,
+            automaton: OnceLock::new(),
+        })
+    }
+}
+
+impl LazyKeySet {
+    pub(crate) fn new(
+        match_type: &MatchType,
+        comparator: &Comparator,
+        key_list: &[StringItem],
+    ) -> Self {
+        LazyKeySet(OnceLock::from(KeySet::new(
+            match_type, comparator, key_list,
+        )))
+    }
+
+    pub(crate) fn get(
+        &self,
+        match_type: &MatchType,
+        comparator: &Comparator,
+        key_list: &[StringItem],
+    ) -> Option<&KeySet> {
+        self.0
+            .get_or_init(|| KeySet::new(match_type, comparator, key_list))
+            .as_ref()
+    }
+}
+
+// Derived from the key list, which is compared instead
+impl PartialEq for LazyKeySet {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for LazyKeySet {}
+
 impl Capability {
     pub fn parse(capability: &str) -> Capability {
         if let Some(capability) = CAPABILITIES.get(capability) {
@@ -486,6 +587,7 @@ impl Capability {
             Capability::Comparator(Comparator::AsciiCaseMap),
             Capability::Comparator(Comparator::AsciiNumeric),
             Capability::Comparator(Comparator::Octet),
+            Capability::Comparator(Comparator::UnicodeCaseMap),
             Capability::Body,
             Capability::Convert,
             Capability::Copy,
@@ -524,6 +626,11 @@ impl Capability {
             Capability::SpamTest,
             Capability::SpamTestPlus,
             Capability::VirusTest,
+            Capability::Expressions,
+            Capability::Loops,
+            Capability::Modifiers,
+            Capability::Procedures,
+            Capability::Debug,
         ]
     }
 }
@@ -541,6 +648,7 @@ static COMPARATOR: phf::Map<&'static str, Comparator> = phf_map! {
     "i;octet" => Comparator::Octet,
     "i;ascii-casemap" => Comparator::AsciiCaseMap,
     "i;ascii-numeric" => Comparator::AsciiNumeric,
+    "i;unicode-casemap" => Comparator::UnicodeCaseMap,
 };
 
 impl Invalid {
@@ -585,7 +693,12 @@ impl Display for Capability {
             Capability::Comparator(Comparator::AsciiNumeric) => {
                 f.write_str("comparator-i;ascii-numeric")
             }
-            Capability::Comparator(Comparator::Other(comparator)) => f.write_str(comparator),
+            Capability::Comparator(Comparator::UnicodeCaseMap) => {
+                f.write_str("comparator-i;unicode-casemap")
+            }
+            Capability::Comparator(Comparator::Other(comparator)) => {
+                write!(f, "comparator-{}", comparator)
+            }
             Capability::Body => f.write_str("body"),
             Capability::Convert => f.write_str("convert"),
             Capability::Copy => f.write_str("copy"),
@@ -625,6 +738,12 @@ impl Display for Capability {
             Capability::SpamTestPlus => f.write_str("spamtestplus"),
             Capability::VirusTest => f.write_str("virustest"),
             Capability::Execute => f.write_str("vnd.stalwart.execute"),
+            Capability::Expressions => f.write_str("vnd.stalwart.expressions"),
+            Capability::Loops => f.write_str("vnd.stalwart.loops"),
+            Capability::Modifiers => f.write_str("vnd.stalwart.modifiers"),
+            Capability::Procedures => f.write_str("vnd.stalwart.procedures"),
+            Capability::Debug => f.write_str("vnd.dovecot.debug"),
+            Capability::Filter => f.write_str("vnd.dovecot.filter"),
             Capability::Other(capability) => f.write_str(capability),
         }
     }
@@ -640,6 +759,7 @@ static CAPABILITIES: phf::Map<&'static str, Capability> = phf_map! {
     "comparator-i;octet" => Capability::Comparator(Comparator::Octet),
     "comparator-i;ascii-casemap" => Capability::Comparator(Comparator::AsciiCaseMap),
     "comparator-i;ascii-numeric" => Capability::Comparator(Comparator::AsciiNumeric),
+    "comparator-i;unicode-casemap" => Capability::Comparator(Comparator::UnicodeCaseMap),
     "body" => Capability::Body,
     "convert" => Capability::Convert,
     "copy" => Capability::Copy,
@@ -681,4 +801,10 @@ static CAPABILITIES: phf::Map<&'static str, Capability> = phf_map! {
 
     // Extensions
     "vnd.stalwart.execute" => Capability::Execute,
+    "vnd.stalwart.expressions" => Capability::Expressions,
+    "vnd.stalwart.loops" => Capability::Loops,
+    "vnd.stalwart.modifiers" => Capability::Modifiers,
+    "vnd.stalwart.procedures" => Capability::Procedures,
+    "vnd.dovecot.debug" => Capability::Debug,
+    "vnd.dovecot.filter" => Capability::Filter,
 };
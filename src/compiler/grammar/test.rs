@@ -30,6 +30,7 @@ use crate::compiler::{
 
 use super::{
     actions::{action_convert::Convert, action_vacation::TestVacation},
+    extension::ExtensionCall,
     instruction::{CompilerState, Instruction},
     tests::{
         test_address::TestAddress,
@@ -37,7 +38,8 @@ use super::{
         test_date::{TestCurrentDate, TestDate},
         test_duplicate::TestDuplicate,
         test_envelope::TestEnvelope,
-        test_execute::Execute,
+        test_eval::TestEval,
+        test_execute::{Execute, Filter},
         test_exists::TestExists,
         test_extlists::TestValidExtList,
         test_hasflag::TestHasFlag,
@@ -116,6 +118,15 @@ pub(crate) enum Test {
     // Execute external command
     Execute(Execute),
 
+    // vnd.dovecot.filter
+    Filter(Filter),
+
+    // Expressions
+    Eval(TestEval),
+
+    // Vendor extensions
+    Extension(ExtensionCall),
+
     #[cfg(test)]
     External(
         (
@@ -148,400 +159,446 @@ impl<'x> CompilerState<'x> {
         loop {
             let token_info = self.tokens.unwrap_next()?;
             self.reset_param_check();
-            let test =
-                match token_info.token {
-                    Token::Comma
-                        if !block_stack.is_empty()
-                            && matches!(self.instructions.last(), Some(Instruction::Test(_)))
-                            && matches!(
-                                self.tokens.peek(),
-                                Some(Ok(TokenInfo {
-                                    token: Token::Identifier(_),
-                                    ..
-                                }))
-                            ) =>
-                    {
-                        is_not = block.is_not;
-                        block.jmps.push(self.instructions.len());
-                        self.instructions.push(if block.is_all {
-                            Instruction::Jz(usize::MAX)
-                        } else {
-                            Instruction::Jnz(usize::MAX)
-                        });
-                        continue;
-                    }
-                    Token::ParenthesisOpen => {
-                        block.p_count += 1;
-                        continue;
-                    }
-                    Token::ParenthesisClose => {
-                        if block.p_count > 0 {
-                            block.p_count -= 1;
-                            continue;
-                        } else if let Some(prev_block) = block_stack.pop() {
-                            let cur_pos = self.instructions.len();
-                            for jmp_pos in block.jmps {
-                                if let Instruction::Jnz(jmp_pos) | Instruction::Jz(jmp_pos) =
-                                    &mut self.instructions[jmp_pos]
-                                {
-                                    *jmp_pos = cur_pos;
-                                } else {
-                                    debug_assert!(false, "This should not have happened")
-                                }
-                            }
-
-                            block = prev_block;
-                            is_not = block.is_not;
-                            if block_stack.is_empty() {
-                                break;
-                            } else {
-                                continue;
-                            }
-                        } else {
-                            return Err(token_info.expected("test name"));
-                        }
-                    }
-                    Token::Identifier(Word::Not) => {
-                        if !matches!(
+            let test = match token_info.token {
+                Token::Comma
+                    if !block_stack.is_empty()
+                        && matches!(self.instructions.last(), Some(Instruction::Test(_)))
+                        && matches!(
                             self.tokens.peek(),
                             Some(Ok(TokenInfo {
-                                token: Token::Identifier(_) | Token::Invalid(_),
+                                token: Token::Identifier(_),
                                 ..
                             }))
-                        ) {
-                            return Err(token_info.expected("test name"));
-                        }
-                        is_not = !is_not;
+                        ) =>
+                {
+                    is_not = block.is_not;
+                    block.jmps.push(self.instructions.len());
+                    self.instructions.push(if block.is_all {
+                        Instruction::Jz(u32::MAX)
+                    } else {
+                        Instruction::Jnz(u32::MAX)
+                    });
+                    continue;
+                }
+                Token::ParenthesisOpen => {
+                    block.p_count += 1;
+                    continue;
+                }
+                Token::ParenthesisClose => {
+                    if block.p_count > 0 {
+                        block.p_count -= 1;
                         continue;
-                    }
-                    Token::Identifier(word @ (Word::AnyOf | Word::AllOf)) => {
-                        if block_stack.len() < self.tokens.compiler.max_nested_tests {
-                            self.tokens.expect_token(Token::ParenthesisOpen)?;
-                            block_stack.push(block);
-                            let (is_all, block_is_not) = if word == Word::AllOf {
-                                if !is_not {
-                                    (true, false)
-                                } else {
-                                    (false, true)
-                                }
-                            } else if !is_not {
-                                (false, false)
+                    } else if let Some(prev_block) = block_stack.pop() {
+                        let cur_pos = self.instructions.len() as u32;
+                        for jmp_pos in block.jmps {
+                            if let Instruction::Jnz(jmp_pos) | Instruction::Jz(jmp_pos) =
+                                &mut self.instructions[jmp_pos]
+                            {
+                                *jmp_pos = cur_pos;
                             } else {
-                                (true, true)
-                            };
-                            block = Block {
-                                is_all,
-                                is_not: block_is_not,
-                                p_count: 0,
-                                jmps: Vec::new(),
-                            };
-                            is_not = block_is_not;
-                            continue;
-                        } else {
-                            return Err(CompileError {
-                                line_num: token_info.line_num,
-                                line_pos: token_info.line_pos,
-                                error_type: ErrorType::TooManyNestedTests,
-                            });
+                                debug_assert!(false, "This should not have happened")
+                            }
                         }
-                    }
-                    Token::Identifier(Word::True) => {
-                        if !is_not {
-                            Test::True
+
+                        block = prev_block;
+                        is_not = block.is_not;
+                        if block_stack.is_empty() {
+                            break;
                         } else {
-                            is_not = false;
-                            Test::False
+                            continue;
                         }
-                    }
-                    Token::Identifier(Word::False) => {
-                        if !is_not {
-                            Test::False
+                    } else {
+                        return Err(token_info.expected("test name"));
+                    }
+                }
+                Token::Identifier(Word::Not) => {
+                    if !matches!(
+                        self.tokens.peek(),
+                        Some(Ok(TokenInfo {
+                            token: Token::Identifier(_) | Token::Invalid(_),
+                            ..
+                        }))
+                    ) {
+                        return Err(token_info.expected("test name"));
+                    }
+                    is_not = !is_not;
+                    continue;
+                }
+                Token::Identifier(word @ (Word::AnyOf | Word::AllOf)) => {
+                    if block_stack.len() < self.tokens.compiler.max_nested_tests {
+                        self.tokens.expect_token(Token::ParenthesisOpen)?;
+                        block_stack.push(block);
+                        let (is_all, block_is_not) = if word == Word::AllOf {
+                            if !is_not {
+                                (true, false)
+                            } else {
+                                (false, true)
+                            }
+                        } else if !is_not {
+                            (false, false)
                         } else {
-                            is_not = false;
-                            Test::True
-                        }
-                    }
-                    Token::Identifier(Word::Address) => self.parse_test_address()?,
-                    Token::Identifier(Word::Envelope) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Envelope.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
-                        self.parse_test_envelope()?
-                    }
-                    Token::Identifier(Word::Header) => self.parse_test_header()?,
-                    Token::Identifier(Word::Size) => self.parse_test_size()?,
-                    Token::Identifier(Word::Exists) => self.parse_test_exists()?,
-
-                    // RFC 5173
-                    Token::Identifier(Word::Body) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Body.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
-                        self.parse_test_body()?
-                    }
-
-                    // RFC 6558
-                    Token::Identifier(Word::Convert) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Convert.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
-                        self.parse_test_convert()?
-                    }
-
-                    // RFC 5260
-                    Token::Identifier(Word::Date) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Date.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
-                        self.parse_test_date()?
-                    }
-                    Token::Identifier(Word::CurrentDate) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Date.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
-                        self.parse_test_currentdate()?
-                    }
-
-                    // RFC 7352
-                    Token::Identifier(Word::Duplicate) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Duplicate.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
-                        self.parse_test_duplicate()?
-                    }
-
-                    // RFC 5229
-                    Token::Identifier(Word::String) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Variables.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
-                        self.parse_test_string()?
-                    }
-
-                    // RFC 5435
-                    Token::Identifier(Word::NotifyMethodCapability) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Enotify.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
-                        self.parse_test_notify_method_capability()?
-                    }
-                    Token::Identifier(Word::ValidNotifyMethod) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Enotify.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
-                        self.parse_test_valid_notify_method()?
-                    }
-
-                    // RFC 5183
-                    Token::Identifier(Word::Environment) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Environment.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
-                        self.parse_test_environment()?
-                    }
-
-                    // RFC 6134
-                    Token::Identifier(Word::ValidExtList) => {
-                        self.validate_argument(
-                            0,
-                            Capability::ExtLists.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
-                        self.parse_test_valid_ext_list()?
-                    }
-
-                    // RFC 5463
-                    Token::Identifier(Word::Ihave) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Ihave.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
-                        self.parse_test_ihave()?
-                    }
-
-                    // RFC 5232
-                    Token::Identifier(Word::HasFlag) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Imap4Flags.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
-                        self.parse_test_hasflag()?
-                    }
-
-                    // RFC 5490
-                    Token::Identifier(Word::MailboxExists) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Mailbox.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
-                        self.parse_test_mailboxexists()?
-                    }
-                    Token::Identifier(Word::Metadata) => {
-                        self.validate_argument(
-                            0,
-                            Capability::MboxMetadata.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
-                        self.parse_test_metadata()?
-                    }
-                    Token::Identifier(Word::MetadataExists) => {
-                        self.validate_argument(
-                            0,
-                            Capability::MboxMetadata.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
-                        self.parse_test_metadataexists()?
-                    }
-                    Token::Identifier(Word::ServerMetadata) => {
-                        self.validate_argument(
-                            0,
-                            Capability::ServerMetadata.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
-                        self.parse_test_servermetadata()?
-                    }
-                    Token::Identifier(Word::ServerMetadataExists) => {
-                        self.validate_argument(
-                            0,
-                            Capability::ServerMetadata.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
-                        self.parse_test_servermetadataexists()?
-                    }
-
-                    // RFC 9042
-                    Token::Identifier(Word::MailboxIdExists) => {
-                        self.validate_argument(
-                            0,
-                            Capability::MailboxId.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
-                        self.parse_test_mailboxidexists()?
-                    }
-
-                    // RFC 5235
-                    Token::Identifier(Word::SpamTest) => {
-                        self.validate_argument(
-                            0,
-                            Capability::SpamTest.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
-                        self.parse_test_spamtest()?
-                    }
-                    Token::Identifier(Word::VirusTest) => {
-                        self.validate_argument(
-                            0,
-                            Capability::VirusTest.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
-                        self.parse_test_virustest()?
-                    }
-
-                    // RFC 8579
-                    Token::Identifier(Word::SpecialUseExists) => {
-                        self.validate_argument(
-                            0,
-                            Capability::SpecialUse.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
-                        self.parse_test_specialuseexists()?
+                            (true, true)
+                        };
+                        block = Block {
+                            is_all,
+                            is_not: block_is_not,
+                            p_count: 0,
+                            jmps: Vec::new(),
+                        };
+                        is_not = block_is_not;
+                        continue;
+                    } else {
+                        return Err(CompileError {
+                            line_num: token_info.line_num,
+                            line_pos: token_info.line_pos,
+                            error_type: ErrorType::TooManyNestedTests,
+                        });
                     }
-
-                    // RFC 8579
-                    Token::Identifier(Word::Execute) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Execute.into(),
+                }
+                Token::Identifier(Word::True) => {
+                    if !is_not {
+                        Test::True
+                    } else {
+                        is_not = false;
+                        Test::False
+                    }
+                }
+                Token::Identifier(Word::False) => {
+                    if !is_not {
+                        Test::False
+                    } else {
+                        is_not = false;
+                        Test::True
+                    }
+                }
+                Token::Identifier(Word::Address) => self.parse_test_address()?,
+                Token::Identifier(Word::Envelope) => {
+                    self.validate_argument(
+                        0,
+                        Capability::Envelope.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_envelope()?
+                }
+                Token::Identifier(Word::Header) => self.parse_test_header()?,
+                Token::Identifier(Word::Size) => self.parse_test_size()?,
+                Token::Identifier(Word::Exists) => self.parse_test_exists()?,
+
+                // RFC 5173
+                Token::Identifier(Word::Body) => {
+                    self.validate_argument(
+                        0,
+                        Capability::Body.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_body()?
+                }
+
+                // RFC 6558
+                Token::Identifier(Word::Convert) => {
+                    self.validate_argument(
+                        0,
+                        Capability::Convert.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_convert()?
+                }
+
+                // RFC 5260
+                Token::Identifier(Word::Date) => {
+                    self.validate_argument(
+                        0,
+                        Capability::Date.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_date()?
+                }
+                Token::Identifier(Word::CurrentDate) => {
+                    self.validate_argument(
+                        0,
+                        Capability::Date.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_currentdate()?
+                }
+
+                // RFC 7352
+                Token::Identifier(Word::Duplicate) => {
+                    self.validate_argument(
+                        0,
+                        Capability::Duplicate.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_duplicate()?
+                }
+
+                // RFC 5229
+                Token::Identifier(Word::String) => {
+                    self.validate_argument(
+                        0,
+                        Capability::Variables.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_string()?
+                }
+
+                // RFC 5435
+                Token::Identifier(Word::NotifyMethodCapability) => {
+                    self.validate_argument(
+                        0,
+                        Capability::Enotify.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_notify_method_capability()?
+                }
+                Token::Identifier(Word::ValidNotifyMethod) => {
+                    self.validate_argument(
+                        0,
+                        Capability::Enotify.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_valid_notify_method()?
+                }
+
+                // RFC 5183
+                Token::Identifier(Word::Environment) => {
+                    self.validate_argument(
+                        0,
+                        Capability::Environment.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_environment()?
+                }
+
+                // RFC 6134
+                Token::Identifier(Word::ValidExtList) => {
+                    self.validate_argument(
+                        0,
+                        Capability::ExtLists.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_valid_ext_list()?
+                }
+
+                // RFC 5463
+                Token::Identifier(Word::Ihave) => {
+                    self.validate_argument(
+                        0,
+                        Capability::Ihave.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_ihave()?
+                }
+
+                // RFC 5232
+                Token::Identifier(Word::HasFlag) => {
+                    self.validate_argument(
+                        0,
+                        Capability::Imap4Flags.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_hasflag()?
+                }
+
+                // RFC 5490
+                Token::Identifier(Word::MailboxExists) => {
+                    self.validate_argument(
+                        0,
+                        Capability::Mailbox.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_mailboxexists()?
+                }
+                Token::Identifier(Word::Metadata) => {
+                    self.validate_argument(
+                        0,
+                        Capability::MboxMetadata.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_metadata()?
+                }
+                Token::Identifier(Word::MetadataExists) => {
+                    self.validate_argument(
+                        0,
+                        Capability::MboxMetadata.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_metadataexists()?
+                }
+                Token::Identifier(Word::ServerMetadata) => {
+                    self.validate_argument(
+                        0,
+                        Capability::ServerMetadata.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_servermetadata()?
+                }
+                Token::Identifier(Word::ServerMetadataExists) => {
+                    self.validate_argument(
+                        0,
+                        Capability::ServerMetadata.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_servermetadataexists()?
+                }
+
+                // RFC 9042
+                Token::Identifier(Word::MailboxIdExists) => {
+                    self.validate_argument(
+                        0,
+                        Capability::MailboxId.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_mailboxidexists()?
+                }
+
+                // RFC 5235
+                Token::Identifier(Word::SpamTest) => {
+                    self.validate_argument(
+                        0,
+                        Capability::SpamTest.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_spamtest()?
+                }
+                Token::Identifier(Word::VirusTest) => {
+                    self.validate_argument(
+                        0,
+                        Capability::VirusTest.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_virustest()?
+                }
+
+                // RFC 8579
+                Token::Identifier(Word::SpecialUseExists) => {
+                    self.validate_argument(
+                        0,
+                        Capability::SpecialUse.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_specialuseexists()?
+                }
+
+                // RFC 8579
+                Token::Identifier(Word::Execute) => {
+                    self.validate_argument(
+                        0,
+                        Capability::Execute.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_execute()?
+                }
+
+                // vnd.dovecot.filter
+                Token::Identifier(Word::Filter) => {
+                    self.validate_argument(
+                        0,
+                        Capability::Filter.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_filter()?
+                }
+
+                // Expressions
+                Token::Identifier(Word::Eval) => {
+                    self.validate_argument(
+                        0,
+                        Capability::Expressions.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    self.parse_test_eval()?
+                }
+
+                Token::Identifier(word) => {
+                    let name = word.to_string();
+                    if let Some(extension) = self.compiler.extension_tests.get(&name) {
+                        Test::Extension(self.parse_extension(
+                            name,
+                            extension,
                             token_info.line_num,
                             token_info.line_pos,
-                        )?;
-                        self.parse_test_execute()?
-                    }
-
-                    Token::Identifier(word) => {
+                        )?)
+                    } else {
                         self.ignore_test()?;
                         Test::Invalid(Invalid {
-                            name: word.to_string(),
+                            name,
                             line_num: token_info.line_num,
                             line_pos: token_info.line_pos,
                         })
                     }
-                    #[cfg(test)]
-                    Token::Invalid(name) if name.contains("test") => {
-                        use crate::compiler::lexer::string::StringItem;
-                        use crate::runtime::string::IntoString;
-
-                        let mut params = Vec::new();
-                        while !matches!(
-                            self.tokens.peek().map(|r| r.map(|t| &t.token)),
-                            Some(Ok(Token::Comma
-                                | Token::ParenthesisClose
-                                | Token::CurlyOpen))
-                        ) {
-                            params.push(match self.tokens.unwrap_next()?.token {
-                                Token::StringConstant(s) => StringItem::Text(s.into_string()),
-                                Token::StringVariable(s) => self
-                                    .tokenize_string(&s, true)
-                                    .map_err(|error_type| CompileError {
+                }
+                #[cfg(test)]
+                Token::Invalid(name) if name.contains("test") => {
+                    use crate::compiler::lexer::string::StringItem;
+                    use crate::runtime::string::IntoString;
+
+                    let mut params = Vec::new();
+                    while !matches!(
+                        self.tokens.peek().map(|r| r.map(|t| &t.token)),
+                        Some(Ok(Token::Comma
+                            | Token::ParenthesisClose
+                            | Token::CurlyOpen))
+                    ) {
+                        params.push(match self.tokens.unwrap_next()?.token {
+                            Token::StringConstant(s) => StringItem::Text(s.into_string().into()),
+                            Token::StringVariable(s) => {
+                                self.tokenize_string(&s, true).map_err(|error_type| {
+                                    CompileError {
                                         line_num: 0,
                                         line_pos: 0,
                                         error_type,
-                                    })?,
-                                Token::Number(n) => StringItem::Text(n.to_string()),
-                                Token::Identifier(s) => StringItem::Text(s.to_string()),
-                                Token::Tag(s) => StringItem::Text(format!(":{}", s)),
-                                Token::Invalid(s) => StringItem::Text(s),
-                                other => panic!("Invalid test param {:?}", other),
-                            });
-                        }
-                        Test::External((name, params, false))
+                                    }
+                                })?
+                            }
+                            Token::Number(n) => StringItem::Text(n.to_string().into()),
+                            Token::Identifier(s) => StringItem::Text(s.to_string().into()),
+                            Token::Tag(s) => StringItem::Text(format!(":{}", s).into()),
+                            Token::CustomTag(s) => StringItem::Text(format!(":{}", s).into()),
+                            Token::Invalid(s) => StringItem::Text(s.into()),
+                            other => panic!("Invalid test param {:?}", other),
+                        });
                     }
-                    Token::Invalid(name) => {
+                    Test::External((name, params, false))
+                }
+                Token::Invalid(name) => {
+                    if let Some(extension) = self
+                        .compiler
+                        .extension_tests
+                        .get(&name.to_ascii_lowercase())
+                    {
+                        Test::Extension(self.parse_extension(
+                            name.to_ascii_lowercase(),
+                            extension,
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?)
+                    } else {
                         self.ignore_test()?;
                         Test::Invalid(Invalid {
                             name,
@@ -549,8 +606,9 @@ impl<'x> CompilerState<'x> {
                             line_pos: token_info.line_pos,
                         })
                     }
-                    _ => return Err(token_info.expected("test name")),
-                };
+                }
+                _ => return Err(token_info.expected("test name")),
+            };
 
             while block.p_count > 0 {
                 self.tokens.expect_token(Token::ParenthesisClose)?;
@@ -558,9 +616,9 @@ impl<'x> CompilerState<'x> {
             }
 
             self.instructions.push(Instruction::Test(if !is_not {
-                test
+                Box::new(test)
             } else {
-                test.set_not()
+                Box::new(test.set_not())
             }));
 
             if block_stack.is_empty() {
@@ -568,7 +626,7 @@ impl<'x> CompilerState<'x> {
             }
         }
 
-        self.instructions.push(Instruction::Jz(usize::MAX));
+        self.instructions.push(Instruction::Jz(u32::MAX));
         Ok(())
     }
 }
@@ -650,6 +708,15 @@ impl Test {
             Test::Execute(op) => {
                 op.is_not = true;
             }
+            Test::Filter(op) => {
+                op.is_not = true;
+            }
+            Test::Eval(op) => {
+                op.is_not = true;
+            }
+            Test::Extension(op) => {
+                op.is_not = true;
+            }
             Test::Vacation(_) | Test::Invalid(_) => {}
 
             #[cfg(test)]
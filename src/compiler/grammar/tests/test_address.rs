@@ -24,7 +24,7 @@
 use serde::{Deserialize, Serialize};
 
 use crate::compiler::{
-    grammar::{instruction::CompilerState, test::Test, Capability, Comparator},
+    grammar::{instruction::CompilerState, test::Test, Capability, Comparator, LazyKeySet},
     lexer::{string::StringItem, word::Word, Token},
     CompileError,
 };
@@ -35,6 +35,8 @@ use crate::compiler::grammar::{AddressPart, MatchType};
 pub(crate) struct TestAddress {
     pub header_list: Vec<StringItem>,
     pub key_list: Vec<StringItem>,
+    #[serde(skip)]
+    pub key_set: LazyKeySet,
     pub address_part: AddressPart,
     pub match_type: MatchType,
     pub comparator: Comparator,
@@ -151,10 +153,11 @@ impl<'x> CompilerState<'x> {
         if !mime && mime_anychild {
             return Err(self.tokens.unwrap_next()?.missing_tag(":mime"));
         }
-        self.validate_match(&match_type, &key_list)?;
+        self.validate_match(&match_type, &comparator, &key_list)?;
 
         Ok(Test::Address(TestAddress {
             header_list: header_list.unwrap(),
+            key_set: LazyKeySet::new(&match_type, &comparator, &key_list),
             key_list,
             address_part,
             match_type,
@@ -24,7 +24,7 @@
 use serde::{Deserialize, Serialize};
 
 use crate::compiler::{
-    grammar::{instruction::CompilerState, Capability, Comparator},
+    grammar::{instruction::CompilerState, Capability, Comparator, LazyKeySet},
     lexer::{string::StringItem, word::Word, Token},
     CompileError,
 };
@@ -34,6 +34,8 @@ use crate::compiler::grammar::{test::Test, MatchType};
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) struct TestBody {
     pub key_list: Vec<StringItem>,
+    #[serde(skip)]
+    pub key_set: LazyKeySet,
     pub body_transform: BodyTransform,
     pub match_type: MatchType,
     pub comparator: Comparator,
@@ -101,9 +103,10 @@ impl<'x> CompilerState<'x> {
                 }
             }
         }
-        self.validate_match(&match_type, &key_list)?;
+        self.validate_match(&match_type, &comparator, &key_list)?;
 
         Ok(Test::Body(TestBody {
+            key_set: LazyKeySet::new(&match_type, &comparator, &key_list),
             key_list,
             body_transform,
             match_type,
@@ -172,7 +172,7 @@ impl<'x> CompilerState<'x> {
                     if header_name.is_none() {
                         let header = self.parse_string_token(token_info)?;
                         if let StringItem::Text(header_name) = &header {
-                            if HeaderName::parse(header_name).is_none() {
+                            if HeaderName::parse(header_name.as_ref()).is_none() {
                                 return Err(self
                                     .tokens
                                     .unwrap_next()?
@@ -203,7 +203,7 @@ impl<'x> CompilerState<'x> {
         if !mime && mime_anychild {
             return Err(self.tokens.unwrap_next()?.missing_tag(":mime"));
         }
-        self.validate_match(&match_type, &key_list)?;
+        self.validate_match(&match_type, &comparator, &key_list)?;
 
         Ok(Test::Date(TestDate {
             header_name: header_name.unwrap(),
@@ -279,7 +279,7 @@ impl<'x> CompilerState<'x> {
                 }
             }
         }
-        self.validate_match(&match_type, &key_list)?;
+        self.validate_match(&match_type, &comparator, &key_list)?;
 
         Ok(Test::CurrentDate(TestCurrentDate {
             key_list,
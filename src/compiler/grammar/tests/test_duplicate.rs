@@ -71,7 +71,7 @@ impl<'x> CompilerState<'x> {
                     self.tokens.next();
                     let header = self.parse_string()?;
                     if let StringItem::Text(header_name) = &header {
-                        if HeaderName::parse(header_name).is_none() {
+                        if HeaderName::parse(header_name.as_ref()).is_none() {
                             return Err(self
                                 .tokens
                                 .unwrap_next()?
@@ -26,7 +26,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     compiler::{
-        grammar::{instruction::CompilerState, Capability, Comparator},
+        grammar::{instruction::CompilerState, Capability, Comparator, LazyKeySet},
         lexer::{string::StringItem, word::Word, Token},
         CompileError, ErrorType,
     },
@@ -40,6 +40,8 @@ use crate::compiler::grammar::{test::Test, AddressPart, MatchType};
 pub(crate) struct TestEnvelope {
     pub envelope_list: Vec<Envelope>,
     pub key_list: Vec<StringItem>,
+    #[serde(skip)]
+    pub key_set: LazyKeySet,
     pub address_part: AddressPart,
     pub match_type: MatchType,
     pub comparator: Comparator,
@@ -190,10 +192,11 @@ impl<'x> CompilerState<'x> {
                 }
             }
         }
-        self.validate_match(&match_type, &key_list)?;
+        self.validate_match(&match_type, &comparator, &key_list)?;
 
         Ok(Test::Envelope(TestEnvelope {
             envelope_list: envelope_list.unwrap(),
+            key_set: LazyKeySet::new(&match_type, &comparator, &key_list),
             key_list,
             address_part,
             match_type,
@@ -23,7 +23,7 @@
 
 use crate::{
     compiler::{
-        grammar::{instruction::CompilerState, Capability, Comparator},
+        grammar::{instruction::CompilerState, Capability, Comparator, LazyKeySet},
         lexer::{string::StringItem, word::Word, Token},
         CompileError,
     },
@@ -85,10 +85,11 @@ impl<'x> CompilerState<'x> {
                 }
             }
         }
-        self.validate_match(&match_type, &key_list)?;
+        self.validate_match(&match_type, &comparator, &key_list)?;
 
         Ok(Test::Environment(TestString {
             source: vec![name.unwrap()],
+            key_set: LazyKeySet::new(&match_type, &comparator, &key_list),
             key_list,
             match_type,
             comparator,
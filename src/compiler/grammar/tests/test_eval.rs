@@ -0,0 +1,65 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use serde::{Deserialize, Serialize};
+
+use crate::compiler::{
+    grammar::{
+        expr::{parse_expression, ExpressionItem},
+        instruction::CompilerState,
+    },
+    lexer::tokenizer::TokenInfo,
+    CompileError, ErrorType,
+};
+
+use crate::compiler::grammar::test::Test;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct TestEval {
+    pub expression: Vec<ExpressionItem>,
+    pub is_not: bool,
+}
+
+impl<'x> CompilerState<'x> {
+    pub(crate) fn parse_test_eval(&mut self) -> Result<Test, CompileError> {
+        let token_info = self.tokens.unwrap_next()?;
+        Ok(Test::Eval(TestEval {
+            expression: self.parse_expression_token(token_info)?,
+            is_not: false,
+        }))
+    }
+
+    pub(crate) fn parse_expression_token(
+        &mut self,
+        token_info: TokenInfo,
+    ) -> Result<Vec<ExpressionItem>, CompileError> {
+        let line_num = token_info.line_num;
+        let line_pos = token_info.line_pos;
+        let expr = self.parse_string_token(token_info)?;
+        parse_expression(&expr).map_err(|err| CompileError {
+            line_num,
+            line_pos,
+            error_type: ErrorType::InvalidExpression(err),
+        })
+    }
+}
@@ -23,9 +23,12 @@
 
 use serde::{Deserialize, Serialize};
 
+use crate::compiler::grammar::actions::action_set::Variable;
 use crate::compiler::grammar::instruction::{CompilerState, Instruction};
 use crate::compiler::lexer::string::StringItem;
-use crate::compiler::CompileError;
+use crate::compiler::lexer::word::Word;
+use crate::compiler::lexer::Token;
+use crate::compiler::{CompileError, ErrorType};
 
 use crate::compiler::grammar::test::Test;
 
@@ -33,6 +36,22 @@ use crate::compiler::grammar::test::Test;
 pub(crate) struct Execute {
     pub command: StringItem,
     pub arguments: Vec<StringItem>,
+    pub input: ExecuteInput,
+    pub output: Option<Variable>,
+    pub is_not: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum ExecuteInput {
+    None,
+    Pipe,
+    Text(StringItem),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Filter {
+    pub program: StringItem,
+    pub arguments: Vec<StringItem>,
     pub is_not: bool,
 }
 
@@ -43,21 +62,96 @@ pub(crate) struct Error {
 
 impl<'x> CompilerState<'x> {
     pub(crate) fn parse_execute(&mut self) -> Result<(), CompileError> {
-        let command = Execute {
-            command: self.parse_string()?,
-            arguments: self.parse_strings()?,
-            is_not: false,
-        };
-        self.instructions.push(Instruction::Execute(command));
+        let command = self.parse_execute_arguments()?;
+        self.instructions
+            .push(Instruction::Execute(Box::new(command)));
 
         Ok(())
     }
 
     pub(crate) fn parse_test_execute(&mut self) -> Result<Test, CompileError> {
-        Ok(Test::Execute(Execute {
-            command: self.parse_string()?,
+        Ok(Test::Execute(self.parse_execute_arguments()?))
+    }
+
+    pub(crate) fn parse_filter(&mut self) -> Result<(), CompileError> {
+        let filter = self.parse_filter_arguments()?;
+        self.instructions
+            .push(Instruction::Filter(Box::new(filter)));
+
+        Ok(())
+    }
+
+    pub(crate) fn parse_test_filter(&mut self) -> Result<Test, CompileError> {
+        Ok(Test::Filter(self.parse_filter_arguments()?))
+    }
+
+    fn parse_filter_arguments(&mut self) -> Result<Filter, CompileError> {
+        let token_info = self.tokens.unwrap_next()?;
+        if self.is_inside_foreverypart() {
+            return Err(token_info.custom(ErrorType::FilterInsideForEveryPart));
+        }
+        let program = self.parse_string_token(token_info)?;
+        let arguments = if let Some(Ok(
+            Token::StringConstant(_) | Token::StringVariable(_) | Token::BracketOpen,
+        )) = self.tokens.peek().map(|r| r.map(|t| &t.token))
+        {
+            self.parse_strings()?
+        } else {
+            Vec::new()
+        };
+
+        Ok(Filter {
+            program,
+            arguments,
+            is_not: false,
+        })
+    }
+
+    fn parse_execute_arguments(&mut self) -> Result<Execute, CompileError> {
+        let mut input = ExecuteInput::None;
+        let mut output = None;
+        let command;
+
+        loop {
+            let token_info = self.tokens.unwrap_next()?;
+            match token_info.token {
+                Token::Tag(Word::Pipe) => {
+                    self.validate_argument(1, None, token_info.line_num, token_info.line_pos)?;
+                    if self.is_inside_foreverypart() {
+                        return Err(token_info.custom(ErrorType::FilterInsideForEveryPart));
+                    }
+                    input = ExecuteInput::Pipe;
+                }
+                Token::Tag(Word::Input) => {
+                    self.validate_argument(1, None, token_info.line_num, token_info.line_pos)?;
+                    input = ExecuteInput::Text(self.parse_string()?);
+                }
+                Token::Tag(Word::Output) => {
+                    self.validate_argument(2, None, token_info.line_num, token_info.line_pos)?;
+                    let token_info = self.tokens.unwrap_next()?;
+                    output = self.parse_variable_name(token_info)?.into();
+                }
+                _ => {
+                    command = self.parse_string_token(token_info)?;
+                    break;
+                }
+            }
+        }
+
+        Ok(Execute {
+            command,
             arguments: self.parse_strings()?,
+            input,
+            output,
             is_not: false,
-        }))
+        })
+    }
+
+    // A replaced message would invalidate the part ids of the enclosing loops
+    fn is_inside_foreverypart(&self) -> bool {
+        [&self.block]
+            .into_iter()
+            .chain(self.block_stack.iter())
+            .any(|block| matches!(block.btype, Word::ForEveryPart))
     }
 }
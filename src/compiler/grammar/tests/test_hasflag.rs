@@ -103,13 +103,15 @@ impl<'x> CompilerState<'x> {
                     for variable in maybe_variables {
                         match variable {
                             StringItem::Text(var_name) => {
-                                variable_list.push(self.register_variable(var_name).map_err(
-                                    |error_type| CompileError {
-                                        line_num,
-                                        line_pos,
-                                        error_type,
-                                    },
-                                )?);
+                                variable_list.push(
+                                    self.register_variable(var_name.to_string()).map_err(
+                                        |error_type| CompileError {
+                                            line_num,
+                                            line_pos,
+                                            error_type,
+                                        },
+                                    )?,
+                                );
                             }
                             _ => {
                                 return Err(self
@@ -120,7 +122,7 @@ impl<'x> CompilerState<'x> {
                         }
                     }
                     let flags = self.parse_strings()?;
-                    self.validate_match(&match_type, &flags)?;
+                    self.validate_match(&match_type, &comparator, &flags)?;
 
                     Ok(Test::HasFlag(TestHasFlag {
                         comparator,
@@ -137,7 +139,7 @@ impl<'x> CompilerState<'x> {
                 }
             }
             _ => {
-                self.validate_match(&match_type, &maybe_variables)?;
+                self.validate_match(&match_type, &comparator, &maybe_variables)?;
 
                 Ok(Test::HasFlag(TestHasFlag {
                     comparator,
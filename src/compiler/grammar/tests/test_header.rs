@@ -25,7 +25,10 @@ use mail_parser::HeaderName;
 use serde::{Deserialize, Serialize};
 
 use crate::compiler::{
-    grammar::{actions::action_mime::MimeOpts, instruction::CompilerState, Capability, Comparator},
+    grammar::{
+        actions::action_mime::MimeOpts, instruction::CompilerState, Capability, Comparator,
+        LazyKeySet,
+    },
     lexer::{string::StringItem, word::Word, Token},
     CompileError, ErrorType,
 };
@@ -36,6 +39,8 @@ use crate::compiler::grammar::{test::Test, MatchType};
 pub(crate) struct TestHeader {
     pub header_list: Vec<StringItem>,
     pub key_list: Vec<StringItem>,
+    #[serde(skip)]
+    pub key_set: LazyKeySet,
     pub match_type: MatchType,
     pub comparator: Comparator,
     pub index: Option<i32>,
@@ -143,7 +148,7 @@ impl<'x> CompilerState<'x> {
                         let headers = self.parse_strings_token(token_info)?;
                         for header in &headers {
                             if let StringItem::Text(header_name) = &header {
-                                if HeaderName::parse(header_name).is_none() {
+                                if HeaderName::parse(header_name.as_ref()).is_none() {
                                     return Err(self
                                         .tokens
                                         .unwrap_next()?
@@ -163,10 +168,11 @@ impl<'x> CompilerState<'x> {
         if !mime && (mime_anychild || mime_opts != MimeOpts::None) {
             return Err(self.tokens.unwrap_next()?.missing_tag(":mime"));
         }
-        self.validate_match(&match_type, &key_list)?;
+        self.validate_match(&match_type, &comparator, &key_list)?;
 
         Ok(Test::Header(TestHeader {
             header_list: header_list.unwrap(),
+            key_set: LazyKeySet::new(&match_type, &comparator, &key_list),
             key_list,
             match_type,
             comparator,
@@ -21,18 +21,25 @@
  * for more details.
 */
 
+use std::sync::Arc;
+
 use serde::{Deserialize, Serialize};
 
 use crate::compiler::grammar::instruction::{CompilerState, Instruction};
 use crate::compiler::grammar::Capability;
 use crate::compiler::lexer::string::StringItem;
 use crate::compiler::CompileError;
+use crate::runtime::memory::{deserialize_shared_list, serialize_shared};
 
 use crate::compiler::grammar::test::Test;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) struct TestIhave {
-    pub capabilities: Vec<Capability>,
+    #[serde(
+        serialize_with = "serialize_shared",
+        deserialize_with = "deserialize_shared_list"
+    )]
+    pub capabilities: Arc<[Capability]>,
     pub is_not: bool,
 }
 
@@ -103,7 +103,7 @@ impl<'x> CompilerState<'x> {
                 }
             }
         }
-        self.validate_match(&match_type, &key_list)?;
+        self.validate_match(&match_type, &comparator, &key_list)?;
 
         Ok(Test::NotifyMethodCapability(TestNotifyMethodCapability {
             key_list,
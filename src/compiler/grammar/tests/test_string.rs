@@ -24,7 +24,7 @@
 use serde::{Deserialize, Serialize};
 
 use crate::compiler::{
-    grammar::{instruction::CompilerState, Capability, Comparator},
+    grammar::{instruction::CompilerState, Capability, Comparator, LazyKeySet},
     lexer::{string::StringItem, word::Word, Token},
     CompileError,
 };
@@ -37,6 +37,8 @@ pub(crate) struct TestString {
     pub comparator: Comparator,
     pub source: Vec<StringItem>,
     pub key_list: Vec<StringItem>,
+    #[serde(skip)]
+    pub key_set: LazyKeySet,
     pub is_not: bool,
 }
 
@@ -87,10 +89,11 @@ impl<'x> CompilerState<'x> {
                 }
             }
         }
-        self.validate_match(&match_type, &key_list)?;
+        self.validate_match(&match_type, &comparator, &key_list)?;
 
         Ok(Test::String(TestString {
             source: source.unwrap(),
+            key_set: LazyKeySet::new(&match_type, &comparator, &key_list),
             key_list,
             match_type,
             comparator,
@@ -44,6 +44,7 @@ pub(crate) enum Token {
     Number(usize),
     Identifier(Word),
     Tag(Word),
+    CustomTag(String),
     Invalid(String),
 }
 
@@ -61,6 +62,7 @@ impl Display for Token {
             Token::Number(n) => write!(f, "{}", n),
             Token::Identifier(w) => w.fmt(f),
             Token::Tag(t) => write!(f, ":{}", t),
+            Token::CustomTag(t) => write!(f, ":{}", t),
             Token::Invalid(s) => f.write_str(s),
             Token::StringConstant(s) | Token::StringVariable(s) => {
                 f.write_str(&String::from_utf8_lossy(s))
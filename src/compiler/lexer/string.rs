@@ -21,13 +21,16 @@
  * for more details.
 */
 
-use std::fmt::Display;
+use std::{fmt::Display, sync::Arc};
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
     compiler::{grammar::instruction::CompilerState, ErrorType},
-    runtime::string::IntoString,
+    runtime::{
+        memory::{deserialize_shared, serialize_shared},
+        string::IntoString,
+    },
     MAX_MATCH_VARIABLES,
 };
 
@@ -42,7 +45,13 @@ enum State {
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) enum StringItem {
-    Text(String),
+    Text(
+        #[serde(
+            serialize_with = "serialize_shared",
+            deserialize_with = "deserialize_shared"
+        )]
+        Arc<str>,
+    ),
     LocalVariable(usize),
     MatchVariable(usize),
     GlobalVariable(String),
@@ -263,7 +272,7 @@ impl<'x> CompilerState<'x> {
 
         Ok(match items.len() {
             1 => items.pop().unwrap(),
-            0 => StringItem::Text(String::new()),
+            0 => StringItem::Text("".into()),
             _ => StringItem::List(items),
         })
     }
@@ -276,7 +285,7 @@ impl<'x> CompilerState<'x> {
         parse_decoded: bool,
     ) -> Result<(), ErrorType> {
         if !parse_decoded {
-            items.push(StringItem::Text(buf.to_vec().into_string()));
+            items.push(StringItem::Text(buf.to_vec().into_string().into()));
         } else {
             match self.tokenize_string(buf, false)? {
                 StringItem::List(new_items) => items.extend(new_items),
@@ -316,7 +325,7 @@ mod tests {
     use crate::compiler::grammar::{Comparator, MatchType};
     use crate::compiler::lexer::tokenizer::Tokenizer;
     use crate::compiler::lexer::word::Word;
-    use crate::{AHashSet, Compiler};
+    use crate::{AHashMap, AHashSet, Compiler};
 
     #[test]
     fn tokenize_string() {
@@ -325,13 +334,14 @@ mod tests {
         block.match_test_pos.push(0);
         let mut compiler = CompilerState {
             compiler: &c,
-            instructions: vec![Instruction::Test(Test::String(TestString {
+            instructions: vec![Instruction::Test(Box::new(Test::String(TestString {
                 match_type: MatchType::Regex(u64::MAX),
                 comparator: Comparator::AsciiCaseMap,
                 source: vec![StringItem::LocalVariable(0)],
                 key_list: vec![StringItem::LocalVariable(0)],
+                key_set: Default::default(),
                 is_not: false,
-            }))],
+            })))],
             block_stack: Vec::new(),
             block,
             last_block_type: Word::Not,
@@ -342,36 +352,31 @@ mod tests {
             vars_match_max: usize::MAX,
             param_check: [false; MAX_PARAMS],
             includes_num: 0,
+            procedures: AHashMap::new(),
         };
 
         for (input, expected_result) in [
-            ("$${hex:24 24}", StringItem::Text("$$$".to_string())),
-            ("$${hex:40}", StringItem::Text("$@".to_string())),
-            ("${hex: 40 }", StringItem::Text("@".to_string())),
-            ("${HEX: 40}", StringItem::Text("@".to_string())),
-            ("${hex:40", StringItem::Text("${hex:40".to_string())),
-            ("${hex:400}", StringItem::Text("${hex:400}".to_string())),
-            (
-                "${hex:4${hex:30}}",
-                StringItem::Text("${hex:40}".to_string()),
-            ),
-            ("${unicode:40}", StringItem::Text("@".to_string())),
-            (
-                "${ unicode:40}",
-                StringItem::Text("${ unicode:40}".to_string()),
-            ),
-            ("${UNICODE:40}", StringItem::Text("@".to_string())),
-            ("${UnICoDE:0000040}", StringItem::Text("@".to_string())),
-            ("${Unicode:40}", StringItem::Text("@".to_string())),
+            ("$${hex:24 24}", StringItem::Text("$$$".into())),
+            ("$${hex:40}", StringItem::Text("$@".into())),
+            ("${hex: 40 }", StringItem::Text("@".into())),
+            ("${HEX: 40}", StringItem::Text("@".into())),
+            ("${hex:40", StringItem::Text("${hex:40".into())),
+            ("${hex:400}", StringItem::Text("${hex:400}".into())),
+            ("${hex:4${hex:30}}", StringItem::Text("${hex:40}".into())),
+            ("${unicode:40}", StringItem::Text("@".into())),
+            ("${ unicode:40}", StringItem::Text("${ unicode:40}".into())),
+            ("${UNICODE:40}", StringItem::Text("@".into())),
+            ("${UnICoDE:0000040}", StringItem::Text("@".into())),
+            ("${Unicode:40}", StringItem::Text("@".into())),
             (
                 "${Unicode:40 40 ",
-                StringItem::Text("${Unicode:40 40 ".to_string()),
+                StringItem::Text("${Unicode:40 40 ".into()),
             ),
             (
                 "${Unicode:Cool}",
-                StringItem::Text("${Unicode:Cool}".to_string()),
+                StringItem::Text("${Unicode:Cool}".into()),
             ),
-            ("", StringItem::Text("".to_string())),
+            ("", StringItem::Text("".into())),
             (
                 "${global.full}",
                 StringItem::GlobalVariable("full".to_string()),
@@ -379,52 +384,52 @@ mod tests {
             (
                 "${BAD${global.Company}",
                 StringItem::List(vec![
-                    StringItem::Text("${BAD".to_string()),
+                    StringItem::Text("${BAD".into()),
                     StringItem::GlobalVariable("company".to_string()),
                 ]),
             ),
             (
                 "${President, ${global.Company} Inc.}",
                 StringItem::List(vec![
-                    StringItem::Text("${President, ".to_string()),
+                    StringItem::Text("${President, ".into()),
                     StringItem::GlobalVariable("company".to_string()),
-                    StringItem::Text(" Inc.}".to_string()),
+                    StringItem::Text(" Inc.}".into()),
                 ]),
             ),
             (
                 "dear${hex:20 24 7b}global.Name}",
                 StringItem::List(vec![
-                    StringItem::Text("dear ".to_string()),
+                    StringItem::Text("dear ".into()),
                     StringItem::GlobalVariable("name".to_string()),
                 ]),
             ),
             (
                 "INBOX.lists.${2}",
                 StringItem::List(vec![
-                    StringItem::Text("INBOX.lists.".to_string()),
+                    StringItem::Text("INBOX.lists.".into()),
                     StringItem::MatchVariable(2),
                 ]),
             ),
             (
                 "Ein unerh${unicode:00F6}rt gro${unicode:00DF}er Test",
-                StringItem::Text("Ein unerhört großer Test".to_string()),
+                StringItem::Text("Ein unerhört großer Test".into()),
             ),
-            ("&%${}!", StringItem::Text("&%${}!".to_string())),
-            ("${doh!}", StringItem::Text("${doh!}".to_string())),
+            ("&%${}!", StringItem::Text("&%${}!".into())),
+            ("${doh!}", StringItem::Text("${doh!}".into())),
             (
                 "${hex: 20 }${global.hi}${hex: 20 }",
                 StringItem::List(vec![
-                    StringItem::Text(" ".to_string()),
+                    StringItem::Text(" ".into()),
                     StringItem::GlobalVariable("hi".to_string()),
-                    StringItem::Text(" ".to_string()),
+                    StringItem::Text(" ".into()),
                 ]),
             ),
             (
                 "${hex:20 24 7b z}${global.hi}${unicode:}${unicode: }${hex:20}",
                 StringItem::List(vec![
-                    StringItem::Text("${hex:20 24 7b z}".to_string()),
+                    StringItem::Text("${hex:20 24 7b z}".into()),
                     StringItem::GlobalVariable("hi".to_string()),
-                    StringItem::Text("${unicode:}${unicode: } ".to_string()),
+                    StringItem::Text("${unicode:}${unicode: } ".into()),
                 ]),
             ),
         ] {
@@ -89,7 +89,7 @@ impl<'x> Tokenizer<'x> {
 
     pub fn get_current_token(&mut self) -> Option<TokenInfo> {
         if !self.buf.is_empty() {
-            let mut word = std::str::from_utf8(&self.buf).unwrap();
+            let word = std::str::from_utf8(&self.buf).unwrap();
             let token = if let Some(word) = WORDS.get(word) {
                 if self.token_is_tag {
                     self.token_line_pos -= 1;
@@ -105,14 +105,17 @@ impl<'x> Tokenizer<'x> {
                     _ => 1,
                 };
 
-                if multiplier > 1 && self.buf.len() > 1 {
-                    word = std::str::from_utf8(&self.buf[..self.buf.len() - 1]).unwrap();
-                }
+                let number = if multiplier > 1 && self.buf.len() > 1 {
+                    &word[..word.len() - 1]
+                } else {
+                    word
+                };
 
-                if let Ok(number) = word.parse::<usize>() {
+                if let Ok(number) = number.parse::<usize>() {
                     Token::Number(number * multiplier)
                 } else if self.token_is_tag {
-                    Token::Invalid(format!(":{}", word))
+                    self.token_line_pos -= 1;
+                    Token::CustomTag(word.to_string())
                 } else {
                     Token::Invalid(word.to_string())
                 }
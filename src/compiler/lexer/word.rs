@@ -151,6 +151,30 @@ pub(crate) enum Word {
     VirusTest,
     Zone,
     Execute,
+    Pipe,
+    Input,
+    Output,
+    Eval,
+    ForEach,
+    While,
+    Split,
+    Trim,
+    Substring,
+    Join,
+    DecodeMime,
+    EncodeBase64,
+    DecodeBase64,
+    HtmlToText,
+    Punycode,
+    Hash,
+    Procedure,
+    Call,
+    DebugLog,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+    Filter,
 }
 
 pub(crate) static WORDS: phf::Map<&'static str, Word> = phf_map! {
@@ -278,6 +302,30 @@ pub(crate) static WORDS: phf::Map<&'static str, Word> = phf_map! {
     "virustest" => Word::VirusTest,
     "zone" => Word::Zone,
     "execute" => Word::Execute,
+    "pipe" => Word::Pipe,
+    "input" => Word::Input,
+    "output" => Word::Output,
+    "eval" => Word::Eval,
+    "foreach" => Word::ForEach,
+    "while" => Word::While,
+    "split" => Word::Split,
+    "trim" => Word::Trim,
+    "substring" => Word::Substring,
+    "join" => Word::Join,
+    "decodemime" => Word::DecodeMime,
+    "encodebase64" => Word::EncodeBase64,
+    "decodebase64" => Word::DecodeBase64,
+    "htmltotext" => Word::HtmlToText,
+    "punycode" => Word::Punycode,
+    "hash" => Word::Hash,
+    "procedure" => Word::Procedure,
+    "call" => Word::Call,
+    "debug_log" => Word::DebugLog,
+    "warn" => Word::Warn,
+    "info" => Word::Info,
+    "debug" => Word::Debug,
+    "trace" => Word::Trace,
+    "filter" => Word::Filter,
 };
 
 impl Display for Word {
@@ -407,6 +455,30 @@ impl Display for Word {
             Word::VirusTest => f.write_str("virustest"),
             Word::Zone => f.write_str("zone"),
             Word::Execute => f.write_str("execute"),
+            Word::Pipe => f.write_str("pipe"),
+            Word::Input => f.write_str("input"),
+            Word::Output => f.write_str("output"),
+            Word::Eval => f.write_str("eval"),
+            Word::ForEach => f.write_str("foreach"),
+            Word::While => f.write_str("while"),
+            Word::Split => f.write_str("split"),
+            Word::Trim => f.write_str("trim"),
+            Word::Substring => f.write_str("substring"),
+            Word::Join => f.write_str("join"),
+            Word::DecodeMime => f.write_str("decodemime"),
+            Word::EncodeBase64 => f.write_str("encodebase64"),
+            Word::DecodeBase64 => f.write_str("decodebase64"),
+            Word::HtmlToText => f.write_str("htmltotext"),
+            Word::Punycode => f.write_str("punycode"),
+            Word::Hash => f.write_str("hash"),
+            Word::Procedure => f.write_str("procedure"),
+            Word::Call => f.write_str("call"),
+            Word::DebugLog => f.write_str("debug_log"),
+            Word::Warn => f.write_str("warn"),
+            Word::Info => f.write_str("info"),
+            Word::Debug => f.write_str("debug"),
+            Word::Trace => f.write_str("trace"),
+            Word::Filter => f.write_str("filter"),
         }
     }
 }
@@ -23,9 +23,20 @@
 
 use std::{borrow::Cow, fmt::Display};
 
-use crate::{runtime::RuntimeError, Compiler};
+use ahash::AHashMap;
 
-use self::{grammar::Capability, lexer::tokenizer::TokenInfo};
+use crate::{
+    runtime::{
+        comparator::Comparators, modifier::StringModifiers, notify::NotificationMethods,
+        RuntimeError,
+    },
+    Compiler, ExtensionSignature,
+};
+
+use self::{
+    grammar::{extension::Extension, Capability},
+    lexer::{tokenizer::TokenInfo, word::WORDS},
+};
 
 pub mod grammar;
 pub mod lexer;
@@ -69,14 +80,21 @@ pub enum ErrorType {
     TooManyNestedBlocks,
     TooManyNestedTests,
     TooManyNestedForEveryParts,
+    TooManyNestedLoops,
     TooManyIncludes,
     LabelAlreadyDefined(String),
     LabelUndefined(String),
+    ProcedureAlreadyDefined(String),
+    ProcedureUndefined(String),
     BreakOutsideLoop,
     UnsupportedComparator(String),
+    UnsupportedRegexComparator(String),
+    ReservedExtensionName(String),
     DuplicatedParameter,
     UndeclaredCapability(Capability),
     MissingTag(Cow<'static, str>),
+    InvalidExpression(String),
+    FilterInsideForEveryPart,
 }
 
 impl Default for Compiler {
@@ -86,7 +104,7 @@ impl Default for Compiler {
 }
 
 impl Compiler {
-    pub const VERSION: u32 = 1;
+    pub const VERSION: u32 = 10;
 
     pub fn new() -> Self {
         Compiler {
@@ -96,10 +114,16 @@ impl Compiler {
             max_nested_blocks: 15,
             max_nested_tests: 15,
             max_nested_foreverypart: 3,
+            max_nested_loops: 3,
             max_match_variables: 30,
             max_local_variables: 128,
             max_header_size: 1024,
             max_includes: 6,
+            notification_methods: NotificationMethods::new(),
+            comparators: Comparators::new(),
+            modifiers: StringModifiers::new(),
+            extension_commands: AHashMap::new(),
+            extension_tests: AHashMap::new(),
         }
     }
 
@@ -148,6 +172,15 @@ impl Compiler {
         self
     }
 
+    pub fn set_max_nested_loops(&mut self, size: usize) {
+        self.max_nested_loops = size;
+    }
+
+    pub fn with_max_nested_loops(mut self, size: usize) -> Self {
+        self.max_nested_loops = size;
+        self
+    }
+
     pub fn set_max_script_size(&mut self, size: usize) {
         self.max_script_size = size;
     }
@@ -184,6 +217,92 @@ impl Compiler {
         self
     }
 
+    pub fn set_notification_methods(&mut self, methods: NotificationMethods) {
+        self.notification_methods = methods;
+    }
+
+    pub fn with_notification_methods(mut self, methods: NotificationMethods) -> Self {
+        self.set_notification_methods(methods);
+        self
+    }
+
+    pub fn set_comparators(&mut self, comparators: Comparators) {
+        self.comparators = comparators;
+    }
+
+    pub fn with_comparators(mut self, comparators: Comparators) -> Self {
+        self.set_comparators(comparators);
+        self
+    }
+
+    pub fn set_modifiers(&mut self, modifiers: StringModifiers) {
+        self.modifiers = modifiers;
+    }
+
+    pub fn with_modifiers(mut self, modifiers: StringModifiers) -> Self {
+        self.set_modifiers(modifiers);
+        self
+    }
+
+    /// Registers a command provided by a vendor extension, which is reported
+    /// with `Event::Extension` when executed. Names of built-in commands,
+    /// tests and tags are rejected.
+    pub fn set_extension_command(
+        &mut self,
+        capability: impl Into<Capability>,
+        name: impl Into<String>,
+        signature: ExtensionSignature,
+    ) -> Result<(), ErrorType> {
+        let name = extension_name(name)?;
+        self.extension_commands.insert(
+            name,
+            Extension {
+                capability: capability.into(),
+                signature,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn with_extension_command(
+        mut self,
+        capability: impl Into<Capability>,
+        name: impl Into<String>,
+        signature: ExtensionSignature,
+    ) -> Result<Self, ErrorType> {
+        self.set_extension_command(capability, name, signature)?;
+        Ok(self)
+    }
+
+    /// Registers a test provided by a vendor extension, the host resumes
+    /// execution with the test result using `Input::Extension`.
+    pub fn set_extension_test(
+        &mut self,
+        capability: impl Into<Capability>,
+        name: impl Into<String>,
+        signature: ExtensionSignature,
+    ) -> Result<(), ErrorType> {
+        let name = extension_name(name)?;
+        self.extension_tests.insert(
+            name,
+            Extension {
+                capability: capability.into(),
+                signature,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn with_extension_test(
+        mut self,
+        capability: impl Into<Capability>,
+        name: impl Into<String>,
+        signature: ExtensionSignature,
+    ) -> Result<Self, ErrorType> {
+        self.set_extension_test(capability, name, signature)?;
+        Ok(self)
+    }
+
     pub fn set_max_local_variables(&mut self, size: usize) {
         self.max_local_variables = size;
     }
@@ -194,6 +313,17 @@ impl Compiler {
     }
 }
 
+// Keywords are tokenized as built-in commands, tests or tags,
+// so an extension with the same name would never be dispatched
+fn extension_name(name: impl Into<String>) -> Result<String, ErrorType> {
+    let name = name.into().to_ascii_lowercase();
+    if !WORDS.contains_key(name.as_str()) {
+        Ok(name)
+    } else {
+        Err(ErrorType::ReservedExtensionName(name))
+    }
+}
+
 impl CompileError {
     pub fn line_num(&self) -> usize {
         self.line_num
@@ -287,18 +417,35 @@ impl Display for CompileError {
             ErrorType::TooManyNestedForEveryParts => {
                 write!(f, "Too many nested foreverypart blocks")
             }
+            ErrorType::TooManyNestedLoops => write!(f, "Too many nested loops"),
             ErrorType::TooManyIncludes => write!(f, "Too many includes"),
             ErrorType::LabelAlreadyDefined(value) => write!(f, "Label {:?} already defined", value),
             ErrorType::LabelUndefined(value) => write!(f, "Label {:?} does not exist", value),
-            ErrorType::BreakOutsideLoop => write!(f, "Break used outside of foreverypart loop"),
+            ErrorType::ProcedureAlreadyDefined(value) => {
+                write!(f, "Procedure {:?} already defined", value)
+            }
+            ErrorType::ProcedureUndefined(value) => {
+                write!(f, "Procedure {:?} does not exist", value)
+            }
+            ErrorType::BreakOutsideLoop => write!(f, "Break used outside of a loop"),
             ErrorType::UnsupportedComparator(value) => {
                 write!(f, "Comparator {:?} is not supported", value)
             }
+            ErrorType::UnsupportedRegexComparator(value) => {
+                write!(f, "Comparator {:?} does not support :regex", value)
+            }
+            ErrorType::ReservedExtensionName(value) => {
+                write!(f, "Extension name {:?} is reserved", value)
+            }
             ErrorType::DuplicatedParameter => write!(f, "Duplicated argument"),
             ErrorType::UndeclaredCapability(value) => {
                 write!(f, "Undeclared capability '{}'", value)
             }
             ErrorType::MissingTag(value) => write!(f, "Missing tag {:?}", value),
+            ErrorType::InvalidExpression(value) => write!(f, "Invalid expression: {}", value),
+            ErrorType::FilterInsideForEveryPart => {
+                write!(f, "Message cannot be filtered inside a foreverypart loop")
+            }
         }?;
 
         write!(
@@ -314,6 +461,9 @@ impl Display for RuntimeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             RuntimeError::TooManyIncludes => write!(f, ""),
+            RuntimeError::TooManyNestedCalls => {
+                write!(f, "Too many nested procedure calls.")
+            }
             RuntimeError::InvalidInstruction(value) => write!(
                 f,
                 "Script executed invalid instruction {:?} at line {}, column {}.",
@@ -334,6 +484,12 @@ impl Display for RuntimeError {
                 f,
                 "Script exceeded the maximum number of instructions allowed to execute."
             ),
+            RuntimeError::MessageRequired => {
+                write!(f, "Script requires the message contents to continue.")
+            }
+            RuntimeError::InvalidNotificationUri(value) => {
+                write!(f, "Invalid notification URI {:?}.", value)
+            }
         }
     }
 }
@@ -366,11 +522,7 @@ mod tests {
                     .compile(&script)
                     .unwrap();
                 let json_sieve = serde_json::to_string_pretty(
-                    &sieve
-                        .instructions
-                        .into_iter()
-                        .enumerate()
-                        .collect::<Vec<_>>(),
+                    &sieve.instructions.iter().enumerate().collect::<Vec<_>>(),
                 )
                 .unwrap();
 
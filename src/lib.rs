@@ -94,12 +94,42 @@
 //!                     // Set to true if the ID is duplicate
 //!                     input = false.into();
 //!                 }
-//!                 Event::Execute { command, arguments } => {
+//!                 Event::Execute {
+//!                     command,
+//!                     arguments,
+//!                     ..
+//!                 } => {
 //!                     println!(
 //!                         "Script executed command {:?} with parameters {:?}",
 //!                         command, arguments
 //!                     );
-//!                     // Set to true if the script succeeded
+//!                     // Set to true if the script succeeded, the program output or the filtered
+//!                     // message are returned with Input::output or Input::message
+//!                     input = false.into();
+//!                 }
+//!                 Event::Extension {
+//!                     name,
+//!                     tags,
+//!                     arguments,
+//!                 } => {
+//!                     println!(
+//!                         "Script called extension {:?} with tags {:?} and arguments {:?}",
+//!                         name, tags, arguments
+//!                     );
+//!                     // Set to true if the extension test succeeded, values are assigned
+//!                     // to the variable arguments in order
+//!                     input = Input::extension(false, Vec::<String>::new());
+//!                 }
+//!                 Event::Filter {
+//!                     program, message, ..
+//!                 } => {
+//!                     println!(
+//!                         "Script filtered the message through {:?} ({} bytes)",
+//!                         program,
+//!                         message.len()
+//!                     );
+//!                     // Return the filtered message with Input::message or set
+//!                     // to false if the program failed
 //!                     input = false.into();
 //!                 }
 //! 
@@ -167,6 +197,26 @@
 //!                     messages.push(String::from_utf8(message).unwrap());
 //!                     input = true.into();
 //!                 }
+//!                 Event::CreatedPatch { patch, .. } => {
+//!                     messages.push(String::from_utf8(patch.to_vec(raw_message.as_bytes())).unwrap());
+//!                     input = true.into();
+//!                 }
+//!                 Event::Log {
+//!                     script,
+//!                     level,
+//!                     message,
+//!                     line_num,
+//!                     ..
+//!                 } => {
+//!                     println!(
+//!                         "Script {:?} logged {:?} at line {}: {}",
+//!                         script.as_str(),
+//!                         level,
+//!                         line_num,
+//!                         message
+//!                     );
+//!                     input = true.into();
+//!                 }
 //! 
 //!                 #[cfg(test)]
 //!                 _ => unreachable!(),
@@ -176,6 +226,9 @@
 //!                     RuntimeError::TooManyIncludes => {
 //!                         eprintln!("Too many included scripts.");
 //!                     }
+//!                     RuntimeError::TooManyNestedCalls => {
+//!                         eprintln!("Too many nested procedure calls.");
+//!                     }
 //!                     RuntimeError::InvalidInstruction(instruction) => {
 //!                         eprintln!(
 //!                             "Invalid instruction {:?} found at {}:{}.",
@@ -199,6 +252,12 @@
 //!                     RuntimeError::CPULimitReached => {
 //!                         eprintln!("Script exceeded the configured CPU limit.");
 //!                     }
+//!                     RuntimeError::MessageRequired => {
+//!                         eprintln!("Script needs the message contents.");
+//!                     }
+//!                     RuntimeError::InvalidNotificationUri(uri) => {
+//!                         eprintln!("Invalid notification URI {:?}.", uri);
+//!                     }
 //!                 }
 //!                 input = true.into();
 //!             }
@@ -214,6 +273,12 @@
 //!  $ cargo test --all-features
 //! ```
 //!
+//! To compare eager and lazy message parsing:
+//!
+//! ```bash
+//!  $ cargo bench --bench lazy_parsing
+//! ```
+//!
 //! To fuzz the library with `cargo-fuzz`:
 //!
 //! ```bash
@@ -224,6 +289,7 @@
 //!
 //! - [RFC 5228 - Sieve: An Email Filtering Language](https://datatracker.ietf.org/doc/html/rfc5228)
 //! - [RFC 3894 - Copying Without Side Effects](https://datatracker.ietf.org/doc/html/rfc3894)
+//! - [RFC 5051 - i;unicode-casemap - Simple Unicode Collation Algorithm](https://datatracker.ietf.org/doc/html/rfc5051)
 //! - [RFC 5173 - Body Extension](https://datatracker.ietf.org/doc/html/rfc5173)
 //! - [RFC 5183 - Environment Extension](https://datatracker.ietf.org/doc/html/rfc5183)
 //! - [RFC 5229 - Variables Extension](https://datatracker.ietf.org/doc/html/rfc5229)
@@ -244,6 +310,7 @@
 //! - [RFC 6134 - Externally Stored Lists](https://datatracker.ietf.org/doc/html/rfc6134)
 //! - [RFC 6558 - Converting Messages before Delivery](https://datatracker.ietf.org/doc/html/rfc6558)
 //! - [RFC 6609 - Include Extension](https://datatracker.ietf.org/doc/html/rfc6609)
+//! - [RFC 6785 - Support for Internet Message Access Protocol (IMAP) Events in Sieve](https://datatracker.ietf.org/doc/html/rfc6785)
 //! - [RFC 7352 - Detecting Duplicate Deliveries](https://datatracker.ietf.org/doc/html/rfc7352)
 //! - [RFC 8579 - Delivering to Special-Use Mailboxes](https://datatracker.ietf.org/doc/html/rfc8579)
 //! - [RFC 8580 - File Carbon Copy (FCC)](https://datatracker.ietf.org/doc/html/rfc8580)
@@ -264,7 +331,7 @@
 //! Copyright (C) 2020-2022, Stalwart Labs Ltd.
 //!
 
-use std::{borrow::Cow, sync::Arc, vec::IntoIter};
+use std::{borrow::Cow, cell::RefCell, sync::Arc, vec::IntoIter};
 
 use ahash::{AHashMap, AHashSet};
 use compiler::grammar::{
@@ -272,8 +339,8 @@ use compiler::grammar::{
     instruction::Instruction,
     Capability,
 };
-use mail_parser::{HeaderName, Message};
-use runtime::context::ScriptStack;
+use mail_parser::HeaderName;
+use runtime::context::{CallStack, MessageCow, ScriptStack, Shared};
 use serde::{Deserialize, Serialize};
 
 pub mod compiler;
@@ -284,9 +351,18 @@ pub(crate) const MAX_LOCAL_VARIABLES: usize = 256;
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Sieve {
-    instructions: Vec<Instruction>,
+    instructions: Box<[Instruction]>,
     num_vars: usize,
     num_match_vars: usize,
+    #[serde(skip)]
+    compiled: runtime::compiled::CompiledOps,
+}
+
+#[derive(Debug, Default)]
+pub struct SieveStore {
+    scripts: AHashMap<u64, Vec<Arc<Sieve>>>,
+    table: runtime::memory::StringTable,
+    hasher: ahash::RandomState,
 }
 
 pub struct Compiler {
@@ -297,10 +373,17 @@ pub struct Compiler {
     pub(crate) max_nested_blocks: usize,
     pub(crate) max_nested_tests: usize,
     pub(crate) max_nested_foreverypart: usize,
+    pub(crate) max_nested_loops: usize,
     pub(crate) max_match_variables: usize,
     pub(crate) max_local_variables: usize,
     pub(crate) max_header_size: usize,
     pub(crate) max_includes: usize,
+
+    pub(crate) notification_methods: runtime::notify::NotificationMethods,
+    pub(crate) comparators: runtime::comparator::Comparators,
+    pub(crate) modifiers: runtime::modifier::StringModifiers,
+    pub(crate) extension_commands: AHashMap<String, compiler::grammar::extension::Extension>,
+    pub(crate) extension_tests: AHashMap<String, compiler::grammar::extension::Extension>,
 }
 
 #[derive(Debug, Clone)]
@@ -312,21 +395,34 @@ pub struct Runtime {
     pub(crate) environment: AHashMap<String, Cow<'static, str>>,
     pub(crate) metadata: Vec<(Metadata<String>, Cow<'static, str>)>,
     pub(crate) include_scripts: AHashMap<String, Arc<Sieve>>,
+    pub(crate) notification_methods: runtime::notify::NotificationMethods,
+    pub(crate) comparators: runtime::comparator::Comparators,
+    pub(crate) modifiers: runtime::modifier::StringModifiers,
 
     pub(crate) max_nested_includes: usize,
+    pub(crate) max_nested_calls: usize,
     pub(crate) cpu_limit: usize,
     pub(crate) max_variable_size: usize,
     pub(crate) max_redirects: usize,
     pub(crate) max_received_headers: usize,
     pub(crate) max_header_size: usize,
     pub(crate) max_out_messages: usize,
+    pub(crate) max_log_messages: usize,
 
     pub(crate) default_vacation_expiry: u64,
     pub(crate) default_duplicate_expiry: u64,
 
     pub(crate) vacation_use_orig_rcpt: bool,
+    pub(crate) message_patches: bool,
+    pub(crate) reject_mdn: bool,
+    pub(crate) redirect_return_path: ReturnPath,
+    pub(crate) vacation_return_path: ReturnPath,
+    pub(crate) notify_return_path: ReturnPath,
     pub(crate) vacation_default_subject: Cow<'static, str>,
     pub(crate) vacation_subject_prefix: Cow<'static, str>,
+
+    pub(crate) message_id_generator: Option<runtime::Generator>,
+    pub(crate) boundary_generator: Option<runtime::Generator>,
 }
 
 #[derive(Clone, Debug)]
@@ -334,31 +430,43 @@ pub struct Context<'x> {
     #[cfg(test)]
     pub(crate) runtime: Runtime,
     #[cfg(not(test))]
-    pub(crate) runtime: &'x Runtime,
+    pub(crate) runtime: Shared<'x, Runtime>,
     pub(crate) user_address: Cow<'x, str>,
     pub(crate) user_full_name: Cow<'x, str>,
     pub(crate) current_time: i64,
+    pub(crate) smtputf8: bool,
+    pub(crate) protocol_reject: bool,
 
-    pub(crate) message: Message<'x>,
+    pub(crate) message: MessageCow<'x>,
+    pub(crate) raw_message: Shared<'x, [u8]>,
     pub(crate) message_size: usize,
+    pub(crate) deferred_message: bool,
+    pub(crate) envelope_only: bool,
+    pub(crate) cache: RefCell<runtime::cache::MessageCache>,
     pub(crate) envelope: Vec<(Envelope, Cow<'x, str>)>,
     pub(crate) metadata: Vec<(Metadata<String>, Cow<'x, str>)>,
 
     pub(crate) part: usize,
     pub(crate) part_iter: IntoIter<usize>,
     pub(crate) part_iter_stack: Vec<(usize, IntoIter<usize>)>,
+    pub(crate) foreach_stack: Vec<IntoIter<String>>,
 
     pub(crate) spam_status: SpamStatus,
     pub(crate) virus_status: VirusStatus,
+    pub(crate) imap_cause: Option<ImapCause>,
 
     pub(crate) pos: usize,
     pub(crate) test_result: bool,
+    pub(crate) test_unknown: bool,
+    pub(crate) test_pending: Vec<(usize, bool)>,
     pub(crate) script_cache: AHashMap<Script, Arc<Sieve>>,
     pub(crate) script_stack: Vec<ScriptStack>,
+    pub(crate) call_stack: Vec<CallStack>,
     pub(crate) vars_global: AHashMap<String, String>,
     pub(crate) vars_env: AHashMap<String, Cow<'x, str>>,
     pub(crate) vars_local: Vec<String>,
     pub(crate) vars_match: Vec<String>,
+    pub(crate) vars_pending: Vec<compiler::grammar::actions::action_set::Variable>,
 
     pub(crate) queued_events: IntoIter<Event>,
     pub(crate) final_event: Option<Event>,
@@ -369,9 +477,78 @@ pub struct Context<'x> {
     pub(crate) num_redirects: usize,
     pub(crate) num_instructions: usize,
     pub(crate) num_out_messages: usize,
+    pub(crate) num_log_messages: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContextSnapshot {
+    pub(crate) user_address: String,
+    pub(crate) user_full_name: String,
+    pub(crate) current_time: i64,
+    pub(crate) smtputf8: bool,
+    pub(crate) protocol_reject: bool,
+
+    pub(crate) message_digest: [u8; 32],
+    pub(crate) message: Option<runtime::serialize::MessageSnapshot>,
+    pub(crate) message_size: usize,
+    pub(crate) deferred_message: bool,
+    pub(crate) envelope_only: bool,
+    pub(crate) envelope: Vec<(Envelope, String)>,
+    pub(crate) metadata: Vec<(Metadata<String>, String)>,
+
+    pub(crate) part: usize,
+    pub(crate) part_iter: Vec<usize>,
+    pub(crate) part_iter_stack: Vec<(usize, Vec<usize>)>,
+    pub(crate) foreach_stack: Vec<Vec<String>>,
+
+    pub(crate) spam_status: SpamStatus,
+    pub(crate) virus_status: VirusStatus,
+    pub(crate) imap_cause: Option<ImapCause>,
+
+    pub(crate) pos: usize,
+    pub(crate) test_result: bool,
+    pub(crate) test_unknown: bool,
+    pub(crate) test_pending: Vec<(usize, bool)>,
+    pub(crate) script_cache: Vec<Script>,
+    pub(crate) script_stack: Vec<runtime::serialize::ScriptStackSnapshot>,
+    pub(crate) call_stack: Vec<CallStack>,
+    pub(crate) vars_global: Vec<(String, String)>,
+    pub(crate) vars_env: Vec<(String, String)>,
+    pub(crate) vars_local: Vec<String>,
+    pub(crate) vars_match: Vec<String>,
+    pub(crate) vars_pending: Vec<compiler::grammar::actions::action_set::Variable>,
+
+    pub(crate) queued_events: Vec<Event>,
+    pub(crate) final_event: Option<Event>,
+    pub(crate) last_message_id: usize,
+    pub(crate) main_message_id: usize,
+
+    pub(crate) has_changes: bool,
+    pub(crate) num_redirects: usize,
+    pub(crate) num_instructions: usize,
+    pub(crate) num_out_messages: usize,
+    pub(crate) num_log_messages: usize,
+}
+
+/// A `Context` that shares ownership of its message and runtime, which can be
+/// stored or sent across threads while waiting for the result of an event.
+#[derive(Clone, Debug)]
+pub struct OwnedContext {
+    pub(crate) ctx: Context<'static>,
+}
+
+/// A recipient of a message filtered with `Runtime::filter_recipients`,
+/// with its own script, envelope, user address and environment.
+#[derive(Debug, Clone)]
+pub struct RecipientFilter<'x> {
+    pub(crate) name: Script,
+    pub(crate) script: Arc<Sieve>,
+    pub(crate) user_address: Cow<'x, str>,
+    pub(crate) envelope: Vec<(Envelope, Cow<'x, str>)>,
+    pub(crate) vars_env: Vec<(String, Cow<'x, str>)>,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Script {
     Personal(String),
     Global(String),
@@ -397,7 +574,7 @@ pub enum Metadata<T> {
     Mailbox { name: T, annotation: T },
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Event {
     IncludeScript {
         name: Script,
@@ -420,13 +597,34 @@ pub enum Event {
     Execute {
         command: String,
         arguments: Vec<String>,
+        input: Option<String>,
+        /// The message piped to the program with `:pipe`.
+        message: Option<Vec<u8>>,
+        /// Whether the program output should be returned with `Input::Output`.
+        output: bool,
+    },
+    Extension {
+        name: String,
+        tags: Vec<(String, Option<ExtensionValue>)>,
+        arguments: Vec<ExtensionValue>,
+    },
+    /// The filtered message is returned with `Input::message`, the current
+    /// message is kept if the program fails.
+    Filter {
+        program: String,
+        arguments: Vec<String>,
+        message: Vec<u8>,
     },
 
     // Actions
+    /// Under IMAPSIEVE the message is left in its mailbox and `flags`
+    /// replace its current flags.
     Keep {
         flags: Vec<String>,
         message_id: usize,
     },
+    /// Under IMAPSIEVE the message is removed from its mailbox, which is
+    /// also the case after `fileinto` or `redirect` cancel the implicit keep.
     Discard,
     Reject {
         extended: bool,
@@ -445,6 +643,7 @@ pub enum Event {
         notify: Notify,
         return_of_content: Ret,
         by_time: ByTime<i64>,
+        envelope: OutboundEnvelope,
         message_id: usize,
     },
     Notify {
@@ -453,11 +652,25 @@ pub enum Event {
         options: Vec<String>,
         message: String,
         method: String,
+        payload: Option<Vec<u8>>,
     },
     CreatedMessage {
         message_id: usize,
         message: Vec<u8>,
     },
+    CreatedPatch {
+        message_id: usize,
+        patch: MessagePatch,
+    },
+    /// Emitted by `debug_log`, execution continues regardless of the input
+    /// passed to the next call to `run`.
+    Log {
+        script: Script,
+        level: LogLevel,
+        message: String,
+        line_num: usize,
+        line_pos: usize,
+    },
 
     #[cfg(test)]
     TestCommand {
@@ -466,6 +679,60 @@ pub enum Event {
     },
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExtensionValue {
+    Number(usize),
+    String(String),
+    StringList(Vec<String>),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExtensionSignature {
+    pub(crate) tags: Vec<(String, Option<ArgumentType>)>,
+    pub(crate) arguments: Vec<ArgumentType>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgumentType {
+    Number,
+    String,
+    StringList,
+    /// Receives a value returned with `Input::Extension`, not included in the event.
+    Variable,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessagePatch {
+    pub(crate) edits: Vec<PatchEdit>,
+    pub(crate) len: usize,
+}
+
+/// Edits are sorted by their offset in the original message and do not overlap.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PatchEdit {
+    /// Headers added before the original bytes at `offset`.
+    InsertHeaders {
+        offset: usize,
+        headers: Vec<u8>,
+    },
+    RemoveHeader {
+        offset: usize,
+        length: usize,
+    },
+    /// Replaces the header name and colon, the value is kept.
+    RenameHeader {
+        offset: usize,
+        length: usize,
+        name: String,
+    },
+    /// Replaces a part body, or a whole message replaced by a filter or enclosed.
+    ReplacePart {
+        offset: usize,
+        length: usize,
+        contents: Vec<u8>,
+    },
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Hash)]
 pub(crate) struct FileCarbonCopy<T> {
     pub mailbox: T,
@@ -475,41 +742,77 @@ pub(crate) struct FileCarbonCopy<T> {
     pub special_use: Option<T>,
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Importance {
     High,
     Normal,
     Low,
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
 pub enum MatchAs {
     Octet,
     Lowercase,
     Number,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Recipient {
     Address(String),
     List(String),
     Group(Vec<String>),
 }
 
+/// Transaction parameters for a message sent with `Event::SendMessage`.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct OutboundEnvelope {
+    /// The MAIL FROM address, empty for a null return path.
+    pub from: String,
+    pub env_id: Option<String>,
+    pub orcpt: Option<String>,
+    pub smtputf8: bool,
+    pub size: usize,
+}
+
+/// Return path used for messages generated or redirected by a script.
+#[derive(Debug, Clone)]
+pub enum ReturnPath {
+    Null,
+    /// The user's address, or the envelope recipient if not set.
+    User,
+    /// The envelope sender of the incoming message.
+    Original,
+    /// Computed by the host from the envelope sender of the incoming
+    /// message, for example to apply SRS to redirects. See `ReturnPath::custom`.
+    Custom(runtime::ReturnPathFn),
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Input {
     True,
     False,
     Script { name: Script, script: Arc<Sieve> },
+    Extension { result: bool, values: Vec<String> },
+    Output { result: bool, output: String },
+    Message { result: bool, message: Vec<u8> },
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Mailbox {
     Name(String),
     Id(String),
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum SpamStatus {
     Unknown,
     Ham,
@@ -517,7 +820,14 @@ pub enum SpamStatus {
     Spam,
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum ImapCause {
+    Append,
+    Copy,
+    Flag,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum VirusStatus {
     Unknown,
     Clean,
@@ -540,8 +850,13 @@ mod tests {
     };
 
     use crate::{
-        compiler::grammar::Capability, runtime::actions::action_mime::reset_test_boundary,
-        Compiler, Envelope, Event, Input, Mailbox, Recipient, Runtime, SpamStatus, VirusStatus,
+        compiler::grammar::Capability,
+        runtime::{
+            actions::action_mime::{make_test_boundary, reset_test_boundary},
+            context::MessageCow,
+        },
+        Compiler, Envelope, Event, ImapCause, Input, Mailbox, Recipient, Runtime, SpamStatus,
+        VirusStatus,
     };
 
     #[test]
@@ -563,7 +878,8 @@ mod tests {
                 continue;
             }*/
             println!("===== {} =====", test.display());
-            run_test(&test);
+            run_test(&test, false);
+            run_test(&test, true);
         }
     }
 
@@ -583,7 +899,8 @@ mod tests {
         }
     }
 
-    fn run_test(script_path: &Path) {
+    fn run_test(script_path: &Path, compiled: bool) {
+        reset_test_boundary();
         let mut compiler = Compiler::new().with_max_string_size(10240);
         let mut ancestors = script_path.ancestors();
         ancestors.next();
@@ -591,6 +908,9 @@ mod tests {
         let script = compiler
             .compile(&add_crlf(&fs::read(&script_path).unwrap()))
             .unwrap();
+        if compiled {
+            script.compile_closures();
+        }
 
         let mut input = Input::script("", script);
         let mut current_test = String::new();
@@ -606,25 +926,30 @@ mod tests {
                 .with_protected_header("Auto-Submitted")
                 .with_protected_header("Received")
                 .with_valid_notification_uri("mailto")
+                .with_valid_notification_uri("xmpp")
                 .with_max_out_messages(100)
-                .with_capability(Capability::Execute);
-            let mut instance = runtime.filter(b"");
+                .with_capability(Capability::Execute)
+                .with_capability(Capability::Filter)
+                .with_message_id_generator(|| "auto-generated@message-id".to_string())
+                .with_boundary_generator(make_test_boundary);
+            let mut instance = runtime.filter(b"").with_current_time(1668921260);
             let raw_message = raw_message_.take().unwrap_or_default();
-            instance.message = Message::parse(&raw_message).unwrap_or_else(|| Message {
-                html_body: vec![],
-                text_body: vec![],
-                attachments: vec![],
-                parts: vec![MessagePart {
-                    headers: vec![],
-                    is_encoding_problem: false,
-                    body: PartType::Text("".into()),
-                    encoding: Encoding::None,
-                    offset_header: 0,
-                    offset_body: 0,
-                    offset_end: 0,
-                }],
-                raw_message: b""[..].into(),
-            });
+            instance.message =
+                MessageCow::Owned(Message::parse(&raw_message).unwrap_or_else(|| Message {
+                    html_body: vec![],
+                    text_body: vec![],
+                    attachments: vec![],
+                    parts: vec![MessagePart {
+                        headers: vec![],
+                        is_encoding_problem: false,
+                        body: PartType::Text("".into()),
+                        encoding: Encoding::None,
+                        offset_header: 0,
+                        offset_body: 0,
+                        offset_end: 0,
+                    }],
+                    raw_message: b""[..].into(),
+                }));
             instance.message_size = raw_message.len();
             if let Some((pos, script_cache, script_stack, vars_global, vars_local, vars_match)) =
                 prev_state.take()
@@ -667,6 +992,9 @@ mod tests {
 
                         if let Ok(bytes) = fs::read(include_path.as_path()) {
                             let script = compiler.compile(&add_crlf(&bytes)).unwrap();
+                            if compiled {
+                                script.compile_closures();
+                            }
                             input = Input::script(name, script);
                         } else if optional {
                             input = Input::False;
@@ -717,16 +1045,45 @@ mod tests {
                     Event::DuplicateId { id, .. } => {
                         input = duplicated_ids.contains(&id).into();
                     }
-                    Event::Execute { command, arguments } => {
-                        assert_eq!(arguments, ["param1", "param2"]);
-                        input = (if command.eq_ignore_ascii_case("always_succeed") {
-                            true
-                        } else if command.eq_ignore_ascii_case("always_fail") {
-                            false
-                        } else {
-                            panic!("Unknown command {}", command);
-                        })
-                        .into();
+                    Event::Execute {
+                        command,
+                        arguments,
+                        input: stdin,
+                        message,
+                        ..
+                    } => {
+                        input = match command.to_ascii_lowercase().as_str() {
+                            "always_succeed" | "always_fail" => {
+                                assert_eq!(arguments, ["param1", "param2"]);
+                                command.eq_ignore_ascii_case("always_succeed").into()
+                            }
+                            "echo" => {
+                                Input::output(true, stdin.unwrap_or_else(|| arguments.join(" ")))
+                            }
+                            "add_header" => {
+                                let mut filtered =
+                                    format!("X-Filtered: {}\r\n", arguments.join(" ")).into_bytes();
+                                filtered.extend_from_slice(&message.expect("Message not piped"));
+                                Input::message(true, filtered)
+                            }
+                            _ => panic!("Unknown command {}", command),
+                        };
+                    }
+                    Event::Filter {
+                        program,
+                        arguments,
+                        message,
+                    } => {
+                        input = match program.as_str() {
+                            "add_header" => {
+                                let mut filtered =
+                                    format!("X-Filtered: {}\r\n", arguments.join(" ")).into_bytes();
+                                filtered.extend_from_slice(&message);
+                                Input::message(true, filtered)
+                            }
+                            "always_fail" => false.into(),
+                            _ => panic!("Unknown filter {}", program),
+                        };
                     }
 
                     Event::TestCommand {
@@ -797,10 +1154,25 @@ mod tests {
                                     if let HeaderValue::DateTime(dt) =
                                         MessageStream::new(&bytes).parse_date()
                                     {
-                                        instance.current_time = dt.to_timestamp();
+                                        instance.set_current_time(dt.to_timestamp());
                                     } else {
                                         panic!("Invalid currentdate");
                                     }
+                                } else if target == "imap.event" {
+                                    let cause = params.next().unwrap();
+                                    instance.set_imap_event(
+                                        ImapCause::try_from(cause.as_str()).unwrap(),
+                                        params.next().unwrap(),
+                                    );
+                                } else if target == "imap.user" {
+                                    instance.set_imap_user(
+                                        params.next().unwrap(),
+                                        params.next().unwrap(),
+                                    );
+                                } else if target == "imap.flags" {
+                                    instance.set_imap_flags(params);
+                                } else if target == "imap.changedflags" {
+                                    instance.set_imap_changed_flags(params);
                                 } else {
                                     panic!("test_set {} not implemented.", target);
                                 }
@@ -912,6 +1284,20 @@ mod tests {
                                         instance.runtime.set_max_header_size(mhs);
                                         compiler.set_max_header_size(mhs);
                                     }
+                                    "sieve_debug_max_log_messages" => {
+                                        instance
+                                            .runtime
+                                            .set_max_log_messages(value.parse().unwrap());
+                                    }
+                                    "sieve_reject_mdn" => {
+                                        instance
+                                            .runtime
+                                            .set_reject_mdn(value.eq_ignore_ascii_case("yes"));
+                                    }
+                                    "sieve_reject_protocol" => {
+                                        instance
+                                            .set_protocol_reject(value.eq_ignore_ascii_case("yes"));
+                                    }
                                     "sieve_include_max_includes" => {
                                         compiler.set_max_includes(if !value.is_empty() {
                                             value.parse::<usize>().unwrap()
@@ -957,6 +1343,19 @@ mod tests {
                                     (matches!(&instance.final_event, Some(Event::Keep { .. }))
                                         || actions.iter().any(|a| matches!(a, Event::Keep { .. })))
                                     .into()
+                                } else if param == "keep_flags" {
+                                    let flags: Option<Vec<String>> = match &instance.final_event {
+                                        Some(Event::Keep { flags, .. }) if flags.is_empty() => {
+                                            instance.get_global_flags().into()
+                                        }
+                                        Some(Event::Keep { flags, .. }) => flags.clone().into(),
+                                        _ => None,
+                                    };
+                                    (flags.as_deref() == Some(&params[1..])).into()
+                                } else if param == "discard" {
+                                    (matches!(&instance.final_event, Some(Event::Discard))
+                                        || actions.iter().any(|a| matches!(a, Event::Discard)))
+                                    .into()
                                 } else if param == "send_message" {
                                     (actions
                                         .iter()
@@ -966,6 +1365,27 @@ mod tests {
                                     panic!("test_result_action {} not implemented", param);
                                 };
                             }
+                            "test_result_log" => {
+                                let mut params = params.into_iter();
+                                let mut script_name = String::new();
+                                let mut level_name = params.next().expect("test_result_log level");
+                                if level_name == ":script" {
+                                    script_name =
+                                        params.next().expect("test_result_log script name");
+                                    level_name = params.next().expect("test_result_log level");
+                                }
+                                let message_ = params.next().expect("test_result_log message");
+                                input = actions
+                                    .iter()
+                                    .any(|a| {
+                                        matches!(a, Event::Log { script, level, message, .. }
+                                            if script.as_str() == &script_name
+                                                && format!("{:?}", level)
+                                                    .eq_ignore_ascii_case(&level_name)
+                                                && message == &message_)
+                                    })
+                                    .into();
+                            }
                             "test_result_action_count" => {
                                 input = (actions.len()
                                     == params.first().unwrap().parse::<usize>().unwrap())
@@ -1002,6 +1422,9 @@ mod tests {
                                 instance.metadata.clear();
                                 instance.has_changes = false;
                                 instance.num_redirects = 0;
+                                instance.num_log_messages = 0;
+                                instance.protocol_reject = true;
+                                instance.runtime.reject_mdn = false;
                                 instance.runtime.vacation_use_orig_rcpt = false;
                                 mailboxes.clear();
                                 lists.clear();
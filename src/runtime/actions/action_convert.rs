@@ -62,8 +62,8 @@ impl Convert {
         } else {
             return TestResult::Bool(false ^ self.is_not);
         };
-        let mut did_convert = false;
-        for part in ctx.message.parts.iter_mut() {
+        let mut conversions = Vec::new();
+        for (part_id, part) in ctx.message.parts.iter().enumerate() {
             let (new_body, ct) = match (&part.body, conversion) {
                 (PartType::Html(html), Conversion::HtmlToText) => (
                     PartType::Text(html_to_text(html.as_ref()).into()),
@@ -88,6 +88,13 @@ impl Convert {
                     continue;
                 }
             };
+            conversions.push((part_id, new_body, ct));
+        }
+
+        // Only copy a shared message when a part is actually converted
+        let did_convert = !conversions.is_empty();
+        for (part_id, new_body, ct) in conversions {
+            let part = &mut ctx.message.to_mut().parts[part_id];
             part.headers = vec![Header {
                 name: HeaderName::Other("Content-Type".into()),
                 value: HeaderValue::Text(ct.to_string().into()),
@@ -104,11 +111,11 @@ impl Convert {
             part.offset_body = 0;
             part.body = new_body;
             part.encoding = Encoding::QuotedPrintable; //Used as non-mime flag
-            did_convert = true;
         }
 
         if did_convert {
             ctx.has_changes = true;
+            ctx.invalidate_cache();
         }
 
         TestResult::Bool(did_convert ^ self.is_not)
@@ -33,6 +33,7 @@ use crate::{
         },
         MatchType,
     },
+    runtime::headers::encode_header_value,
     Context,
 };
 
@@ -67,6 +68,7 @@ impl AddHeader {
 
 impl DeleteHeader {
     pub(crate) fn exec(&self, ctx: &mut Context) {
+        let comparator = ctx.comparator(&self.comparator);
         let header_name =
             if let Some(header_name) = HeaderName::parse(ctx.eval_string(&self.field_name)) {
                 header_name
@@ -90,26 +92,17 @@ impl DeleteHeader {
                     let did_match = ctx.find_header_values(header, &MimeOpts::None, |value| {
                         for pattern in &value_patterns {
                             if match &self.match_type {
-                                MatchType::Is => self.comparator.is(value, pattern.as_ref()),
-                                MatchType::Contains => {
-                                    self.comparator.contains(value, pattern.as_ref())
-                                }
+                                MatchType::Is => comparator.is(value, pattern.as_ref()),
+                                MatchType::Contains => comparator.contains(value, pattern.as_ref()),
                                 MatchType::Value(rel_match) => {
-                                    self.comparator
-                                        .relational(rel_match, value, pattern.as_ref())
+                                    comparator.relational(rel_match, value, pattern.as_ref())
+                                }
+                                MatchType::Matches(_) => {
+                                    comparator.matches(value, pattern.as_ref(), 0, &mut Vec::new())
+                                }
+                                MatchType::Regex(_) => {
+                                    comparator.regex(value, pattern.as_ref(), 0, &mut Vec::new())
                                 }
-                                MatchType::Matches(_) => self.comparator.matches(
-                                    value,
-                                    pattern.as_ref(),
-                                    0,
-                                    &mut Vec::new(),
-                                ),
-                                MatchType::Regex(_) => self.comparator.regex(
-                                    value,
-                                    pattern.as_ref(),
-                                    0,
-                                    &mut Vec::new(),
-                                ),
                                 MatchType::Count(_) => false,
                                 MatchType::List => false,
                             } {
@@ -127,7 +120,10 @@ impl DeleteHeader {
                 if header.offset_end != 0 {
                     deleted_bytes += header.offset_end - header.offset_field;
                 } else {
-                    deleted_bytes += header.name.as_str().len() + header.value.len() + 4;
+                    deleted_bytes += ctx.header_size(
+                        header.name.as_str(),
+                        header.value.as_text_ref().unwrap_or(""),
+                    );
                 }
                 deleted_headers.push((part_id, header_pos));
 
@@ -137,8 +133,11 @@ impl DeleteHeader {
 
         if !deleted_headers.is_empty() {
             ctx.has_changes = true;
+            ctx.invalidate_cache();
             for (part_id, header_pos) in deleted_headers.iter().rev() {
-                ctx.message.parts[*part_id].headers.remove(*header_pos);
+                ctx.message.to_mut().parts[*part_id]
+                    .headers
+                    .remove(*header_pos);
             }
         }
 
@@ -175,7 +174,7 @@ impl<'x> Context<'x> {
         last: bool,
     ) {
         let header_value = header_value.into();
-        self.message_size += header_name.len() + header_value.len() + 4;
+        self.message_size += self.header_size(header_name.as_str(), &header_value);
         let header = Header {
             name: header_name,
             value: HeaderValue::Text(header_value),
@@ -184,10 +183,18 @@ impl<'x> Context<'x> {
             offset_field: 0,
         };
 
+        self.invalidate_cache();
         if !last {
-            self.message.parts[part_id].headers.insert(0, header);
+            self.message.to_mut().parts[part_id]
+                .headers
+                .insert(0, header);
         } else {
-            self.message.parts[part_id].headers.push(header);
+            self.message.to_mut().parts[part_id].headers.push(header);
         }
     }
+
+    // Size of an added header once encoded and folded by build_message
+    pub(crate) fn header_size(&self, header_name: &str, header_value: &str) -> usize {
+        header_name.len() + encode_header_value(header_name, header_value, self.smtputf8).len() + 4
+    }
 }
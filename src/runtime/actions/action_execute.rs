@@ -0,0 +1,214 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use mail_parser::{
+    Addr, ContentType, Group, Header, HeaderName, HeaderValue, Message, MessagePart, PartType,
+};
+
+use crate::{
+    compiler::grammar::{
+        instruction::Instruction,
+        test::Test,
+        tests::test_execute::{Execute, ExecuteInput, Filter},
+    },
+    runtime::{context::MessageCow, parse_message},
+    Context, Event,
+};
+
+impl Execute {
+    pub(crate) fn event(&self, ctx: &Context) -> Event {
+        Event::Execute {
+            command: ctx.eval_string(&self.command).into_owned(),
+            arguments: ctx.eval_strings_owned(&self.arguments),
+            input: if let ExecuteInput::Text(input) = &self.input {
+                ctx.eval_string(input).into_owned().into()
+            } else {
+                None
+            },
+            message: if self.input == ExecuteInput::Pipe {
+                ctx.build_message().into()
+            } else {
+                None
+            },
+            output: self.output.is_some(),
+        }
+    }
+}
+
+impl Filter {
+    pub(crate) fn event(&self, ctx: &Context) -> Event {
+        Event::Filter {
+            program: ctx.eval_string(&self.program).into_owned(),
+            arguments: ctx.eval_strings_owned(&self.arguments),
+            message: ctx.build_message(),
+        }
+    }
+}
+
+impl<'x> Context<'x> {
+    pub(crate) fn set_execute_output(&mut self, output: String) {
+        // The output is assigned to the variable of the last executed command
+        let script = if let Some(script_stack) = self.script_stack.last() {
+            script_stack.script.clone()
+        } else {
+            return;
+        };
+        let execute = match script.instructions.get(self.pos.wrapping_sub(1)) {
+            Some(Instruction::Execute(execute)) => execute.as_ref(),
+            Some(Instruction::Test(test)) => match test.as_ref() {
+                Test::Execute(execute) => execute,
+                _ => return,
+            },
+            _ => return,
+        };
+        if let Some(variable) = &execute.output {
+            self.set_variable(variable, output);
+        }
+    }
+
+    pub(crate) fn set_filter_output(&mut self, result: bool, raw_message: Vec<u8>) {
+        // Only the output of a piped command or a filter replaces the message
+        let is_filter = self.script_stack.last().map_or(false, |script_stack| {
+            match script_stack
+                .script
+                .instructions
+                .get(self.pos.wrapping_sub(1))
+            {
+                Some(Instruction::Execute(execute)) => execute.input == ExecuteInput::Pipe,
+                Some(Instruction::Filter(_)) => true,
+                Some(Instruction::Test(test)) => match test.as_ref() {
+                    Test::Execute(execute) => execute.input == ExecuteInput::Pipe,
+                    Test::Filter(_) => true,
+                    _ => false,
+                },
+                _ => false,
+            }
+        });
+        if !is_filter {
+            self.test_result ^= result;
+            return;
+        }
+
+        // Filters reached through an include or a procedure call inside
+        // a foreverypart loop are not rejected by the compiler, fail them
+        // to keep the part ids of the loop valid.
+        if !result || !self.part_iter_stack.is_empty() {
+            return;
+        }
+
+        self.test_result ^= true;
+        self.replace_message(raw_message);
+    }
+
+    fn replace_message(&mut self, raw_message: Vec<u8>) {
+        self.message = MessageCow::Owned(owned_message(parse_message(&raw_message)));
+        self.message_size = raw_message.len();
+        self.deferred_message = false;
+        self.part = 0;
+        self.has_changes = true;
+        self.invalidate_cache();
+    }
+}
+
+pub(crate) fn owned_message(message: Message) -> Message<'static> {
+    Message {
+        html_body: message.html_body,
+        text_body: message.text_body,
+        attachments: message.attachments,
+        parts: message.parts.into_iter().map(owned_part).collect(),
+        raw_message: message.raw_message.into_owned().into(),
+    }
+}
+
+fn owned_part(part: MessagePart) -> MessagePart<'static> {
+    MessagePart {
+        headers: part.headers.into_iter().map(owned_header).collect(),
+        is_encoding_problem: part.is_encoding_problem,
+        body: match part.body {
+            PartType::Text(text) => PartType::Text(text.into_owned().into()),
+            PartType::Html(html) => PartType::Html(html.into_owned().into()),
+            PartType::Binary(bytes) => PartType::Binary(bytes.into_owned().into()),
+            PartType::InlineBinary(bytes) => PartType::InlineBinary(bytes.into_owned().into()),
+            PartType::Message(message) => PartType::Message(owned_message(message)),
+            PartType::Multipart(parts) => PartType::Multipart(parts),
+        },
+        encoding: part.encoding,
+        offset_header: part.offset_header,
+        offset_body: part.offset_body,
+        offset_end: part.offset_end,
+    }
+}
+
+fn owned_header(header: Header) -> Header<'static> {
+    Header {
+        name: match header.name {
+            HeaderName::Rfc(name) => HeaderName::Rfc(name),
+            HeaderName::Other(name) => HeaderName::Other(name.into_owned().into()),
+        },
+        value: match header.value {
+            HeaderValue::Address(addr) => HeaderValue::Address(owned_addr(addr)),
+            HeaderValue::AddressList(list) => {
+                HeaderValue::AddressList(list.into_iter().map(owned_addr).collect())
+            }
+            HeaderValue::Group(group) => HeaderValue::Group(owned_group(group)),
+            HeaderValue::GroupList(list) => {
+                HeaderValue::GroupList(list.into_iter().map(owned_group).collect())
+            }
+            HeaderValue::Text(text) => HeaderValue::Text(text.into_owned().into()),
+            HeaderValue::TextList(list) => HeaderValue::TextList(
+                list.into_iter()
+                    .map(|text| text.into_owned().into())
+                    .collect(),
+            ),
+            HeaderValue::DateTime(datetime) => HeaderValue::DateTime(datetime),
+            HeaderValue::ContentType(ct) => HeaderValue::ContentType(ContentType {
+                c_type: ct.c_type.into_owned().into(),
+                c_subtype: ct.c_subtype.map(|s| s.into_owned().into()),
+                attributes: ct.attributes.map(|attributes| {
+                    attributes
+                        .into_iter()
+                        .map(|(k, v)| (k.into_owned().into(), v.into_owned().into()))
+                        .collect()
+                }),
+            }),
+            HeaderValue::Empty => HeaderValue::Empty,
+        },
+        offset_field: header.offset_field,
+        offset_start: header.offset_start,
+        offset_end: header.offset_end,
+    }
+}
+
+fn owned_addr(addr: Addr) -> Addr<'static> {
+    Addr {
+        name: addr.name.map(|s| s.into_owned().into()),
+        address: addr.address.map(|s| s.into_owned().into()),
+    }
+}
+
+fn owned_group(group: Group) -> Group<'static> {
+    Group {
+        name: group.name.map(|s| s.into_owned().into()),
+        addresses: group.addresses.into_iter().map(owned_addr).collect(),
+    }
+}
@@ -31,10 +31,21 @@ impl FileInto {
             events.push(event);
         }
 
+        // Filing into the mailbox the IMAP message is already in is a keep
+        if ctx.is_imap_mailbox(&folder) {
+            ctx.final_event = Event::Keep {
+                flags: ctx.get_local_or_global_flags(&self.flags),
+                message_id: ctx.main_message_id,
+            }
+            .into();
+            ctx.queued_events = events.into_iter();
+            return;
+        }
+
         if !self.copy
             && !matches!(&ctx.final_event, Some(Event::Keep { flags, .. }) if !flags.is_empty())
         {
-            ctx.final_event = None;
+            ctx.cancel_implicit_keep();
         }
 
         events.push(Event::FileInto {
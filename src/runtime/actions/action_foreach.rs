@@ -0,0 +1,96 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use crate::{
+    compiler::grammar::actions::{
+        action_foreach::{ForEach, ForEachSource},
+        action_mime::MimeOpts,
+    },
+    Context,
+};
+
+impl ForEachSource {
+    pub(crate) fn exec(&self, ctx: &mut Context) {
+        let mut items = Vec::new();
+
+        match self {
+            ForEachSource::List(values) => {
+                items = ctx.eval_strings_owned(values);
+            }
+            ForEachSource::Split { separator, values } => {
+                let separator = ctx.eval_string(separator);
+                for value in values {
+                    let value = ctx.eval_string(value);
+                    if !separator.is_empty() {
+                        items.extend(
+                            value
+                                .split(separator.as_ref())
+                                .map(|item| item.trim())
+                                .filter(|item| !item.is_empty())
+                                .map(|item| item.to_string()),
+                        );
+                    } else if !value.is_empty() {
+                        items.push(value.into_owned());
+                    }
+                }
+            }
+            ForEachSource::Header(header_list) => {
+                let header_list = ctx.parse_header_names(header_list);
+                ctx.find_headers(&header_list, None, false, |header, _, _| {
+                    ctx.find_header_values(header, &MimeOpts::None, |value| {
+                        items.push(value.to_string());
+                        false
+                    })
+                });
+            }
+            ForEachSource::Address {
+                header_list,
+                address_part,
+            } => {
+                let header_list = ctx.parse_header_names(header_list);
+                ctx.find_headers(&header_list, None, false, |header, _, _| {
+                    ctx.find_addresses(header, address_part, |value| {
+                        if !value.is_empty() {
+                            items.push(value.to_string());
+                        }
+                        false
+                    })
+                });
+            }
+        }
+
+        ctx.foreach_stack.push(items.into_iter());
+    }
+}
+
+impl ForEach {
+    pub(crate) fn exec(&self, ctx: &mut Context) {
+        if let Some(item) = ctx.foreach_stack.last_mut().and_then(|items| items.next()) {
+            ctx.set_variable(&self.var_name, item);
+        } else {
+            debug_assert!(self.jz_pos as usize > ctx.pos - 1);
+            ctx.foreach_stack.pop();
+            ctx.pos = self.jz_pos as usize;
+        }
+    }
+}
@@ -30,7 +30,7 @@ use crate::{
 };
 
 pub(crate) enum IncludeResult {
-    Cached(Arc<Sieve>),
+    Cached(Script, Arc<Sieve>),
     Event(Event),
     Error(RuntimeError),
     None,
@@ -52,7 +52,7 @@ impl Include {
                     if let Some(script) = cached_script
                         .or_else(|| ctx.runtime.include_scripts.get(script_name.as_str()))
                     {
-                        return IncludeResult::Cached(script.clone());
+                        return IncludeResult::Cached(script_name, script.clone());
                     } else {
                         return IncludeResult::Event(Event::IncludeScript {
                             name: script_name,
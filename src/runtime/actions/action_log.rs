@@ -0,0 +1,41 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use crate::{compiler::grammar::actions::action_log::Log, Context, Event};
+
+impl Log {
+    pub(crate) fn event(&self, ctx: &mut Context) -> Option<Event> {
+        if ctx.num_log_messages < ctx.runtime.max_log_messages {
+            ctx.num_log_messages += 1;
+            Some(Event::Log {
+                script: ctx.script_stack.last()?.name.clone(),
+                level: self.level,
+                message: ctx.eval_string(&self.message).into_owned(),
+                line_num: self.line_num,
+                line_pos: self.line_pos,
+            })
+        } else {
+            None
+        }
+    }
+}
@@ -21,10 +21,11 @@
  * for more details.
 */
 
-use std::cmp::Reverse;
+use std::{borrow::Cow, cmp::Reverse, io::Write};
 
 use mail_parser::{
-    decoders::html::html_to_text, Encoding, HeaderName, Message, MessagePart, PartType, RfcHeader,
+    decoders::html::html_to_text, Encoding, Header, HeaderName, Message, MessagePart, PartType,
+    RfcHeader,
 };
 
 use crate::{
@@ -32,29 +33,28 @@ use crate::{
         action_mime::{Enclose, ExtractText, Replace},
         action_set::Variable,
     },
-    Context, Event,
+    runtime::{context::MessageCow, headers::encode_header_value, parse_message},
+    Context, Event, MessagePatch, PatchEdit,
 };
 
 use super::action_editheader::RemoveCrLf;
 
-#[cfg(not(test))]
-use mail_builder::headers::message_id::generate_message_id_header;
-
 impl Replace {
     pub(crate) fn exec(&self, ctx: &mut Context) {
         // Delete children parts
         let mut part_ids = ctx.find_nested_parts_ids(false);
         part_ids.sort_unstable_by_key(|a| Reverse(*a));
         for part_id in part_ids {
-            ctx.message.parts.remove(part_id);
+            ctx.message.to_mut().parts.remove(part_id);
         }
         ctx.has_changes = true;
+        ctx.invalidate_cache();
 
         // Update part
         let body = ctx.eval_string(&self.replacement).into_owned();
         let body_len = body.len();
 
-        let part = &mut ctx.message.parts[ctx.part];
+        let part = &mut ctx.message.to_mut().parts[ctx.part];
 
         ctx.message_size = ctx.message_size + body_len
             - (if part.offset_body != 0 {
@@ -138,30 +138,19 @@ impl Replace {
 
             // Add Date
             if add_date {
-                #[cfg(not(test))]
-                let header_value = mail_builder::headers::date::Date::now().to_rfc822();
-                #[cfg(test)]
-                let header_value = "Tue, 20 Nov 2022 05:14:20 -0300".to_string();
-
                 ctx.insert_header(
                     0,
                     HeaderName::Other("Date".to_string().into()),
-                    header_value,
+                    ctx.current_date(),
                     true,
                 );
             }
 
             // Add Message-ID
-            let mut header_value = Vec::with_capacity(20);
-            #[cfg(not(test))]
-            generate_message_id_header(&mut header_value).unwrap();
-            #[cfg(test)]
-            header_value.extend_from_slice(b"<auto-generated@message-id>");
-
             ctx.insert_header(
                 0,
                 HeaderName::Other("Message-ID".to_string().into()),
-                String::from_utf8(header_value).unwrap(),
+                ctx.generate_message_id(),
                 true,
             );
         }
@@ -191,16 +180,14 @@ impl Enclose {
             .or_else(|| ctx.message.get_subject().map(|s| s.to_string()))
             .unwrap_or_default();
 
-        let message = std::mem::take(&mut ctx.message);
-        #[cfg(test)]
-        let boundary = make_test_boundary();
-        #[cfg(not(test))]
-        let boundary = mail_builder::mime::make_boundary(".");
+        let message = std::mem::take(&mut ctx.message).into_owned();
+        let boundary = ctx.generate_boundary();
 
         ctx.message_size += ((boundary.len() + 6) * 3) + body.len() + 2;
         ctx.part = 0;
         ctx.has_changes = true;
-        ctx.message = Message {
+        ctx.invalidate_cache();
+        ctx.message = MessageCow::Owned(Message {
             html_body: Vec::with_capacity(0),
             text_body: Vec::with_capacity(0),
             attachments: Vec::with_capacity(0),
@@ -234,7 +221,7 @@ impl Enclose {
                 },
             ],
             raw_message: b""[..].into(),
-        };
+        });
 
         ctx.insert_header(
             0,
@@ -303,30 +290,19 @@ impl Enclose {
         }
 
         if add_date {
-            #[cfg(not(test))]
-            let header_value = mail_builder::headers::date::Date::now().to_rfc822();
-            #[cfg(test)]
-            let header_value = "Tue, 20 Nov 2022 05:14:20 -0300".to_string();
-
             ctx.insert_header(
                 0,
                 HeaderName::Other("Date".to_string().into()),
-                header_value,
+                ctx.current_date(),
                 true,
             );
         }
 
         if add_message_id {
-            let mut header_value = Vec::with_capacity(20);
-            #[cfg(not(test))]
-            generate_message_id_header(&mut header_value).unwrap();
-            #[cfg(test)]
-            header_value.extend_from_slice(b"<auto-generated@message-id>");
-
             ctx.insert_header(
                 0,
                 HeaderName::Other("Message-ID".to_string().into()),
-                String::from_utf8(header_value).unwrap(),
+                ctx.generate_message_id(),
                 true,
             );
         }
@@ -358,7 +334,7 @@ impl ExtractText {
 
             if !self.modifiers.is_empty() && !value.is_empty() {
                 for modifier in &self.modifiers {
-                    value = modifier.apply(&value, ctx.runtime.max_variable_size);
+                    value = modifier.apply(&value, ctx);
                 }
             }
         }
@@ -390,20 +366,181 @@ impl<'x> Context<'x> {
             self.last_message_id += 1;
             self.main_message_id = self.last_message_id;
             self.has_changes = false;
-            let message = self.build_message();
-            Some(Event::CreatedMessage {
-                message_id: self.main_message_id,
-                message,
+            Some(if self.runtime.message_patches {
+                Event::CreatedPatch {
+                    message_id: self.main_message_id,
+                    patch: self.message_patch(),
+                }
+            } else {
+                Event::CreatedMessage {
+                    message_id: self.main_message_id,
+                    message: self.build_message(),
+                }
             })
         } else {
             None
         }
     }
 
-    pub(crate) fn build_message(&mut self) -> Vec<u8> {
-        let mut current_message = &self.message;
-        let mut current_boundary = "";
+    pub(crate) fn build_message(&self) -> Vec<u8> {
         let mut message = Vec::with_capacity(self.message_size);
+        self.visit_message(&mut |bytes| message.extend_from_slice(bytes));
+        message
+    }
+
+    pub(crate) fn message_len(&self) -> usize {
+        let mut len = 0;
+        self.visit_message(&mut |bytes| len += bytes.len());
+        len
+    }
+
+    /// Streams the current message to `out` without building it in memory.
+    pub fn write_message(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let mut result = Ok(());
+        self.visit_message(&mut |bytes| {
+            if result.is_ok() {
+                result = out.write_all(bytes);
+            }
+        });
+        result
+    }
+
+    /// Describes the current message as edits to the header and part ranges
+    /// of the original raw message. Messages replaced by a filter or enclosed
+    /// are described as a single replaced part.
+    pub fn message_patch(&self) -> MessagePatch {
+        self.part_edits()
+            .map(|edits| MessagePatch {
+                len: edits.iter().fold(self.raw_message.len(), |len, edit| {
+                    len + edit.contents_len() - edit.length()
+                }),
+                edits,
+            })
+            .unwrap_or_else(|| {
+                let message = self.build_message();
+                MessagePatch {
+                    len: message.len(),
+                    edits: vec![PatchEdit::ReplacePart {
+                        offset: 0,
+                        length: self.raw_message.len(),
+                        contents: message,
+                    }],
+                }
+            })
+    }
+
+    fn part_edits(&self) -> Option<Vec<PatchEdit>> {
+        if matches!(&self.message.raw_message, Cow::Owned(raw_message) if !raw_message.is_empty()) {
+            return None;
+        }
+
+        let original = parse_message(&self.raw_message);
+        let mut edits = Vec::new();
+
+        for part in &self.message.parts {
+            let original_part = &original.parts[find_original_part(&original, part)?];
+            let header_end = original_part
+                .headers
+                .last()
+                .map_or(original_part.offset_header, |header| header.offset_end);
+            let mut kept_headers = vec![false; original_part.headers.len()];
+            let mut offset = original_part.offset_header;
+            let mut headers = Vec::new();
+
+            for header in &part.headers {
+                if header.offset_end != 0 {
+                    let header_pos = original_part
+                        .headers
+                        .iter()
+                        .position(|h| h.offset_end == header.offset_end)?;
+                    let original_header = &original_part.headers[header_pos];
+                    if original_header.offset_field < offset {
+                        // Headers were reordered
+                        return None;
+                    }
+                    if !headers.is_empty() {
+                        edits.push(PatchEdit::InsertHeaders {
+                            offset,
+                            headers: std::mem::take(&mut headers),
+                        });
+                    }
+                    if header.offset_field != original_header.offset_field {
+                        edits.push(PatchEdit::RenameHeader {
+                            offset: original_header.offset_field,
+                            length: original_header.offset_start - original_header.offset_field,
+                            name: header.name.as_str().to_string(),
+                        });
+                    }
+                    kept_headers[header_pos] = true;
+                    offset = header.offset_end;
+                } else {
+                    self.write_header(header, &mut |bytes| headers.extend_from_slice(bytes));
+                }
+            }
+            if !headers.is_empty() {
+                edits.push(PatchEdit::InsertHeaders { offset, headers });
+            }
+
+            for (header, _) in original_part
+                .headers
+                .iter()
+                .zip(kept_headers)
+                .filter(|(_, kept)| !kept)
+            {
+                edits.push(PatchEdit::RemoveHeader {
+                    offset: header.offset_field,
+                    length: header.offset_end - header.offset_field,
+                });
+            }
+
+            if part.offset_body == 0 {
+                if matches!(part.body, PartType::Multipart(_) | PartType::Message(_)) {
+                    return None;
+                }
+                let mut contents = Vec::new();
+                if part.encoding != Encoding::None {
+                    contents.extend_from_slice(b"\r\n");
+                }
+                contents.extend_from_slice(part.get_contents());
+                edits.push(PatchEdit::ReplacePart {
+                    offset: header_end,
+                    length: original_part.offset_end - header_end,
+                    contents,
+                });
+            }
+        }
+
+        // Insertions go before removals at the same offset
+        edits.sort_by_key(|edit| (edit.offset(), edit.length()));
+        let mut offset = 0;
+        for edit in &edits {
+            if edit.offset() < offset {
+                return None;
+            }
+            offset = edit.offset() + edit.length();
+        }
+
+        Some(edits)
+    }
+
+    fn write_header(&self, header: &Header, sink: &mut impl FnMut(&[u8])) {
+        let header_name = header.name.as_str();
+        sink(header_name.as_bytes());
+        sink(b": ");
+        sink(
+            encode_header_value(
+                header_name,
+                header.value.as_text_ref().unwrap_or(""),
+                self.smtputf8,
+            )
+            .as_bytes(),
+        );
+        sink(b"\r\n");
+    }
+
+    fn visit_message(&self, sink: &mut impl FnMut(&[u8])) {
+        let mut current_message: &Message = &self.message;
+        let mut current_boundary = "";
         let mut iter = [0].iter();
         let mut iter_stack = Vec::new();
         let mut last_offset = 0;
@@ -411,16 +548,14 @@ impl<'x> Context<'x> {
         'outer: loop {
             while let Some(part) = iter.next().and_then(|p| current_message.parts.get(*p)) {
                 if last_offset > 0 {
-                    message.extend_from_slice(
-                        &current_message.raw_message[last_offset..part.offset_header],
-                    );
+                    sink(&self.raw_bytes(current_message)[last_offset..part.offset_header]);
                 } else if !current_boundary.is_empty()
                     && part.offset_end == 0
                     && !matches!(iter_stack.last(), Some((StackItem::Message(_), _, _)))
                 {
-                    message.extend_from_slice(b"\r\n--");
-                    message.extend_from_slice(current_boundary.as_bytes());
-                    message.extend_from_slice(b"\r\n");
+                    sink(b"\r\n--");
+                    sink(current_boundary.as_bytes());
+                    sink(b"\r\n");
                 }
 
                 let mut ct_pos = usize::MAX;
@@ -428,16 +563,16 @@ impl<'x> Context<'x> {
                 for (header_pos, header) in part.headers.iter().enumerate() {
                     if header.offset_end != 0 {
                         if header.offset_field != header.offset_start {
-                            message.extend_from_slice(
-                                &current_message.raw_message
+                            sink(
+                                &self.raw_bytes(current_message)
                                     [header.offset_field..header.offset_end],
                             );
                         } else {
                             // Renamed header
-                            message.extend_from_slice(header.name.as_str().as_bytes());
-                            message.extend_from_slice(b":");
-                            message.extend_from_slice(
-                                &current_message.raw_message
+                            sink(header.name.as_str().as_bytes());
+                            sink(b":");
+                            sink(
+                                &self.raw_bytes(current_message)
                                     [header.offset_start..header.offset_end],
                             );
                         }
@@ -445,18 +580,13 @@ impl<'x> Context<'x> {
                         if header.name == HeaderName::Other("Content-Type".into()) {
                             ct_pos = header_pos;
                         }
-
-                        message.extend_from_slice(header.name.as_str().as_bytes());
-                        message.extend_from_slice(b": ");
-                        message
-                            .extend_from_slice(header.value.as_text_ref().unwrap_or("").as_bytes());
-                        message.extend_from_slice(b"\r\n");
+                        self.write_header(header, sink);
                     }
                 }
 
                 if part.offset_body != 0 || part.encoding != Encoding::None {
                     // Add CRLF unless this is a :mime replaced part
-                    message.extend_from_slice(b"\r\n");
+                    sink(b"\r\n");
                 }
 
                 if part.offset_body != 0 {
@@ -472,9 +602,7 @@ impl<'x> Context<'x> {
                         last_offset = part.offset_body;
                         continue 'outer;
                     } else {
-                        message.extend_from_slice(
-                            &current_message.raw_message[part.offset_body..part.offset_end],
-                        )
+                        sink(&self.raw_bytes(current_message)[part.offset_body..part.offset_end])
                     }
                 } else {
                     match &part.body {
@@ -515,7 +643,7 @@ impl<'x> Context<'x> {
                         }
                         _ => {
                             // Replaced part
-                            message.extend_from_slice(part.get_contents());
+                            sink(part.get_contents());
                         }
                     }
                 }
@@ -526,8 +654,9 @@ impl<'x> Context<'x> {
                 match prev_item {
                     StackItem::Message(prev_message) => {
                         if last_offset > 0 {
-                            if let Some(bytes) = current_message.raw_message.get(last_offset..) {
-                                message.extend_from_slice(bytes);
+                            if let Some(bytes) = self.raw_bytes(current_message).get(last_offset..)
+                            {
+                                sink(bytes);
                             }
                             last_offset = 0;
                         }
@@ -535,16 +664,14 @@ impl<'x> Context<'x> {
                     }
                     StackItem::Boundary(prev_boundary) => {
                         if !current_boundary.is_empty() {
-                            message.extend_from_slice(b"\r\n--");
-                            message.extend_from_slice(current_boundary.as_bytes());
-                            message.extend_from_slice(b"--\r\n");
+                            sink(b"\r\n--");
+                            sink(current_boundary.as_bytes());
+                            sink(b"--\r\n");
                         }
                         current_boundary = prev_boundary;
                     }
                     StackItem::None => {
-                        message.extend_from_slice(
-                            &current_message.raw_message[last_offset..prev_part.offset_end],
-                        );
+                        sink(&self.raw_bytes(current_message)[last_offset..prev_part.offset_end]);
                         last_offset = prev_part.offset_end;
                     }
                 }
@@ -555,13 +682,100 @@ impl<'x> Context<'x> {
         }
 
         if last_offset > 0 {
-            if let Some(bytes) = current_message.raw_message.get(last_offset..) {
-                message.extend_from_slice(bytes);
+            if let Some(bytes) = self.raw_bytes(current_message).get(last_offset..) {
+                sink(bytes);
+            }
+        }
+    }
+}
+
+// Parts keep the offsets of the part they were parsed from
+pub(crate) fn find_original_part(original: &Message, part: &MessagePart) -> Option<usize> {
+    original.parts.iter().position(|original_part| {
+        part.offset_end != 0
+            && original_part.offset_header == part.offset_header
+            && original_part.offset_end == part.offset_end
+    })
+}
+
+impl MessagePatch {
+    pub fn edits(&self) -> &[PatchEdit] {
+        &self.edits
+    }
+
+    /// Writes the patched message, `raw_message` has to be the original message.
+    pub fn apply(&self, raw_message: &[u8], out: &mut impl Write) -> std::io::Result<()> {
+        let mut offset = 0;
+        for edit in &self.edits {
+            let bytes = raw_message
+                .get(offset..edit.offset())
+                .filter(|_| edit.offset() + edit.length() <= raw_message.len())
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "Patch does not match the original message.",
+                    )
+                })?;
+            out.write_all(bytes)?;
+            match edit {
+                PatchEdit::InsertHeaders { headers, .. } => out.write_all(headers)?,
+                PatchEdit::RemoveHeader { .. } => (),
+                PatchEdit::RenameHeader { name, .. } => {
+                    out.write_all(name.as_bytes())?;
+                    out.write_all(b":")?;
+                }
+                PatchEdit::ReplacePart { contents, .. } => out.write_all(contents)?,
             }
+            offset = edit.offset() + edit.length();
         }
+        out.write_all(raw_message.get(offset..).unwrap_or_default())
+    }
 
+    pub fn to_vec(&self, raw_message: &[u8]) -> Vec<u8> {
+        let mut message = Vec::with_capacity(self.len());
+        self.apply(raw_message, &mut message).ok();
         message
     }
+
+    /// Size in bytes of the patched message.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl PatchEdit {
+    /// Offset in the original message.
+    pub fn offset(&self) -> usize {
+        match self {
+            PatchEdit::InsertHeaders { offset, .. }
+            | PatchEdit::RemoveHeader { offset, .. }
+            | PatchEdit::RenameHeader { offset, .. }
+            | PatchEdit::ReplacePart { offset, .. } => *offset,
+        }
+    }
+
+    /// Number of bytes of the original message replaced by this edit.
+    pub fn length(&self) -> usize {
+        match self {
+            PatchEdit::InsertHeaders { .. } => 0,
+            PatchEdit::RemoveHeader { length, .. }
+            | PatchEdit::RenameHeader { length, .. }
+            | PatchEdit::ReplacePart { length, .. } => *length,
+        }
+    }
+
+    fn contents_len(&self) -> usize {
+        match self {
+            PatchEdit::InsertHeaders { headers, .. } => headers.len(),
+            PatchEdit::RemoveHeader { .. } => 0,
+            PatchEdit::RenameHeader { name, .. } => name.len() + 1,
+            PatchEdit::ReplacePart { contents, .. } => contents.len(),
+        }
+    }
 }
 
 #[cfg(test)]
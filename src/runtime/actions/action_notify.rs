@@ -21,7 +21,6 @@
  * for more details.
 */
 
-use mail_builder::headers::{date::Date, message_id::generate_message_id_header};
 use mail_parser::{decoders::quoted_printable::HEX_MAP, HeaderName, RfcHeader};
 
 use crate::{
@@ -29,13 +28,14 @@ use crate::{
         action_notify::Notify,
         action_redirect::{ByTime, Ret},
     },
+    runtime::{headers::encode_header_value, notify::Notification, RuntimeError},
     Context, Event, Importance, Recipient,
 };
 
 use super::action_vacation::MAX_SUBJECT_LEN;
 
 impl Notify {
-    pub(crate) fn exec(&self, ctx: &mut Context) {
+    pub(crate) fn exec(&self, ctx: &mut Context) -> Result<(), RuntimeError> {
         // Do not notify on Auto-Submitted messages
         for header in &ctx.message.parts[0].headers {
             if matches!(&header.name, HeaderName::Other(name) if name.eq_ignore_ascii_case("Auto-Submitted"))
@@ -44,16 +44,21 @@ impl Notify {
                     .as_text_ref()
                     .map_or(true, |v| !v.eq_ignore_ascii_case("no"))
             {
-                return;
+                return Ok(());
             }
         }
 
         let uri = ctx.eval_string(&self.method).into_owned();
-        let (scheme, params) = if let Some(parts) = parse_uri(&uri) {
-            parts
-        } else {
-            return;
-        };
+        if ctx
+            .runtime
+            .notification_methods
+            .validate_uri(&uri)
+            .is_none()
+        {
+            return Err(RuntimeError::InvalidNotificationUri(uri));
+        }
+        let (scheme, params) = parse_uri(&uri).unwrap_or_default();
+        let method = ctx.runtime.notification_methods.get(scheme).cloned();
 
         let has_fcc = self.fcc.is_some();
         let is_mailto = scheme.eq_ignore_ascii_case("mailto")
@@ -65,7 +70,7 @@ impl Notify {
                 if let Some(params) = parse_mailto(params) {
                     params
                 } else {
-                    return;
+                    return Ok(());
                 }
             } else {
                 MailtoMessage {
@@ -108,7 +113,9 @@ impl Notify {
 
             let mut message = Vec::with_capacity(message_len);
             message.extend_from_slice(b"From: ");
-            message.extend_from_slice(from.as_bytes());
+            message.extend_from_slice(
+                encode_header_value("From", from.as_ref(), ctx.smtputf8).as_bytes(),
+            );
             message.extend_from_slice(b"\r\n");
 
             for (header, addresses) in [("To: ", &params.to), ("Cc: ", &params.cc)] {
@@ -152,19 +159,21 @@ impl Notify {
                 }
                 message.extend_from_slice(header.as_str().as_bytes());
                 message.extend_from_slice(b": ");
-                message.extend_from_slice(value.as_bytes());
+                message.extend_from_slice(
+                    encode_header_value(header.as_str(), value, ctx.smtputf8).as_bytes(),
+                );
                 message.extend_from_slice(b"\r\n");
             }
 
             if !has_date {
                 message.extend_from_slice(b"Date: ");
-                message.extend_from_slice(Date::now().to_rfc822().as_bytes());
+                message.extend_from_slice(ctx.current_date().as_bytes());
                 message.extend_from_slice(b"\r\n");
             }
 
             if !has_message_id {
                 message.extend_from_slice(b"Message-ID: ");
-                generate_message_id_header(&mut message).unwrap();
+                message.extend_from_slice(ctx.generate_message_id().as_bytes());
                 message.extend_from_slice(b"\r\n");
             }
 
@@ -196,19 +205,22 @@ impl Notify {
             } else {
                 ""
             };
+            let mut subject_ = String::with_capacity(std::cmp::min(subject.len(), MAX_SUBJECT_LEN));
             let mut iter = subject.chars().enumerate();
-            let mut buf = [0; 4];
             #[allow(clippy::while_let_on_iterator)]
             while let Some((pos, char)) = iter.next() {
                 if pos < MAX_SUBJECT_LEN {
-                    message.extend_from_slice(char.encode_utf8(&mut buf).as_bytes());
+                    subject_.push(char);
                 } else {
                     break;
                 }
             }
             if iter.next().is_some() {
-                message.extend_from_slice('…'.encode_utf8(&mut buf).as_bytes());
+                subject_.push('…');
             }
+            message.extend_from_slice(
+                encode_header_value("Subject", &subject_, ctx.smtputf8).as_bytes(),
+            );
             message.extend_from_slice(b"\r\n");
 
             message.extend_from_slice(b"Auto-Submitted: auto-notified\r\n");
@@ -222,6 +234,7 @@ impl Notify {
                 message.extend_from_slice(subject.as_bytes());
             }
 
+            let envelope = ctx.outbound_envelope(&ctx.runtime.notify_return_path, Some(&message));
             ctx.last_message_id += 1;
             events.push(Event::CreatedMessage {
                 message_id: ctx.last_message_id,
@@ -251,29 +264,45 @@ impl Notify {
                     notify: crate::compiler::grammar::actions::action_redirect::Notify::Never,
                     return_of_content: Ret::Default,
                     by_time: ByTime::None,
+                    envelope,
                     message_id: ctx.last_message_id,
                 });
             }
         }
 
         if !is_mailto {
+            let from = self.from.as_ref().map(|f| ctx.eval_string(f).into_owned());
+            let importance = self.importance.as_ref().map_or(Importance::Normal, |i| {
+                match ctx.eval_string(i).as_ref() {
+                    "1" => Importance::High,
+                    "3" => Importance::Low,
+                    _ => Importance::Normal,
+                }
+            });
+            let options = ctx.eval_strings_owned(&self.options);
+            let message = self
+                .message
+                .as_ref()
+                .map(|m| ctx.eval_string(m).into_owned())
+                .or_else(|| ctx.message.get_subject().map(|s| s.to_string()))
+                .unwrap_or_default();
+            let payload = method.and_then(|method| {
+                method.payload(&Notification {
+                    uri: &uri,
+                    from: from.as_deref(),
+                    importance,
+                    options: &options,
+                    message: &message,
+                })
+            });
+
             events.push(Event::Notify {
                 method: uri,
-                from: self.from.as_ref().map(|f| ctx.eval_string(f).into_owned()),
-                importance: self.importance.as_ref().map_or(Importance::Normal, |i| {
-                    match ctx.eval_string(i).as_ref() {
-                        "1" => Importance::High,
-                        "3" => Importance::Low,
-                        _ => Importance::Normal,
-                    }
-                }),
-                options: ctx.eval_strings_owned(&self.options),
-                message: self
-                    .message
-                    .as_ref()
-                    .map(|m| ctx.eval_string(m).into_owned())
-                    .or_else(|| ctx.message.get_subject().map(|s| s.to_string()))
-                    .unwrap_or_default(),
+                from,
+                importance,
+                options,
+                message,
+                payload,
             });
             ctx.num_out_messages += 1;
         }
@@ -296,6 +325,7 @@ impl Notify {
             });
         }
         ctx.queued_events = events.into_iter();
+        Ok(())
     }
 }
 
@@ -347,18 +377,6 @@ pub fn validate_from(addr: &str) -> bool {
     has_dot && has_at && !in_angle
 }
 
-pub fn validate_uri(uri: &str) -> Option<&str> {
-    let (scheme, uri) = parse_uri(uri)?;
-    if scheme.eq_ignore_ascii_case("mailto") {
-        parse_mailto(uri)?;
-        scheme.into()
-    } else if ["xmpp", "tel", "http", "https"].contains(&scheme) {
-        scheme.into()
-    } else {
-        None
-    }
-}
-
 pub(crate) fn parse_uri(uri: &str) -> Option<(&str, &str)> {
     let (scheme, uri) = uri.split_once(':')?;
 
@@ -382,7 +400,7 @@ enum State {
 }
 
 #[derive(Default)]
-struct MailtoMessage {
+pub(crate) struct MailtoMessage {
     to: Vec<String>,
     cc: Vec<String>,
     bcc: Vec<String>,
@@ -390,7 +408,7 @@ struct MailtoMessage {
     headers: Vec<(HeaderName<'static>, String)>,
 }
 
-fn parse_mailto(uri: &str) -> Option<MailtoMessage> {
+pub(crate) fn parse_mailto(uri: &str) -> Option<MailtoMessage> {
     let mut params = MailtoMessage::default();
 
     let mut state = State::Address((RfcHeader::To, false));
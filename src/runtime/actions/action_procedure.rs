@@ -0,0 +1,83 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use crate::{
+    compiler::grammar::actions::action_procedure::ProcedureCall,
+    runtime::{context::CallStack, RuntimeError},
+    Context,
+};
+
+impl ProcedureCall {
+    pub(crate) fn exec(&self, ctx: &mut Context) -> Result<(), RuntimeError> {
+        if ctx.call_stack.len() >= ctx.runtime.max_nested_calls {
+            return Err(RuntimeError::TooManyNestedCalls);
+        }
+
+        // Procedures run with their own local and match variables, as included scripts do
+        let args = ctx.eval_strings_owned(&self.args);
+        let num_vars = ctx.vars_local.len();
+        let num_match_vars = ctx.vars_match.len();
+        ctx.call_stack.push(CallStack {
+            prev_pos: ctx.pos,
+            prev_vars_local: std::mem::replace(
+                &mut ctx.vars_local,
+                vec![String::with_capacity(0); num_vars],
+            ),
+            prev_vars_match: std::mem::replace(
+                &mut ctx.vars_match,
+                vec![String::with_capacity(0); num_match_vars],
+            ),
+        });
+        for (param, arg) in self.params.iter().zip(args) {
+            ctx.set_variable(param, arg);
+        }
+        ctx.pos = self.pos;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{runtime::RuntimeError, Compiler, Input, Runtime};
+
+    #[test]
+    fn nested_calls() {
+        let script = Compiler::new()
+            .compile(
+                br#"require "vnd.stalwart.procedures";
+                procedure "forever" {
+                    call "forever";
+                }
+                call "forever";
+                "#,
+            )
+            .unwrap();
+        let runtime = Runtime::new().with_max_nested_calls(5);
+        let mut instance = runtime.filter(b"Subject: Hello\r\n\r\nTest\r\n");
+        assert!(matches!(
+            instance.run(Input::script("test", script)),
+            Some(Err(RuntimeError::TooManyNestedCalls))
+        ));
+    }
+}
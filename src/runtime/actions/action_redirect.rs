@@ -43,6 +43,10 @@ impl Redirect {
                 // Try to avoid fowarding loops
                 if !self.list
                     && (address.eq_ignore_ascii_case(ctx.user_address.as_ref())
+                        || ctx.imap_cause.is_some()
+                            && ctx.vars_env.get("imap.email").map_or(false, |email| {
+                                address.eq_ignore_ascii_case(email.as_ref())
+                            })
                         || ctx.envelope.iter().any(|(e, v)| {
                             matches!(e, Envelope::From) && v.eq_ignore_ascii_case(address.as_str())
                         }))
@@ -51,7 +55,7 @@ impl Redirect {
                 }
 
                 if !self.copy && matches!(&ctx.final_event, Some(Event::Keep { .. })) {
-                    ctx.final_event = None;
+                    ctx.cancel_implicit_keep();
                 }
 
                 let mut events = Vec::with_capacity(2);
@@ -97,6 +101,7 @@ impl Redirect {
                         },
                         ByTime::None => ByTime::None,
                     },
+                    envelope: ctx.outbound_envelope(&ctx.runtime.redirect_return_path, None),
                     message_id: ctx.main_message_id,
                 });
                 ctx.queued_events = events.into_iter();
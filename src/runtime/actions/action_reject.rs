@@ -0,0 +1,297 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use mail_parser::{HeaderName, RfcHeader};
+
+use crate::{
+    compiler::grammar::actions::{
+        action_redirect::{ByTime, Notify, Ret},
+        action_reject::Reject,
+    },
+    Context, Envelope, Event, Recipient, ReturnPath,
+};
+
+use super::action_vacation::write_header;
+
+impl Reject {
+    pub(crate) fn exec(&self, ctx: &mut Context) {
+        let reason = ctx.eval_string(&self.reason).into_owned();
+
+        // ereject is left to the host while it can still refuse the message during the SMTP session
+        if !ctx.runtime.reject_mdn || (self.ereject && ctx.protocol_reject) {
+            ctx.queued_events = vec![Event::Reject {
+                extended: self.ereject,
+                reason,
+            }]
+            .into_iter();
+            return;
+        }
+
+        let mut return_path = String::new();
+        let mut final_recipient = String::new();
+        for (name, value) in &ctx.envelope {
+            match name {
+                Envelope::From => {
+                    return_path = value.to_string();
+                }
+                Envelope::To if final_recipient.is_empty() => {
+                    final_recipient = value.to_string();
+                }
+                _ => (),
+            }
+        }
+        if final_recipient.is_empty() {
+            final_recipient = ctx.user_address.to_string();
+        }
+
+        // Messages with a null or unknown return path are rejected by the host
+        if !return_path.is_empty()
+            && return_path != "<>"
+            && ctx.num_out_messages < ctx.runtime.max_out_messages
+        {
+            let message = ctx.build_mdn(&return_path, &final_recipient, &reason);
+            let envelope = ctx.outbound_envelope(&ReturnPath::Null, Some(&message));
+            ctx.last_message_id += 1;
+            ctx.num_out_messages += 1;
+            ctx.queued_events = vec![
+                Event::CreatedMessage {
+                    message_id: ctx.last_message_id,
+                    message,
+                },
+                Event::SendMessage {
+                    recipient: Recipient::Address(return_path),
+                    notify: Notify::Never,
+                    return_of_content: Ret::Default,
+                    by_time: ByTime::None,
+                    envelope,
+                    message_id: ctx.last_message_id,
+                },
+            ]
+            .into_iter();
+        } else {
+            ctx.queued_events = vec![Event::Reject {
+                extended: self.ereject,
+                reason,
+            }]
+            .into_iter();
+        }
+    }
+}
+
+impl<'x> Context<'x> {
+    fn build_mdn(&self, return_path: &str, final_recipient: &str, reason: &str) -> Vec<u8> {
+        let mut subject = None;
+        let mut message_id = None;
+        let root = &self.message.parts[0];
+        for header in &root.headers {
+            match &header.name {
+                HeaderName::Rfc(RfcHeader::Subject) if subject.is_none() => {
+                    subject = header.value.as_text_ref();
+                }
+                HeaderName::Rfc(RfcHeader::MessageId) if message_id.is_none() => {
+                    message_id = header.value.as_text_ref();
+                }
+                _ => (),
+            }
+        }
+        let from = if !self.user_address.is_empty() {
+            self.user_from_field()
+        } else {
+            final_recipient.to_string()
+        };
+        let reporting_ua = final_recipient
+            .rsplit_once('@')
+            .map_or("localhost", |(_, domain)| domain);
+        let headers = self
+            .raw_bytes(&self.message)
+            .get(root.offset_header..root.offset_body)
+            .unwrap_or_default();
+        let headers_len = headers
+            .iter()
+            .rposition(|ch| !ch.is_ascii_whitespace())
+            .map_or(0, |pos| pos + 1);
+        let boundary = self.generate_boundary();
+
+        let mut message = Vec::with_capacity(reason.len() + headers.len() + 1024);
+        write_header(&mut message, "From", &from, self.smtputf8);
+        write_header(&mut message, "To", return_path, self.smtputf8);
+        write_header(
+            &mut message,
+            "Subject",
+            &format!("Rejected: {}", subject.unwrap_or("(no subject)")),
+            self.smtputf8,
+        );
+        message.extend_from_slice(b"Date: ");
+        message.extend_from_slice(self.current_date().as_bytes());
+        message.extend_from_slice(b"\r\nMessage-ID: ");
+        message.extend_from_slice(self.generate_message_id().as_bytes());
+        message.extend_from_slice(b"\r\n");
+        if let Some(message_id) = message_id {
+            message.extend_from_slice(b"In-Reply-To: <");
+            message.extend_from_slice(message_id.as_bytes());
+            message.extend_from_slice(b">\r\n");
+        }
+        message.extend_from_slice(b"Auto-Submitted: auto-replied (rejected)\r\n");
+        message.extend_from_slice(b"MIME-Version: 1.0\r\n");
+        message.extend_from_slice(
+            b"Content-Type: multipart/report; report-type=disposition-notification;\r\n",
+        );
+        message.extend_from_slice(format!("\tboundary=\"{}\"\r\n\r\n", boundary).as_bytes());
+        message.extend_from_slice(b"This is a MIME-encapsulated message\r\n\r\n");
+
+        // Human readable reason
+        message.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        message.extend_from_slice(b"Content-Type: text/plain; charset=utf-8\r\n");
+        message.extend_from_slice(b"Content-Transfer-Encoding: 8bit\r\n\r\n");
+        message.extend_from_slice(
+            format!(
+                "Your message to <{}> was automatically rejected:\r\n",
+                final_recipient
+            )
+            .as_bytes(),
+        );
+        message.extend_from_slice(reason.as_bytes());
+        message.extend_from_slice(b"\r\n\r\n");
+
+        // Disposition notification (RFC 3798)
+        message.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        message.extend_from_slice(b"Content-Type: message/disposition-notification\r\n\r\n");
+        message.extend_from_slice(
+            format!("Reporting-UA: {}; Stalwart Sieve\r\n", reporting_ua).as_bytes(),
+        );
+        message.extend_from_slice(
+            format!("Final-Recipient: rfc822; {}\r\n", final_recipient).as_bytes(),
+        );
+        if let Some(message_id) = message_id {
+            message
+                .extend_from_slice(format!("Original-Message-ID: <{}>\r\n", message_id).as_bytes());
+        }
+        message.extend_from_slice(
+            b"Disposition: automatic-action/MDN-sent-automatically; deleted\r\n\r\n",
+        );
+
+        // Original headers
+        message.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        message.extend_from_slice(b"Content-Type: text/rfc822-headers\r\n\r\n");
+        message.extend_from_slice(&headers[..headers_len]);
+        message.extend_from_slice(format!("\r\n\r\n--{}--\r\n", boundary).as_bytes());
+
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{runtime::run_script, Compiler, Event, Input, Recipient, Runtime};
+
+    #[test]
+    fn reject_mdn() {
+        let compiler = Compiler::new();
+        let reject = compiler
+            .compile(br#"require "reject"; reject "Not interested";"#)
+            .unwrap();
+        let ereject = compiler
+            .compile(br#"require "ereject"; ereject "Not interested";"#)
+            .unwrap();
+        let raw_message = b"From: john@example.org\r\nSubject: Offer\r\nMessage-ID: <1234@example.org>\r\n\r\nTest\r\n";
+        let runtime = Runtime::new()
+            .with_reject_mdn(true)
+            .with_message_id_generator(|| "mdn@example.net".to_string())
+            .with_boundary_generator(|| "boundary".to_string());
+
+        let mut instance = runtime
+            .filter(raw_message)
+            .with_envelope("from", "john@example.org")
+            .with_envelope("to", "jane@example.net");
+        let events = run_script(
+            |input| instance.run(input),
+            Input::script("reject", reject.clone()),
+        );
+        assert_eq!(events.len(), 2);
+        if let Event::CreatedMessage { message, .. } = &events[0] {
+            let message = std::str::from_utf8(message).unwrap();
+            for line in [
+                "To: john@example.org\r\n",
+                "Subject: Rejected: Offer\r\n",
+                "Content-Type: multipart/report; report-type=disposition-notification;\r\n",
+                "Not interested\r\n",
+                "Reporting-UA: example.net; Stalwart Sieve\r\n",
+                "Final-Recipient: rfc822; jane@example.net\r\n",
+                "Original-Message-ID: <1234@example.org>\r\n",
+                "Disposition: automatic-action/MDN-sent-automatically; deleted\r\n",
+                "Content-Type: text/rfc822-headers\r\n\r\nFrom: john@example.org\r\n",
+                "--boundary--\r\n",
+            ] {
+                assert!(
+                    message.contains(line),
+                    "{:?} not found in {}",
+                    line,
+                    message
+                );
+            }
+        } else {
+            panic!("Unexpected event {:?}", events[0]);
+        }
+        assert!(matches!(
+            &events[1],
+            Event::SendMessage { recipient: Recipient::Address(address), envelope, .. }
+                if address == "john@example.org" && envelope.from.is_empty() && envelope.orcpt.is_none()
+        ));
+
+        // Null return path is rejected by the host
+        let mut instance = runtime
+            .filter(raw_message)
+            .with_envelope("to", "jane@example.net");
+        assert!(matches!(
+            instance.run(Input::script("reject", reject)),
+            Some(Ok(Event::Reject {
+                extended: false,
+                ..
+            }))
+        ));
+
+        // ereject is returned to the host during the SMTP session
+        let mut instance = runtime
+            .filter(raw_message)
+            .with_envelope("from", "john@example.org");
+        assert!(matches!(
+            instance.run(Input::script("ereject", ereject.clone())),
+            Some(Ok(Event::Reject { extended: true, .. }))
+        ));
+
+        // and sends a notification once delivery has been accepted
+        let mut instance = runtime
+            .filter(raw_message)
+            .with_envelope("from", "john@example.org")
+            .with_protocol_reject(false);
+        let events = run_script(
+            |input| instance.run(input),
+            Input::script("ereject", ereject),
+        );
+        assert!(matches!(
+            &events[..],
+            [Event::CreatedMessage { .. }, Event::SendMessage { recipient: Recipient::Address(address), .. }]
+                if address == "john@example.org"
+        ));
+    }
+}
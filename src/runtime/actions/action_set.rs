@@ -22,16 +22,30 @@
 */
 
 use crate::{
-    compiler::grammar::actions::action_set::{Modifier, Set, Variable},
+    compiler::grammar::actions::action_set::{HashAlgorithm, Modifier, Set, Variable},
+    runtime::modifier::punycode_domain,
     Context,
 };
+use mail_builder::encoders::base64::base64_encode;
+use mail_parser::{
+    decoders::{base64::base64_decode, html::html_to_text},
+    parsers::MessageStream,
+    HeaderValue,
+};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
 use std::fmt::Write;
 
 impl Set {
     pub(crate) fn exec(&self, ctx: &mut Context) {
-        let mut value = ctx.eval_string(&self.value).into_owned();
+        let mut value = if let Some(expression) = &self.expression {
+            ctx.num_instructions += expression.len();
+            ctx.eval_expression(expression).into_string()
+        } else {
+            ctx.eval_string(&self.value).into_owned()
+        };
         for modifier in &self.modifiers {
-            value = modifier.apply(&value, ctx.runtime.max_variable_size);
+            value = modifier.apply(&value, ctx);
         }
 
         ctx.set_variable(&self.name, value);
@@ -75,7 +89,8 @@ impl<'x> Context<'x> {
 }
 
 impl Modifier {
-    pub(crate) fn apply(&self, input: &str, max_len: usize) -> String {
+    pub(crate) fn apply(&self, input: &str, ctx: &Context) -> String {
+        let max_len = ctx.runtime.max_variable_size;
         match self {
             Modifier::Lower => input.to_lowercase(),
             Modifier::Upper => input.to_uppercase(),
@@ -176,6 +191,89 @@ impl Modifier {
                 }
                 result
             }
+            Modifier::Trim => input.trim().to_string(),
+            Modifier::Replace { find, replace } => {
+                if find.is_empty() {
+                    return input.to_string();
+                }
+                let mut result = String::with_capacity(input.len());
+                let mut last_pos = 0;
+                for (pos, _) in input.match_indices(find.as_str()) {
+                    result.push_str(&input[last_pos..pos]);
+                    result.push_str(replace);
+                    last_pos = pos + find.len();
+                    if result.len() > max_len {
+                        return result;
+                    }
+                }
+                result.push_str(&input[last_pos..]);
+                result
+            }
+            Modifier::Substring { offset, length } => {
+                input.chars().skip(*offset).take(*length).collect()
+            }
+            Modifier::Split(separator) => {
+                if separator.is_empty() {
+                    return input.to_string();
+                }
+                let mut result = String::with_capacity(input.len());
+                for item in input.split(separator.as_str()) {
+                    let item = item.trim();
+                    if !item.is_empty() {
+                        if !result.is_empty() {
+                            result.push('\n');
+                        }
+                        result.push_str(item);
+                    }
+                }
+                result
+            }
+            Modifier::Join(separator) => {
+                let mut result = String::with_capacity(input.len());
+                for item in input.split('\n') {
+                    if !item.is_empty() {
+                        if !result.is_empty() {
+                            result.push_str(separator);
+                        }
+                        result.push_str(item);
+                        if result.len() > max_len {
+                            return result;
+                        }
+                    }
+                }
+                result
+            }
+            Modifier::DecodeMime => {
+                let raw_value = format!("{}\n", input);
+                if let HeaderValue::Text(text) =
+                    MessageStream::new(raw_value.as_bytes()).parse_unstructured()
+                {
+                    text.into_owned()
+                } else {
+                    String::new()
+                }
+            }
+            Modifier::DecodeBase64 => base64_decode(input.as_bytes())
+                .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+                .unwrap_or_else(|| input.to_string()),
+            Modifier::EncodeBase64 => base64_encode(input.as_bytes())
+                .ok()
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .unwrap_or_default(),
+            Modifier::HtmlToText => html_to_text(input),
+            Modifier::Punycode => punycode_domain(input),
+            Modifier::Hash(algorithm) => match algorithm {
+                HashAlgorithm::Md5 => format!("{:x}", md5::compute(input.as_bytes())),
+                HashAlgorithm::Sha1 => format!("{:x}", Sha1::digest(input.as_bytes())),
+                HashAlgorithm::Sha256 => format!("{:x}", Sha256::digest(input.as_bytes())),
+                HashAlgorithm::Sha512 => format!("{:x}", Sha512::digest(input.as_bytes())),
+            },
+            Modifier::Custom(name) => ctx
+                .runtime
+                .modifiers
+                .get(name)
+                .map(|modifier| modifier.apply(input))
+                .unwrap_or_else(|| input.to_string()),
         }
     }
 }
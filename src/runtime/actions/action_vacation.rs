@@ -21,7 +21,6 @@
  * for more details.
 */
 
-use mail_builder::headers::{date::Date, message_id::generate_message_id_header};
 use mail_parser::{Addr, HeaderName, HeaderValue, RfcHeader};
 
 use crate::{
@@ -32,7 +31,7 @@ use crate::{
         },
         AddressPart,
     },
-    runtime::tests::TestResult,
+    runtime::{headers::encode_header_value, tests::TestResult},
     Context, Envelope, Event, Recipient,
 };
 
@@ -230,7 +229,7 @@ impl Vacation {
                     }
                     RfcHeader::References => {
                         if header.offset_start > 0 {
-                            references = (&ctx.message.raw_message
+                            references = (&ctx.raw_bytes(&ctx.message)
                                 [header.offset_start..header.offset_end])
                                 .into();
                         }
@@ -239,7 +238,7 @@ impl Vacation {
                         if matches!(&header.value, HeaderValue::Address(Addr { address: Some(address), ..}) if address.eq_ignore_ascii_case(vacation_to))
                             && header.offset_start > 0
                         {
-                            vacation_to_full = (&ctx.message.raw_message
+                            vacation_to_full = (&ctx.raw_bytes(&ctx.message)
                                 [header.offset_start..header.offset_end])
                                 .into();
                         }
@@ -278,14 +277,19 @@ impl Vacation {
             + 160;
 
         let mut message = Vec::with_capacity(message_len);
-        write_header(&mut message, "From: ", vacation_from.as_ref());
+        write_header(&mut message, "From", vacation_from.as_ref(), ctx.smtputf8);
         if let Some(vacation_to_full) = vacation_to_full {
             message.extend_from_slice(b"To:");
             message.extend_from_slice(vacation_to_full);
         } else {
-            write_header(&mut message, "To: ", vacation_to);
+            write_header(&mut message, "To", vacation_to, ctx.smtputf8);
         }
-        write_header(&mut message, "Subject: ", vacation_subject.as_ref());
+        write_header(
+            &mut message,
+            "Subject",
+            vacation_subject.as_ref(),
+            ctx.smtputf8,
+        );
         if let Some(message_id) = message_id {
             message.extend_from_slice(b"In-Reply-To: <");
             message.extend_from_slice(message_id.as_bytes());
@@ -301,14 +305,14 @@ impl Vacation {
             }
         }
         message.extend_from_slice(b"Date: ");
-        message.extend_from_slice(Date::now().to_rfc822().as_bytes());
+        message.extend_from_slice(ctx.current_date().as_bytes());
         message.extend_from_slice(b"\r\n");
 
         message.extend_from_slice(b"Message-ID: ");
-        generate_message_id_header(&mut message).unwrap();
+        message.extend_from_slice(ctx.generate_message_id().as_bytes());
         message.extend_from_slice(b"\r\n");
 
-        write_header(&mut message, "Auto-Submitted: ", "auto-replied");
+        write_header(&mut message, "Auto-Submitted", "auto-replied", ctx.smtputf8);
         if !self.mime {
             message.extend_from_slice(b"Content-type: text/plain; charset=utf-8\r\n\r\n");
         }
@@ -316,6 +320,7 @@ impl Vacation {
 
         // Add action
         let mut events = Vec::with_capacity(3);
+        let envelope = ctx.outbound_envelope(&ctx.runtime.vacation_return_path, Some(&message));
         ctx.last_message_id += 1;
         ctx.num_out_messages += 1;
         events.push(Event::CreatedMessage {
@@ -327,6 +332,7 @@ impl Vacation {
             notify: Notify::Never,
             return_of_content: Ret::Default,
             by_time: ByTime::None,
+            envelope,
             message_id: ctx.last_message_id,
         });
 
@@ -351,8 +357,9 @@ impl Vacation {
     }
 }
 
-fn write_header(buf: &mut Vec<u8>, name: &str, value: &str) {
+pub(crate) fn write_header(buf: &mut Vec<u8>, name: &str, value: &str, utf8: bool) {
     buf.extend_from_slice(name.as_bytes());
-    buf.extend_from_slice(value.as_bytes());
+    buf.extend_from_slice(b": ");
+    buf.extend_from_slice(encode_header_value(name, value, utf8).as_bytes());
     buf.extend_from_slice(b"\r\n");
 }
@@ -0,0 +1,137 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use ahash::AHashMap;
+use mail_parser::{Header, HeaderValue, MessagePart};
+
+use crate::Context;
+
+pub(crate) type HeaderIndex = AHashMap<String, Vec<usize>>;
+
+#[derive(Debug, Default)]
+pub(crate) struct MessageCache {
+    headers: AHashMap<usize, Arc<HeaderIndex>>,
+    addresses: AHashMap<(usize, usize), Arc<[String]>>,
+    bodies: AHashMap<(Vec<usize>, BodyText), Arc<str>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum BodyText {
+    Text,
+    Content,
+}
+
+// Cached entries point into the original message, clones start empty
+impl Clone for MessageCache {
+    fn clone(&self) -> Self {
+        MessageCache::default()
+    }
+}
+
+impl<'x> Context<'x> {
+    pub(crate) fn header_index(&self, part_id: usize, part: &MessagePart) -> Arc<HeaderIndex> {
+        self.cache
+            .borrow_mut()
+            .headers
+            .entry(part_id)
+            .or_insert_with(|| {
+                let mut index = HeaderIndex::new();
+                for (pos, header) in part.headers.iter().enumerate() {
+                    index
+                        .entry(header.name.as_str().to_ascii_lowercase())
+                        .or_insert_with(Vec::new)
+                        .push(pos);
+                }
+                index.into()
+            })
+            .clone()
+    }
+
+    pub(crate) fn header_addresses<'y>(
+        &self,
+        header: &Header,
+        parse_fnc: impl FnOnce() -> HeaderValue<'y>,
+    ) -> Arc<[String]> {
+        if let Some(addresses) = self
+            .cache
+            .borrow()
+            .addresses
+            .get(&(header.offset_start, header.offset_end))
+        {
+            return addresses.clone();
+        }
+
+        let addresses: Arc<[String]> = match parse_fnc() {
+            HeaderValue::Address(addr) => addr
+                .address
+                .or(addr.name)
+                .map(|addr| addr.into_owned())
+                .into_iter()
+                .collect(),
+            HeaderValue::AddressList(addr_list) => addr_list
+                .into_iter()
+                .filter_map(|addr| addr.address.or(addr.name).map(|addr| addr.into_owned()))
+                .collect(),
+            HeaderValue::Group(group) => group
+                .addresses
+                .into_iter()
+                .filter_map(|addr| addr.address.or(addr.name).map(|addr| addr.into_owned()))
+                .collect(),
+            HeaderValue::GroupList(group_list) => group_list
+                .into_iter()
+                .flat_map(|group| group.addresses)
+                .filter_map(|addr| addr.address.or(addr.name).map(|addr| addr.into_owned()))
+                .collect(),
+            // Non-address values are matched as an empty string
+            _ => [String::new()].into_iter().collect(),
+        };
+        self.cache
+            .borrow_mut()
+            .addresses
+            .insert((header.offset_start, header.offset_end), addresses.clone());
+        addresses
+    }
+
+    // Parts are keyed by their part id, prefixed with the part ids of
+    // any enclosing message/rfc822 parts
+    pub(crate) fn body_text(
+        &self,
+        part_path: &[usize],
+        body_text: BodyText,
+        extract_fnc: impl FnOnce() -> String,
+    ) -> Arc<str> {
+        let key = (part_path.to_vec(), body_text);
+        if let Some(text) = self.cache.borrow().bodies.get(&key) {
+            return text.clone();
+        }
+        let text: Arc<str> = extract_fnc().into();
+        self.cache.borrow_mut().bodies.insert(key, text.clone());
+        text
+    }
+
+    pub(crate) fn invalidate_cache(&mut self) {
+        *self.cache.get_mut() = MessageCache::default();
+    }
+}
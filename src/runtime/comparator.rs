@@ -0,0 +1,194 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{cmp::Ordering, fmt::Debug, sync::Arc};
+
+use ahash::AHashMap;
+use unicode_normalization::UnicodeNormalization;
+
+pub trait Comparator: Debug + Send + Sync {
+    fn is(&self, a: &str, b: &str) -> bool;
+
+    fn contains(&self, haystack: &str, needle: &str) -> bool;
+
+    fn matches(
+        &self,
+        value: &str,
+        pattern: &str,
+        capture_positions: u64,
+        captured_values: &mut Vec<(usize, String)>,
+    ) -> bool;
+
+    fn relational(&self, a: &str, b: &str) -> Ordering;
+}
+
+/// Comparators registered by the host, available to scripts that require
+/// "comparator-<name>". The same registry should be passed to both the
+/// compiler and the runtime. Custom comparators do not support `:regex`.
+#[derive(Debug, Clone, Default)]
+pub struct Comparators {
+    comparators: AHashMap<String, Arc<dyn Comparator>>,
+}
+
+impl Comparators {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_comparator(
+        &mut self,
+        name: impl Into<String>,
+        comparator: impl Comparator + 'static,
+    ) {
+        self.comparators.insert(name.into(), Arc::new(comparator));
+    }
+
+    pub fn with_comparator(
+        mut self,
+        name: impl Into<String>,
+        comparator: impl Comparator + 'static,
+    ) -> Self {
+        self.set_comparator(name, comparator);
+        self
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&Arc<dyn Comparator>> {
+        self.comparators.get(name)
+    }
+
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.comparators.contains_key(name)
+    }
+
+    pub(crate) fn names(&self) -> impl Iterator<Item = &String> {
+        self.comparators.keys()
+    }
+}
+
+// RFC 5051: simple titlecase mapping followed by NFKD decomposition
+pub(crate) fn unicode_casemap(value: &str) -> String {
+    value.chars().map(titlecase).nfkd().collect()
+}
+
+pub(crate) fn unicode_casemap_char(ch: char, mut f: impl FnMut(char)) {
+    for ch in std::iter::once(titlecase(ch)).nfkd() {
+        f(ch);
+    }
+}
+
+fn titlecase(ch: char) -> char {
+    match ch {
+        '\u{01C4}'..='\u{01C6}' => '\u{01C5}',
+        '\u{01C7}'..='\u{01C9}' => '\u{01C8}',
+        '\u{01CA}'..='\u{01CC}' => '\u{01CB}',
+        '\u{01F1}'..='\u{01F3}' => '\u{01F2}',
+        _ => {
+            // Only single character mappings are considered (i.e. 'ß' stays as is)
+            let mut upper = ch.to_uppercase();
+            match (upper.next(), upper.next()) {
+                (Some(upper), None) => upper,
+                _ => ch,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use crate::{runtime::RuntimeError, Compiler, Event, Input, Runtime};
+
+    use super::{Comparator, Comparators};
+
+    // Compares only the digits of each value
+    #[derive(Debug)]
+    struct Digits;
+
+    impl Digits {
+        fn digits(value: &str) -> String {
+            value.chars().filter(|ch| ch.is_ascii_digit()).collect()
+        }
+    }
+
+    impl Comparator for Digits {
+        fn is(&self, a: &str, b: &str) -> bool {
+            Self::digits(a) == Self::digits(b)
+        }
+
+        fn contains(&self, haystack: &str, needle: &str) -> bool {
+            Self::digits(haystack).contains(&Self::digits(needle))
+        }
+
+        fn matches(
+            &self,
+            value: &str,
+            pattern: &str,
+            _: u64,
+            _: &mut Vec<(usize, String)>,
+        ) -> bool {
+            self.is(value, pattern)
+        }
+
+        fn relational(&self, a: &str, b: &str) -> Ordering {
+            Self::digits(a).cmp(&Self::digits(b))
+        }
+    }
+
+    #[test]
+    fn custom_comparator() {
+        let comparators = Comparators::new().with_comparator("x-digits", Digits);
+        let script = br#"require ["comparator-x-digits", "fileinto"];
+            if header :is :comparator "x-digits" "Phone" "555 0100" {
+                fileinto "Calls";
+            }
+        "#;
+
+        assert!(Compiler::new().compile(script).is_err());
+        let compiler = Compiler::new().with_comparators(comparators.clone());
+        let script = compiler.compile(script).unwrap();
+
+        // :regex is not supported by custom comparators
+        assert!(compiler
+            .compile(
+                br#"require ["comparator-x-digits", "regex"];
+                if header :regex :comparator "x-digits" "Phone" "^5" { stop; }"#
+            )
+            .is_err());
+
+        let runtime = Runtime::new().with_comparators(comparators);
+        let mut instance = runtime.filter(b"Phone: (555) 01-00\r\n\r\nTest\r\n");
+        match instance.run(Input::script("test", script.clone())) {
+            Some(Ok(Event::FileInto { folder, .. })) => assert_eq!(folder, "Calls"),
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        // The comparator is required at runtime even when the capability is allowed
+        let runtime = Runtime::new().with_capability("comparator-x-digits");
+        let mut instance = runtime.filter(b"Phone: (555) 01-00\r\n\r\nTest\r\n");
+        assert!(matches!(
+            instance.run(Input::script("test", script)),
+            Some(Err(RuntimeError::CapabilityNotSupported(name))) if name == "comparator-x-digits"
+        ));
+    }
+}
@@ -0,0 +1,289 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    borrow::Cow,
+    fmt::Debug,
+    sync::{Arc, OnceLock},
+};
+
+use mail_parser::HeaderName;
+use regex::Regex;
+
+use crate::{
+    compiler::{
+        grammar::{
+            actions::action_mime::MimeOpts, instruction::Instruction, test::Test, Comparator,
+            LazyKeySet, MatchType,
+        },
+        lexer::string::StringItem,
+    },
+    Context, Sieve,
+};
+
+use super::tests::TestResult;
+
+pub(crate) type Op = Box<dyn Fn(&mut Context<'_>) -> TestResult + Send + Sync>;
+
+// Closures bound to the tests of a script, indexed by instruction position.
+// Instructions without one are run by the interpreter.
+#[derive(Clone, Default)]
+pub(crate) struct CompiledOps(OnceLock<Arc<[Option<Op>]>>);
+
+impl Sieve {
+    /// Binds the header, address and exists tests of this script whose
+    /// arguments are constant to closures holding the already parsed header
+    /// names, keys and regular expressions, so they are not evaluated again
+    /// on every run. Other instructions are still interpreted.
+    pub fn compile_closures(&self) {
+        self.compiled.0.get_or_init(|| {
+            self.instructions
+                .iter()
+                .map(|instruction| match instruction {
+                    Instruction::Test(test) => compile_test(test),
+                    _ => None,
+                })
+                .collect()
+        });
+    }
+
+    pub fn is_compiled(&self) -> bool {
+        self.compiled.0.get().is_some()
+    }
+}
+
+impl CompiledOps {
+    #[inline(always)]
+    pub(crate) fn get(&self, pos: usize) -> Option<&Op> {
+        self.0.get()?.get(pos)?.as_ref()
+    }
+}
+
+fn compile_test(test: &Test) -> Option<Op> {
+    match test {
+        Test::Header(test) => {
+            let header_list = header_names(&test.header_list)?;
+            let (key_list, regex_list) = keys(
+                &test.key_list,
+                &test.key_set,
+                &test.match_type,
+                &test.comparator,
+            )?;
+            let mime_opts = match &test.mime_opts {
+                MimeOpts::Type => MimeOpts::Type,
+                MimeOpts::Subtype => MimeOpts::Subtype,
+                MimeOpts::ContentType => MimeOpts::ContentType,
+                MimeOpts::Param(params) => MimeOpts::Param(constants(params)?),
+                MimeOpts::None => MimeOpts::None,
+            };
+            let test = test.clone();
+
+            Some(Box::new(move |ctx| {
+                let mut captured_values = Vec::new();
+                let result = test.exec_with(
+                    ctx,
+                    &header_list,
+                    &key_list,
+                    &regex_list,
+                    &mime_opts,
+                    &mut captured_values,
+                );
+                if !captured_values.is_empty() {
+                    ctx.set_match_variables(captured_values);
+                }
+                result
+            }))
+        }
+        Test::Address(test) => {
+            let header_list = header_names(&test.header_list)?;
+            let (key_list, regex_list) = keys(
+                &test.key_list,
+                &test.key_set,
+                &test.match_type,
+                &test.comparator,
+            )?;
+            let test = test.clone();
+
+            Some(Box::new(move |ctx| {
+                let mut captured_values = Vec::new();
+                let result = test.exec_with(
+                    ctx,
+                    &header_list,
+                    &key_list,
+                    &regex_list,
+                    &mut captured_values,
+                );
+                if !captured_values.is_empty() {
+                    ctx.set_match_variables(captured_values);
+                }
+                result
+            }))
+        }
+        Test::Exists(test) => {
+            let header_names = header_names(&test.header_names)?;
+            let test = test.clone();
+
+            Some(Box::new(move |ctx| test.exec_with(ctx, &header_names)))
+        }
+        _ => None,
+    }
+}
+
+// Regular expressions are compiled here unless a custom comparator matches them
+fn keys(
+    key_list: &[StringItem],
+    key_set: &LazyKeySet,
+    match_type: &MatchType,
+    comparator: &Comparator,
+) -> Option<(Vec<Cow<'static, str>>, Vec<Regex>)> {
+    if key_set.get(match_type, comparator, key_list).is_some() {
+        Some((Vec::new(), Vec::new()))
+    } else if matches!(match_type, MatchType::Regex(_))
+        && !matches!(comparator, Comparator::Other(_))
+    {
+        let regex_list = constants(key_list)?
+            .iter()
+            .map(|pattern| Regex::new(pattern).ok())
+            .collect::<Option<Vec<_>>>()?;
+        Some((Vec::new(), regex_list))
+    } else {
+        Some((constants(key_list)?, Vec::new()))
+    }
+}
+
+fn constants(items: &[StringItem]) -> Option<Vec<Cow<'static, str>>> {
+    items
+        .iter()
+        .map(|item| match item {
+            StringItem::Text(text) => Some(Cow::Owned(text.to_string())),
+            _ => None,
+        })
+        .collect()
+}
+
+fn header_names(items: &[StringItem]) -> Option<Vec<HeaderName<'static>>> {
+    Some(
+        constants(items)?
+            .into_iter()
+            .filter_map(HeaderName::parse)
+            .collect(),
+    )
+}
+
+impl Debug for CompiledOps {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("CompiledOps")
+            .field(&self.0.get().map(|ops| ops.iter().flatten().count()))
+            .finish()
+    }
+}
+
+// Derived from the instructions, which are compared instead
+impl PartialEq for CompiledOps {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for CompiledOps {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        runtime::{run_script_with, RuntimeError},
+        Compiler, Input, Runtime, Sieve,
+    };
+
+    #[test]
+    fn compiled_closures() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["fileinto", "variables", "regex", "imap4flags"];
+                set "folder" "subject";
+                if header :contains "subject" "report" {
+                    addflag "$Report";
+                }
+                if header :contains "${folder}" "report" {
+                    addflag "$Variable";
+                }
+                if address :domain :regex "from" "^(.+)\\.org$" {
+                    fileinto "Orgs/${1}";
+                }
+                if not exists ["x-spam", "x-virus"] {
+                    fileinto "Clean";
+                }
+                if string :is "${folder}" "subject" {
+                    keep;
+                }"#,
+            )
+            .unwrap();
+        let compiled = Arc::new(script.clone());
+        compiled.compile_closures();
+        assert!(compiled.is_compiled() && !script.is_compiled());
+        assert_eq!(
+            compiled
+                .compiled
+                .0
+                .get()
+                .unwrap()
+                .iter()
+                .filter(|op| op.is_some())
+                .count(),
+            3
+        );
+
+        let message = concat!(
+            "From: John Doe <john@example.org>\r\n",
+            "To: Jane Doe <jane@example.com>\r\n",
+            "Subject: Quarterly report\r\n",
+            "\r\n",
+            "Hi.\r\n"
+        );
+        let script = Arc::new(script);
+        for cpu_limit in [5, 12, 20, 5000] {
+            let runtime = Runtime::new().with_cpu_limit(cpu_limit);
+            let run = |script: &Arc<Sieve>| {
+                let mut instance = runtime.filter(message.as_bytes());
+                run_script_with(
+                    |input| instance.run(input),
+                    Input::script("test", script.clone()),
+                    |_| Input::True,
+                )
+            };
+            let results = run(&script);
+            assert_eq!(
+                format!("{results:?}"),
+                format!("{:?}", run(&compiled)),
+                "cpu_limit {cpu_limit}"
+            );
+            assert_eq!(
+                results
+                    .iter()
+                    .any(|r| matches!(r, Err(RuntimeError::CPULimitReached))),
+                cpu_limit < 20
+            );
+        }
+    }
+}
@@ -21,53 +21,103 @@
  * for more details.
 */
 
-use std::{borrow::Cow, sync::Arc, time::SystemTime};
+use std::{borrow::Cow, cell::RefCell, ops::Deref, sync::Arc, time::SystemTime};
 
 use ahash::AHashMap;
+use mail_builder::{
+    headers::{date::Date, message_id::generate_message_id_header},
+    mime::make_boundary,
+};
 use mail_parser::Message;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    compiler::grammar::{instruction::Instruction, Capability},
-    Context, Envelope, Event, Input, Metadata, Runtime, Sieve, SpamStatus, VirusStatus,
-    MAX_LOCAL_VARIABLES, MAX_MATCH_VARIABLES,
+    compiler::grammar::{instruction::Instruction, Capability, Comparator},
+    Context, Envelope, Event, ImapCause, Input, Metadata, OutboundEnvelope, ReturnPath, Runtime,
+    Script, Sieve, SpamStatus, VirusStatus, MAX_LOCAL_VARIABLES, MAX_MATCH_VARIABLES,
 };
 
 use super::{
-    actions::action_include::IncludeResult,
+    actions::{action_execute::owned_message, action_include::IncludeResult},
+    cache::MessageCache,
+    parse_message,
     tests::{test_envelope::parse_envelope_address, TestResult},
     RuntimeError,
 };
 
 #[derive(Clone, Debug)]
 pub(crate) struct ScriptStack {
+    pub(crate) name: Script,
     pub(crate) script: Arc<Sieve>,
     pub(crate) prev_pos: usize,
     pub(crate) prev_vars_local: Vec<String>,
     pub(crate) prev_vars_match: Vec<String>,
 }
 
+// Unlike `Cow<'x, Message<'x>>`, which is invariant over 'x, this keeps
+// `Context` covariant so it can be reborrowed with shorter lifetimes.
+#[derive(Clone, Debug)]
+pub(crate) enum MessageCow<'x> {
+    Borrowed(&'x Message<'x>),
+    Owned(Message<'x>),
+}
+
+// Data borrowed from the caller, or shared with an `OwnedContext`.
+#[derive(Debug)]
+pub(crate) enum Shared<'x, T: ?Sized> {
+    Borrowed(&'x T),
+    Owned(Arc<T>),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct CallStack {
+    pub(crate) prev_pos: usize,
+    pub(crate) prev_vars_local: Vec<String>,
+    pub(crate) prev_vars_match: Vec<String>,
+}
+
 impl<'x> Context<'x> {
-    pub(crate) fn new(runtime: &'x Runtime, message: Message<'x>) -> Self {
+    pub(crate) fn new(runtime: &'x Runtime, message: MessageCow<'x>) -> Self {
+        let raw_message: &'x [u8] = match &message {
+            MessageCow::Borrowed(message) => {
+                let message: &'x Message<'x> = message;
+                &message.raw_message[..]
+            }
+            MessageCow::Owned(message) => match &message.raw_message {
+                Cow::Borrowed(raw_message) => raw_message,
+                Cow::Owned(_) => b"",
+            },
+        };
+
         Context {
             #[cfg(test)]
             runtime: runtime.clone(),
             #[cfg(not(test))]
-            runtime,
+            runtime: Shared::Borrowed(runtime),
             message,
+            raw_message: Shared::Borrowed(raw_message),
             part: 0,
             part_iter: Vec::new().into_iter(),
             part_iter_stack: Vec::new(),
+            foreach_stack: Vec::new(),
             pos: usize::MAX,
             test_result: false,
+            test_unknown: false,
+            test_pending: Vec::new(),
             script_cache: AHashMap::new(),
             script_stack: Vec::with_capacity(0),
+            call_stack: Vec::with_capacity(0),
             vars_global: AHashMap::new(),
             vars_env: AHashMap::new(),
             vars_local: Vec::with_capacity(0),
             vars_match: Vec::with_capacity(0),
+            vars_pending: Vec::new(),
             envelope: Vec::new(),
             metadata: Vec::new(),
             message_size: usize::MAX,
+            deferred_message: false,
+            envelope_only: false,
+            cache: RefCell::new(MessageCache::default()),
             final_event: Event::Keep {
                 flags: Vec::with_capacity(0),
                 message_id: 0,
@@ -81,17 +131,20 @@ impl<'x> Context<'x> {
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .map(|d| d.as_secs())
                 .unwrap_or(0) as i64,
+            smtputf8: false,
+            protocol_reject: true,
             num_redirects: 0,
             num_instructions: 0,
             num_out_messages: 0,
+            num_log_messages: 0,
             last_message_id: 0,
             main_message_id: 0,
             virus_status: VirusStatus::Unknown,
             spam_status: SpamStatus::Unknown,
+            imap_cause: None,
         }
     }
 
-    #[allow(clippy::while_let_on_iterator)]
     pub fn run(&mut self, input: Input) -> Option<Result<Event, RuntimeError>> {
         match input {
             Input::True => self.test_result ^= true,
@@ -102,11 +155,12 @@ impl<'x> Context<'x> {
 
                 if num_match_vars <= MAX_MATCH_VARIABLES && num_vars <= MAX_LOCAL_VARIABLES {
                     if self.message_size == usize::MAX {
-                        self.message_size = self.message.raw_message.len();
+                        self.message_size = self.raw_bytes(&self.message).len();
                     }
 
-                    self.script_cache.insert(name, script.clone());
+                    self.script_cache.insert(name.clone(), script.clone());
                     self.script_stack.push(ScriptStack {
+                        name,
                         script,
                         prev_pos: self.pos,
                         prev_vars_local: std::mem::replace(
@@ -122,6 +176,17 @@ impl<'x> Context<'x> {
                     self.test_result = false;
                 }
             }
+            Input::Extension { result, values } => {
+                self.test_result ^= result;
+                self.set_extension_values(values);
+            }
+            Input::Output { result, output } => {
+                self.test_result ^= result;
+                self.set_execute_output(output);
+            }
+            Input::Message { result, message } => {
+                self.set_filter_output(result, message);
+            }
         }
 
         // Return any queued events
@@ -130,10 +195,9 @@ impl<'x> Context<'x> {
         }
 
         let mut current_script = self.script_stack.last()?.script.clone();
-        let mut iter = current_script.instructions.get(self.pos..)?.iter();
 
         'outer: loop {
-            while let Some(instruction) = iter.next() {
+            while let Some(instruction) = current_script.instructions.get(self.pos) {
                 self.num_instructions += 1;
                 if self.num_instructions > self.runtime.cpu_limit {
                     self.finish_loop();
@@ -141,42 +205,91 @@ impl<'x> Context<'x> {
                 }
                 self.pos += 1;
 
+                if self.deferred_message && instruction.needs_message_body() {
+                    self.parse_deferred_message();
+                }
+
+                if self.envelope_only {
+                    // Tests that need the message evaluate to unknown, which is then
+                    // combined with the remaining tests of the enclosing anyof/allof.
+                    while let Some((jmp_pos, is_any)) = self.test_pending.last().copied() {
+                        if jmp_pos != self.pos - 1 {
+                            break;
+                        }
+                        self.test_pending.pop();
+                        self.test_unknown |= self.test_result != is_any;
+                    }
+
+                    let needs_message = match instruction {
+                        Instruction::Test(_) => {
+                            self.test_unknown = instruction.needs_message();
+                            if self.test_unknown {
+                                continue;
+                            }
+                            false
+                        }
+                        Instruction::Jz(jmp_pos) | Instruction::Jnz(jmp_pos)
+                            if self.test_unknown =>
+                        {
+                            // Jumps followed by a test short-circuit anyof/allof,
+                            // otherwise the outcome of the condition is needed.
+                            if matches!(
+                                current_script.instructions.get(self.pos),
+                                Some(Instruction::Test(_))
+                            ) {
+                                self.test_pending.push((
+                                    *jmp_pos as usize,
+                                    matches!(instruction, Instruction::Jnz(_)),
+                                ));
+                                continue;
+                            }
+                            true
+                        }
+                        Instruction::Reject(reject) => {
+                            self.runtime.reject_mdn && (!reject.ereject || !self.protocol_reject)
+                        }
+                        _ => instruction.needs_message(),
+                    };
+
+                    if needs_message {
+                        self.script_stack.clear();
+                        self.final_event = None;
+                        self.test_pending.clear();
+                        return Some(Err(RuntimeError::MessageRequired));
+                    }
+                }
+
+                if let Some(op) = current_script.compiled.get(self.pos - 1) {
+                    let result = op(self);
+                    if let Some(result) = self.test_outcome(result) {
+                        return Some(result);
+                    }
+                    continue;
+                }
+
                 match instruction {
                     Instruction::Jz(jmp_pos) => {
                         if !self.test_result {
-                            debug_assert!(*jmp_pos > self.pos - 1);
-                            self.pos = *jmp_pos;
-                            iter = current_script.instructions.get(self.pos..)?.iter();
-                            continue;
+                            debug_assert!(*jmp_pos as usize > self.pos - 1);
+                            self.pos = *jmp_pos as usize;
                         }
                     }
                     Instruction::Jnz(jmp_pos) => {
                         if self.test_result {
-                            debug_assert!(*jmp_pos > self.pos - 1);
-                            self.pos = *jmp_pos;
-                            iter = current_script.instructions.get(self.pos..)?.iter();
-                            continue;
+                            debug_assert!(*jmp_pos as usize > self.pos - 1);
+                            self.pos = *jmp_pos as usize;
                         }
                     }
                     Instruction::Jmp(jmp_pos) => {
-                        debug_assert_ne!(*jmp_pos, self.pos - 1);
-                        self.pos = *jmp_pos;
-                        iter = current_script.instructions.get(self.pos..)?.iter();
-                        continue;
-                    }
-                    Instruction::Test(test) => match test.exec(self) {
-                        TestResult::Bool(result) => {
-                            self.test_result = result;
-                        }
-                        TestResult::Event { event, is_not } => {
-                            self.test_result = is_not;
-                            return Some(Ok(event));
-                        }
-                        TestResult::Error(err) => {
-                            self.finish_loop();
-                            return Some(Err(err));
+                        debug_assert_ne!(*jmp_pos as usize, self.pos - 1);
+                        self.pos = *jmp_pos as usize;
+                    }
+                    Instruction::Test(test) => {
+                        let result = test.exec(self);
+                        if let Some(result) = self.test_outcome(result) {
+                            return Some(result);
                         }
-                    },
+                    }
                     Instruction::Clear(clear) => {
                         if clear.local_vars_num > 0 {
                             if let Some(local_vars) = self.vars_local.get_mut(
@@ -231,23 +344,31 @@ impl<'x> Context<'x> {
                         break 'outer;
                     }
                     Instruction::Reject(reject) => {
+                        if self.imap_cause.is_some() {
+                            self.finish_loop();
+                            return Some(Err(RuntimeError::CapabilityNotAllowed(
+                                if reject.ereject {
+                                    Capability::Ereject
+                                } else {
+                                    Capability::Reject
+                                },
+                            )));
+                        }
                         self.final_event = None;
-                        return Some(Ok(Event::Reject {
-                            extended: reject.ereject,
-                            reason: self.eval_string(&reject.reason).into_owned(),
-                        }));
+                        reject.exec(self);
+                        if let Some(event) = self.queued_events.next() {
+                            return Some(Ok(event));
+                        }
                     }
                     Instruction::ForEveryPart(fep) => {
                         if let Some(next_part) = self.part_iter.next() {
                             self.part = next_part;
                         } else if let Some((prev_part, prev_part_iter)) = self.part_iter_stack.pop()
                         {
-                            debug_assert!(fep.jz_pos > self.pos - 1);
+                            debug_assert!(fep.jz_pos as usize > self.pos - 1);
                             self.part_iter = prev_part_iter;
                             self.part = prev_part;
-                            self.pos = fep.jz_pos;
-                            iter = current_script.instructions.get(self.pos..)?.iter();
-                            continue;
+                            self.pos = fep.jz_pos as usize;
                         } else {
                             self.part = 0;
                             #[cfg(test)]
@@ -284,7 +405,10 @@ impl<'x> Context<'x> {
                     Instruction::DeleteHeader(delete_header) => delete_header.exec(self),
                     Instruction::Set(set) => set.exec(self),
                     Instruction::Notify(notify) => {
-                        notify.exec(self);
+                        if let Err(err) = notify.exec(self) {
+                            self.finish_loop();
+                            return Some(Err(err));
+                        }
                         if let Some(event) = self.queued_events.next() {
                             return Some(Ok(event));
                         }
@@ -297,8 +421,9 @@ impl<'x> Context<'x> {
                     }
                     Instruction::EditFlags(flags) => flags.exec(self),
                     Instruction::Include(include) => match include.exec(self) {
-                        IncludeResult::Cached(script) => {
+                        IncludeResult::Cached(name, script) => {
                             self.script_stack.push(ScriptStack {
+                                name,
                                 script: script.clone(),
                                 prev_pos: self.pos,
                                 prev_vars_local: std::mem::replace(
@@ -312,7 +437,6 @@ impl<'x> Context<'x> {
                             });
                             self.pos = 0;
                             current_script = script;
-                            iter = current_script.instructions.iter();
                             continue;
                         }
                         IncludeResult::Event(event) => {
@@ -331,7 +455,7 @@ impl<'x> Context<'x> {
                         break;
                     }
                     Instruction::Require(capabilities) => {
-                        for capability in capabilities {
+                        for capability in capabilities.iter() {
                             if !self.runtime.allowed_capabilities.contains(capability) {
                                 self.finish_loop();
                                 return Some(Err(
@@ -341,6 +465,15 @@ impl<'x> Context<'x> {
                                         RuntimeError::CapabilityNotAllowed(capability.clone())
                                     },
                                 ));
+                            } else if let Capability::Comparator(Comparator::Other(name)) =
+                                capability
+                            {
+                                if !self.runtime.comparators.contains(name) {
+                                    self.finish_loop();
+                                    return Some(Err(RuntimeError::CapabilityNotSupported(
+                                        capability.to_string(),
+                                    )));
+                                }
                             }
                         }
                     }
@@ -351,10 +484,43 @@ impl<'x> Context<'x> {
                         )));
                     }
                     Instruction::Execute(execute) => {
-                        return Some(Ok(Event::Execute {
-                            command: self.eval_string(&execute.command).into_owned(),
-                            arguments: self.eval_strings_owned(&execute.arguments),
-                        }));
+                        return Some(Ok(execute.event(self)));
+                    }
+                    Instruction::Filter(filter) => {
+                        return Some(Ok(filter.event(self)));
+                    }
+                    Instruction::Extension(call) => {
+                        return Some(Ok(call.event(self)));
+                    }
+                    Instruction::ForEachPush(source) => source.exec(self),
+                    Instruction::ForEach(foreach) => foreach.exec(self),
+                    Instruction::ForEachPop(num_pops) => {
+                        debug_assert!(
+                            *num_pops > 0 && *num_pops <= self.foreach_stack.len(),
+                            "Pop out of range: {} with {} items.",
+                            num_pops,
+                            self.foreach_stack.len()
+                        );
+                        let num_items = self.foreach_stack.len().saturating_sub(*num_pops);
+                        self.foreach_stack.truncate(num_items);
+                    }
+                    Instruction::Call(call) => {
+                        if let Err(err) = call.exec(self) {
+                            self.finish_loop();
+                            return Some(Err(err));
+                        }
+                    }
+                    Instruction::ProcedureReturn => {
+                        if let Some(prev_call) = self.call_stack.pop() {
+                            self.pos = prev_call.prev_pos;
+                            self.vars_local = prev_call.prev_vars_local;
+                            self.vars_match = prev_call.prev_vars_match;
+                        }
+                    }
+                    Instruction::Log(log) => {
+                        if let Some(event) = log.event(self) {
+                            return Some(Ok(event));
+                        }
                     }
                     Instruction::Invalid(invalid) => {
                         self.finish_loop();
@@ -382,7 +548,6 @@ impl<'x> Context<'x> {
 
             if let Some(script_stack) = self.script_stack.last() {
                 current_script = script_stack.script.clone();
-                iter = current_script.instructions.get(self.pos..)?.iter();
             } else {
                 break;
             }
@@ -422,6 +587,47 @@ impl<'x> Context<'x> {
         }
     }
 
+    // Stores the outcome of a test, returning the event or error that interrupts the script
+    fn test_outcome(&mut self, result: TestResult) -> Option<Result<Event, RuntimeError>> {
+        match result {
+            TestResult::Bool(result) => {
+                self.test_result = result;
+                None
+            }
+            TestResult::Event { event, is_not } => {
+                self.test_result = is_not;
+                Some(Ok(event))
+            }
+            TestResult::Error(err) => {
+                self.finish_loop();
+                Some(Err(err))
+            }
+        }
+    }
+
+    pub(crate) fn parse_deferred_message(&mut self) {
+        if std::mem::take(&mut self.deferred_message) {
+            self.message = MessageCow::Owned(match &self.raw_message {
+                Shared::Borrowed(raw_message) => parse_message(raw_message),
+                Shared::Owned(raw_message) => owned_message(Message {
+                    raw_message: b""[..].into(),
+                    ..parse_message(raw_message)
+                }),
+            });
+            self.invalidate_cache();
+        }
+    }
+
+    // The top-level message of an owned context leaves its raw bytes
+    // in `raw_message`.
+    pub(crate) fn raw_bytes<'y>(&'y self, message: &'y Message<'_>) -> &'y [u8] {
+        if message.raw_message.is_empty() {
+            &self.raw_message
+        } else {
+            &message.raw_message
+        }
+    }
+
     pub(crate) fn finish_loop(&mut self) {
         self.script_stack.clear();
         if let Some(event) = self.final_event.take() {
@@ -560,14 +766,213 @@ impl<'x> Context<'x> {
         self
     }
 
+    pub fn set_current_time(&mut self, timestamp: i64) {
+        self.current_time = timestamp;
+    }
+
+    pub fn with_current_time(mut self, timestamp: i64) -> Self {
+        self.set_current_time(timestamp);
+        self
+    }
+
+    /// Writes non-ASCII header values as raw UTF-8 rather than RFC 2047
+    /// encoded-words, for messages received with SMTPUTF8.
+    pub fn set_smtputf8(&mut self, smtputf8: bool) {
+        self.smtputf8 = smtputf8;
+    }
+
+    pub fn with_smtputf8(mut self, smtputf8: bool) -> Self {
+        self.set_smtputf8(smtputf8);
+        self
+    }
+
+    /// Whether the message can still be refused at the SMTP or LMTP protocol
+    /// level, enabled by default. Once delivery has been accepted, `ereject`
+    /// sends a disposition notification like `reject` does.
+    pub fn set_protocol_reject(&mut self, protocol_reject: bool) {
+        self.protocol_reject = protocol_reject;
+    }
+
+    pub fn with_protocol_reject(mut self, protocol_reject: bool) -> Self {
+        self.set_protocol_reject(protocol_reject);
+        self
+    }
+
+    pub fn set_imap_event(&mut self, cause: ImapCause, mailbox: impl Into<Cow<'x, str>>) {
+        self.imap_cause = cause.into();
+        self.vars_env
+            .insert("imap.cause".to_string(), cause.as_str().into());
+        self.vars_env
+            .insert("imap.mailbox".to_string(), mailbox.into());
+        self.vars_env.insert("phase".to_string(), "post".into());
+        self.vars_env.insert("location".to_string(), "MS".into());
+    }
+
+    pub fn with_imap_event(mut self, cause: ImapCause, mailbox: impl Into<Cow<'x, str>>) -> Self {
+        self.set_imap_event(cause, mailbox);
+        self
+    }
+
+    pub fn set_imap_user(&mut self, user: impl Into<Cow<'x, str>>, email: impl Into<Cow<'x, str>>) {
+        self.vars_env.insert("imap.user".to_string(), user.into());
+        self.vars_env.insert("imap.email".to_string(), email.into());
+    }
+
+    pub fn with_imap_user(
+        mut self,
+        user: impl Into<Cow<'x, str>>,
+        email: impl Into<Cow<'x, str>>,
+    ) -> Self {
+        self.set_imap_user(user, email);
+        self
+    }
+
+    pub fn set_imap_flags(&mut self, flags: impl IntoIterator<Item = impl AsRef<str>>) {
+        self.vars_global
+            .insert("__flags".to_string(), join_flags(flags));
+    }
+
+    pub fn with_imap_flags(mut self, flags: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        self.set_imap_flags(flags);
+        self
+    }
+
+    pub fn set_imap_changed_flags(&mut self, flags: impl IntoIterator<Item = impl AsRef<str>>) {
+        self.vars_env
+            .insert("imap.changedflags".to_string(), join_flags(flags).into());
+    }
+
+    pub fn with_imap_changed_flags(
+        mut self,
+        flags: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Self {
+        self.set_imap_changed_flags(flags);
+        self
+    }
+
     pub fn take_message(&mut self) -> Message<'x> {
-        std::mem::take(&mut self.message)
+        self.parse_deferred_message();
+        self.invalidate_cache();
+        std::mem::take(&mut self.message).into_owned()
     }
 
     pub fn has_message_changed(&self) -> bool {
         self.main_message_id > 0
     }
 
+    // Under IMAPSIEVE the message already exists, so cancelling the
+    // implicit keep removes it from its mailbox.
+    pub(crate) fn cancel_implicit_keep(&mut self) {
+        self.final_event = if self.imap_cause.is_some() {
+            Event::Discard.into()
+        } else {
+            None
+        };
+    }
+
+    pub(crate) fn is_imap_mailbox(&self, folder: &str) -> bool {
+        self.imap_cause.is_some()
+            && self.vars_env.get("imap.mailbox").map_or(false, |mailbox| {
+                mailbox.as_ref() == folder
+                    || (mailbox.eq_ignore_ascii_case("INBOX")
+                        && folder.eq_ignore_ascii_case("INBOX"))
+            })
+    }
+
+    pub(crate) fn current_date(&self) -> String {
+        Date::new(self.current_time).to_rfc822()
+    }
+
+    pub(crate) fn generate_message_id(&self) -> String {
+        if let Some(generator) = &self.runtime.message_id_generator {
+            format!("<{}>", generator.generate())
+        } else {
+            let mut message_id = Vec::with_capacity(20);
+            generate_message_id_header(&mut message_id).unwrap();
+            String::from_utf8(message_id).unwrap()
+        }
+    }
+
+    pub(crate) fn generate_boundary(&self) -> String {
+        if let Some(generator) = &self.runtime.boundary_generator {
+            generator.generate()
+        } else {
+            make_boundary(".")
+        }
+    }
+
+    /// Builds the envelope of an outgoing message, `message` is `None` when
+    /// the current message is being relayed.
+    pub(crate) fn outbound_envelope(
+        &self,
+        return_path: &ReturnPath,
+        message: Option<&[u8]>,
+    ) -> OutboundEnvelope {
+        let get_envelope = |envelope: Envelope| {
+            self.envelope
+                .iter()
+                .find_map(|(e, v)| {
+                    if e == &envelope {
+                        Some(v.as_ref())
+                    } else {
+                        None
+                    }
+                })
+                .filter(|v| !v.is_empty())
+        };
+
+        // IMAP events carry no SMTP envelope, messages are sent on behalf of the user
+        let user_address = if !self.user_address.is_empty() {
+            Some(self.user_address.as_ref())
+        } else if self.imap_cause.is_some() {
+            self.vars_env
+                .get("imap.email")
+                .map(|email| email.as_ref())
+                .filter(|email| !email.is_empty())
+        } else {
+            None
+        };
+        let (sender, recipient) = if self.imap_cause.is_some() {
+            (user_address, user_address)
+        } else {
+            (get_envelope(Envelope::From), get_envelope(Envelope::To))
+        };
+
+        let from = match return_path {
+            ReturnPath::Null => String::new(),
+            ReturnPath::User => user_address.or(recipient).unwrap_or_default().to_string(),
+            ReturnPath::Original => sender.unwrap_or_default().to_string(),
+            ReturnPath::Custom(return_path) => return_path.rewrite(sender.unwrap_or_default()),
+        };
+
+        if let Some(message) = message {
+            OutboundEnvelope {
+                from,
+                env_id: None,
+                orcpt: None,
+                smtputf8: self.smtputf8 && !message.is_ascii(),
+                size: message.len(),
+            }
+        } else {
+            OutboundEnvelope {
+                from,
+                env_id: get_envelope(Envelope::Envid).map(|v| v.to_string()),
+                orcpt: get_envelope(Envelope::Orcpt)
+                    .map(|v| v.to_string())
+                    .or_else(|| {
+                        get_envelope(Envelope::To).map(|v| format!("rfc822;{}", xtext_encode(v)))
+                    }),
+                smtputf8: self.smtputf8,
+                // Edits only estimate the size, count the message that is sent
+                size: if self.main_message_id > 0 {
+                    self.message_len()
+                } else {
+                    self.message_size
+                },
+            }
+        }
+    }
+
     pub(crate) fn user_from_field(&self) -> String {
         if !self.user_full_name.is_empty() {
             format!("\"{}\" <{}>", self.user_full_name, self.user_address)
@@ -576,3 +981,119 @@ impl<'x> Context<'x> {
         }
     }
 }
+
+fn join_flags(flags: impl IntoIterator<Item = impl AsRef<str>>) -> String {
+    let mut result = String::new();
+    for flag in flags {
+        let flag = flag.as_ref().trim();
+        if !flag.is_empty() {
+            if !result.is_empty() {
+                result.push(' ');
+            }
+            result.push_str(flag);
+        }
+    }
+    result
+}
+
+impl ImapCause {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImapCause::Append => "APPEND",
+            ImapCause::Copy => "COPY",
+            ImapCause::Flag => "FLAG",
+        }
+    }
+}
+
+impl<'x> TryFrom<&'x str> for ImapCause {
+    type Error = &'x str;
+
+    fn try_from(value: &'x str) -> Result<Self, Self::Error> {
+        if value.eq_ignore_ascii_case("append") {
+            Ok(ImapCause::Append)
+        } else if value.eq_ignore_ascii_case("copy") {
+            Ok(ImapCause::Copy)
+        } else if value.eq_ignore_ascii_case("flag") {
+            Ok(ImapCause::Flag)
+        } else {
+            Err(value)
+        }
+    }
+}
+
+impl<'x> MessageCow<'x> {
+    pub(crate) fn to_mut(&mut self) -> &mut Message<'x> {
+        if let MessageCow::Borrowed(message) = *self {
+            *self = MessageCow::Owned(message.clone());
+        }
+        match self {
+            MessageCow::Owned(message) => message,
+            MessageCow::Borrowed(_) => unreachable!(),
+        }
+    }
+
+    pub(crate) fn into_owned(self) -> Message<'x> {
+        match self {
+            MessageCow::Borrowed(message) => message.clone(),
+            MessageCow::Owned(message) => message,
+        }
+    }
+}
+
+impl<'x> Deref for MessageCow<'x> {
+    type Target = Message<'x>;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            MessageCow::Borrowed(message) => message,
+            MessageCow::Owned(message) => message,
+        }
+    }
+}
+
+impl Default for MessageCow<'_> {
+    fn default() -> Self {
+        MessageCow::Owned(Message::default())
+    }
+}
+
+impl<T: ?Sized> Deref for Shared<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Shared::Borrowed(value) => value,
+            Shared::Owned(value) => value,
+        }
+    }
+}
+
+impl<T: ?Sized> Clone for Shared<'_, T> {
+    fn clone(&self) -> Self {
+        match self {
+            Shared::Borrowed(value) => Shared::Borrowed(value),
+            Shared::Owned(value) => Shared::Owned(value.clone()),
+        }
+    }
+}
+
+// RFC 3461 xtext, used for the ORCPT parameter
+fn xtext_encode(value: &str) -> Cow<'_, str> {
+    if value
+        .bytes()
+        .all(|ch| (b'!'..=b'~').contains(&ch) && ch != b'+' && ch != b'=')
+    {
+        return value.into();
+    }
+
+    let mut result = String::with_capacity(value.len() + 6);
+    for ch in value.bytes() {
+        if (b'!'..=b'~').contains(&ch) && ch != b'+' && ch != b'=' {
+            result.push(char::from(ch));
+        } else {
+            result.push_str(&format!("+{:02X}", ch));
+        }
+    }
+    result.into()
+}
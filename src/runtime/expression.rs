@@ -0,0 +1,317 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{borrow::Cow, cmp::Ordering};
+
+use crate::{
+    compiler::grammar::{
+        expr::{BinaryOperator, Constant, ExpressionItem, Function, UnaryOperator},
+        tests::test_eval::TestEval,
+    },
+    Context,
+};
+
+use super::tests::TestResult;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value<'x> {
+    Integer(i64),
+    Float(f64),
+    String(Cow<'x, str>),
+}
+
+impl TestEval {
+    pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
+        ctx.num_instructions += self.expression.len();
+        TestResult::Bool(ctx.eval_expression(&self.expression).to_bool() ^ self.is_not)
+    }
+}
+
+impl<'x> Context<'x> {
+    pub(crate) fn eval_expression<'y>(&'y self, expression: &'y [ExpressionItem]) -> Value<'y> {
+        let max_len = self.runtime.max_variable_size;
+        let mut stack: Vec<Value> = Vec::with_capacity(expression.len());
+
+        for item in expression {
+            match item {
+                ExpressionItem::Variable(variable) => {
+                    stack.push(Value::String(self.eval_string(variable)));
+                }
+                ExpressionItem::Constant(constant) => stack.push(match constant {
+                    Constant::Integer(value) => Value::Integer(*value),
+                    Constant::Float(value) => Value::Float(*value),
+                    Constant::String(value) => Value::String(value.as_str().into()),
+                }),
+                ExpressionItem::UnaryOperator(op) => {
+                    let value = stack.pop().unwrap_or(Value::Integer(0));
+                    stack.push(match op {
+                        UnaryOperator::Not => Value::from(!value.to_bool()),
+                        UnaryOperator::Minus => match value.to_number() {
+                            Value::Integer(value) => value
+                                .checked_neg()
+                                .map(Value::Integer)
+                                .unwrap_or(Value::Float(-(value as f64))),
+                            Value::Float(value) => Value::Float(-value),
+                            Value::String(_) => Value::Integer(0),
+                        },
+                    });
+                }
+                ExpressionItem::BinaryOperator(op) => {
+                    let right = stack.pop().unwrap_or(Value::Integer(0));
+                    let left = stack.pop().unwrap_or(Value::Integer(0));
+                    stack.push(left.apply(*op, right, max_len));
+                }
+                ExpressionItem::Function { id, num_args } => {
+                    let args = stack.split_off(stack.len().saturating_sub(*num_args));
+                    stack.push(id.apply(args, max_len));
+                }
+            }
+        }
+
+        stack.pop().unwrap_or(Value::Integer(0))
+    }
+}
+
+impl<'x> Value<'x> {
+    fn to_number(&self) -> Value<'static> {
+        match self {
+            Value::Integer(value) => Value::Integer(*value),
+            Value::Float(value) => Value::Float(*value),
+            Value::String(value) => {
+                let value = value.trim();
+                if let Ok(value) = value.parse::<i64>() {
+                    Value::Integer(value)
+                } else if let Some(value) = value.parse::<f64>().ok().filter(|v| v.is_finite()) {
+                    Value::Float(value)
+                } else {
+                    Value::Integer(0)
+                }
+            }
+        }
+    }
+
+    fn is_number(&self) -> bool {
+        match self {
+            Value::Integer(_) | Value::Float(_) => true,
+            Value::String(value) => {
+                let value = value.trim();
+                value.parse::<i64>().is_ok() || value.parse::<f64>().is_ok_and(|v| v.is_finite())
+            }
+        }
+    }
+
+    fn to_float(&self) -> f64 {
+        match self.to_number() {
+            Value::Integer(value) => value as f64,
+            Value::Float(value) => value,
+            Value::String(_) => 0.0,
+        }
+    }
+
+    pub(crate) fn to_bool(&self) -> bool {
+        match self {
+            Value::String(value) if !self.is_number() => !value.is_empty(),
+            _ => match self.to_number() {
+                Value::Integer(value) => value != 0,
+                Value::Float(value) => value != 0.0,
+                Value::String(_) => false,
+            },
+        }
+    }
+
+    fn as_str(&self) -> Cow<'_, str> {
+        match self {
+            Value::Integer(value) => value.to_string().into(),
+            Value::Float(value) => value.to_string().into(),
+            Value::String(value) => value.as_ref().into(),
+        }
+    }
+
+    pub(crate) fn into_string(self) -> String {
+        match self {
+            Value::String(value) => value.into_owned(),
+            value => value.as_str().into_owned(),
+        }
+    }
+
+    fn apply(self, op: BinaryOperator, right: Value, max_len: usize) -> Value<'x> {
+        match op {
+            BinaryOperator::And => Value::from(self.to_bool() && right.to_bool()),
+            BinaryOperator::Or => Value::from(self.to_bool() || right.to_bool()),
+            BinaryOperator::Eq
+            | BinaryOperator::Ne
+            | BinaryOperator::Lt
+            | BinaryOperator::Le
+            | BinaryOperator::Gt
+            | BinaryOperator::Ge => {
+                let ordering = if self.is_number() && right.is_number() {
+                    match (self.to_number(), right.to_number()) {
+                        (Value::Integer(a), Value::Integer(b)) => a.cmp(&b),
+                        (a, b) => a
+                            .to_float()
+                            .partial_cmp(&b.to_float())
+                            .unwrap_or(Ordering::Equal),
+                    }
+                } else {
+                    self.as_str().cmp(&right.as_str())
+                };
+                Value::from(match op {
+                    BinaryOperator::Eq => ordering == Ordering::Equal,
+                    BinaryOperator::Ne => ordering != Ordering::Equal,
+                    BinaryOperator::Lt => ordering == Ordering::Less,
+                    BinaryOperator::Le => ordering != Ordering::Greater,
+                    BinaryOperator::Gt => ordering == Ordering::Greater,
+                    _ => ordering != Ordering::Less,
+                })
+            }
+            BinaryOperator::Add if !self.is_number() || !right.is_number() => {
+                let mut value = self.into_string();
+                value.push_str(right.as_str().as_ref());
+                truncate(&mut value, max_len);
+                Value::String(value.into())
+            }
+            _ => match (self.to_number(), right.to_number()) {
+                (Value::Integer(a), Value::Integer(b)) => match op {
+                    BinaryOperator::Add => a.checked_add(b).map(Value::Integer),
+                    BinaryOperator::Subtract => a.checked_sub(b).map(Value::Integer),
+                    BinaryOperator::Multiply => a.checked_mul(b).map(Value::Integer),
+                    BinaryOperator::Divide => a
+                        .checked_rem(b)
+                        .filter(|rem| *rem == 0)
+                        .and_then(|_| a.checked_div(b))
+                        .map(Value::Integer),
+                    _ => a.checked_rem(b).map(Value::Integer),
+                }
+                .unwrap_or_else(|| Value::Float(float_op(op, a as f64, b as f64))),
+                (a, b) => Value::Float(float_op(op, a.to_float(), b.to_float())),
+            },
+        }
+    }
+}
+
+fn float_op(op: BinaryOperator, a: f64, b: f64) -> f64 {
+    let result = match op {
+        BinaryOperator::Add => a + b,
+        BinaryOperator::Subtract => a - b,
+        BinaryOperator::Multiply => a * b,
+        BinaryOperator::Divide => a / b,
+        _ => a % b,
+    };
+    if result.is_finite() {
+        result
+    } else {
+        0.0
+    }
+}
+
+impl Function {
+    fn apply<'x>(&self, args: Vec<Value<'x>>, max_len: usize) -> Value<'x> {
+        match self {
+            Function::Len => Value::Integer(string_arg(&args, 0).chars().count() as i64),
+            Function::Lower => {
+                let mut value = string_arg(&args, 0).to_lowercase();
+                truncate(&mut value, max_len);
+                Value::String(value.into())
+            }
+            Function::Upper => {
+                let mut value = string_arg(&args, 0).to_uppercase();
+                truncate(&mut value, max_len);
+                Value::String(value.into())
+            }
+            Function::Trim => Value::String(string_arg(&args, 0).trim().to_string().into()),
+            Function::Contains => {
+                Value::from(string_arg(&args, 0).contains(string_arg(&args, 1).as_ref()))
+            }
+            Function::StartsWith => {
+                Value::from(string_arg(&args, 0).starts_with(string_arg(&args, 1).as_ref()))
+            }
+            Function::EndsWith => {
+                Value::from(string_arg(&args, 0).ends_with(string_arg(&args, 1).as_ref()))
+            }
+            Function::Substring => {
+                let start = args
+                    .get(1)
+                    .map_or(0, |arg| arg.to_float().max(0.0) as usize);
+                let len = args
+                    .get(2)
+                    .map_or(usize::MAX, |arg| arg.to_float().max(0.0) as usize);
+                Value::String(
+                    string_arg(&args, 0)
+                        .chars()
+                        .skip(start)
+                        .take(len)
+                        .collect::<String>()
+                        .into(),
+                )
+            }
+            Function::Abs => match args.first().map(|arg| arg.to_number()) {
+                Some(Value::Integer(value)) => Value::Integer(value.saturating_abs()),
+                Some(Value::Float(value)) => Value::Float(value.abs()),
+                _ => Value::Integer(0),
+            },
+            Function::Round => match args.first().map(|arg| arg.to_number()) {
+                Some(Value::Float(value)) => Value::Integer(value.round() as i64),
+                Some(value) => value,
+                None => Value::Integer(0),
+            },
+            Function::Min | Function::Max => args
+                .iter()
+                .map(|arg| arg.to_number())
+                .reduce(|a, b| {
+                    let ordering = match (&a, &b) {
+                        (Value::Integer(a), Value::Integer(b)) => a.cmp(b),
+                        _ => a
+                            .to_float()
+                            .partial_cmp(&b.to_float())
+                            .unwrap_or(Ordering::Equal),
+                    };
+                    if (ordering == Ordering::Greater) == (*self == Function::Max) {
+                        a
+                    } else {
+                        b
+                    }
+                })
+                .unwrap_or(Value::Integer(0)),
+        }
+    }
+}
+
+fn string_arg<'y>(args: &'y [Value], pos: usize) -> Cow<'y, str> {
+    args.get(pos).map_or(Cow::Borrowed(""), |arg| arg.as_str())
+}
+
+impl From<bool> for Value<'_> {
+    fn from(value: bool) -> Self {
+        Value::Integer(i64::from(value))
+    }
+}
+
+fn truncate(value: &mut String, max_len: usize) {
+    if value.len() > max_len {
+        let mut pos = max_len;
+        while !value.is_char_boundary(pos) {
+            pos -= 1;
+        }
+        value.truncate(pos);
+    }
+}
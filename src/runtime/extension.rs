@@ -0,0 +1,85 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use crate::{
+    compiler::grammar::extension::{ExtensionArgument, ExtensionCall},
+    Context, Event, ExtensionValue,
+};
+
+impl ExtensionCall {
+    pub(crate) fn event(&self, ctx: &mut Context) -> Event {
+        // Values returned by the host are assigned to these variables
+        ctx.vars_pending = self
+            .tags
+            .iter()
+            .filter_map(|(_, value)| value.as_ref())
+            .chain(self.arguments.iter())
+            .filter_map(|value| match value {
+                ExtensionArgument::Variable(variable) => Some(variable.clone()),
+                _ => None,
+            })
+            .collect();
+
+        Event::Extension {
+            name: self.name.clone(),
+            tags: self
+                .tags
+                .iter()
+                .map(|(tag, value)| {
+                    (
+                        tag.clone(),
+                        value.as_ref().and_then(|value| value.eval(ctx)),
+                    )
+                })
+                .collect(),
+            arguments: self
+                .arguments
+                .iter()
+                .filter_map(|value| value.eval(ctx))
+                .collect(),
+        }
+    }
+}
+
+impl ExtensionArgument {
+    fn eval(&self, ctx: &Context) -> Option<ExtensionValue> {
+        match self {
+            ExtensionArgument::Number(number) => ExtensionValue::Number(*number).into(),
+            ExtensionArgument::String(value) => {
+                ExtensionValue::String(ctx.eval_string(value).into_owned()).into()
+            }
+            ExtensionArgument::StringList(values) => {
+                ExtensionValue::StringList(ctx.eval_strings_owned(values)).into()
+            }
+            ExtensionArgument::Variable(_) => None,
+        }
+    }
+}
+
+impl<'x> Context<'x> {
+    pub(crate) fn set_extension_values(&mut self, values: Vec<String>) {
+        for (variable, value) in std::mem::take(&mut self.vars_pending).iter().zip(values) {
+            self.set_variable(variable, value);
+        }
+    }
+}
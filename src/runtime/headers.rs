@@ -0,0 +1,391 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::borrow::Cow;
+
+use mail_builder::encoders::base64::base64_encode;
+
+// RFC 5322 recommended and maximum line lengths, excluding CRLF
+const MAX_LINE_LEN: usize = 78;
+const MAX_RAW_LINE_LEN: usize = 998;
+
+// Encoded-words are limited to 75 characters, which leaves room
+// for 45 bytes of base64 encoded text after "=?utf-8?B?" and "?=".
+const MAX_ENCODED_WORD_BYTES: usize = 45;
+
+// Maximum length of each RFC 2231 parameter value section
+const MAX_PARAM_SECTION_LEN: usize = 60;
+
+/// Encodes a header value for writing after "Name: ". Non-ASCII text is
+/// encoded as RFC 2047 encoded-words unless `utf8` is set, and long lines
+/// are folded. Encoded-words are not allowed in structured headers, so
+/// non-ASCII parameters are written as RFC 2231 extended parameters and
+/// any other structured value is left as is.
+pub(crate) fn encode_header_value<'x>(name: &str, value: &'x str, utf8: bool) -> Cow<'x, str> {
+    let needs_encoding = !utf8 && !value.is_ascii();
+    if !needs_encoding && name.len() + 2 + value.len() <= MAX_LINE_LEN {
+        return Cow::Borrowed(value);
+    }
+
+    let mut writer = HeaderWriter {
+        value: String::with_capacity(value.len() + (value.len() / MAX_LINE_LEN * 3) + 16),
+        line_len: name.len() + 2,
+    };
+
+    if is_address_header(name) {
+        for (pos, mailbox) in split_unquoted(value, ',').enumerate() {
+            if pos > 0 {
+                writer.value.push(',');
+                writer.line_len += 1;
+            }
+            writer.write_mailbox(mailbox.trim(), pos > 0, needs_encoding);
+        }
+    } else if is_structured_header(name) {
+        if needs_encoding && is_parameterized_header(name) {
+            for (pos, param) in split_unquoted(value, ';').enumerate() {
+                if pos > 0 {
+                    writer.value.push(';');
+                    writer.line_len += 1;
+                }
+                writer.write_param(param.trim(), pos > 0);
+            }
+        } else {
+            writer.write_text(value, false);
+        }
+    } else if needs_encoding
+        || value
+            .split(' ')
+            .any(|word| name.len() + 2 + word.len() > MAX_RAW_LINE_LEN)
+    {
+        writer.write_encoded(value, false);
+    } else {
+        writer.write_text(value, false);
+    }
+
+    writer.value.into()
+}
+
+struct HeaderWriter {
+    value: String,
+    line_len: usize,
+}
+
+impl HeaderWriter {
+    fn write_token(&mut self, token: &str, separator: bool) {
+        if separator {
+            if self.line_len + 1 + token.len() > MAX_LINE_LEN && self.line_len > 1 {
+                self.value.push_str("\r\n ");
+                self.line_len = 1;
+            } else {
+                self.value.push(' ');
+                self.line_len += 1;
+            }
+        }
+        self.value.push_str(token);
+        self.line_len += token.len();
+    }
+
+    fn write_text(&mut self, text: &str, separator: bool) {
+        for (pos, word) in text.split(' ').enumerate() {
+            self.write_token(word, separator || pos > 0);
+        }
+    }
+
+    fn write_encoded(&mut self, mut text: &str, mut separator: bool) {
+        loop {
+            // Fill the current line if there is enough room left, otherwise fold
+            let max_bytes = match (MAX_LINE_LEN
+                .saturating_sub(self.line_len + usize::from(separator) + 12)
+                / 4)
+                * 3
+            {
+                max_bytes if max_bytes >= 12 => max_bytes.min(MAX_ENCODED_WORD_BYTES),
+                _ => MAX_ENCODED_WORD_BYTES,
+            };
+            let word_end = text
+                .char_indices()
+                .find(|(pos, ch)| *pos > 0 && pos + ch.len_utf8() > max_bytes)
+                .map_or(text.len(), |(pos, _)| pos);
+
+            self.write_encoded_word(&text[..word_end], separator);
+            text = &text[word_end..];
+            separator = true;
+            if text.is_empty() {
+                break;
+            }
+        }
+    }
+
+    fn write_encoded_word(&mut self, text: &str, separator: bool) {
+        let mut word = Vec::with_capacity(12 + ((text.len() + 2) / 3 * 4));
+        word.extend_from_slice(b"=?utf-8?B?");
+        word.extend(base64_encode(text.as_bytes()).unwrap_or_default());
+        word.extend_from_slice(b"?=");
+        self.write_token(&String::from_utf8(word).unwrap_or_default(), separator);
+    }
+
+    fn write_param(&mut self, param: &str, separator: bool) {
+        match param.split_once('=') {
+            Some((attribute, value)) if !value.is_ascii() => {
+                let attribute = attribute.trim();
+                let mut sections = vec![String::from("utf-8''")];
+                let mut buf = [0u8; 4];
+                for ch in unquote(value.trim()).chars() {
+                    let mut encoded = String::with_capacity(12);
+                    for &byte in ch.encode_utf8(&mut buf).as_bytes() {
+                        if is_attribute_char(byte) {
+                            encoded.push(char::from(byte));
+                        } else {
+                            encoded.push_str(&format!("%{:02X}", byte));
+                        }
+                    }
+
+                    // Long values are split into RFC 2231 continuations
+                    // without breaking a character across sections
+                    let section = sections.last_mut().unwrap();
+                    if section.len() + encoded.len() > MAX_PARAM_SECTION_LEN {
+                        sections.push(encoded);
+                    } else {
+                        section.push_str(&encoded);
+                    }
+                }
+
+                if sections.len() == 1 {
+                    self.write_token(&format!("{}*={}", attribute, sections[0]), separator);
+                } else {
+                    for (pos, section) in sections.iter().enumerate() {
+                        if pos > 0 {
+                            self.value.push(';');
+                            self.line_len += 1;
+                        }
+                        self.write_token(
+                            &format!("{}*{}*={}", attribute, pos, section),
+                            separator || pos > 0,
+                        );
+                    }
+                }
+            }
+            _ => self.write_text(param, separator),
+        }
+    }
+
+    fn write_mailbox(&mut self, mailbox: &str, separator: bool, needs_encoding: bool) {
+        if needs_encoding {
+            if let Some((phrase, address)) = mailbox.rsplit_once('<') {
+                let phrase = phrase.trim();
+                if !phrase.is_ascii() {
+                    self.write_encoded(&unquote(phrase), separator);
+                    self.write_token(&format!("<{}", address.trim()), true);
+                    return;
+                }
+            }
+        }
+        self.write_text(mailbox, separator);
+    }
+}
+
+fn unquote(phrase: &str) -> Cow<'_, str> {
+    if let Some(phrase) = phrase
+        .strip_prefix('"')
+        .and_then(|phrase| phrase.strip_suffix('"'))
+    {
+        let mut result = String::with_capacity(phrase.len());
+        let mut is_escaped = false;
+        for ch in phrase.chars() {
+            if ch == '\\' && !is_escaped {
+                is_escaped = true;
+            } else {
+                result.push(ch);
+                is_escaped = false;
+            }
+        }
+        result.into()
+    } else {
+        phrase.into()
+    }
+}
+
+fn split_unquoted(value: &str, separator: char) -> impl Iterator<Item = &str> {
+    let mut items = Vec::new();
+    let mut in_quote = false;
+    let mut is_escaped = false;
+    let mut start = 0;
+
+    for (pos, ch) in value.char_indices() {
+        match ch {
+            '\\' if in_quote && !is_escaped => {
+                is_escaped = true;
+                continue;
+            }
+            '"' if !is_escaped => {
+                in_quote = !in_quote;
+            }
+            _ if ch == separator && !in_quote => {
+                items.push(&value[start..pos]);
+                start = pos + 1;
+            }
+            _ => (),
+        }
+        is_escaped = false;
+    }
+    items.push(&value[start..]);
+    items.into_iter()
+}
+
+// RFC 2231 attribute-char: any CHAR except SPACE, CTLs, "*", "'", "%" or tspecials
+fn is_attribute_char(byte: u8) -> bool {
+    byte.is_ascii_graphic() && !b"*'%()<>@,;:\\\"/[]?=".contains(&byte)
+}
+
+fn is_address_header(name: &str) -> bool {
+    [
+        "From",
+        "To",
+        "Cc",
+        "Bcc",
+        "Reply-To",
+        "Sender",
+        "Resent-From",
+        "Resent-To",
+        "Resent-Cc",
+        "Resent-Bcc",
+        "Resent-Sender",
+    ]
+    .iter()
+    .any(|h| h.eq_ignore_ascii_case(name))
+}
+
+fn is_structured_header(name: &str) -> bool {
+    [
+        "Date",
+        "Message-ID",
+        "In-Reply-To",
+        "References",
+        "Content-Type",
+        "Content-Transfer-Encoding",
+        "Content-Disposition",
+        "Content-ID",
+        "MIME-Version",
+        "Received",
+        "Return-Path",
+    ]
+    .iter()
+    .any(|h| h.eq_ignore_ascii_case(name))
+}
+
+fn is_parameterized_header(name: &str) -> bool {
+    name.eq_ignore_ascii_case("Content-Type") || name.eq_ignore_ascii_case("Content-Disposition")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::encode_header_value;
+
+    #[test]
+    fn encode_header() {
+        // Short ASCII values are not modified
+        assert_eq!(
+            encode_header_value("Subject", "Hello world", false),
+            "Hello world"
+        );
+
+        // Non-ASCII values
+        assert_eq!(
+            encode_header_value("X-Some-Header", "Это тест!", false),
+            "=?utf-8?B?0K3RgtC+INGC0LXRgdGCIQ==?="
+        );
+        assert_eq!(
+            encode_header_value("X-Some-Header", "Это тест!", true),
+            "Это тест!"
+        );
+        assert_eq!(
+            encode_header_value("From", "\"Jöhn Doe\" <john@example.org>", false),
+            "=?utf-8?B?SsO2aG4gRG9l?= <john@example.org>"
+        );
+        assert_eq!(
+            encode_header_value(
+                "To",
+                "\"Doe, John\" <john@example.org>, Jané <jane@example.org>",
+                false
+            ),
+            "\"Doe, John\" <john@example.org>, =?utf-8?B?SmFuw6k=?= <jane@example.org>"
+        );
+
+        // Structured headers
+        assert_eq!(
+            encode_header_value(
+                "Content-Type",
+                "application/pdf; name=\"Müller.pdf\"; charset=us-ascii",
+                false
+            ),
+            "application/pdf; name*=utf-8''M%C3%BCller.pdf; charset=us-ascii"
+        );
+        assert_eq!(
+            encode_header_value(
+                "Content-Disposition",
+                &format!("attachment; filename=\"{}.pdf\"", "ü".repeat(20)),
+                false
+            ),
+            concat!(
+                "attachment;\r\n filename*0*=utf-8''%C3%BC%C3%BC%C3%BC%C3%BC%C3%BC%C3%BC%C3%BC%C3%BC;",
+                "\r\n filename*1*=%C3%BC%C3%BC%C3%BC%C3%BC%C3%BC%C3%BC%C3%BC%C3%BC%C3%BC%C3%BC;",
+                "\r\n filename*2*=%C3%BC%C3%BC.pdf"
+            )
+        );
+        assert_eq!(
+            encode_header_value("Message-ID", "<1234@exämple.org>", false),
+            "<1234@exämple.org>"
+        );
+        assert_eq!(
+            encode_header_value("References", "<1@exämple.org> <2@example.org>", false),
+            "<1@exämple.org> <2@example.org>"
+        );
+        assert_eq!(
+            encode_header_value("Message-ID", "<1234@exämple.org>", true),
+            "<1234@exämple.org>"
+        );
+
+        // Folding
+        for (name, value) in [
+            ("X-Some-Header", "This is very long header content, folded to fit inside multiple header lines. This may cause problems, so that is why it is tested here.".to_string()),
+            ("Subject", "ñ".repeat(100)),
+        ] {
+            let encoded = encode_header_value(name, &value, false);
+            assert!(encoded.contains("\r\n "), "{:?}", encoded);
+            assert!(
+                encoded
+                    .split("\r\n")
+                    .enumerate()
+                    .all(|(pos, line)| {
+                        let prefix_len = if pos == 0 { name.len() + 2 } else { 0 };
+                        prefix_len + line.len() <= 78
+                    }),
+                "{:?}",
+                encoded
+            );
+            if value.is_ascii() {
+                assert_eq!(encoded.replace("\r\n", ""), value);
+            }
+        }
+    }
+}
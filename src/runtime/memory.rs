@@ -0,0 +1,573 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    mem::{align_of, size_of, size_of_val},
+    sync::Arc,
+};
+
+use ahash::AHashSet;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    compiler::{
+        grammar::{
+            actions::{
+                action_convert::Convert,
+                action_editheader::{AddHeader, DeleteHeader},
+                action_fileinto::FileInto,
+                action_flags::{Action, EditFlags},
+                action_foreach::{ForEach, ForEachSource},
+                action_include::{Include, Location},
+                action_keep::Keep,
+                action_log::Log,
+                action_mime::{Enclose, ExtractText, ForEveryPart, MimeOpts, Replace},
+                action_notify::Notify,
+                action_procedure::ProcedureCall,
+                action_redirect::{ByMode, ByTime, Notify as DsnNotify, NotifyItem, Redirect, Ret},
+                action_reject::Reject,
+                action_set::{HashAlgorithm, Modifier, Set, Variable},
+                action_vacation::{Period, TestVacation, Vacation},
+            },
+            expr::{BinaryOperator, Constant, ExpressionItem, Function, UnaryOperator},
+            extension::{ExtensionArgument, ExtensionCall},
+            instruction::Instruction,
+            test::Test,
+            tests::{
+                test_address::TestAddress,
+                test_body::{BodyTransform, TestBody},
+                test_date::{DatePart, TestCurrentDate, TestDate, Zone},
+                test_duplicate::{DupMatch, TestDuplicate},
+                test_envelope::TestEnvelope,
+                test_eval::TestEval,
+                test_execute::{Execute, ExecuteInput, Filter},
+                test_exists::TestExists,
+                test_extlists::TestValidExtList,
+                test_hasflag::TestHasFlag,
+                test_header::TestHeader,
+                test_ihave::{Error, TestIhave},
+                test_mailbox::{TestMailboxExists, TestMetadata, TestMetadataExists},
+                test_mailboxid::TestMailboxIdExists,
+                test_notify::{TestNotifyMethodCapability, TestValidNotifyMethod},
+                test_size::TestSize,
+                test_spamtest::{TestSpamTest, TestVirusTest},
+                test_specialuse::TestSpecialUseExists,
+                test_string::TestString,
+            },
+            AddressPart, Capability, Clear, Comparator, Invalid, KeySet, LazyKeySet, MatchType,
+            RelationalMatch,
+        },
+        lexer::string::StringItem,
+    },
+    Envelope, FileCarbonCopy, LogLevel, Mailbox, Metadata,
+};
+
+/// Texts and capability lists shared by the scripts of a `SieveStore`.
+#[derive(Debug, Default)]
+pub(crate) struct StringTable {
+    strings: AHashSet<Arc<str>>,
+    capabilities: AHashSet<Arc<[Capability]>>,
+}
+
+impl StringTable {
+    fn text(&mut self, text: &Arc<str>) -> Arc<str> {
+        if let Some(text) = self.strings.get(text) {
+            text.clone()
+        } else {
+            self.strings.insert(text.clone());
+            text.clone()
+        }
+    }
+
+    fn capabilities(&mut self, capabilities: &Arc<[Capability]>) -> Arc<[Capability]> {
+        if let Some(capabilities) = self.capabilities.get(capabilities) {
+            capabilities.clone()
+        } else {
+            self.capabilities.insert(capabilities.clone());
+            capabilities.clone()
+        }
+    }
+
+    /// Drops the entries no longer referenced outside of the table.
+    pub(crate) fn purge(&mut self) {
+        self.strings.retain(|text| Arc::strong_count(text) > 1);
+        self.capabilities
+            .retain(|capabilities| Arc::strong_count(capabilities) > 1);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.strings.len() + self.capabilities.len()
+    }
+
+    pub(crate) fn memory_usage(&self) -> usize {
+        self.strings
+            .iter()
+            .map(|text| shared_size(text.as_bytes()))
+            .sum::<usize>()
+            + self
+                .capabilities
+                .iter()
+                .map(|capabilities| shared_size(capabilities.as_ref()))
+                .sum::<usize>()
+    }
+}
+
+pub(crate) fn serialize_shared<T: Serialize + ?Sized, S: Serializer>(
+    value: &Arc<T>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    value.as_ref().serialize(serializer)
+}
+
+pub(crate) fn deserialize_shared<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Arc<str>, D::Error> {
+    String::deserialize(deserializer).map(Into::into)
+}
+
+pub(crate) fn deserialize_shared_list<'de, T: Deserialize<'de>, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Arc<[T]>, D::Error> {
+    Vec::<T>::deserialize(deserializer).map(Into::into)
+}
+
+pub(crate) trait Compact {
+    /// Bytes allocated on the heap by this value. Values referenced more than
+    /// once are not included, their owner accounts for them.
+    fn heap_size(&self) -> usize;
+
+    /// Replaces shared values with the copies in `table`, adding the ones
+    /// it does not have yet.
+    fn intern(&mut self, table: &mut StringTable);
+}
+
+impl Compact for String {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+
+    fn intern(&mut self, _: &mut StringTable) {}
+}
+
+impl Compact for Arc<str> {
+    fn heap_size(&self) -> usize {
+        if Arc::strong_count(self) == 1 {
+            shared_size(self.as_bytes())
+        } else {
+            0
+        }
+    }
+
+    fn intern(&mut self, table: &mut StringTable) {
+        *self = table.text(self);
+    }
+}
+
+impl Compact for Arc<[Capability]> {
+    fn heap_size(&self) -> usize {
+        if Arc::strong_count(self) == 1 {
+            shared_size(self.as_ref())
+        } else {
+            0
+        }
+    }
+
+    fn intern(&mut self, table: &mut StringTable) {
+        *self = table.capabilities(self);
+    }
+}
+
+// Size of the allocation behind an `Arc<str>` or `Arc<[T]>`
+fn shared_size<T: Compact>(items: &[T]) -> usize {
+    let align = align_of::<usize>().max(align_of::<T>());
+    (2 * size_of::<usize>() + size_of_val(items) + align - 1) / align * align
+        + items.iter().map(T::heap_size).sum::<usize>()
+}
+
+impl<T: Compact> Compact for Vec<T> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * size_of::<T>() + self.iter().map(T::heap_size).sum::<usize>()
+    }
+
+    fn intern(&mut self, table: &mut StringTable) {
+        self.iter_mut().for_each(|item| item.intern(table));
+    }
+}
+
+impl<T: Compact> Compact for Box<[T]> {
+    fn heap_size(&self) -> usize {
+        self.len() * size_of::<T>() + self.iter().map(T::heap_size).sum::<usize>()
+    }
+
+    fn intern(&mut self, table: &mut StringTable) {
+        self.iter_mut().for_each(|item| item.intern(table));
+    }
+}
+
+impl<T: Compact> Compact for Box<T> {
+    fn heap_size(&self) -> usize {
+        size_of::<T>() + self.as_ref().heap_size()
+    }
+
+    fn intern(&mut self, table: &mut StringTable) {
+        self.as_mut().intern(table);
+    }
+}
+
+impl<T: Compact> Compact for Option<T> {
+    fn heap_size(&self) -> usize {
+        self.as_ref().map_or(0, T::heap_size)
+    }
+
+    fn intern(&mut self, table: &mut StringTable) {
+        if let Some(value) = self {
+            value.intern(table);
+        }
+    }
+}
+
+impl<A: Compact, B: Compact> Compact for (A, B) {
+    fn heap_size(&self) -> usize {
+        self.0.heap_size() + self.1.heap_size()
+    }
+
+    fn intern(&mut self, table: &mut StringTable) {
+        self.0.intern(table);
+        self.1.intern(table);
+    }
+}
+
+impl<A: Compact, B: Compact, C: Compact> Compact for (A, B, C) {
+    fn heap_size(&self) -> usize {
+        self.0.heap_size() + self.1.heap_size() + self.2.heap_size()
+    }
+
+    fn intern(&mut self, table: &mut StringTable) {
+        self.0.intern(table);
+        self.1.intern(table);
+        self.2.intern(table);
+    }
+}
+
+impl Compact for LazyKeySet {
+    fn heap_size(&self) -> usize {
+        self.0
+            .get()
+            .and_then(Option::as_ref)
+            .map_or(0, Compact::heap_size)
+    }
+
+    // Key sets hold copies of the keys, which are interned with the key list
+    fn intern(&mut self, _: &mut StringTable) {}
+}
+
+impl Compact for KeySet {
+    fn heap_size(&self) -> usize {
+        self.keys.heap_size()
+            + self.automaton.get().map_or(0, |automaton| {
+                size_of_val(automaton.as_ref()) + automaton.heap_bytes()
+            })
+    }
+
+    fn intern(&mut self, _: &mut StringTable) {}
+}
+
+macro_rules! no_heap {
+    ($($ty:ty),* $(,)?) => {
+        $(impl Compact for $ty {
+            fn heap_size(&self) -> usize {
+                0
+            }
+
+            fn intern(&mut self, _: &mut StringTable) {}
+        })*
+    };
+}
+
+macro_rules! compact_struct {
+    ($($name:ident $(<$g:ident>)? { $($field:ident),* $(,)? })*) => {
+        $(impl$(<$g: Compact>)? Compact for $name$(<$g>)? {
+            fn heap_size(&self) -> usize {
+                let $name { $($field),* } = self;
+                0 $(+ $field.heap_size())*
+            }
+
+            fn intern(&mut self, _table: &mut StringTable) {
+                let $name { $($field),* } = self;
+                $($field.intern(_table);)*
+            }
+        })*
+    };
+}
+
+macro_rules! compact_enum {
+    ($($name:ident $(<$g:ident>)? {
+        $(
+            $(#[$meta:meta])*
+            $variant:ident $(($($value:ident),*))? $({ $($field:ident),* })?
+        ),* $(,)?
+    })*) => {
+        $(impl$(<$g: Compact>)? Compact for $name$(<$g>)? {
+            fn heap_size(&self) -> usize {
+                match self {
+                    $($(#[$meta])* $name::$variant $(($($value),*))? $({ $($field),* })? => {
+                        0 $($(+ $value.heap_size())*)? $($(+ $field.heap_size())*)?
+                    })*
+                }
+            }
+
+            fn intern(&mut self, _table: &mut StringTable) {
+                match self {
+                    $($(#[$meta])* $name::$variant $(($($value),*))? $({ $($field),* })? => {
+                        $($($value.intern(_table);)*)? $($($field.intern(_table);)*)?
+                    })*
+                }
+            }
+        })*
+    };
+}
+
+no_heap!(
+    bool,
+    u8,
+    u32,
+    u64,
+    usize,
+    i32,
+    i64,
+    f64,
+    Action,
+    AddressPart,
+    BinaryOperator,
+    ByMode,
+    DatePart,
+    Envelope,
+    Function,
+    HashAlgorithm,
+    Location,
+    LogLevel,
+    NotifyItem,
+    RelationalMatch,
+    Ret,
+    UnaryOperator,
+);
+
+compact_struct!(
+    AddHeader { last, field_name, value }
+    Clear { local_vars_idx, local_vars_num, match_vars }
+    Convert { from_media_type, to_media_type, transcoding_params, is_not }
+    DeleteHeader { index, comparator, match_type, field_name, value_patterns, mime_anychild }
+    EditFlags { action, name, flags }
+    Enclose { subject, headers, value }
+    Error { message }
+    Execute { command, arguments, input, output, is_not }
+    ExtensionCall { name, tags, arguments, is_not }
+    ExtractText { modifiers, first, name }
+    FileCarbonCopy<T> { mailbox, mailbox_id, create, flags, special_use }
+    FileInto { copy, create, folder, flags, mailbox_id, special_use }
+    Filter { program, arguments, is_not }
+    ForEach { var_name, jz_pos }
+    ForEveryPart { jz_pos }
+    Include { location, once, optional, value }
+    Invalid { name, line_num, line_pos }
+    Keep { flags }
+    Log { level, message, line_num, line_pos }
+    Notify { from, importance, options, message, fcc, method }
+    ProcedureCall { pos, params, args }
+    Redirect { copy, address, notify, return_of_content, by_time, list }
+    Reject { ereject, reason }
+    Replace { subject, from, replacement, mime }
+    Set { modifiers, name, value, expression }
+    TestAddress {
+        header_list, key_list, key_set, address_part, match_type, comparator, index, mime_anychild,
+        is_not,
+    }
+    TestBody { key_list, key_set, body_transform, match_type, comparator, is_not }
+    TestCurrentDate { zone, match_type, comparator, date_part, key_list, is_not }
+    TestDate {
+        header_name, key_list, match_type, comparator, index, zone, date_part, mime_anychild,
+        is_not,
+    }
+    TestDuplicate { handle, dup_match, seconds, last, is_not }
+    TestEnvelope {
+        envelope_list, key_list, key_set, address_part, match_type, comparator, zone, is_not,
+    }
+    TestEval { expression, is_not }
+    TestExists { header_names, mime_anychild, is_not }
+    TestHasFlag { comparator, match_type, variable_list, flags, is_not }
+    TestHeader {
+        header_list, key_list, key_set, match_type, comparator, index, mime_opts, mime_anychild,
+        is_not,
+    }
+    TestIhave { capabilities, is_not }
+    TestMailboxExists { mailbox_names, is_not }
+    TestMailboxIdExists { mailbox_ids, is_not }
+    TestMetadata { match_type, comparator, medatata, key_list, is_not }
+    TestMetadataExists { mailbox, annotation_names, is_not }
+    TestNotifyMethodCapability {
+        comparator, match_type, notification_uri, notification_capability, key_list, is_not,
+    }
+    TestSize { over, limit, is_not }
+    TestSpamTest { value, match_type, comparator, percent, is_not }
+    TestSpecialUseExists { mailbox, attributes, is_not }
+    TestString { match_type, comparator, source, key_list, key_set, is_not }
+    TestVacation { addresses, period, handle, reason }
+    TestValidExtList { list_names, is_not }
+    TestValidNotifyMethod { notification_uris, is_not }
+    TestVirusTest { value, match_type, comparator, is_not }
+    Vacation { subject, from, mime, fcc, reason }
+);
+
+compact_enum!(
+    BodyTransform { Raw, Content(v), Text }
+    ByTime<T> { Relative { rlimit, mode, trace }, Absolute { alimit, mode, trace }, None }
+    Capability {
+        Envelope, EnvelopeDsn, EnvelopeDeliverBy, FileInto, EncodedCharacter, Comparator(v),
+        Other(v), Body, Convert, Copy, Relational, Date, Index, Duplicate, Variables, EditHeader,
+        ForEveryPart, Mime, Replace, Enclose, ExtractText, Enotify, RedirectDsn,
+        RedirectDeliverBy, Environment, Reject, Ereject, ExtLists, SubAddress, Vacation,
+        VacationSeconds, Fcc, Mailbox, MailboxId, MboxMetadata, ServerMetadata, SpecialUse,
+        Imap4Flags, Ihave, ImapSieve, Include, Regex, SpamTest, SpamTestPlus, VirusTest, Execute,
+        Expressions, Loops, Modifiers, Procedures, Debug, Filter,
+    }
+    Comparator { Elbonia, Octet, AsciiCaseMap, AsciiNumeric, UnicodeCaseMap, Other(v) }
+    Constant { Integer(v), Float(v), String(v) }
+    DsnNotify { Never, Items(v), Default }
+    DupMatch { Header(v), UniqueId(v), Default }
+    ExecuteInput { None, Pipe, Text(v) }
+    ExpressionItem {
+        Variable(v), Constant(v), UnaryOperator(v), BinaryOperator(v), Function { id, num_args },
+    }
+    ExtensionArgument { Number(v), String(v), StringList(v), Variable(v) }
+    ForEachSource {
+        List(v), Split { separator, values }, Header(v), Address { header_list, address_part },
+    }
+    Instruction {
+        Require(v), Keep(v), FileInto(v), Redirect(v), Discard, Stop, Invalid(v), Test(v), Jmp(v),
+        Jz(v), Jnz(v), ForEveryPartPush, ForEveryPart(v), ForEveryPartPop(v), Replace(v),
+        Enclose(v), ExtractText(v), Convert(v), AddHeader(v), DeleteHeader(v), Set(v), Clear(v),
+        Notify(v), Reject(v), Vacation(v), Error(v), EditFlags(v), Include(v), Return, Execute(v),
+        Extension(v), ForEachPush(v), ForEach(v), ForEachPop(v), Call(v), ProcedureReturn, Log(v),
+        Filter(v), #[cfg(test)] External(v),
+    }
+    Mailbox { Name(v), Id(v) }
+    MatchType { Is, Contains, Matches(v), Regex(v), Value(v), Count(v), List }
+    Metadata<T> { Server { annotation }, Mailbox { name, annotation } }
+    MimeOpts<T> { Type, Subtype, ContentType, Param(v), None }
+    Modifier {
+        Lower, Upper, LowerFirst, UpperFirst, QuoteWildcard, QuoteRegex, EncodeUrl, Length, Trim,
+        Replace { find, replace }, Substring { offset, length }, Split(v), Join(v), DecodeMime,
+        DecodeBase64, EncodeBase64, HtmlToText, Punycode, Hash(v), Custom(v),
+    }
+    Period { Days(v), Seconds(v), Default }
+    StringItem {
+        Text(v), LocalVariable(v), MatchVariable(v), GlobalVariable(v), EnvironmentVariable(v),
+        List(v),
+    }
+    Test {
+        True, False, Address(v), Envelope(v), Exists(v), Header(v), Size(v), Invalid(v), Body(v),
+        Convert(v), Date(v), CurrentDate(v), Duplicate(v), String(v), Environment(v),
+        NotifyMethodCapability(v), ValidNotifyMethod(v), ValidExtList(v), Ihave(v), HasFlag(v),
+        MailboxExists(v), Metadata(v), MetadataExists(v), MailboxIdExists(v), SpamTest(v),
+        VirusTest(v), SpecialUseExists(v), Vacation(v), Execute(v), Filter(v), Eval(v),
+        Extension(v), #[cfg(test)] External(v),
+    }
+    Variable { Local(v), Global(v) }
+    Zone { Time(v), Original, Local }
+);
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+        fs,
+        mem::size_of,
+        path::PathBuf,
+    };
+
+    use crate::{Compiler, Sieve};
+
+    // Tracks the bytes allocated by each thread
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATED: Cell<isize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATED.try_with(|bytes| bytes.set(bytes.get() + layout.size() as isize));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            let _ = ALLOCATED.try_with(|bytes| bytes.set(bytes.get() - layout.size() as isize));
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    #[test]
+    fn memory_usage() {
+        let mut scripts = Vec::new();
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("tests");
+        read_dir(path, &mut scripts);
+
+        let compiler = Compiler::new();
+        let mut num_scripts = 0;
+        for path in scripts {
+            let script = match compiler.compile(&fs::read(&path).unwrap()) {
+                Ok(script) => script.serialize().unwrap(),
+                Err(_) => continue,
+            };
+
+            // Everything the deserialized script keeps on the heap is reported
+            let allocated = ALLOCATED.with(Cell::get);
+            let script = Sieve::deserialize(&script).unwrap();
+            let allocated = (ALLOCATED.with(Cell::get) - allocated) as usize;
+            assert_eq!(
+                script.memory_usage(),
+                size_of::<Sieve>() + allocated,
+                "{}",
+                path.display()
+            );
+            num_scripts += 1;
+        }
+        assert!(num_scripts > 50, "{num_scripts}");
+    }
+
+    fn read_dir(path: PathBuf, files: &mut Vec<PathBuf>) {
+        for entry in fs::read_dir(path).unwrap() {
+            let entry = entry.unwrap().path();
+            if entry.is_dir() {
+                read_dir(entry, files);
+            } else if entry.extension().map_or(false, |e| e == "sieve") {
+                files.push(entry);
+            }
+        }
+    }
+}
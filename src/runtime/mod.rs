@@ -21,18 +21,40 @@
  * for more details.
 */
 
-use std::{borrow::Cow, fmt::Display, ops::Deref, sync::Arc};
+use std::{
+    borrow::Cow,
+    fmt::{Debug, Display},
+    ops::Deref,
+    sync::Arc,
+};
 
 use ahash::{AHashMap, AHashSet};
 use mail_parser::{Encoding, HeaderName, Message, MessagePart, PartType};
 
 use crate::{
-    compiler::grammar::{Capability, Invalid},
-    Context, Input, Metadata, Runtime, Script, Sieve,
+    compiler::grammar::{Capability, Comparator, Invalid},
+    Context, Envelope, Event, Input, Metadata, RecipientFilter, ReturnPath, Runtime, Script, Sieve,
+};
+
+use self::{
+    comparator::Comparators,
+    context::{MessageCow, Shared},
+    modifier::StringModifiers,
+    notify::NotificationMethods,
 };
 
 pub mod actions;
+pub mod cache;
+pub mod comparator;
+pub mod compiled;
 pub mod context;
+pub mod expression;
+pub mod extension;
+pub mod headers;
+pub mod memory;
+pub mod modifier;
+pub mod notify;
+pub mod owned;
 pub mod serialize;
 pub mod string;
 pub mod tests;
@@ -41,13 +63,22 @@ pub mod variables;
 #[derive(Debug)]
 pub enum RuntimeError {
     TooManyIncludes,
+    TooManyNestedCalls,
     InvalidInstruction(Invalid),
     ScriptErrorMessage(String),
     CapabilityNotAllowed(Capability),
     CapabilityNotSupported(String),
     CPULimitReached,
+    MessageRequired,
+    InvalidNotificationUri(String),
 }
 
+#[derive(Clone)]
+pub(crate) struct Generator(Arc<dyn Fn() -> String + Send + Sync>);
+
+#[derive(Clone)]
+pub struct ReturnPathFn(Arc<dyn Fn(&str) -> String + Send + Sync>);
+
 impl Runtime {
     pub fn new() -> Self {
         #[allow(unused_mut)]
@@ -64,7 +95,11 @@ impl Runtime {
             ]),
             metadata: Vec::new(),
             include_scripts: AHashMap::new(),
+            notification_methods: NotificationMethods::new(),
+            comparators: Comparators::new(),
+            modifiers: StringModifiers::new(),
             max_nested_includes: 3,
+            max_nested_calls: 3,
             cpu_limit: 5000,
             max_variable_size: 4096,
             max_redirects: 1,
@@ -76,12 +111,20 @@ impl Runtime {
             valid_notification_uris: AHashSet::new(),
             valid_ext_lists: AHashSet::new(),
             vacation_use_orig_rcpt: false,
+            message_patches: false,
+            reject_mdn: false,
+            redirect_return_path: ReturnPath::Original,
+            vacation_return_path: ReturnPath::Null,
+            notify_return_path: ReturnPath::User,
             vacation_default_subject: "Automated reply".into(),
             vacation_subject_prefix: "Auto: ".into(),
             max_header_size: 1024,
             max_out_messages: 3,
+            max_log_messages: 100,
             default_vacation_expiry: 30 * 86400,
             default_duplicate_expiry: 7 * 86400,
+            message_id_generator: None,
+            boundary_generator: None,
         }
     }
 
@@ -103,6 +146,15 @@ impl Runtime {
         self
     }
 
+    pub fn set_max_nested_calls(&mut self, size: usize) {
+        self.max_nested_calls = size;
+    }
+
+    pub fn with_max_nested_calls(mut self, size: usize) -> Self {
+        self.max_nested_calls = size;
+        self
+    }
+
     pub fn set_max_redirects(&mut self, size: usize) {
         self.max_redirects = size;
     }
@@ -121,6 +173,15 @@ impl Runtime {
         self
     }
 
+    pub fn set_max_log_messages(&mut self, size: usize) {
+        self.max_log_messages = size;
+    }
+
+    pub fn with_max_log_messages(mut self, size: usize) -> Self {
+        self.max_log_messages = size;
+        self
+    }
+
     pub fn set_max_received_headers(&mut self, size: usize) {
         self.max_received_headers = size;
     }
@@ -267,6 +328,40 @@ impl Runtime {
         self
     }
 
+    /// Schemes still have to be allowed with `set_valid_notification_uri`
+    /// before `valid_notify_method` accepts them.
+    pub fn set_notification_methods(&mut self, methods: NotificationMethods) {
+        self.notification_methods = methods;
+    }
+
+    pub fn with_notification_methods(mut self, methods: NotificationMethods) -> Self {
+        self.set_notification_methods(methods);
+        self
+    }
+
+    pub fn set_comparators(&mut self, comparators: Comparators) {
+        self.allowed_capabilities.extend(
+            comparators
+                .names()
+                .map(|name| Capability::Comparator(Comparator::Other(name.clone()))),
+        );
+        self.comparators = comparators;
+    }
+
+    pub fn with_comparators(mut self, comparators: Comparators) -> Self {
+        self.set_comparators(comparators);
+        self
+    }
+
+    pub fn set_modifiers(&mut self, modifiers: StringModifiers) {
+        self.modifiers = modifiers;
+    }
+
+    pub fn with_modifiers(mut self, modifiers: StringModifiers) -> Self {
+        self.set_modifiers(modifiers);
+        self
+    }
+
     pub fn set_valid_ext_list(&mut self, name: impl Into<Cow<'static, str>>) {
         self.valid_ext_lists.insert(name.into());
     }
@@ -280,6 +375,57 @@ impl Runtime {
         self.vacation_use_orig_rcpt = value;
     }
 
+    /// Report modified messages as `Event::CreatedPatch` instead of
+    /// `Event::CreatedMessage`.
+    pub fn set_message_patches(&mut self, value: bool) {
+        self.message_patches = value;
+    }
+
+    pub fn with_message_patches(mut self, value: bool) -> Self {
+        self.set_message_patches(value);
+        self
+    }
+
+    /// Send an RFC 3798 disposition notification to the envelope sender on
+    /// `reject` instead of returning `Event::Reject`. `ereject` is returned
+    /// to the host unless the context no longer allows protocol-level
+    /// rejection, see `Context::set_protocol_reject`.
+    pub fn set_reject_mdn(&mut self, value: bool) {
+        self.reject_mdn = value;
+    }
+
+    pub fn with_reject_mdn(mut self, value: bool) -> Self {
+        self.set_reject_mdn(value);
+        self
+    }
+
+    pub fn set_redirect_return_path(&mut self, value: ReturnPath) {
+        self.redirect_return_path = value;
+    }
+
+    pub fn with_redirect_return_path(mut self, value: ReturnPath) -> Self {
+        self.set_redirect_return_path(value);
+        self
+    }
+
+    pub fn set_vacation_return_path(&mut self, value: ReturnPath) {
+        self.vacation_return_path = value;
+    }
+
+    pub fn with_vacation_return_path(mut self, value: ReturnPath) -> Self {
+        self.set_vacation_return_path(value);
+        self
+    }
+
+    pub fn set_notify_return_path(&mut self, value: ReturnPath) {
+        self.notify_return_path = value;
+    }
+
+    pub fn with_notify_return_path(mut self, value: ReturnPath) -> Self {
+        self.set_notify_return_path(value);
+        self
+    }
+
     pub fn with_valid_ext_lists(
         mut self,
         lists: impl IntoIterator<Item = impl Into<Cow<'static, str>>>,
@@ -311,36 +457,200 @@ impl Runtime {
         self
     }
 
+    pub fn set_message_id_generator(
+        &mut self,
+        generator: impl Fn() -> String + Send + Sync + 'static,
+    ) {
+        self.message_id_generator = Generator(Arc::new(generator)).into();
+    }
+
+    pub fn with_message_id_generator(
+        mut self,
+        generator: impl Fn() -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.set_message_id_generator(generator);
+        self
+    }
+
+    pub fn set_boundary_generator(
+        &mut self,
+        generator: impl Fn() -> String + Send + Sync + 'static,
+    ) {
+        self.boundary_generator = Generator(Arc::new(generator)).into();
+    }
+
+    pub fn with_boundary_generator(
+        mut self,
+        generator: impl Fn() -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.set_boundary_generator(generator);
+        self
+    }
+
     pub fn filter<'z: 'x, 'x>(&'z self, raw_message: &'x [u8]) -> Context<'x> {
-        Context::new(
-            self,
-            Message::parse(raw_message).unwrap_or_else(|| Message {
-                parts: vec![MessagePart {
-                    headers: vec![],
-                    is_encoding_problem: false,
-                    body: PartType::Text("".into()),
-                    encoding: Encoding::None,
-                    offset_header: 0,
-                    offset_body: 0,
-                    offset_end: 0,
-                }],
-                raw_message: b""[..].into(),
-                ..Default::default()
-            }),
-        )
+        Context::new(self, MessageCow::Owned(parse_message(raw_message)))
     }
 
     pub fn filter_parsed<'z: 'x, 'x>(&'z self, message: Message<'x>) -> Context<'x> {
-        Context::new(self, message)
+        Context::new(self, MessageCow::Owned(message))
+    }
+
+    /// Filters a message that is shared between multiple instances, such as
+    /// one per recipient. The message is only copied if a script modifies it.
+    pub fn filter_shared<'z: 'x, 'x>(&'z self, message: &'x Message<'x>) -> Context<'x> {
+        Context::new(self, MessageCow::Borrowed(message))
+    }
+
+    /// Parses the message once and runs the script of each recipient against
+    /// it, returning the results of each recipient in order. `reply` answers
+    /// the events of the recipient at the given index.
+    pub fn filter_recipients<'x>(
+        &self,
+        raw_message: &'x [u8],
+        recipients: impl IntoIterator<Item = RecipientFilter<'x>>,
+        mut reply: impl FnMut(usize, &Result<Event, RuntimeError>) -> Input,
+    ) -> Vec<Vec<Result<Event, RuntimeError>>> {
+        let message = parse_message(raw_message);
+
+        recipients
+            .into_iter()
+            .enumerate()
+            .map(|(idx, recipient)| {
+                let mut instance = self
+                    .filter_shared(&message)
+                    .with_user_address(recipient.user_address);
+                for (envelope, value) in recipient.envelope {
+                    instance.set_envelope(envelope, value);
+                }
+                for (name, value) in recipient.vars_env {
+                    instance.set_env_variable(name, value);
+                }
+
+                let mut results = Vec::new();
+                let mut input = Input::Script {
+                    name: recipient.name,
+                    script: recipient.script,
+                };
+                while let Some(result) = instance.run(input) {
+                    input = reply(idx, &result);
+                    results.push(result);
+                }
+                results
+            })
+            .collect()
+    }
+
+    /// Parses only the message headers, the MIME structure and body are
+    /// parsed on demand the first time a script needs them.
+    pub fn filter_lazy<'z: 'x, 'x>(&'z self, raw_message: &'x [u8]) -> Context<'x> {
+        let mut ctx = Context::new(
+            self,
+            MessageCow::Owned(parse_message(&raw_message[..header_end(raw_message)])),
+        );
+        ctx.message_size = raw_message.len();
+        ctx.raw_message = Shared::Borrowed(raw_message);
+        ctx.deferred_message = true;
+        ctx
+    }
+
+    /// Evaluates scripts using only the envelope and environment. Tests on the
+    /// message contents are unknown, so `anyof` and `allof` conditions decided
+    /// by their other tests still run. Execution stops with
+    /// `RuntimeError::MessageRequired` as soon as the outcome depends on the
+    /// message contents, any events returned before that are final.
+    pub fn filter_envelope<'z: 'x, 'x>(&'z self) -> Context<'x> {
+        let mut ctx = Context::new(self, MessageCow::Owned(parse_message(b"")));
+        ctx.envelope_only = true;
+        ctx
     }
 }
 
+pub(crate) fn parse_message(raw_message: &[u8]) -> Message<'_> {
+    Message::parse(raw_message).unwrap_or_else(|| Message {
+        parts: vec![MessagePart {
+            headers: vec![],
+            is_encoding_problem: false,
+            body: PartType::Text("".into()),
+            encoding: Encoding::None,
+            offset_header: 0,
+            offset_body: 0,
+            offset_end: 0,
+        }],
+        raw_message: b""[..].into(),
+        ..Default::default()
+    })
+}
+
+pub(crate) fn header_end(raw_message: &[u8]) -> usize {
+    let mut pos = 0;
+    for line in raw_message.split_inclusive(|&ch| ch == b'\n') {
+        pos += line.len();
+        if line == b"\n" || line == b"\r\n" {
+            return pos;
+        }
+    }
+    raw_message.len()
+}
+
 impl Default for Runtime {
     fn default() -> Self {
         Self::new()
     }
 }
 
+impl<'x> RecipientFilter<'x> {
+    pub fn new(name: impl Into<Script>, script: impl Into<Arc<Sieve>>) -> Self {
+        RecipientFilter {
+            name: name.into(),
+            script: script.into(),
+            user_address: "".into(),
+            envelope: Vec::new(),
+            vars_env: Vec::new(),
+        }
+    }
+
+    pub fn set_envelope(
+        &mut self,
+        envelope: impl TryInto<Envelope>,
+        value: impl Into<Cow<'x, str>>,
+    ) {
+        if let Ok(envelope) = envelope.try_into() {
+            self.envelope.push((envelope, value.into()));
+        }
+    }
+
+    pub fn with_envelope(
+        mut self,
+        envelope: impl TryInto<Envelope>,
+        value: impl Into<Cow<'x, str>>,
+    ) -> Self {
+        self.set_envelope(envelope, value);
+        self
+    }
+
+    pub fn set_user_address(&mut self, from: impl Into<Cow<'x, str>>) {
+        self.user_address = from.into();
+    }
+
+    pub fn with_user_address(mut self, from: impl Into<Cow<'x, str>>) -> Self {
+        self.set_user_address(from);
+        self
+    }
+
+    pub fn set_env_variable(&mut self, name: impl Into<String>, value: impl Into<Cow<'x, str>>) {
+        self.vars_env.push((name.into(), value.into()));
+    }
+
+    pub fn with_env_variable(
+        mut self,
+        name: impl Into<String>,
+        value: impl Into<Cow<'x, str>>,
+    ) -> Self {
+        self.set_env_variable(name, value);
+        self
+    }
+}
+
 impl Input {
     pub fn script(name: impl Into<Script>, script: impl Into<Arc<Sieve>>) -> Self {
         Input::Script {
@@ -349,6 +659,29 @@ impl Input {
         }
     }
 
+    pub fn extension(result: bool, values: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Input::Extension {
+            result,
+            values: values.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Returns the output of an executed program, stored in the `:output` variable.
+    pub fn output(result: bool, output: impl Into<String>) -> Self {
+        Input::Output {
+            result,
+            output: output.into(),
+        }
+    }
+
+    /// Replaces the message with the output of a program it was piped to.
+    pub fn message(result: bool, message: impl Into<Vec<u8>>) -> Self {
+        Input::Message {
+            result,
+            message: message.into(),
+        }
+    }
+
     pub fn success() -> Self {
         Input::True
     }
@@ -469,3 +802,784 @@ impl From<(&'_ str, &'_ str)> for Metadata<String> {
         }
     }
 }
+
+impl Generator {
+    pub(crate) fn generate(&self) -> String {
+        (self.0)()
+    }
+}
+
+impl Debug for Generator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Generator")
+    }
+}
+
+impl ReturnPath {
+    /// Builds the return path by calling `rewrite` with the envelope sender
+    /// of the incoming message, which is empty for a null return path.
+    pub fn custom(rewrite: impl Fn(&str) -> String + Send + Sync + 'static) -> Self {
+        ReturnPath::Custom(ReturnPathFn(Arc::new(rewrite)))
+    }
+}
+
+impl ReturnPathFn {
+    pub(crate) fn rewrite(&self, from: &str) -> String {
+        (self.0)(from)
+    }
+}
+
+impl Debug for ReturnPathFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ReturnPathFn")
+    }
+}
+
+// Runs a script to completion, answering each event with `reply`
+#[cfg(test)]
+pub(crate) fn run_script_with(
+    mut run: impl FnMut(Input) -> Option<Result<crate::Event, RuntimeError>>,
+    mut input: Input,
+    mut reply: impl FnMut(&Result<crate::Event, RuntimeError>) -> Input,
+) -> Vec<Result<crate::Event, RuntimeError>> {
+    let mut results = Vec::new();
+    while let Some(result) = run(input) {
+        input = reply(&result);
+        results.push(result);
+    }
+    results
+}
+
+// Runs a script to completion, answering all events with `true`
+#[cfg(test)]
+pub(crate) fn run_script(
+    run: impl FnMut(Input) -> Option<Result<crate::Event, RuntimeError>>,
+    input: Input,
+) -> Vec<crate::Event> {
+    run_script_with(run, input, |_| Input::True)
+        .into_iter()
+        .map(|result| result.unwrap())
+        .collect()
+}
+
+#[cfg(test)]
+mod filter_tests {
+    use mail_parser::Message;
+
+    use crate::{
+        compiler::grammar::Capability,
+        compiler::ErrorType,
+        runtime::{context::MessageCow, run_script, run_script_with, RuntimeError},
+        ArgumentType, Compiler, Envelope, Event, ExtensionSignature, ExtensionValue, ImapCause,
+        Input, PatchEdit, Recipient, RecipientFilter, ReturnPath, Runtime,
+    };
+
+    #[test]
+    fn lazy_parsing() {
+        let compiler = Compiler::new();
+        let header_script = compiler
+            .compile(
+                br#"require "fileinto";
+                if header :contains "subject" "hello" {
+                    fileinto "Inbox/Hello";
+                }
+                "#,
+            )
+            .unwrap();
+        let body_script = compiler
+            .compile(
+                br#"require ["body", "fileinto"];
+                if body :text :contains "attached" {
+                    fileinto "Inbox/Attachments";
+                }
+                "#,
+            )
+            .unwrap();
+        assert!(!header_script.needs_message_body());
+        assert!(body_script.needs_message_body());
+
+        let raw_message = concat!(
+            "From: john@example.org\r\n",
+            "Subject: Hello\r\n",
+            "Content-Type: multipart/mixed; boundary=\"xyz\"\r\n",
+            "\r\n",
+            "--xyz\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "File attached.\r\n",
+            "--xyz\r\n",
+            "Content-Type: application/octet-stream\r\n",
+            "\r\n",
+            "AAAA\r\n",
+            "--xyz--\r\n"
+        )
+        .as_bytes();
+        let runtime = Runtime::new();
+
+        for script in [header_script, body_script] {
+            let needs_body = script.needs_message_body();
+            let mut instance = runtime.filter(raw_message);
+            let expected_events = run_script(
+                |input| instance.run(input),
+                Input::script("test", script.clone()),
+            );
+
+            let mut instance = runtime.filter_lazy(raw_message);
+            let events = run_script(|input| instance.run(input), Input::script("test", script));
+
+            assert_eq!(events, expected_events);
+            assert_eq!(!instance.deferred_message, needs_body);
+            assert_eq!(instance.message_size, raw_message.len());
+        }
+    }
+
+    #[test]
+    fn envelope_pre_evaluation() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["envelope", "ereject", "fileinto"];
+                if envelope :is "from" "spammer@example.com" {
+                    ereject "Go away";
+                    stop;
+                }
+                if envelope :domain :is "to" "lists.example.org" {
+                    discard;
+                    stop;
+                }
+                if header :contains "subject" "urgent" {
+                    fileinto "Urgent";
+                }
+                "#,
+            )
+            .unwrap();
+        let runtime = Runtime::new();
+
+        for (from, to, expected_event) in [
+            (
+                "spammer@example.com",
+                "jane@example.org",
+                Some(Event::Reject {
+                    extended: true,
+                    reason: "Go away".to_string(),
+                }),
+            ),
+            (
+                "john@example.com",
+                "jane@lists.example.org",
+                Some(Event::Discard),
+            ),
+            ("john@example.com", "jane@example.org", None),
+        ] {
+            let mut instance = runtime
+                .filter_envelope()
+                .with_envelope(Envelope::From, from)
+                .with_envelope(Envelope::To, to);
+            let mut events = Vec::new();
+            let mut is_determined = true;
+            for result in run_script_with(
+                |input| instance.run(input),
+                Input::script("test", script.clone()),
+                |_| Input::True,
+            ) {
+                match result {
+                    Ok(event) => events.push(event),
+                    Err(RuntimeError::MessageRequired) => is_determined = false,
+                    Err(err) => panic!("Unexpected error {:?}", err),
+                }
+            }
+
+            assert_eq!(is_determined, expected_event.is_some(), "{}", from);
+            assert_eq!(events, expected_event.into_iter().collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn envelope_unknown_tests() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["envelope", "fileinto"];
+                if allof(header :contains "subject" "urgent",
+                         envelope :is "from" "boss@example.com") {
+                    fileinto "Urgent";
+                } elsif anyof(header :contains "subject" "sale",
+                              envelope :domain :is "from" "shop.example.com") {
+                    fileinto "Promotions";
+                }
+                "#,
+            )
+            .unwrap();
+        let runtime = Runtime::new();
+
+        for (from, expected_event) in [
+            ("boss@example.com", None),
+            (
+                "deals@shop.example.com",
+                Some(Event::FileInto {
+                    folder: "Promotions".to_string(),
+                    flags: vec![],
+                    mailbox_id: None,
+                    special_use: None,
+                    create: false,
+                    message_id: 0,
+                }),
+            ),
+            ("john@example.com", None),
+        ] {
+            let mut instance = runtime
+                .filter_envelope()
+                .with_envelope(Envelope::From, from);
+            let mut events = Vec::new();
+            let mut is_determined = true;
+            for result in run_script_with(
+                |input| instance.run(input),
+                Input::script("test", script.clone()),
+                |_| Input::True,
+            ) {
+                match result {
+                    Ok(event) => events.push(event),
+                    Err(RuntimeError::MessageRequired) => is_determined = false,
+                    Err(err) => panic!("Unexpected error {:?}", err),
+                }
+            }
+
+            assert_eq!(is_determined, expected_event.is_some(), "{}", from);
+            assert_eq!(events, expected_event.into_iter().collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn shared_message() {
+        let compiler = Compiler::new();
+        let scripts = [
+            compiler
+                .compile(
+                    br#"require "editheader";
+                    addheader "X-Filtered" "yes";
+                    "#,
+                )
+                .unwrap(),
+            compiler
+                .compile(
+                    br#"require "fileinto";
+                    fileinto "Archive";
+                    "#,
+                )
+                .unwrap(),
+        ];
+        let raw_message = b"From: john@example.org\r\nSubject: Hello\r\n\r\nTest\r\n";
+        let message = Message::parse(raw_message).unwrap();
+        let runtime = Runtime::new();
+
+        let mut results = Vec::new();
+        for ((rcpt, script), is_shared) in ["jane@example.org", "bill@example.org"]
+            .into_iter()
+            .zip(scripts)
+            .zip([false, true])
+        {
+            let mut instance = runtime
+                .filter_shared(&message)
+                .with_envelope(Envelope::To, rcpt)
+                .with_user_address(rcpt);
+            let events = run_script(|input| instance.run(input), Input::script(rcpt, script));
+            assert_eq!(
+                matches!(instance.message, MessageCow::Borrowed(_)),
+                is_shared
+            );
+            results.push(events);
+        }
+
+        assert!(matches!(
+            &results[0][..],
+            [Event::CreatedMessage { message, .. }, Event::Keep { .. }]
+                if String::from_utf8_lossy(message).contains("X-Filtered: yes")
+        ));
+        assert!(matches!(
+            &results[1][..],
+            [Event::FileInto { message_id: 0, .. }]
+        ));
+        assert_eq!(message.parts[0].headers.len(), 2);
+    }
+
+    #[test]
+    fn filter_recipients() {
+        let compiler = Compiler::new();
+        let editor = compiler
+            .compile(
+                br#"require ["editheader", "envelope", "fileinto", "variables"];
+                if envelope :is "to" "jane@example.org" {
+                    addheader "X-Filtered" "${env.tag}";
+                    fileinto "Inbox";
+                }
+                "#,
+            )
+            .unwrap();
+        let reader = compiler
+            .compile(
+                br#"require ["envelope", "fileinto", "mailbox", "variables"];
+                if allof(envelope :is "to" "bill@example.org",
+                         not exists "X-Filtered",
+                         mailboxexists "${env.folder}") {
+                    fileinto "${env.folder}";
+                }
+                "#,
+            )
+            .unwrap();
+        let raw_message = b"From: john@example.org\r\nSubject: Hello\r\n\r\nTest\r\n";
+        let runtime = Runtime::new();
+
+        let mut replies = Vec::new();
+        let results = runtime.filter_recipients(
+            raw_message,
+            [
+                RecipientFilter::new("jane", editor)
+                    .with_envelope(Envelope::To, "jane@example.org")
+                    .with_user_address("jane@example.org")
+                    .with_env_variable("tag", "jane"),
+                RecipientFilter::new("bill", reader)
+                    .with_envelope(Envelope::To, "bill@example.org")
+                    .with_user_address("bill@example.org")
+                    .with_env_variable("folder", "Archive"),
+            ],
+            |idx, result| {
+                replies.push(idx);
+                if matches!(result, Ok(Event::MailboxExists { .. })) {
+                    Input::True
+                } else {
+                    Input::False
+                }
+            },
+        );
+
+        assert_eq!(results.len(), 2);
+        assert!(matches!(
+            &results[0][..],
+            [Ok(Event::CreatedMessage { message, .. }), Ok(Event::FileInto { folder, message_id: 1, .. })]
+                if String::from_utf8_lossy(message).starts_with("X-Filtered: jane\r\n") && folder == "Inbox"
+        ));
+        assert!(matches!(
+            &results[1][..],
+            [Ok(Event::MailboxExists { .. }), Ok(Event::FileInto { folder, message_id: 0, .. })]
+                if folder == "Archive"
+        ));
+        assert_eq!(replies, [0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn message_patch() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["editheader", "fileinto"];
+                addheader "X-Filtered" "yes";
+                deleteheader "X-Spam";
+                fileinto "Archive";
+                "#,
+            )
+            .unwrap();
+        let raw_message = concat!(
+            "From: john@example.org\r\n",
+            "X-Spam: no\r\n",
+            "Subject: Hello\r\n",
+            "\r\n",
+            "Test\r\n"
+        )
+        .as_bytes();
+
+        let mut messages = Vec::new();
+        for message_patches in [false, true] {
+            let runtime = Runtime::new().with_message_patches(message_patches);
+            let mut instance = runtime.filter(raw_message);
+            for event in run_script(
+                |input| instance.run(input),
+                Input::script("test", script.clone()),
+            ) {
+                match event {
+                    Event::CreatedMessage { message, .. } => {
+                        let mut written = Vec::new();
+                        instance.write_message(&mut written).unwrap();
+                        assert_eq!(written, message);
+                        messages.push(message);
+                    }
+                    Event::CreatedPatch { patch, .. } => {
+                        assert_eq!(
+                            patch.edits(),
+                            &[
+                                PatchEdit::InsertHeaders {
+                                    offset: 0,
+                                    headers: b"X-Filtered: yes\r\n".to_vec()
+                                },
+                                PatchEdit::RemoveHeader {
+                                    offset: 24,
+                                    length: 12
+                                }
+                            ]
+                        );
+                        assert_eq!(patch.len(), raw_message.len() + 5);
+                        messages.push(patch.to_vec(raw_message));
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0], messages[1]);
+    }
+
+    #[test]
+    fn message_patch_parts() {
+        let raw_message = concat!(
+            "From: john@example.org\r\n",
+            "Subject: Parts\r\n",
+            "Content-Type: multipart/alternative; boundary=\"inner\"\r\n\r\n",
+            "--inner\r\n",
+            "Content-Type: text/plain\r\n\r\n",
+            "Plain text\r\n",
+            "--inner\r\n",
+            "Content-Type: text/html\r\n\r\n",
+            "<p>HTML text</p>\r\n",
+            "--inner--\r\n"
+        )
+        .as_bytes();
+        let runtime = Runtime::new();
+
+        for (script, expected_edits) in [
+            (
+                r#"require ["foreverypart", "mime", "replace"];
+                foreverypart {
+                    if header :mime :contenttype "Content-Type" "text/html" {
+                        replace "The HTML part was removed.";
+                    }
+                }"#,
+                &["InsertHeaders", "RemoveHeader", "ReplacePart"][..],
+            ),
+            (
+                r#"require "replace";
+                replace :subject "Away" :from "jane@example.org" "I am away.";"#,
+                &[
+                    "RenameHeader",
+                    "RenameHeader",
+                    "InsertHeaders",
+                    "RemoveHeader",
+                    "ReplacePart",
+                ][..],
+            ),
+            (
+                r#"require "enclose";
+                enclose "Original message attached.";"#,
+                &["ReplacePart"][..],
+            ),
+        ] {
+            let script = Compiler::new().compile(script.as_bytes()).unwrap();
+            let mut instance = runtime.filter(raw_message);
+            run_script(|input| instance.run(input), Input::script("test", script));
+
+            let patch = instance.message_patch();
+            assert_eq!(
+                patch
+                    .edits()
+                    .iter()
+                    .map(|edit| match edit {
+                        PatchEdit::InsertHeaders { .. } => "InsertHeaders",
+                        PatchEdit::RemoveHeader { .. } => "RemoveHeader",
+                        PatchEdit::RenameHeader { .. } => "RenameHeader",
+                        PatchEdit::ReplacePart { .. } => "ReplacePart",
+                    })
+                    .collect::<Vec<_>>(),
+                expected_edits,
+                "{:?}",
+                patch
+            );
+            assert_eq!(patch.to_vec(raw_message), instance.build_message());
+            assert_eq!(patch.len(), instance.message_len());
+        }
+    }
+
+    #[test]
+    fn message_input_without_filter() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["mailbox", "fileinto", "vnd.dovecot.filter"];
+                if mailboxexists "Archive" {
+                    fileinto "Archive";
+                }
+                if filter "decrypt" {
+                    fileinto "Decrypted";
+                }
+                "#,
+            )
+            .unwrap();
+        let runtime = Runtime::new().with_capability(Capability::Filter);
+        let mut instance = runtime.filter(b"Subject: Test\r\n\r\nTest\r\n");
+        let events = run_script_with(
+            |input| instance.run(input),
+            Input::script("test", script),
+            |event| match event {
+                Ok(Event::MailboxExists { .. }) => {
+                    Input::message(true, b"Subject: Replaced\r\n\r\nTest\r\n".to_vec())
+                }
+                Ok(Event::Filter { message, .. }) => {
+                    assert_eq!(message, b"Subject: Test\r\n\r\nTest\r\n");
+                    Input::message(true, b"Subject: Decrypted\r\n\r\nTest\r\n".to_vec())
+                }
+                _ => Input::True,
+            },
+        )
+        .into_iter()
+        .map(|event| event.unwrap())
+        .collect::<Vec<_>>();
+
+        assert!(
+            matches!(
+                &events[..],
+                [
+                    Event::MailboxExists { .. },
+                    Event::FileInto { folder: archive, message_id: 0, .. },
+                    Event::Filter { .. },
+                    Event::CreatedMessage { message_id: 1, message },
+                    Event::FileInto { folder: decrypted, message_id: 1, .. },
+                ] if archive == "Archive" && decrypted == "Decrypted" && message.starts_with(b"Subject: Decrypted")
+            ),
+            "{:?}",
+            events
+        );
+    }
+
+    #[test]
+    fn encoded_header_size() {
+        let script = Compiler::new()
+            .compile(
+                r#"require ["editheader", "fileinto"];
+                addheader "X-Comment" "Ce message a été filtré automatiquement par le serveur, merci de ne pas répondre";
+                addheader "X-Spam-Status" "No";
+                deleteheader "X-Spam-Status";
+                fileinto "Archive";
+                "#
+                .as_bytes(),
+            )
+            .unwrap();
+        let raw_message = b"Subject: Test\r\n\r\nTest\r\n";
+
+        for smtputf8 in [false, true] {
+            let runtime = Runtime::new();
+            let mut instance = runtime.filter(raw_message).with_smtputf8(smtputf8);
+            let message = run_script(
+                |input| instance.run(input),
+                Input::script("test", script.clone()),
+            )
+            .into_iter()
+            .find_map(|event| match event {
+                Event::CreatedMessage { message, .. } => Some(message),
+                _ => None,
+            });
+
+            let message = String::from_utf8(message.unwrap()).unwrap();
+            assert_eq!(message.is_ascii(), !smtputf8, "{}", message);
+            assert_eq!(instance.message_size, message.len(), "{}", message);
+        }
+    }
+
+    #[test]
+    fn outbound_envelopes() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["editheader", "vacation", "enotify"];
+                addheader "X-Forwarded" "yes";
+                deleteheader "X-Spam-Status";
+                redirect "bob@example.net";
+                vacation "I am away";
+                notify "mailto:admin@example.net";
+                "#,
+            )
+            .unwrap();
+        let raw_message = b"From: john@example.org\r\nX-Spam-Status: No,\r\n score=0.1\r\nTo: jane@example.net\r\nSubject: Hello\r\n\r\nTest\r\n";
+        let runtime = Runtime::new()
+            .with_redirect_return_path(ReturnPath::custom(|from| {
+                format!("SRS0={}@forwarder.example.net", from.replace('@', "="))
+            }))
+            .with_vacation_return_path(ReturnPath::Null)
+            .with_notify_return_path(ReturnPath::User);
+        let mut instance = runtime
+            .filter(raw_message)
+            .with_user_address("jane@example.net")
+            .with_envelope("from", "john@example.org")
+            .with_envelope("to", "jane+sieve@example.net")
+            .with_envelope("envid", "1234");
+        let events = run_script_with(
+            |input| instance.run(input),
+            Input::script("test", script),
+            |result| match result {
+                Ok(Event::DuplicateId { .. }) => Input::False,
+                _ => Input::True,
+            },
+        )
+        .into_iter()
+        .map(|result| result.unwrap())
+        .collect::<Vec<_>>();
+        let envelopes = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::SendMessage {
+                    recipient,
+                    envelope,
+                    message_id,
+                    ..
+                } => {
+                    let address = match recipient {
+                        Recipient::Address(address) | Recipient::List(address) => address,
+                        Recipient::Group(addresses) => &addresses[0],
+                    };
+                    let size = events.iter().find_map(|event| match event {
+                        Event::CreatedMessage {
+                            message_id: id,
+                            message,
+                        } if id == message_id => Some(message.len()),
+                        _ => None,
+                    });
+                    Some((address.as_str(), envelope, size))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(envelopes.len(), 3, "{:?}", events);
+        for (address, envelope, size) in envelopes {
+            assert_eq!(Some(envelope.size), size, "{}", address);
+            match address {
+                "bob@example.net" => {
+                    assert_eq!(envelope.from, "SRS0=john=example.org@forwarder.example.net");
+                    assert_eq!(envelope.env_id.as_deref(), Some("1234"));
+                    assert_eq!(
+                        envelope.orcpt.as_deref(),
+                        Some("rfc822;jane+2Bsieve@example.net")
+                    );
+                }
+                "john@example.org" => {
+                    assert_eq!(envelope.from, "");
+                    assert_eq!(envelope.env_id, None);
+                    assert_eq!(envelope.orcpt, None);
+                }
+                "admin@example.net" => {
+                    assert_eq!(envelope.from, "jane@example.net");
+                    assert_eq!(envelope.env_id, None);
+                    assert_eq!(envelope.orcpt, None);
+                }
+                _ => panic!("Unexpected recipient {}", address),
+            }
+        }
+
+        // IMAP events have no envelope, the return path policy applies to the user's address
+        let script = Compiler::new()
+            .compile(br#"redirect "bob@example.net";"#)
+            .unwrap();
+        let mut instance = runtime
+            .filter(raw_message)
+            .with_imap_event(ImapCause::Append, "INBOX")
+            .with_imap_user("jane", "jane@example.net");
+        match instance.run(Input::script("test", script)) {
+            Some(Ok(Event::SendMessage { envelope, .. })) => {
+                assert_eq!(envelope.from, "SRS0=jane=example.net@forwarder.example.net");
+                assert_eq!(envelope.orcpt, None);
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn extensions() {
+        let compiler = Compiler::new()
+            .with_extension_command(
+                "vnd.acme.tag",
+                "tag",
+                ExtensionSignature::new()
+                    .with_tag("priority", Some(ArgumentType::Number))
+                    .with_tag("sticky", None)
+                    .with_argument(ArgumentType::StringList),
+            )
+            .unwrap()
+            .with_extension_test(
+                "vnd.acme.tag",
+                "lookup",
+                ExtensionSignature::new()
+                    .with_argument(ArgumentType::String)
+                    .with_argument(ArgumentType::Variable),
+            )
+            .unwrap();
+
+        // Extensions cannot shadow built-in commands or tests
+        for name in ["keep", "Header"] {
+            assert!(matches!(
+                Compiler::new().with_extension_command(
+                    "vnd.acme.tag",
+                    name,
+                    ExtensionSignature::new()
+                ),
+                Err(ErrorType::ReservedExtensionName(_))
+            ));
+            assert!(matches!(
+                Compiler::new().with_extension_test(
+                    "vnd.acme.tag",
+                    name,
+                    ExtensionSignature::new()
+                ),
+                Err(ErrorType::ReservedExtensionName(_))
+            ));
+        }
+        let script = compiler
+            .compile(
+                br#"require ["vnd.acme.tag", "variables", "fileinto"];
+                if lookup "john" "folder" {
+                    fileinto "${folder}";
+                }
+                tag :priority 2 ["a", "b"];
+                "#,
+            )
+            .unwrap();
+
+        for script in [
+            &br#"tag ["a"];"#[..],
+            br#"require "vnd.acme.tag"; tag :unknown ["a"];"#,
+            br#"require "vnd.acme.tag"; tag :priority "high" ["a"];"#,
+            br#"require "vnd.acme.tag"; if lookup "john" { stop; }"#,
+        ] {
+            assert!(compiler.compile(script).is_err());
+        }
+
+        let runtime = Runtime::new().with_capability("vnd.acme.tag");
+        let mut instance = runtime.filter(b"Subject: test\r\n\r\nTest\r\n");
+        let events = run_script_with(
+            |input| instance.run(input),
+            Input::script("test", script),
+            |event| {
+                if matches!(event, Ok(Event::Extension { name, .. }) if name == "lookup") {
+                    Input::extension(true, ["Work"])
+                } else {
+                    Input::True
+                }
+            },
+        )
+        .into_iter()
+        .map(|event| event.unwrap())
+        .collect::<Vec<_>>();
+
+        assert_eq!(
+            events[0],
+            Event::Extension {
+                name: "lookup".to_string(),
+                tags: vec![],
+                arguments: vec![ExtensionValue::String("john".to_string())],
+            }
+        );
+        assert!(matches!(&events[1], Event::FileInto { folder, .. } if folder == "Work"));
+        assert_eq!(
+            events[2],
+            Event::Extension {
+                name: "tag".to_string(),
+                tags: vec![("priority".to_string(), Some(ExtensionValue::Number(2)))],
+                arguments: vec![ExtensionValue::StringList(vec![
+                    "a".to_string(),
+                    "b".to_string()
+                ])],
+            }
+        );
+    }
+}
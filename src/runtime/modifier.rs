@@ -0,0 +1,152 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{fmt::Debug, sync::Arc};
+
+use ahash::AHashMap;
+
+/// A string modifier registered by the host, used by scripts that require
+/// "vnd.stalwart.modifiers" as a `:name` tag of `set` or `extracttext`.
+pub trait StringModifier: Debug + Send + Sync {
+    fn apply(&self, value: &str) -> String;
+
+    /// Modifiers with a higher precedence are applied first. The RFC 5229
+    /// modifiers range from 10 (`:length`) to 41 (`:lower`).
+    fn precedence(&self) -> u32 {
+        25
+    }
+}
+
+/// String modifiers available to scripts. The same registry should be
+/// passed to both the compiler and the runtime so that scripts are
+/// validated and executed against the same modifiers.
+#[derive(Debug, Clone, Default)]
+pub struct StringModifiers {
+    modifiers: AHashMap<String, Arc<dyn StringModifier>>,
+}
+
+impl StringModifiers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_modifier(
+        &mut self,
+        name: impl Into<String>,
+        modifier: impl StringModifier + 'static,
+    ) {
+        self.modifiers
+            .insert(name.into().to_ascii_lowercase(), Arc::new(modifier));
+    }
+
+    pub fn with_modifier(
+        mut self,
+        name: impl Into<String>,
+        modifier: impl StringModifier + 'static,
+    ) -> Self {
+        self.set_modifier(name, modifier);
+        self
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&Arc<dyn StringModifier>> {
+        self.modifiers.get(name)
+    }
+
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.modifiers.contains_key(name)
+    }
+}
+
+/// Converts the domain of an address, or a bare domain, to its ASCII form.
+pub(crate) fn punycode_domain(value: &str) -> String {
+    let (local_part, domain) = match value.rsplit_once('@') {
+        Some((local_part, domain)) => (Some(local_part), domain),
+        None => (None, value),
+    };
+    if domain.is_ascii() {
+        return value.to_string();
+    }
+
+    match idna::domain_to_ascii(domain) {
+        Ok(domain) => {
+            if let Some(local_part) = local_part {
+                format!("{}@{}", local_part, domain)
+            } else {
+                domain
+            }
+        }
+        Err(_) => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{runtime::run_script, Compiler, Event, Input, Runtime};
+
+    use super::{punycode_domain, StringModifier, StringModifiers};
+
+    #[derive(Debug)]
+    struct Reverse;
+
+    impl StringModifier for Reverse {
+        fn apply(&self, value: &str) -> String {
+            value.chars().rev().collect()
+        }
+    }
+
+    #[test]
+    fn custom_modifier() {
+        let modifiers = StringModifiers::new().with_modifier("reverse", Reverse);
+        let script = Compiler::new()
+            .with_modifiers(modifiers.clone())
+            .compile(
+                br#"require ["variables", "fileinto", "vnd.stalwart.modifiers"];
+                set :reverse :upper "folder" "xobni";
+                fileinto "${folder}";
+                "#,
+            )
+            .unwrap();
+        let runtime = Runtime::new().with_modifiers(modifiers);
+        let mut instance = runtime.filter(b"Subject: Hello\r\n\r\nTest\r\n");
+        assert!(matches!(
+            &run_script(|input| instance.run(input), Input::script("test", script))[..],
+            [Event::FileInto { folder, .. }] if folder == "INBOX"
+        ));
+
+        assert!(Compiler::new()
+            .compile(br#"require ["variables", "vnd.stalwart.modifiers"]; set :reverse "a" "b";"#)
+            .is_err());
+    }
+
+    #[test]
+    fn punycode() {
+        for (domain, expected) in [
+            ("example.org", "example.org"),
+            ("bücher.example", "xn--bcher-kva.example"),
+            ("jane@münchen.de", "jane@xn--mnchen-3ya.de"),
+            ("日本語.jp", "xn--wgv71a119e.jp"),
+        ] {
+            assert_eq!(punycode_domain(domain), expected);
+        }
+    }
+}
@@ -0,0 +1,329 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{fmt::Debug, sync::Arc};
+
+use ahash::AHashMap;
+use mail_parser::decoders::quoted_printable::HEX_MAP;
+
+use crate::Importance;
+
+use super::actions::action_notify::{parse_mailto, parse_uri};
+
+pub trait NotificationMethod: Debug + Send + Sync {
+    fn validate_uri(&self, uri: &str) -> bool;
+
+    fn capability(&self, _uri: &str, capability: &str) -> Option<String> {
+        if capability.eq_ignore_ascii_case("online") {
+            Some("maybe".to_string())
+        } else {
+            None
+        }
+    }
+
+    fn payload(&self, _notification: &Notification<'_>) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification<'x> {
+    pub uri: &'x str,
+    pub from: Option<&'x str>,
+    pub importance: Importance,
+    pub options: &'x [String],
+    pub message: &'x str,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Mailto;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Xmpp;
+
+/// Accepts any URI of its scheme, used for `tel`, `http` and `https`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Generic;
+
+/// Notification methods supported by the compiler and runtime. The same
+/// registry should be passed to both so that scripts are validated and
+/// executed against the same methods.
+#[derive(Debug, Clone)]
+pub struct NotificationMethods {
+    methods: AHashMap<String, Arc<dyn NotificationMethod>>,
+}
+
+impl Default for NotificationMethods {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NotificationMethods {
+    pub fn new() -> Self {
+        NotificationMethods {
+            methods: AHashMap::new(),
+        }
+        .with_method("mailto", Mailto)
+        .with_method("xmpp", Xmpp)
+        .with_method("tel", Generic)
+        .with_method("http", Generic)
+        .with_method("https", Generic)
+    }
+
+    pub fn set_method(
+        &mut self,
+        scheme: impl Into<String>,
+        method: impl NotificationMethod + 'static,
+    ) {
+        self.methods
+            .insert(scheme.into().to_ascii_lowercase(), Arc::new(method));
+    }
+
+    pub fn with_method(
+        mut self,
+        scheme: impl Into<String>,
+        method: impl NotificationMethod + 'static,
+    ) -> Self {
+        self.set_method(scheme, method);
+        self
+    }
+
+    pub fn schemes(&self) -> impl Iterator<Item = &str> {
+        self.methods.keys().map(|scheme| scheme.as_str())
+    }
+
+    pub(crate) fn get(&self, scheme: &str) -> Option<&Arc<dyn NotificationMethod>> {
+        self.methods.get(&scheme.to_ascii_lowercase())
+    }
+
+    // Returns the scheme of a URI supported by a registered method
+    pub(crate) fn validate_uri<'x>(&self, uri: &'x str) -> Option<&'x str> {
+        let (scheme, _) = parse_uri(uri)?;
+        if self.get(scheme)?.validate_uri(uri) {
+            Some(scheme)
+        } else {
+            None
+        }
+    }
+}
+
+impl NotificationMethod for Mailto {
+    fn validate_uri(&self, uri: &str) -> bool {
+        parse_uri(uri).map_or(false, |(_, uri)| parse_mailto(uri).is_some())
+    }
+}
+
+impl NotificationMethod for Generic {
+    fn validate_uri(&self, uri: &str) -> bool {
+        parse_uri(uri).is_some()
+    }
+}
+
+impl NotificationMethod for Xmpp {
+    fn validate_uri(&self, uri: &str) -> bool {
+        parse_uri(uri).map_or(false, |(_, uri)| parse_xmpp(uri).is_some())
+    }
+
+    fn payload(&self, notification: &Notification<'_>) -> Option<Vec<u8>> {
+        let params = parse_uri(notification.uri).and_then(|(_, uri)| parse_xmpp(uri))?;
+        let subject = params.subject.as_deref();
+        let body = params.body.as_deref().unwrap_or(notification.message);
+
+        let mut stanza = String::with_capacity(
+            params.jid.len() + subject.map_or(0, |s| s.len()) + body.len() + 64,
+        );
+        stanza.push_str("<message to='");
+        escape_xml(&mut stanza, &params.jid);
+        stanza.push('\'');
+        if let Some(from) = notification.from {
+            stanza.push_str(" from='");
+            escape_xml(&mut stanza, from);
+            stanza.push('\'');
+        }
+        stanza.push('>');
+        if let Some(subject) = subject {
+            stanza.push_str("<subject>");
+            escape_xml(&mut stanza, subject);
+            stanza.push_str("</subject>");
+        }
+        stanza.push_str("<body>");
+        escape_xml(&mut stanza, body);
+        stanza.push_str("</body></message>");
+
+        Some(stanza.into_bytes())
+    }
+}
+
+pub(crate) struct XmppMessage {
+    jid: String,
+    subject: Option<String>,
+    body: Option<String>,
+}
+
+pub(crate) fn parse_xmpp(uri: &str) -> Option<XmppMessage> {
+    let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+    let path = if let Some(path) = path.strip_prefix("//") {
+        // Skip authority component
+        path.split_once('/')?.1
+    } else {
+        path
+    };
+
+    let jid = decode_uri(path)?;
+    let (bare_jid, resource) = jid.split_once('/').unwrap_or((jid.as_str(), ""));
+    let (node, domain) = bare_jid.rsplit_once('@').unwrap_or(("", bare_jid));
+    let is_invalid = |part: &str| {
+        part.chars()
+            .any(|ch| ch.is_whitespace() || "\"&'/:<>@".contains(ch))
+    };
+    if domain.is_empty()
+        || (bare_jid.contains('@') && node.is_empty())
+        || (jid.contains('/') && resource.is_empty())
+        || is_invalid(domain)
+        || is_invalid(node)
+    {
+        return None;
+    }
+
+    let mut message = XmppMessage {
+        jid,
+        subject: None,
+        body: None,
+    };
+
+    if !query.is_empty() {
+        let mut params = query.split(';');
+        if !params.next()?.eq_ignore_ascii_case("message") {
+            return None;
+        }
+        for param in params {
+            let (name, value) = param.split_once('=')?;
+            if name.eq_ignore_ascii_case("subject") {
+                message.subject = decode_uri(value)?.into();
+            } else if name.eq_ignore_ascii_case("body") {
+                message.body = decode_uri(value)?.into();
+            }
+        }
+    }
+
+    Some(message)
+}
+
+fn decode_uri(value: &str) -> Option<String> {
+    let mut result = Vec::with_capacity(value.len());
+    let mut iter = value.as_bytes().iter();
+
+    while let Some(&ch) = iter.next() {
+        if ch == b'%' {
+            let hex1 = HEX_MAP[*iter.next()? as usize];
+            let hex2 = HEX_MAP[*iter.next()? as usize];
+            if hex1 != -1 && hex2 != -1 {
+                result.push(((hex1 as u8) << 4) | hex2 as u8);
+            } else {
+                return None;
+            }
+        } else {
+            result.push(ch);
+        }
+    }
+
+    String::from_utf8(result).ok()
+}
+
+fn escape_xml(buf: &mut String, value: &str) {
+    for ch in value.chars() {
+        match ch {
+            '&' => buf.push_str("&amp;"),
+            '<' => buf.push_str("&lt;"),
+            '>' => buf.push_str("&gt;"),
+            '\'' => buf.push_str("&apos;"),
+            '"' => buf.push_str("&quot;"),
+            _ => buf.push(ch),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{runtime::RuntimeError, Compiler, Event, Input, Runtime};
+
+    use super::{Notification, NotificationMethod, NotificationMethods};
+
+    #[derive(Debug)]
+    struct Sip;
+
+    impl NotificationMethod for Sip {
+        fn validate_uri(&self, uri: &str) -> bool {
+            uri.contains('@')
+        }
+
+        fn payload(&self, notification: &Notification<'_>) -> Option<Vec<u8>> {
+            Some(notification.message.as_bytes().to_vec())
+        }
+    }
+
+    #[test]
+    fn shared_registry() {
+        let methods = NotificationMethods::new().with_method("sip", Sip);
+        let script = br#"require ["enotify", "variables"];
+            if valid_notify_method "sip:bob@example.org" {
+                notify :message "Hi" "sip:bob@example.org";
+            }
+            set "uri" "sip:bob";
+            notify "${uri}";
+        "#;
+
+        assert!(Compiler::new().compile(script).is_err());
+        let script = Compiler::new()
+            .with_notification_methods(methods.clone())
+            .compile(script)
+            .unwrap();
+
+        let runtime = Runtime::new().with_notification_methods(methods);
+        let mut instance = runtime.filter(b"Subject: Test\r\n\r\nTest\r\n");
+        assert!(matches!(
+            instance.run(Input::script("test", script.clone())),
+            Some(Err(RuntimeError::InvalidNotificationUri(uri))) if uri == "sip:bob"
+        ));
+
+        // Registering a method does not allow its scheme, nor the built-in ones.
+        assert!(!runtime.valid_notification_uris.contains("sip"));
+        assert!(!runtime.valid_notification_uris.contains("xmpp"));
+        let runtime = runtime.with_valid_notification_uri("sip");
+        let mut instance = runtime.filter(b"Subject: Test\r\n\r\nTest\r\n");
+        match instance.run(Input::script("test", script)) {
+            Some(Ok(Event::Notify {
+                method, payload, ..
+            })) => {
+                assert_eq!(method, "sip:bob@example.org");
+                assert_eq!(payload, Some(b"Hi".to_vec()));
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert!(matches!(
+            instance.run(Input::True),
+            Some(Err(RuntimeError::InvalidNotificationUri(uri))) if uri == "sip:bob"
+        ));
+    }
+}
@@ -0,0 +1,328 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{borrow::Cow, sync::Arc};
+
+use mail_parser::Message;
+
+use crate::{Context, ContextSnapshot, Event, Input, OwnedContext, Runtime, Script, Sieve};
+
+use super::{
+    actions::action_execute::owned_message,
+    context::{MessageCow, Shared},
+    serialize::RestoreError,
+    RuntimeError,
+};
+
+impl Runtime {
+    pub fn filter_owned(self: &Arc<Self>, raw_message: impl Into<Arc<[u8]>>) -> OwnedContext {
+        OwnedContext::new(self.clone(), raw_message)
+    }
+}
+
+impl OwnedContext {
+    pub fn new(runtime: Arc<Runtime>, raw_message: impl Into<Arc<[u8]>>) -> Self {
+        let raw_message = raw_message.into();
+        OwnedContext {
+            ctx: runtime
+                .filter(&raw_message)
+                .into_owned(runtime.clone(), raw_message.clone()),
+        }
+    }
+
+    pub fn from_snapshot(
+        runtime: Arc<Runtime>,
+        snapshot: &ContextSnapshot,
+        raw_message: impl Into<Arc<[u8]>>,
+        scripts: impl Fn(&Script) -> Option<Arc<Sieve>>,
+    ) -> Result<Self, RestoreError> {
+        let raw_message = raw_message.into();
+        Ok(OwnedContext {
+            ctx: runtime
+                .restore(snapshot, &raw_message, scripts)?
+                .into_owned(runtime.clone(), raw_message.clone()),
+        })
+    }
+
+    pub fn run(&mut self, input: Input) -> Option<Result<Event, RuntimeError>> {
+        self.ctx.run(input)
+    }
+
+    /// Applies changes to the underlying `Context`, such as setting the
+    /// envelope or environment variables.
+    pub fn update<T>(&mut self, fnc: impl FnOnce(&mut Context<'static>) -> T) -> T {
+        fnc(&mut self.ctx)
+    }
+
+    pub fn with_update(mut self, fnc: impl FnOnce(&mut Context<'static>)) -> Self {
+        self.update(fnc);
+        self
+    }
+
+    pub fn snapshot(&self) -> ContextSnapshot {
+        self.ctx.snapshot()
+    }
+
+    pub fn raw_message(&self) -> &[u8] {
+        &self.ctx.raw_message
+    }
+}
+
+impl<'x> Context<'x> {
+    // Detaches the context from the borrowed runtime and message, which
+    // have to be the same ones `runtime` and `raw_message` point to.
+    pub(crate) fn into_owned(
+        self,
+        runtime: Arc<Runtime>,
+        raw_message: Arc<[u8]>,
+    ) -> Context<'static> {
+        let message = self.message.into_owned();
+        let message = if matches!(&message.raw_message, Cow::Owned(raw) if !raw.is_empty()) {
+            owned_message(message)
+        } else {
+            // The top-level message reads its raw bytes from the context
+            owned_message(Message {
+                raw_message: b""[..].into(),
+                ..message
+            })
+        };
+
+        Context {
+            #[cfg(test)]
+            runtime: runtime.as_ref().clone(),
+            #[cfg(not(test))]
+            runtime: Shared::Owned(runtime),
+            user_address: self.user_address.into_owned().into(),
+            user_full_name: self.user_full_name.into_owned().into(),
+            current_time: self.current_time,
+            smtputf8: self.smtputf8,
+            protocol_reject: self.protocol_reject,
+            message: MessageCow::Owned(message),
+            raw_message: Shared::Owned(raw_message),
+            message_size: self.message_size,
+            deferred_message: self.deferred_message,
+            envelope_only: self.envelope_only,
+            cache: self.cache,
+            envelope: self
+                .envelope
+                .into_iter()
+                .map(|(name, value)| (name, value.into_owned().into()))
+                .collect(),
+            metadata: self
+                .metadata
+                .into_iter()
+                .map(|(name, value)| (name, value.into_owned().into()))
+                .collect(),
+            part: self.part,
+            part_iter: self.part_iter,
+            part_iter_stack: self.part_iter_stack,
+            foreach_stack: self.foreach_stack,
+            spam_status: self.spam_status,
+            virus_status: self.virus_status,
+            imap_cause: self.imap_cause,
+            pos: self.pos,
+            test_result: self.test_result,
+            test_unknown: self.test_unknown,
+            test_pending: self.test_pending,
+            script_cache: self.script_cache,
+            script_stack: self.script_stack,
+            call_stack: self.call_stack,
+            vars_global: self.vars_global,
+            vars_env: self
+                .vars_env
+                .into_iter()
+                .map(|(name, value)| (name, value.into_owned().into()))
+                .collect(),
+            vars_local: self.vars_local,
+            vars_match: self.vars_match,
+            vars_pending: self.vars_pending,
+            queued_events: self.queued_events,
+            final_event: self.final_event,
+            last_message_id: self.last_message_id,
+            main_message_id: self.main_message_id,
+            has_changes: self.has_changes,
+            num_redirects: self.num_redirects,
+            num_log_messages: self.num_log_messages,
+            num_instructions: self.num_instructions,
+            num_out_messages: self.num_out_messages,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{runtime::run_script, Compiler, Envelope, Event, Input, OwnedContext, Runtime};
+
+    #[test]
+    fn owned_context() {
+        let script = Arc::new(
+            Compiler::new()
+                .compile(
+                    br#"require ["envelope", "fileinto", "mailbox", "variables"];
+                    if envelope :matches "from" "*@example.org" {
+                        set "folder" "Lists/${1}";
+                    }
+                    if mailboxexists "${folder}" {
+                        fileinto "${folder}";
+                    }
+                    "#,
+                )
+                .unwrap(),
+        );
+        let raw_message = b"From: john@example.org\r\nSubject: Hello\r\n\r\nTest\r\n";
+        let runtime = Arc::new(Runtime::new());
+
+        let mut instance = runtime
+            .filter(raw_message)
+            .with_envelope(Envelope::From, "john@example.org");
+        let expected_events = run_script(
+            |input| instance.run(input),
+            Input::script("test", script.clone()),
+        );
+        assert!(matches!(
+            expected_events.first(),
+            Some(Event::MailboxExists { .. })
+        ));
+
+        let instance = runtime
+            .filter_owned(&raw_message[..])
+            .with_update(|ctx| ctx.set_envelope(Envelope::From, "john@example.org"));
+        let events = std::thread::spawn(move || {
+            let mut instance = instance;
+            run_script(|input| instance.run(input), Input::script("test", script))
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(events, expected_events);
+    }
+
+    #[test]
+    fn owned_context_message() {
+        let raw_message = concat!(
+            "From: john@example.org\r\n",
+            "Date: Tue, 1 Jul 2003 10:52:37 +0200\r\n",
+            "Subject: Hello\r\n",
+            "\r\n",
+            "Test\r\n"
+        )
+        .as_bytes();
+        let runtime = Arc::new(
+            Runtime::new()
+                .with_message_id_generator(|| "id@example.org".to_string())
+                .with_boundary_generator(|| "boundary".to_string()),
+        );
+
+        for script in [
+            r#"require ["body", "date", "editheader", "fileinto"];
+            if allof(body :contains "Test", date :is "date" "year" "2003") {
+                addheader "X-Filtered" "yes";
+                deleteheader "Subject";
+                fileinto "Archive";
+            }"#,
+            r#"require ["foreverypart", "mime", "replace"];
+            foreverypart {
+                replace "Replaced";
+            }"#,
+            r#"require "enclose";
+            enclose "Original message attached.";"#,
+        ] {
+            let script = Arc::new(Compiler::new().compile(script.as_bytes()).unwrap());
+
+            let mut instance = runtime.filter(raw_message);
+            let expected_events = run_script(
+                |input| instance.run(input),
+                Input::script("test", script.clone()),
+            );
+            let expected_message = instance.build_message();
+
+            for mut instance in [
+                runtime.filter_owned(raw_message),
+                OwnedContext {
+                    ctx: runtime
+                        .filter_lazy(raw_message)
+                        .into_owned(runtime.clone(), raw_message.into()),
+                },
+            ] {
+                let events = run_script(
+                    |input| instance.run(input),
+                    Input::script("test", script.clone()),
+                );
+                assert_eq!(events, expected_events);
+                assert_eq!(instance.ctx.build_message(), expected_message);
+                assert_eq!(instance.raw_message(), raw_message);
+            }
+        }
+    }
+
+    #[test]
+    fn owned_context_snapshot() {
+        let script = Arc::new(
+            Compiler::new()
+                .compile(
+                    br#"require ["editheader", "fileinto", "mailbox"];
+                    addheader "X-Filtered" "yes";
+                    if mailboxexists "Archive" {
+                        fileinto "Archive";
+                    }
+                    "#,
+                )
+                .unwrap(),
+        );
+        let raw_message = b"From: john@example.org\r\nSubject: Hello\r\n\r\nTest\r\n";
+        let runtime = Arc::new(Runtime::new());
+
+        let mut instance = runtime.filter(raw_message);
+        let expected_events = run_script(
+            |input| instance.run(input),
+            Input::script("test", script.clone()),
+        );
+
+        // Suspend on the first event and resume from a snapshot
+        let mut instance = runtime.filter_owned(&raw_message[..]);
+        let mut events = vec![instance
+            .run(Input::script("test", script.clone()))
+            .unwrap()
+            .unwrap()];
+        let snapshot = instance.snapshot();
+        drop(instance);
+
+        let instance = OwnedContext::from_snapshot(runtime, &snapshot, &raw_message[..], |_| {
+            Some(script.clone())
+        })
+        .unwrap();
+        assert_eq!(instance.raw_message(), &raw_message[..]);
+        events.extend(
+            std::thread::spawn(move || {
+                let mut instance = instance;
+                run_script(|input| instance.run(input), Input::True)
+            })
+            .join()
+            .unwrap(),
+        );
+
+        assert_eq!(events, expected_events);
+    }
+}
@@ -21,14 +21,99 @@
  * for more details.
 */
 
-use crate::{Compiler, Sieve};
+use std::{borrow::Cow, sync::Arc};
+
+use ahash::AHashMap;
+use mail_parser::{Encoding, Header, HeaderName, HeaderValue, Message, MessagePart, PartType};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{Compiler, Context, ContextSnapshot, Runtime, Script, Sieve, SieveStore};
+
+use super::{
+    actions::action_mime::find_original_part,
+    context::{MessageCow, ScriptStack, Shared},
+    memory::Compact,
+    parse_message,
+};
 
 const SIEVE_MARKER: u8 = 0xff;
+const SNAPSHOT_MARKER: u8 = 0xfe;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ScriptStackSnapshot {
+    name: Script,
+    prev_pos: usize,
+    prev_vars_local: Vec<String>,
+    prev_vars_match: Vec<String>,
+}
+
+// Changes made to a message, parts and headers left untouched refer to the
+// ones parsed from the raw message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct MessageSnapshot {
+    source: MessageSource,
+    parts: Vec<PartSnapshot>,
+    html_body: Vec<usize>,
+    text_body: Vec<usize>,
+    attachments: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum MessageSource {
+    Original,
+    Filtered(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum PartSnapshot {
+    Original(usize),
+    Modified {
+        original: Option<usize>,
+        headers: Vec<HeaderSnapshot>,
+        body: BodySnapshot,
+        encoding: u8,
+        is_encoding_problem: bool,
+        offset_header: usize,
+        offset_body: usize,
+        offset_end: usize,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum HeaderSnapshot {
+    Original(usize),
+    Renamed(usize, String),
+    Added {
+        name: String,
+        is_other: bool,
+        value: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum BodySnapshot {
+    Original,
+    Text(String),
+    Html(String),
+    Binary(Vec<u8>),
+    InlineBinary(Vec<u8>),
+    Message(Box<MessageSnapshot>),
+    Multipart(Vec<usize>),
+}
 
 pub enum SerializeError {
     Other,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RestoreError {
+    /// The raw message is not the one the snapshot was taken from.
+    MessageMismatch,
+    ScriptNotFound(Script),
+    InvalidSnapshot,
+}
+
 impl Sieve {
     pub fn deserialize(bytes: &[u8]) -> Result<Self, Box<bincode::ErrorKind>> {
         if bytes.len() > 2 && bytes[0] == SIEVE_MARKER && bytes[1] == Compiler::VERSION as u8 {
@@ -47,4 +132,850 @@ impl Sieve {
         bincode::serialize_into(&mut buf, self)?;
         Ok(buf)
     }
+
+    /// Number of bytes used by this script in memory, strings and capability
+    /// lists shared with other scripts in a `SieveStore` are accounted for by
+    /// the store.
+    pub fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Sieve>() + self.instructions.heap_size()
+    }
+}
+
+impl SieveStore {
+    pub fn new() -> Self {
+        SieveStore::default()
+    }
+
+    /// Deserializes a script, sharing its strings, capability lists and
+    /// identical scripts with the ones already in the store.
+    pub fn deserialize(&mut self, bytes: &[u8]) -> Result<Arc<Sieve>, Box<bincode::ErrorKind>> {
+        let mut script = Sieve::deserialize(bytes)?;
+        script.instructions.intern(&mut self.table);
+        let scripts = self.scripts.entry(self.hasher.hash_one(bytes)).or_default();
+        if let Some(script) = scripts.iter().find(|s| ***s == script) {
+            Ok(script.clone())
+        } else {
+            let script = Arc::new(script);
+            scripts.push(script.clone());
+            Ok(script)
+        }
+    }
+
+    /// Stores a copy of `script`, see `SieveStore::deserialize`.
+    pub fn insert(&mut self, script: &Sieve) -> Arc<Sieve> {
+        script
+            .serialize()
+            .and_then(|bytes| self.deserialize(&bytes))
+            .unwrap_or_else(|_| Arc::new(script.clone()))
+    }
+
+    /// Drops scripts and shared values no longer referenced outside of the
+    /// store.
+    pub fn purge(&mut self) {
+        self.scripts.retain(|_, scripts| {
+            scripts.retain(|script| Arc::strong_count(script) > 1);
+            !scripts.is_empty()
+        });
+        self.table.purge();
+    }
+
+    pub fn len(&self) -> usize {
+        self.scripts.values().map(|scripts| scripts.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scripts.is_empty()
+    }
+
+    /// Number of bytes used by the stored scripts and their shared values.
+    pub fn memory_usage(&self) -> usize {
+        self.scripts
+            .values()
+            .flatten()
+            .map(|script| 2 * std::mem::size_of::<usize>() + script.memory_usage())
+            .sum::<usize>()
+            + self.table.memory_usage()
+    }
+}
+
+impl<'x> Context<'x> {
+    /// Captures the execution state. The snapshot refers to the raw message
+    /// and the running scripts instead of copying them, only changes made to
+    /// the message by the scripts are stored.
+    pub fn snapshot(&self) -> ContextSnapshot {
+        let message = if self.has_changes || self.main_message_id > 0 {
+            Some(MessageSnapshot::new(
+                &self.message,
+                &parse_message(&self.raw_message),
+            ))
+        } else {
+            None
+        };
+
+        ContextSnapshot {
+            user_address: self.user_address.to_string(),
+            user_full_name: self.user_full_name.to_string(),
+            current_time: self.current_time,
+            smtputf8: self.smtputf8,
+            protocol_reject: self.protocol_reject,
+            message_digest: Sha256::digest(&*self.raw_message).into(),
+            message,
+            message_size: self.message_size,
+            deferred_message: self.deferred_message,
+            envelope_only: self.envelope_only,
+            envelope: self
+                .envelope
+                .iter()
+                .map(|(e, v)| (e.clone(), v.to_string()))
+                .collect(),
+            metadata: self
+                .metadata
+                .iter()
+                .map(|(m, v)| (m.clone(), v.to_string()))
+                .collect(),
+            part: self.part,
+            part_iter: self.part_iter.as_slice().to_vec(),
+            part_iter_stack: self
+                .part_iter_stack
+                .iter()
+                .map(|(part, iter)| (*part, iter.as_slice().to_vec()))
+                .collect(),
+            foreach_stack: self
+                .foreach_stack
+                .iter()
+                .map(|iter| iter.as_slice().to_vec())
+                .collect(),
+            spam_status: self.spam_status,
+            virus_status: self.virus_status,
+            imap_cause: self.imap_cause,
+            pos: self.pos,
+            test_result: self.test_result,
+            test_unknown: self.test_unknown,
+            test_pending: self.test_pending.clone(),
+            script_cache: self.script_cache.keys().cloned().collect(),
+            script_stack: self
+                .script_stack
+                .iter()
+                .map(|s| ScriptStackSnapshot {
+                    name: s.name.clone(),
+                    prev_pos: s.prev_pos,
+                    prev_vars_local: s.prev_vars_local.clone(),
+                    prev_vars_match: s.prev_vars_match.clone(),
+                })
+                .collect(),
+            call_stack: self.call_stack.clone(),
+            vars_global: self
+                .vars_global
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            vars_env: self
+                .vars_env
+                .iter()
+                .map(|(k, v)| (k.clone(), v.to_string()))
+                .collect(),
+            vars_local: self.vars_local.clone(),
+            vars_match: self.vars_match.clone(),
+            vars_pending: self.vars_pending.clone(),
+            queued_events: self.queued_events.as_slice().to_vec(),
+            final_event: self.final_event.clone(),
+            last_message_id: self.last_message_id,
+            main_message_id: self.main_message_id,
+            has_changes: self.has_changes,
+            num_redirects: self.num_redirects,
+            num_instructions: self.num_instructions,
+            num_out_messages: self.num_out_messages,
+            num_log_messages: self.num_log_messages,
+        }
+    }
+}
+
+impl Runtime {
+    /// Resumes a snapshot against the raw message it was taken from, `scripts`
+    /// returns the compiled scripts that were running at the time.
+    pub fn restore<'z: 'x, 'x>(
+        &'z self,
+        snapshot: &'x ContextSnapshot,
+        raw_message: &'x [u8],
+        scripts: impl Fn(&Script) -> Option<Arc<Sieve>>,
+    ) -> Result<Context<'x>, RestoreError> {
+        if !snapshot.envelope_only
+            && Sha256::digest(raw_message).as_slice() != snapshot.message_digest
+        {
+            return Err(RestoreError::MessageMismatch);
+        }
+
+        let mut ctx = if snapshot.envelope_only {
+            self.filter_envelope()
+        } else if let Some(message) = &snapshot.message {
+            let message = message
+                .restore(raw_message, &parse_message(raw_message))
+                .ok_or(RestoreError::InvalidSnapshot)?;
+            let mut ctx = Context::new(self, MessageCow::Owned(message));
+            ctx.raw_message = Shared::Borrowed(raw_message);
+            ctx
+        } else if snapshot.deferred_message {
+            self.filter_lazy(raw_message)
+        } else {
+            self.filter(raw_message)
+        };
+
+        let mut script_cache = AHashMap::with_capacity(snapshot.script_cache.len());
+        for name in &snapshot.script_cache {
+            script_cache.insert(
+                name.clone(),
+                scripts(name).ok_or_else(|| RestoreError::ScriptNotFound(name.clone()))?,
+            );
+        }
+        ctx.script_stack = snapshot
+            .script_stack
+            .iter()
+            .map(|s| {
+                Some(ScriptStack {
+                    name: s.name.clone(),
+                    script: script_cache.get(&s.name)?.clone(),
+                    prev_pos: s.prev_pos,
+                    prev_vars_local: s.prev_vars_local.clone(),
+                    prev_vars_match: s.prev_vars_match.clone(),
+                })
+            })
+            .collect::<Option<_>>()
+            .ok_or(RestoreError::InvalidSnapshot)?;
+        if ctx
+            .script_stack
+            .last()
+            .map_or(false, |s| snapshot.pos > s.script.instructions.len())
+        {
+            return Err(RestoreError::InvalidSnapshot);
+        }
+        ctx.script_cache = script_cache;
+
+        ctx.user_address = Cow::Borrowed(snapshot.user_address.as_str());
+        ctx.user_full_name = Cow::Borrowed(snapshot.user_full_name.as_str());
+        ctx.current_time = snapshot.current_time;
+        ctx.smtputf8 = snapshot.smtputf8;
+        ctx.protocol_reject = snapshot.protocol_reject;
+        ctx.message_size = snapshot.message_size;
+        ctx.envelope = snapshot
+            .envelope
+            .iter()
+            .map(|(e, v)| (e.clone(), Cow::Borrowed(v.as_str())))
+            .collect();
+        ctx.metadata = snapshot
+            .metadata
+            .iter()
+            .map(|(m, v)| (m.clone(), Cow::Borrowed(v.as_str())))
+            .collect();
+        ctx.part = snapshot.part;
+        ctx.part_iter = snapshot.part_iter.clone().into_iter();
+        ctx.part_iter_stack = snapshot
+            .part_iter_stack
+            .iter()
+            .map(|(part, iter)| (*part, iter.clone().into_iter()))
+            .collect();
+        ctx.foreach_stack = snapshot
+            .foreach_stack
+            .iter()
+            .map(|iter| iter.clone().into_iter())
+            .collect();
+        ctx.spam_status = snapshot.spam_status;
+        ctx.virus_status = snapshot.virus_status;
+        ctx.imap_cause = snapshot.imap_cause;
+        ctx.pos = snapshot.pos;
+        ctx.test_result = snapshot.test_result;
+        ctx.test_unknown = snapshot.test_unknown;
+        ctx.test_pending = snapshot.test_pending.clone();
+        ctx.call_stack = snapshot.call_stack.clone();
+        ctx.vars_global = snapshot.vars_global.iter().cloned().collect();
+        ctx.vars_env = snapshot
+            .vars_env
+            .iter()
+            .map(|(k, v)| (k.clone(), Cow::Borrowed(v.as_str())))
+            .collect();
+        ctx.vars_local = snapshot.vars_local.clone();
+        ctx.vars_match = snapshot.vars_match.clone();
+        ctx.vars_pending = snapshot.vars_pending.clone();
+        ctx.queued_events = snapshot.queued_events.clone().into_iter();
+        ctx.final_event = snapshot.final_event.clone();
+        ctx.last_message_id = snapshot.last_message_id;
+        ctx.main_message_id = snapshot.main_message_id;
+        ctx.has_changes = snapshot.has_changes;
+        ctx.num_redirects = snapshot.num_redirects;
+        ctx.num_instructions = snapshot.num_instructions;
+        ctx.num_out_messages = snapshot.num_out_messages;
+        ctx.num_log_messages = snapshot.num_log_messages;
+        Ok(ctx)
+    }
+}
+
+impl MessageSnapshot {
+    fn new(message: &Message, original: &Message) -> Self {
+        // Messages replaced by the output of a filter own their raw bytes
+        let filtered;
+        let (source, reference) = match &message.raw_message {
+            Cow::Owned(raw_message) if !raw_message.is_empty() => {
+                filtered = parse_message(raw_message);
+                (MessageSource::Filtered(raw_message.clone()), &filtered)
+            }
+            _ => (MessageSource::Original, original),
+        };
+
+        MessageSnapshot {
+            source,
+            parts: message
+                .parts
+                .iter()
+                .map(|part| PartSnapshot::new(part, reference, original))
+                .collect(),
+            html_body: message.html_body.clone(),
+            text_body: message.text_body.clone(),
+            attachments: message.attachments.clone(),
+        }
+    }
+
+    fn restore<'x>(
+        &'x self,
+        original_raw: &'x [u8],
+        original: &Message<'x>,
+    ) -> Option<Message<'x>> {
+        let filtered;
+        let (raw_message, reference) = match &self.source {
+            MessageSource::Original => (Cow::Borrowed(original_raw), original),
+            MessageSource::Filtered(raw_message) => {
+                filtered = parse_message(raw_message);
+                (Cow::Owned(raw_message.clone()), &filtered)
+            }
+        };
+
+        Some(Message {
+            html_body: self.html_body.clone(),
+            text_body: self.text_body.clone(),
+            attachments: self.attachments.clone(),
+            parts: self
+                .parts
+                .iter()
+                .map(|part| part.restore(reference, original_raw, original))
+                .collect::<Option<_>>()?,
+            raw_message,
+        })
+    }
+}
+
+impl PartSnapshot {
+    fn new(part: &MessagePart, reference: &Message, original: &Message) -> Self {
+        let original_id = find_original_part(reference, part);
+        let original_part = match original_id {
+            Some(id) if reference.parts[id] == *part => return PartSnapshot::Original(id),
+            Some(id) => Some(&reference.parts[id]),
+            None => None,
+        };
+
+        PartSnapshot::Modified {
+            original: original_id,
+            headers: part
+                .headers
+                .iter()
+                .map(|header| {
+                    match original_part.and_then(|original_part| {
+                        original_part
+                            .headers
+                            .iter()
+                            .position(|h| {
+                                header.offset_end != 0 && h.offset_end == header.offset_end
+                            })
+                            .map(|id| (id, &original_part.headers[id]))
+                    }) {
+                        Some((id, original_header))
+                            if original_header.name == header.name
+                                && original_header.offset_field == header.offset_field =>
+                        {
+                            HeaderSnapshot::Original(id)
+                        }
+                        Some((id, _)) => {
+                            HeaderSnapshot::Renamed(id, header.name.as_str().to_string())
+                        }
+                        None => HeaderSnapshot::Added {
+                            name: header.name.as_str().to_string(),
+                            is_other: matches!(header.name, HeaderName::Other(_)),
+                            value: header.value.as_text_ref().unwrap_or("").to_string(),
+                        },
+                    }
+                })
+                .collect(),
+            body: match &part.body {
+                body if original_part.map_or(false, |p| p.body == *body) => BodySnapshot::Original,
+                PartType::Text(text) => BodySnapshot::Text(text.to_string()),
+                PartType::Html(html) => BodySnapshot::Html(html.to_string()),
+                PartType::Binary(bytes) => BodySnapshot::Binary(bytes.to_vec()),
+                PartType::InlineBinary(bytes) => BodySnapshot::InlineBinary(bytes.to_vec()),
+                PartType::Message(message) => {
+                    BodySnapshot::Message(Box::new(MessageSnapshot::new(message, original)))
+                }
+                PartType::Multipart(parts) => BodySnapshot::Multipart(parts.clone()),
+            },
+            encoding: part.encoding as u8,
+            is_encoding_problem: part.is_encoding_problem,
+            offset_header: part.offset_header,
+            offset_body: part.offset_body,
+            offset_end: part.offset_end,
+        }
+    }
+
+    fn restore<'x>(
+        &'x self,
+        reference: &Message<'x>,
+        original_raw: &'x [u8],
+        original: &Message<'x>,
+    ) -> Option<MessagePart<'x>> {
+        match self {
+            PartSnapshot::Original(id) => reference.parts.get(*id).cloned(),
+            PartSnapshot::Modified {
+                original: original_id,
+                headers,
+                body,
+                encoding,
+                is_encoding_problem,
+                offset_header,
+                offset_body,
+                offset_end,
+            } => {
+                let original_part = match original_id {
+                    Some(id) => Some(reference.parts.get(*id)?),
+                    None => None,
+                };
+                let original_header = |id: usize| original_part?.headers.get(id).cloned();
+
+                Some(MessagePart {
+                    headers: headers
+                        .iter()
+                        .map(|header| match header {
+                            HeaderSnapshot::Original(id) => original_header(*id),
+                            HeaderSnapshot::Renamed(id, name) => {
+                                original_header(*id).map(|mut header| {
+                                    header.name = HeaderName::Other(name.as_str().into());
+                                    header.offset_field = header.offset_start;
+                                    header
+                                })
+                            }
+                            HeaderSnapshot::Added {
+                                name,
+                                is_other,
+                                value,
+                            } => Some(Header {
+                                name: if *is_other {
+                                    HeaderName::Other(name.as_str().into())
+                                } else {
+                                    HeaderName::parse(name.clone())?
+                                },
+                                value: HeaderValue::Text(value.as_str().into()),
+                                offset_field: 0,
+                                offset_start: 0,
+                                offset_end: 0,
+                            }),
+                        })
+                        .collect::<Option<_>>()?,
+                    is_encoding_problem: *is_encoding_problem,
+                    body: match body {
+                        BodySnapshot::Original => original_part?.body.clone(),
+                        BodySnapshot::Text(text) => PartType::Text(text.as_str().into()),
+                        BodySnapshot::Html(html) => PartType::Html(html.as_str().into()),
+                        BodySnapshot::Binary(bytes) => PartType::Binary(bytes.as_slice().into()),
+                        BodySnapshot::InlineBinary(bytes) => {
+                            PartType::InlineBinary(bytes.as_slice().into())
+                        }
+                        BodySnapshot::Message(message) => {
+                            PartType::Message(message.restore(original_raw, original)?)
+                        }
+                        BodySnapshot::Multipart(parts) => PartType::Multipart(parts.clone()),
+                    },
+                    encoding: match encoding {
+                        1 => Encoding::QuotedPrintable,
+                        2 => Encoding::Base64,
+                        _ => Encoding::None,
+                    },
+                    offset_header: *offset_header,
+                    offset_body: *offset_body,
+                    offset_end: *offset_end,
+                })
+            }
+        }
+    }
+}
+
+impl ContextSnapshot {
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, Box<bincode::ErrorKind>> {
+        if bytes.len() > 2 && bytes[0] == SNAPSHOT_MARKER && bytes[1] == Compiler::VERSION as u8 {
+            bincode::deserialize(&bytes[2..])
+        } else {
+            Err(Box::new(bincode::ErrorKind::Custom(
+                "Incompatible version".to_string(),
+            )))
+        }
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>, Box<bincode::ErrorKind>> {
+        let mut buf = Vec::with_capacity(bincode::serialized_size(self)? as usize + 2);
+        buf.push(SNAPSHOT_MARKER);
+        buf.push(Compiler::VERSION as u8);
+        bincode::serialize_into(&mut buf, self)?;
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{mem::size_of, sync::Arc};
+
+    use mail_parser::MessagePart;
+
+    use crate::{
+        compiler::{
+            grammar::{instruction::Instruction, test::Test},
+            lexer::string::StringItem,
+        },
+        runtime::run_script,
+        Compiler, ContextSnapshot, Event, Input, MessagePatch, PatchEdit, Runtime, Script, Sieve,
+        SieveStore,
+    };
+
+    use super::RestoreError;
+
+    #[test]
+    fn snapshot_restore() {
+        let script = Arc::new(
+            Compiler::new()
+                .compile(
+                    br#"require ["fileinto", "mailbox", "variables"];
+                    set "folder" "Archive";
+                    if mailboxexists "${folder}" {
+                        fileinto "${folder}";
+                    }
+                    "#,
+                )
+                .unwrap(),
+        );
+        let raw_message = b"From: john@example.org\r\nSubject: Hello\r\n\r\nTest\r\n";
+        let runtime = Runtime::new();
+
+        // Run without interruptions
+        let mut instance = runtime.filter(raw_message);
+        let expected_events = run_script(
+            |input| instance.run(input),
+            Input::script("test", script.clone()),
+        );
+
+        // Suspend execution on the first event and resume it later
+        let mut events = Vec::new();
+        let mut instance = runtime.filter(raw_message);
+        let event = instance
+            .run(Input::script("test", script.clone()))
+            .unwrap()
+            .unwrap();
+        assert!(matches!(event, Event::MailboxExists { .. }));
+        events.push(event);
+        let snapshot = instance.snapshot().serialize().unwrap();
+        drop(instance);
+
+        let snapshot = ContextSnapshot::deserialize(&snapshot).unwrap();
+        assert_eq!(
+            runtime
+                .restore(&snapshot, b"Subject: Other\r\n\r\n", |_| Some(
+                    script.clone()
+                ))
+                .err(),
+            Some(RestoreError::MessageMismatch)
+        );
+        assert_eq!(
+            runtime.restore(&snapshot, raw_message, |_| None).err(),
+            Some(RestoreError::ScriptNotFound(Script::from("test")))
+        );
+        let mut instance = runtime
+            .restore(&snapshot, raw_message, |_| Some(script.clone()))
+            .unwrap();
+        events.extend(run_script(|input| instance.run(input), Input::True));
+
+        assert_eq!(events, expected_events);
+    }
+
+    #[test]
+    fn restore_patch() {
+        let script = Arc::new(
+            Compiler::new()
+                .compile(
+                    br#"require ["fileinto", "mailbox", "editheader"];
+                    addheader "X-Spam" "no";
+                    if mailboxexists "Archive" {
+                        deleteheader "Subject";
+                        fileinto "Archive";
+                    }
+                    "#,
+                )
+                .unwrap(),
+        );
+        let raw_message = b"From: john@example.org\r\nSubject: Hello\r\n\r\nTest\r\n";
+        let runtime = Runtime::new().with_message_patches(true);
+
+        // Patch produced without interruptions
+        let mut instance = runtime.filter(raw_message);
+        let expected_patch = find_patch(run_script(
+            |input| instance.run(input),
+            Input::script("test", script.clone()),
+        ));
+
+        // Suspend after the message was modified
+        let mut instance = runtime.filter(raw_message);
+        assert!(matches!(
+            instance.run(Input::script("test", script.clone())),
+            Some(Ok(Event::MailboxExists { .. }))
+        ));
+        let snapshot =
+            ContextSnapshot::deserialize(&instance.snapshot().serialize().unwrap()).unwrap();
+        drop(instance);
+
+        let mut instance = runtime
+            .restore(&snapshot, raw_message, |_| Some(script.clone()))
+            .unwrap();
+        let patch = find_patch(run_script(|input| instance.run(input), Input::True));
+
+        // Offsets still refer to the original message
+        assert_eq!(patch, expected_patch);
+        assert_eq!(
+            patch.edits(),
+            &[
+                PatchEdit::InsertHeaders {
+                    offset: 0,
+                    headers: b"X-Spam: no\r\n".to_vec()
+                },
+                PatchEdit::RemoveHeader {
+                    offset: 24,
+                    length: 16
+                }
+            ]
+        );
+        let message = String::from_utf8(patch.to_vec(raw_message)).unwrap();
+        assert!(message.contains("X-Spam: no\r\n"), "{}", message);
+        assert!(!message.contains("Subject"), "{}", message);
+    }
+
+    #[test]
+    fn restore_parts() {
+        let script = Arc::new(
+            Compiler::new()
+                .compile(
+                    br#"require ["foreverypart", "mime", "editheader", "replace", "fileinto", "mailbox"];
+                    foreverypart {
+                        addheader "X-Part" "checked";
+                        if header :mime :contenttype "Content-Type" "text/html" {
+                            replace "The HTML part was removed.";
+                        }
+                        if mailboxexists "Archive" {
+                            fileinto "Archive";
+                        }
+                    }
+                    "#,
+                )
+                .unwrap(),
+        );
+        let raw_message = concat!(
+            "From: john@example.org\r\n",
+            "Subject: Parts\r\n",
+            "Content-Type: multipart/mixed; boundary=\"outer\"\r\n\r\n",
+            "--outer\r\n",
+            "Content-Type: multipart/alternative; boundary=\"inner\"\r\n\r\n",
+            "--inner\r\n",
+            "Content-Type: text/plain\r\n\r\n",
+            "Plain text\r\n",
+            "--inner\r\n",
+            "Content-Type: text/html\r\n\r\n",
+            "<p>HTML text</p>\r\n",
+            "--inner--\r\n",
+            "--outer\r\n",
+            "Content-Type: message/rfc822\r\n\r\n",
+            "From: jane@example.org\r\n",
+            "Subject: Attached\r\n\r\n",
+            "Attached message\r\n",
+            "--outer--\r\n"
+        )
+        .as_bytes();
+        let runtime = Runtime::new();
+
+        let mut instance = runtime.filter(raw_message);
+        let expected_events = run_script(
+            |input| instance.run(input),
+            Input::script("test", script.clone()),
+        );
+
+        // Suspend on every event once the parts were modified
+        let mut instance = runtime.filter(raw_message);
+        let mut events = vec![instance
+            .run(Input::script("test", script.clone()))
+            .unwrap()
+            .unwrap()];
+        resume(
+            &runtime,
+            raw_message,
+            &script,
+            &instance.snapshot(),
+            &instance.message.parts,
+            &mut events,
+        );
+
+        assert_eq!(events, expected_events);
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Event::CreatedMessage { .. })));
+    }
+
+    // Restores each snapshot and checks that the message has the same parts,
+    // so part ids taken before suspending are still valid.
+    fn resume(
+        runtime: &Runtime,
+        raw_message: &[u8],
+        script: &Arc<Sieve>,
+        snapshot: &ContextSnapshot,
+        parts: &[MessagePart],
+        events: &mut Vec<Event>,
+    ) {
+        let snapshot = ContextSnapshot::deserialize(&snapshot.serialize().unwrap()).unwrap();
+        let mut instance = runtime
+            .restore(&snapshot, raw_message, |_| Some(script.clone()))
+            .unwrap();
+        assert_eq!(instance.message.parts, parts);
+        if let Some(event) = instance.run(Input::True) {
+            events.push(event.unwrap());
+            resume(
+                runtime,
+                raw_message,
+                script,
+                &instance.snapshot(),
+                &instance.message.parts,
+                events,
+            );
+        }
+    }
+
+    fn find_patch(events: Vec<Event>) -> MessagePatch {
+        events
+            .into_iter()
+            .find_map(|event| match event {
+                Event::CreatedPatch { patch, .. } => Some(patch),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn sieve_store() {
+        let compiler = Compiler::new();
+        assert_eq!(
+            compiler.compile(b"keep;").unwrap().memory_usage(),
+            size_of::<Sieve>() + size_of::<Instruction>()
+        );
+
+        let script = compiler
+            .compile(
+                br#"require ["fileinto", "vacation"];
+                if header :contains "List-Id" "announce" {
+                    fileinto "Lists";
+                } elsif address :domain :is "from" ["example.org", "example.net"] {
+                    vacation :days 7 "I am away.";
+                }
+                "#,
+            )
+            .unwrap();
+        let mut store = SieveStore::new();
+        let first = store.insert(&script);
+        let second = store.insert(
+            &compiler
+                .compile(br#"require "fileinto"; fileinto "Lists"; fileinto "Archive";"#)
+                .unwrap(),
+        );
+        let third = store.deserialize(&script.serialize().unwrap()).unwrap();
+        assert_eq!(*first, script);
+        assert!(Arc::ptr_eq(&first, &third));
+        assert_eq!(store.len(), 2);
+
+        // Header names, texts and capability lists are shared
+        let header_name = |script: &Sieve| match &script.instructions[1] {
+            Instruction::Test(test) => match test.as_ref() {
+                Test::Header(test) => match &test.header_list[0] {
+                    StringItem::Text(text) => text.clone(),
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        let capabilities = |script: &Sieve| match &script.instructions[0] {
+            Instruction::Require(capabilities) => capabilities.clone(),
+            _ => unreachable!(),
+        };
+        let fourth = store.insert(
+            &compiler
+                .compile(
+                    br#"require ["fileinto", "vacation"];
+                    if header :is "List-Id" "news" {
+                        fileinto "News";
+                    }
+                    "#,
+                )
+                .unwrap(),
+        );
+        assert!(Arc::ptr_eq(&header_name(&first), &header_name(&fourth)));
+        assert!(Arc::ptr_eq(&capabilities(&first), &capabilities(&fourth)));
+        assert!(!Arc::ptr_eq(&capabilities(&first), &capabilities(&second)));
+        drop(fourth);
+
+        // Values held by the store are not counted again by each script
+        assert!(first.memory_usage() < script.memory_usage());
+        let num_values = store.table.len();
+        drop(second);
+        store.purge();
+        assert_eq!(store.len(), 1);
+        assert!(store.table.len() < num_values);
+        assert_eq!(
+            store.memory_usage(),
+            2 * size_of::<usize>() + first.memory_usage() + store.table.memory_usage()
+        );
+    }
+
+    #[test]
+    fn key_set_rebuild() {
+        let script = Compiler::new()
+            .compile(
+                br#"require "fileinto";
+                if header :is "Subject" ["Hello", "Hi"] {
+                    fileinto "Greetings";
+                }
+                "#,
+            )
+            .unwrap();
+        let is_folded = |script: &Sieve| {
+            script
+                .instructions
+                .iter()
+                .find_map(|instruction| match instruction {
+                    Instruction::Test(test) => match test.as_ref() {
+                        Test::Header(test) => Some(test.key_set.0.get().map(Option::is_some)),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .unwrap()
+        };
+
+        // Constant keys are folded on compilation and only the key list is serialized
+        assert_eq!(is_folded(&script), Some(true));
+        let script = Sieve::deserialize(&script.serialize().unwrap()).unwrap();
+        assert_eq!(is_folded(&script), None);
+
+        let script = Arc::new(script);
+        let runtime = Runtime::new();
+        let mut instance = runtime.filter(b"Subject: Hi\r\n\r\nTest\r\n");
+        assert!(matches!(
+            instance.run(Input::script("test", script.clone())),
+            Some(Ok(Event::FileInto { folder, .. })) if folder == "Greetings"
+        ));
+        assert_eq!(is_folded(&script), Some(true));
+    }
 }
@@ -28,7 +28,7 @@ use crate::{compiler::lexer::string::StringItem, Context};
 impl<'x> Context<'x> {
     pub(crate) fn eval_string<'z: 'y, 'y>(&'z self, string: &'y StringItem) -> Cow<'y, str> {
         match string {
-            StringItem::Text(text) => text.into(),
+            StringItem::Text(text) => text.as_ref().into(),
             StringItem::LocalVariable(var_num) => {
                 if let Some(data) = self.vars_local.get(*var_num) {
                     data.into()
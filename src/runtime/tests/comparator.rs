@@ -21,39 +21,116 @@
  * for more details.
 */
 
+use std::{borrow::Cow, cmp::Ordering, sync::Arc};
+
+use aho_corasick::AhoCorasick;
 use regex::Regex;
 
 use crate::{
-    compiler::grammar::{Comparator, RelationalMatch},
-    MatchAs,
+    compiler::grammar::{Comparator, KeySet, RelationalMatch},
+    runtime::comparator::{self, unicode_casemap},
+    Context, MatchAs,
 };
 
-use super::glob::{glob_match, glob_match_capture};
+use super::glob::{glob_match, glob_match_capture, CaseFold};
+
+pub(crate) struct ComparatorRef<'x> {
+    comparator: &'x Comparator,
+    custom: Option<Arc<dyn comparator::Comparator>>,
+}
+
+impl<'x> Context<'x> {
+    pub(crate) fn comparator<'y>(&self, comparator: &'y Comparator) -> ComparatorRef<'y> {
+        ComparatorRef {
+            custom: if let Comparator::Other(name) = comparator {
+                self.runtime.comparators.get(name).cloned()
+            } else {
+                None
+            },
+            comparator,
+        }
+    }
+}
+
+impl<'x> ComparatorRef<'x> {
+    pub(crate) fn is(&self, a: &str, b: &str) -> bool {
+        match &self.custom {
+            Some(custom) => custom.is(a, b),
+            None => self.comparator.is(a, b),
+        }
+    }
+
+    pub(crate) fn contains(&self, haystack: &str, needle: &str) -> bool {
+        match &self.custom {
+            Some(custom) => custom.contains(haystack, needle),
+            None => self.comparator.contains(haystack, needle),
+        }
+    }
+
+    pub(crate) fn relational(&self, relation: &RelationalMatch, a: &str, b: &str) -> bool {
+        match &self.custom {
+            Some(custom) => relation.cmp_ordering(custom.relational(a, b)),
+            None => self.comparator.relational(relation, a, b),
+        }
+    }
+
+    pub(crate) fn matches(
+        &self,
+        value: &str,
+        pattern: &str,
+        capture_positions: u64,
+        captured_values: &mut Vec<(usize, String)>,
+    ) -> bool {
+        match &self.custom {
+            Some(custom) => custom.matches(value, pattern, capture_positions, captured_values),
+            None => self
+                .comparator
+                .matches(value, pattern, capture_positions, captured_values),
+        }
+    }
+
+    pub(crate) fn regex(
+        &self,
+        value: &str,
+        pattern: &str,
+        capture_positions: u64,
+        captured_values: &mut Vec<(usize, String)>,
+    ) -> bool {
+        self.comparator
+            .regex(value, pattern, capture_positions, captured_values)
+    }
+
+    pub(crate) fn as_match(&self) -> MatchAs {
+        self.comparator.as_match()
+    }
+}
 
 impl Comparator {
     pub(crate) fn is(&self, a: &str, b: &str) -> bool {
         match self {
-            Comparator::Octet => a == b,
-            Comparator::AsciiNumeric => a.parse::<f64>() == b.parse::<f64>(),
-            _ => a.to_lowercase() == b.to_lowercase(),
+            Comparator::Octet | Comparator::Other(_) => a == b,
+            Comparator::AsciiNumeric => cmp_numeric(a, b) == Ordering::Equal,
+            Comparator::UnicodeCaseMap => unicode_casemap(a) == unicode_casemap(b),
+            Comparator::AsciiCaseMap | Comparator::Elbonia => a.to_lowercase() == b.to_lowercase(),
         }
     }
 
     pub(crate) fn contains(&self, haystack: &str, needle: &str) -> bool {
         needle.is_empty()
             || match self {
-                Comparator::Octet => haystack.contains(needle),
+                Comparator::Octet | Comparator::Other(_) => haystack.contains(needle),
+                Comparator::UnicodeCaseMap => {
+                    unicode_casemap(haystack).contains(&unicode_casemap(needle))
+                }
                 _ => haystack.to_lowercase().contains(&needle.to_lowercase()),
             }
     }
 
     pub(crate) fn relational(&self, relation: &RelationalMatch, a: &str, b: &str) -> bool {
         match self {
-            Comparator::Octet => relation.cmp(a, b.as_ref()),
-            Comparator::AsciiNumeric => relation.cmp(
-                &a.parse::<f64>().unwrap_or(f64::MAX),
-                &b.parse::<f64>().unwrap_or(f64::MAX),
-            ),
+            Comparator::Octet | Comparator::Other(_) => relation.cmp(a, b),
+            Comparator::AsciiNumeric => relation.cmp_ordering(cmp_numeric(a, b)),
+            Comparator::UnicodeCaseMap => relation.cmp(&unicode_casemap(a), &unicode_casemap(b)),
             _ => relation.cmp(&a.to_lowercase(), &b.to_lowercase()),
         }
     }
@@ -65,15 +142,15 @@ impl Comparator {
         capture_positions: u64,
         captured_values: &mut Vec<(usize, String)>,
     ) -> bool {
-        match self {
-            Comparator::AsciiCaseMap if capture_positions == 0 => {
-                glob_match(&value.to_lowercase(), pattern, true)
-            }
-            Comparator::AsciiCaseMap => {
-                glob_match_capture(value, pattern, true, capture_positions, captured_values)
-            }
-            _ if capture_positions == 0 => glob_match(value, pattern, false),
-            _ => glob_match_capture(value, pattern, false, capture_positions, captured_values),
+        let fold = match self {
+            Comparator::AsciiCaseMap => CaseFold::Lowercase,
+            Comparator::UnicodeCaseMap => CaseFold::UnicodeCaseMap,
+            _ => CaseFold::None,
+        };
+        if capture_positions == 0 {
+            glob_match(value, pattern, fold)
+        } else {
+            glob_match_capture(value, pattern, fold, capture_positions, captured_values)
         }
     }
 
@@ -81,28 +158,11 @@ impl Comparator {
         &self,
         value: &str,
         pattern: &str,
-        mut capture_positions: u64,
+        capture_positions: u64,
         captured_values: &mut Vec<(usize, String)>,
     ) -> bool {
         match Regex::new(pattern) {
-            Ok(re) => {
-                //TODO cache compilation
-                if capture_positions == 0 {
-                    re.is_match(value)
-                } else if let Some(captures) = re.captures(value) {
-                    captured_values.clear();
-                    while capture_positions != 0 {
-                        let index = 63 - capture_positions.leading_zeros();
-                        capture_positions ^= 1 << index;
-                        if let Some(match_var) = captures.get(index as usize) {
-                            captured_values.push((index as usize, match_var.as_str().to_string()));
-                        }
-                    }
-                    true
-                } else {
-                    false
-                }
-            }
+            Ok(re) => regex_match(&re, value, capture_positions, captured_values),
             Err(err) => {
                 debug_assert!(false, "Failed to compile regex: {:?}", err);
                 false
@@ -110,16 +170,47 @@ impl Comparator {
         }
     }
 
+    pub(crate) fn fold<'y>(&self, value: &'y str) -> Option<Cow<'y, str>> {
+        match self {
+            Comparator::Octet => Some(value.into()),
+            Comparator::AsciiCaseMap => Some(value.to_lowercase().into()),
+            Comparator::UnicodeCaseMap => Some(unicode_casemap(value).into()),
+            _ => None,
+        }
+    }
+
     pub(crate) fn as_match(&self) -> MatchAs {
         match self {
-            Comparator::AsciiCaseMap => MatchAs::Lowercase,
+            Comparator::AsciiCaseMap | Comparator::UnicodeCaseMap => MatchAs::Lowercase,
             Comparator::AsciiNumeric => MatchAs::Number,
             _ => MatchAs::Octet,
         }
     }
 }
 
+impl KeySet {
+    pub(crate) fn matches(&self, comparator: &Comparator, value: &str) -> bool {
+        if let Some(value) = comparator.fold(value) {
+            if self.is_contains {
+                self.automaton
+                    .get_or_init(|| Box::new(AhoCorasick::new(&self.keys)))
+                    .is_match(value.as_bytes())
+            } else {
+                self.keys
+                    .binary_search_by(|key| key.as_str().cmp(value.as_ref()))
+                    .is_ok()
+            }
+        } else {
+            false
+        }
+    }
+}
+
 impl RelationalMatch {
+    pub(crate) fn cmp_ordering(&self, ordering: Ordering) -> bool {
+        self.cmp(&ordering, &Ordering::Equal)
+    }
+
     pub(crate) fn cmp_num(&self, num: f64, value: &str) -> bool {
         if let Ok(value) = value.parse::<f64>() {
             self.cmp(&num, &value)
@@ -142,3 +233,46 @@ impl RelationalMatch {
         }
     }
 }
+
+// RFC 4790: values are compared by their leading digits, strings that do not
+// start with a digit are considered equal to each other and greater than any number.
+fn cmp_numeric(a: &str, b: &str) -> Ordering {
+    match (leading_digits(a), leading_digits(b)) {
+        (Some(a), Some(b)) => a.len().cmp(&b.len()).then_with(|| a.cmp(b)),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+fn leading_digits(value: &str) -> Option<&str> {
+    let len = value.bytes().take_while(|ch| ch.is_ascii_digit()).count();
+    if len > 0 {
+        Some(value[..len].trim_start_matches('0'))
+    } else {
+        None
+    }
+}
+
+pub(crate) fn regex_match(
+    re: &Regex,
+    value: &str,
+    mut capture_positions: u64,
+    captured_values: &mut Vec<(usize, String)>,
+) -> bool {
+    if capture_positions == 0 {
+        re.is_match(value)
+    } else if let Some(captures) = re.captures(value) {
+        captured_values.clear();
+        while capture_positions != 0 {
+            let index = 63 - capture_positions.leading_zeros();
+            capture_positions ^= 1 << index;
+            if let Some(match_var) = captures.get(index as usize) {
+                captured_values.push((index as usize, match_var.as_str().to_string()));
+            }
+        }
+        true
+    } else {
+        false
+    }
+}
@@ -23,7 +23,28 @@
 
 use std::char::REPLACEMENT_CHARACTER;
 
-use crate::MAX_MATCH_VARIABLES;
+use crate::{runtime::comparator::unicode_casemap_char, MAX_MATCH_VARIABLES};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CaseFold {
+    None,
+    Lowercase,
+    UnicodeCaseMap,
+}
+
+impl CaseFold {
+    fn fold(&self, ch: char, mut f: impl FnMut(char)) {
+        match self {
+            CaseFold::Lowercase if ch.is_uppercase() => {
+                for ch in ch.to_lowercase() {
+                    f(ch);
+                }
+            }
+            CaseFold::UnicodeCaseMap => unicode_casemap_char(ch, f),
+            _ => f(ch),
+        }
+    }
+}
 
 #[derive(Debug)]
 enum PatternChar {
@@ -32,7 +53,7 @@ enum PatternChar {
     Char { char: char, match_pos: usize },
 }
 
-fn compile(str: &str, to_lower: bool) -> Vec<PatternChar> {
+fn compile(str: &str, fold: CaseFold) -> Vec<PatternChar> {
     let mut chars = Vec::new();
     let mut is_escaped = false;
     let mut str = str.chars().peekable();
@@ -58,13 +79,9 @@ fn compile(str: &str, to_lower: bool) -> Vec<PatternChar> {
                 if is_escaped {
                     is_escaped = false;
                 }
-                if to_lower && char.is_uppercase() {
-                    for char in char.to_lowercase() {
-                        chars.push(PatternChar::Char { char, match_pos: 0 });
-                    }
-                } else {
-                    chars.push(PatternChar::Char { char, match_pos: 0 });
-                }
+                fold.fold(char, |char| {
+                    chars.push(PatternChar::Char { char, match_pos: 0 })
+                });
             }
         }
     }
@@ -73,12 +90,18 @@ fn compile(str: &str, to_lower: bool) -> Vec<PatternChar> {
 
 // Credits: Algorithm ported from https://research.swtch.com/glob
 
-pub(crate) fn glob_match(value: &str, pattern: &str, to_lower: bool) -> bool {
-    let pattern = compile(pattern, to_lower);
-    let value = if to_lower {
-        value.to_lowercase().chars().collect::<Vec<_>>()
-    } else {
-        value.chars().collect::<Vec<_>>()
+pub(crate) fn glob_match(value: &str, pattern: &str, fold: CaseFold) -> bool {
+    let pattern = compile(pattern, fold);
+    let value = match fold {
+        CaseFold::None => value.chars().collect::<Vec<_>>(),
+        CaseFold::Lowercase => value.to_lowercase().chars().collect::<Vec<_>>(),
+        CaseFold::UnicodeCaseMap => {
+            let mut folded = Vec::with_capacity(value.len());
+            for char in value.chars() {
+                fold.fold(char, |char| folded.push(char));
+            }
+            folded
+        }
     };
 
     let mut px = 0;
@@ -123,28 +146,26 @@ pub(crate) fn glob_match(value: &str, pattern: &str, to_lower: bool) -> bool {
 pub(crate) fn glob_match_capture(
     value_: &str,
     pattern: &str,
-    to_lower: bool,
+    fold: CaseFold,
     capture_positions: u64,
     captured_values: &mut Vec<(usize, String)>,
 ) -> bool {
-    let mut pattern = compile(pattern, to_lower);
-    let value = if to_lower {
+    let mut pattern = compile(pattern, fold);
+    let value = if fold != CaseFold::None {
         let mut value = Vec::with_capacity(value_.len());
         for char in value_.chars() {
-            if char.is_uppercase() {
-                for (pos, lowerchar) in char.to_lowercase().into_iter().enumerate() {
-                    value.push((
-                        lowerchar,
-                        if pos == 0 {
-                            char
-                        } else {
-                            REPLACEMENT_CHARACTER
-                        },
-                    ));
-                }
-            } else {
-                value.push((char, char));
-            }
+            let mut is_first = true;
+            fold.fold(char, |folded_char| {
+                value.push((
+                    folded_char,
+                    if is_first {
+                        char
+                    } else {
+                        REPLACEMENT_CHARACTER
+                    },
+                ));
+                is_first = false;
+            });
         }
         value
     } else {
@@ -265,6 +286,8 @@ pub(crate) fn glob_match_capture(
 
 #[cfg(test)]
 mod tests {
+    use super::CaseFold;
+
     #[test]
     fn glob_match() {
         for (value, pattern, expected_result) in [
@@ -293,27 +316,35 @@ mod tests {
                 vec!["Straße"],
             ),
         ] {
-            let mut match_values = Vec::new();
-            assert!(
-                super::glob_match_capture(value, pattern, true, u64::MAX ^ 1, &mut match_values),
-                "{:?} {:?}",
-                value,
-                pattern
-            );
+            for fold in [CaseFold::Lowercase, CaseFold::UnicodeCaseMap] {
+                let mut match_values = Vec::new();
+                assert!(
+                    super::glob_match_capture(
+                        value,
+                        pattern,
+                        fold,
+                        u64::MAX ^ 1,
+                        &mut match_values
+                    ),
+                    "{:?} {:?}",
+                    value,
+                    pattern
+                );
 
-            assert_eq!(
-                match_values.into_iter().map(|(_, v)| v).collect::<Vec<_>>(),
-                expected_result,
-                "{:?} {:?}",
-                value,
-                pattern
-            );
-            assert!(
-                super::glob_match(value, pattern, true),
-                "{:?} {:?}",
-                value,
-                pattern
-            );
+                assert_eq!(
+                    match_values.into_iter().map(|(_, v)| v).collect::<Vec<_>>(),
+                    expected_result,
+                    "{:?} {:?}",
+                    value,
+                    pattern
+                );
+                assert!(
+                    super::glob_match(value, pattern, fold),
+                    "{:?} {:?}",
+                    value,
+                    pattern
+                );
+            }
         }
     }
 }
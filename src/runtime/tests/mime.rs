@@ -78,10 +78,12 @@ impl<'x> Context<'x> {
         &'z self,
         mut message: &'x Message<'x>,
         ct_filter: &[ContentTypeFilter],
-        visitor_fnc: &mut impl FnMut(&MessagePart, &[u8]) -> bool,
+        visitor_fnc: &mut impl FnMut(&[usize], &MessagePart, &[u8]) -> bool,
     ) -> bool {
         let mut iter_stack = Vec::new();
         let mut iter = vec![self.part].into_iter();
+        // Part ids of the enclosing message/rfc822 parts followed by the current part id
+        let mut part_path = Vec::new();
 
         loop {
             while let Some(part_id) = iter.next() {
@@ -123,8 +125,13 @@ impl<'x> Context<'x> {
                     } else {
                         true
                     };
-                    if process_part && visitor_fnc(subpart, message.raw_message.as_ref()) {
-                        return true;
+                    if process_part {
+                        part_path.push(part_id);
+                        let result = visitor_fnc(&part_path, subpart, self.raw_bytes(message));
+                        part_path.pop();
+                        if result {
+                            return true;
+                        }
                     }
                     match &subpart.body {
                         PartType::Multipart(subparts) => {
@@ -134,6 +141,7 @@ impl<'x> Context<'x> {
                             ));
                         }
                         PartType::Message(next_message) => {
+                            part_path.push(part_id);
                             iter_stack.push((
                                 std::mem::replace(&mut iter, vec![0].into_iter()),
                                 Some(message),
@@ -148,6 +156,7 @@ impl<'x> Context<'x> {
                 iter = prev_iter;
                 if let Some(prev_message) = prev_message {
                     message = prev_message;
+                    part_path.pop();
                 }
             } else {
                 break;
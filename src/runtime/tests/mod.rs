@@ -116,10 +116,16 @@ impl Test {
             },
             Test::Convert(test) => test.exec(ctx),
             Test::Execute(test) => TestResult::Event {
-                event: Event::Execute {
-                    command: ctx.eval_string(&test.command).into_owned(),
-                    arguments: ctx.eval_strings_owned(&test.arguments),
-                },
+                event: test.event(ctx),
+                is_not: test.is_not,
+            },
+            Test::Filter(test) => TestResult::Event {
+                event: test.event(ctx),
+                is_not: test.is_not,
+            },
+            Test::Eval(test) => test.exec(ctx),
+            Test::Extension(test) => TestResult::Event {
+                event: test.event(ctx),
                 is_not: test.is_not,
             },
             Test::True => TestResult::Bool(true),
@@ -21,6 +21,8 @@
  * for more details.
 */
 
+use std::borrow::Cow;
+
 use mail_parser::{
     parsers::{
         fields::address::{
@@ -31,34 +33,67 @@ use mail_parser::{
     },
     Header, HeaderName, HeaderValue,
 };
+use regex::Regex;
 
 use crate::{
     compiler::grammar::{tests::test_address::TestAddress, AddressPart, MatchType},
     Context, Event,
 };
 
-use super::TestResult;
+use super::{comparator::regex_match, TestResult};
 
 impl TestAddress {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
-        let key_list = ctx.eval_strings(&self.key_list);
+        let mut captured_values = Vec::new();
+        let key_list = if self
+            .key_set
+            .get(&self.match_type, &self.comparator, &self.key_list)
+            .is_none()
+        {
+            ctx.eval_strings(&self.key_list)
+        } else {
+            Vec::new()
+        };
         let header_list = ctx.parse_header_names(&self.header_list);
 
+        let result = self.exec_with(ctx, &header_list, &key_list, &[], &mut captured_values);
+        if !captured_values.is_empty() {
+            ctx.set_match_variables(captured_values);
+        }
+        result
+    }
+
+    pub(crate) fn exec_with(
+        &self,
+        ctx: &Context,
+        header_list: &[HeaderName],
+        key_list: &[Cow<str>],
+        regex_list: &[Regex],
+        captured_values: &mut Vec<(usize, String)>,
+    ) -> TestResult {
+        let comparator = ctx.comparator(&self.comparator);
+        let key_set = self
+            .key_set
+            .get(&self.match_type, &self.comparator, &self.key_list);
+
         let result = match &self.match_type {
             MatchType::Is | MatchType::Contains => {
                 let is_is = matches!(&self.match_type, MatchType::Is);
                 ctx.find_headers(
-                    &header_list,
+                    header_list,
                     self.index,
                     self.mime_anychild,
                     |header, _, _| {
                         ctx.find_addresses(header, &self.address_part, |value| {
-                            for key in &key_list {
+                            if let Some(key_set) = key_set {
+                                return key_set.matches(&self.comparator, value);
+                            }
+                            for key in key_list {
                                 if is_is {
-                                    if self.comparator.is(value, key.as_ref()) {
+                                    if comparator.is(value, key.as_ref()) {
                                         return true;
                                     }
-                                } else if self.comparator.contains(value, key.as_ref()) {
+                                } else if comparator.contains(value, key.as_ref()) {
                                     return true;
                                 }
                             }
@@ -68,13 +103,13 @@ impl TestAddress {
                 )
             }
             MatchType::Value(rel_match) => ctx.find_headers(
-                &header_list,
+                header_list,
                 self.index,
                 self.mime_anychild,
                 |header, _, _| {
                     ctx.find_addresses(header, &self.address_part, |value| {
-                        for key in &key_list {
-                            if self.comparator.relational(rel_match, value, key.as_ref()) {
+                        for key in key_list {
+                            if comparator.relational(rel_match, value, key.as_ref()) {
                                 return true;
                             }
                         }
@@ -83,29 +118,33 @@ impl TestAddress {
                 },
             ),
             MatchType::Matches(capture_positions) | MatchType::Regex(capture_positions) => {
-                let mut captured_positions = Vec::new();
                 let is_matches = matches!(&self.match_type, MatchType::Matches(_));
-                let result = ctx.find_headers(
-                    &header_list,
+                ctx.find_headers(
+                    header_list,
                     self.index,
                     self.mime_anychild,
                     |header, _, _| {
                         ctx.find_addresses(header, &self.address_part, |value| {
-                            for key in &key_list {
+                            if regex_list.iter().any(|re| {
+                                regex_match(re, value, *capture_positions, captured_values)
+                            }) {
+                                return true;
+                            }
+                            for key in key_list {
                                 if is_matches {
-                                    if self.comparator.matches(
+                                    if comparator.matches(
                                         value,
                                         key.as_ref(),
                                         *capture_positions,
-                                        &mut captured_positions,
+                                        captured_values,
                                     ) {
                                         return true;
                                     }
-                                } else if self.comparator.regex(
+                                } else if comparator.regex(
                                     value,
                                     key.as_ref(),
                                     *capture_positions,
-                                    &mut captured_positions,
+                                    captured_values,
                                 ) {
                                     return true;
                                 }
@@ -113,16 +152,12 @@ impl TestAddress {
                             false
                         })
                     },
-                );
-                if !captured_positions.is_empty() {
-                    ctx.set_match_variables(captured_positions);
-                }
-                result
+                )
             }
             MatchType::Count(rel_match) => {
                 let mut count = 0;
                 ctx.find_headers(
-                    &header_list,
+                    header_list,
                     self.index,
                     self.mime_anychild,
                     |header, _, _| {
@@ -136,7 +171,7 @@ impl TestAddress {
                 );
 
                 let mut result = false;
-                for key in &key_list {
+                for key in key_list {
                     if rel_match.cmp_num(count as f64, key.as_ref()) {
                         result = true;
                         break;
@@ -148,7 +183,7 @@ impl TestAddress {
                 let mut values: Vec<String> = Vec::new();
 
                 ctx.find_headers(
-                    &header_list,
+                    header_list,
                     self.index,
                     self.mime_anychild,
                     |header, _, _| {
@@ -166,7 +201,7 @@ impl TestAddress {
                         event: Event::ListContains {
                             lists: ctx.eval_strings_owned(&self.key_list),
                             values,
-                            match_as: self.comparator.as_match(),
+                            match_as: comparator.as_match(),
                         },
                         is_not: self.is_not,
                     };
@@ -193,13 +228,25 @@ impl<'x> Context<'x> {
         let value = if let HeaderName::Rfc(_) = &header.name {
             value_ = None;
             &header.value
+        } else if header.offset_end > 0 {
+            let addresses = self.header_addresses(header, || {
+                MessageStream::new(
+                    self.raw_bytes(&self.message)
+                        .get(header.offset_start..header.offset_end)
+                        .unwrap_or(b""),
+                )
+                .parse_address()
+            });
+            for addr in addresses.iter() {
+                if let Some(addr) = part.eval(addr) {
+                    if visitor_fnc(addr) {
+                        return true;
+                    }
+                }
+            }
+            return false;
         } else {
-            let bytes = if header.offset_end > 0 {
-                self.message
-                    .raw_message
-                    .get(header.offset_start..header.offset_end)
-                    .unwrap_or(b"")
-            } else if let HeaderValue::Text(text) = &header.value {
+            let bytes = if let HeaderValue::Text(text) = &header.value {
                 // Inserted header
                 raw_header = format!("{}\n", text).into_bytes().into();
                 raw_header.as_deref().unwrap()
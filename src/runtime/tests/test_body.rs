@@ -28,6 +28,7 @@ use crate::{
         tests::test_body::{BodyTransform, TestBody},
         MatchType,
     },
+    runtime::cache::BodyText,
     Context,
 };
 
@@ -35,7 +36,15 @@ use super::{mime::ContentTypeFilter, TestResult};
 
 impl TestBody {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
-        let key_list = ctx.eval_strings(&self.key_list);
+        let comparator = ctx.comparator(&self.comparator);
+        let key_set = self
+            .key_set
+            .get(&self.match_type, &self.comparator, &self.key_list);
+        let key_list = if key_set.is_none() {
+            ctx.eval_strings(&self.key_list)
+        } else {
+            Vec::new()
+        };
         let ct_filter = match &self.body_transform {
             BodyTransform::Text | BodyTransform::Raw => Vec::new(),
             BodyTransform::Content(values) => {
@@ -58,10 +67,14 @@ impl TestBody {
             let mut count = 0;
             let mut result = false;
 
-            ctx.find_nested_parts(&ctx.message, &ct_filter, &mut |_part, _raw_message| {
-                count += 1;
-                false
-            });
+            ctx.find_nested_parts(
+                &ctx.message,
+                &ct_filter,
+                &mut |_part_path, _part, _raw_message| {
+                    count += 1;
+                    false
+                },
+            );
 
             for key in &self.key_list {
                 if rel_match.cmp_num(count as f64, ctx.eval_string(key).as_ref()) {
@@ -72,117 +85,132 @@ impl TestBody {
 
             result
         } else {
-            ctx.find_nested_parts(&ctx.message, &ct_filter, &mut |part, raw_message| {
-                let text = match (&self.body_transform, &part.body) {
-                    (BodyTransform::Content(_), PartType::Message(message)) => {
-                        if let Some(part) = message.parts.get(0) {
-                            String::from_utf8_lossy(
-                                raw_message
-                                    .get(part.raw_header_offset()..part.raw_body_offset())
-                                    .unwrap_or(b""),
-                            )
-                        } else {
-                            return false;
-                        }
-                    }
-                    (BodyTransform::Content(_), PartType::Multipart(_)) => {
-                        if let Some(boundary) = part
-                            .get_content_type()
-                            .and_then(|ct| ct.get_attribute("boundary"))
-                        {
-                            let mime_body = std::str::from_utf8(
-                                raw_message
-                                    .get(part.raw_body_offset()..part.raw_end_offset())
-                                    .unwrap_or(b""),
-                            )
-                            .unwrap_or("");
-                            let mut mime_part = String::with_capacity(64);
-                            if let Some((prologue, epilogue)) =
-                                mime_body.split_once(&format!("\n--{}", boundary))
-                            {
-                                mime_part.push_str(prologue);
-                                if let Some((_, epilogue)) =
-                                    epilogue.rsplit_once(&format!("\n--{}--", boundary))
-                                {
-                                    mime_part.push_str(epilogue);
-                                }
+            ctx.find_nested_parts(
+                &ctx.message,
+                &ct_filter,
+                &mut |part_path, part, raw_message| {
+                    let cached_text;
+                    let text = match (&self.body_transform, &part.body) {
+                        (BodyTransform::Content(_), PartType::Message(message)) => {
+                            if let Some(part) = message.parts.get(0) {
+                                String::from_utf8_lossy(
+                                    raw_message
+                                        .get(part.raw_header_offset()..part.raw_body_offset())
+                                        .unwrap_or(b""),
+                                )
+                            } else {
+                                return false;
                             }
-                            mime_part.into()
-                        } else {
-                            String::from_utf8_lossy(
-                                raw_message
-                                    .get(part.raw_body_offset()..part.raw_end_offset())
-                                    .unwrap_or(b""),
-                            )
                         }
-                    }
-                    (BodyTransform::Raw, _) => {
-                        match &part.body {
-                            PartType::Text(text) if part.raw_body_offset() == 0 => {
-                                // Inserted part
-                                text.as_ref().into()
-                            }
-                            _ if part.raw_end_offset() > part.raw_body_offset() => {
+                        (BodyTransform::Content(_), PartType::Multipart(_)) => {
+                            if let Some(boundary) = part
+                                .get_content_type()
+                                .and_then(|ct| ct.get_attribute("boundary"))
+                            {
+                                cached_text = ctx.body_text(part_path, BodyText::Content, || {
+                                    let mime_body = std::str::from_utf8(
+                                        raw_message
+                                            .get(part.raw_body_offset()..part.raw_end_offset())
+                                            .unwrap_or(b""),
+                                    )
+                                    .unwrap_or("");
+                                    let mut mime_part = String::with_capacity(64);
+                                    if let Some((prologue, epilogue)) =
+                                        mime_body.split_once(&format!("\n--{}", boundary))
+                                    {
+                                        mime_part.push_str(prologue);
+                                        if let Some((_, epilogue)) =
+                                            epilogue.rsplit_once(&format!("\n--{}--", boundary))
+                                        {
+                                            mime_part.push_str(epilogue);
+                                        }
+                                    }
+                                    mime_part
+                                });
+                                cached_text.as_ref().into()
+                            } else {
                                 String::from_utf8_lossy(
                                     raw_message
                                         .get(part.raw_body_offset()..part.raw_end_offset())
                                         .unwrap_or(b""),
                                 )
                             }
-                            _ => return false,
                         }
-                    }
-                    (_, PartType::Text(text))
-                    | (BodyTransform::Content(_), PartType::Html(text)) => text.as_ref().into(),
-                    (_, PartType::Html(html)) => html_to_text(html.as_ref()).into(),
-                    (
-                        BodyTransform::Text,
-                        PartType::Binary(bytes) | PartType::InlineBinary(bytes),
-                    ) if part.get_content_type().map_or(false, |ct| {
-                        ct.c_type.eq_ignore_ascii_case("application")
-                            && ct.c_subtype.as_ref().map_or(false, |st| st.contains("xml"))
-                    }) =>
-                    {
-                        html_to_text(std::str::from_utf8(bytes.as_ref()).unwrap_or("")).into()
-                    }
-                    (
-                        BodyTransform::Content(_),
-                        PartType::Binary(bytes) | PartType::InlineBinary(bytes),
-                    ) => String::from_utf8_lossy(bytes.as_ref()),
-                    _ => {
-                        return false;
-                    }
-                };
-                let mut result = false;
-
-                for key in &key_list {
-                    result = match &self.match_type {
-                        MatchType::Is => self.comparator.is(text.as_ref(), key.as_ref()),
-                        MatchType::Contains => {
-                            self.comparator.contains(text.as_ref(), key.as_ref())
+                        (BodyTransform::Raw, _) => {
+                            match &part.body {
+                                PartType::Text(text) if part.raw_body_offset() == 0 => {
+                                    // Inserted part
+                                    text.as_ref().into()
+                                }
+                                _ if part.raw_end_offset() > part.raw_body_offset() => {
+                                    String::from_utf8_lossy(
+                                        raw_message
+                                            .get(part.raw_body_offset()..part.raw_end_offset())
+                                            .unwrap_or(b""),
+                                    )
+                                }
+                                _ => return false,
+                            }
                         }
-                        MatchType::Value(rel_match) => {
-                            self.comparator
-                                .relational(rel_match, text.as_ref(), key.as_ref())
+                        (_, PartType::Text(text))
+                        | (BodyTransform::Content(_), PartType::Html(text)) => text.as_ref().into(),
+                        (_, PartType::Html(html)) => {
+                            cached_text = ctx.body_text(part_path, BodyText::Text, || {
+                                html_to_text(html.as_ref())
+                            });
+                            cached_text.as_ref().into()
                         }
-                        MatchType::Matches(_) => {
-                            self.comparator
-                                .matches(text.as_ref(), key.as_ref(), 0, &mut Vec::new())
+                        (
+                            BodyTransform::Text,
+                            PartType::Binary(bytes) | PartType::InlineBinary(bytes),
+                        ) if part.get_content_type().map_or(false, |ct| {
+                            ct.c_type.eq_ignore_ascii_case("application")
+                                && ct.c_subtype.as_ref().map_or(false, |st| st.contains("xml"))
+                        }) =>
+                        {
+                            cached_text = ctx.body_text(part_path, BodyText::Text, || {
+                                html_to_text(std::str::from_utf8(bytes.as_ref()).unwrap_or(""))
+                            });
+                            cached_text.as_ref().into()
                         }
-                        MatchType::Regex(_) => {
-                            self.comparator
-                                .regex(text.as_ref(), key.as_ref(), 0, &mut Vec::new())
+                        (
+                            BodyTransform::Content(_),
+                            PartType::Binary(bytes) | PartType::InlineBinary(bytes),
+                        ) => String::from_utf8_lossy(bytes.as_ref()),
+                        _ => {
+                            return false;
                         }
-                        _ => false,
                     };
+                    if let Some(key_set) = key_set {
+                        return key_set.matches(&self.comparator, text.as_ref());
+                    }
 
-                    if result {
-                        break;
+                    let mut result = false;
+
+                    for key in &key_list {
+                        result = match &self.match_type {
+                            MatchType::Is => comparator.is(text.as_ref(), key.as_ref()),
+                            MatchType::Contains => comparator.contains(text.as_ref(), key.as_ref()),
+                            MatchType::Value(rel_match) => {
+                                comparator.relational(rel_match, text.as_ref(), key.as_ref())
+                            }
+                            MatchType::Matches(_) => {
+                                comparator.matches(text.as_ref(), key.as_ref(), 0, &mut Vec::new())
+                            }
+                            MatchType::Regex(_) => {
+                                comparator.regex(text.as_ref(), key.as_ref(), 0, &mut Vec::new())
+                            }
+                            _ => false,
+                        };
+
+                        if result {
+                            break;
+                        }
                     }
-                }
 
-                result
-            })
+                    result
+                },
+            )
         };
 
         TestResult::Bool(result ^ self.is_not)
@@ -37,6 +37,7 @@ use super::TestResult;
 
 impl TestDate {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
+        let comparator = ctx.comparator(&self.comparator);
         let header_name = if let Some(header_name) = ctx.parse_header_name(&self.header_name) {
             header_name
         } else {
@@ -89,7 +90,7 @@ impl TestDate {
                         event: Event::ListContains {
                             lists: ctx.eval_strings_owned(&self.key_list),
                             values,
-                            match_as: self.comparator.as_match(),
+                            match_as: comparator.as_match(),
                         },
                         is_not: self.is_not,
                     };
@@ -110,24 +111,20 @@ impl TestDate {
                                 self.date_part.eval(self.zone.eval(dt.as_ref()).as_ref());
                             for key in &key_list {
                                 if match &self.match_type {
-                                    MatchType::Is => self.comparator.is(&date_part, key.as_ref()),
+                                    MatchType::Is => comparator.is(&date_part, key.as_ref()),
                                     MatchType::Contains => {
-                                        self.comparator.contains(&date_part, key.as_ref())
+                                        comparator.contains(&date_part, key.as_ref())
                                     }
-                                    MatchType::Value(rel_match) => self.comparator.relational(
-                                        rel_match,
+                                    MatchType::Value(rel_match) => {
+                                        comparator.relational(rel_match, &date_part, key.as_ref())
+                                    }
+                                    MatchType::Matches(capture_positions) => comparator.matches(
                                         &date_part,
                                         key.as_ref(),
+                                        *capture_positions,
+                                        &mut captured_values,
                                     ),
-                                    MatchType::Matches(capture_positions) => {
-                                        self.comparator.matches(
-                                            &date_part,
-                                            key.as_ref(),
-                                            *capture_positions,
-                                            &mut captured_values,
-                                        )
-                                    }
-                                    MatchType::Regex(capture_positions) => self.comparator.matches(
+                                    MatchType::Regex(capture_positions) => comparator.matches(
                                         &date_part,
                                         key.as_ref(),
                                         *capture_positions,
@@ -156,6 +153,7 @@ impl TestDate {
 
 impl TestCurrentDate {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
+        let comparator = ctx.comparator(&self.comparator);
         let mut result = false;
 
         match &self.match_type {
@@ -180,7 +178,7 @@ impl TestCurrentDate {
                         event: Event::ListContains {
                             lists: ctx.eval_strings_owned(&self.key_list),
                             values: vec![value],
-                            match_as: self.comparator.as_match(),
+                            match_as: comparator.as_match(),
                         },
                         is_not: self.is_not,
                     };
@@ -200,19 +198,18 @@ impl TestCurrentDate {
                     let key = ctx.eval_string(key);
 
                     if match &self.match_type {
-                        MatchType::Is => self.comparator.is(&date_part, key.as_ref()),
-                        MatchType::Contains => self.comparator.contains(&date_part, key.as_ref()),
+                        MatchType::Is => comparator.is(&date_part, key.as_ref()),
+                        MatchType::Contains => comparator.contains(&date_part, key.as_ref()),
                         MatchType::Value(rel_match) => {
-                            self.comparator
-                                .relational(rel_match, &date_part, key.as_ref())
+                            comparator.relational(rel_match, &date_part, key.as_ref())
                         }
-                        MatchType::Matches(capture_positions) => self.comparator.matches(
+                        MatchType::Matches(capture_positions) => comparator.matches(
                             &date_part,
                             key.as_ref(),
                             *capture_positions,
                             &mut captured_values,
                         ),
-                        MatchType::Regex(capture_positions) => self.comparator.matches(
+                        MatchType::Regex(capture_positions) => comparator.matches(
                             &date_part,
                             key.as_ref(),
                             *capture_positions,
@@ -244,8 +241,7 @@ impl<'x> Context<'x> {
             }
         } else if header.offset_end > 0 {
             let bytes = self
-                .message
-                .raw_message
+                .raw_bytes(&self.message)
                 .get(header.offset_start..header.offset_end)?;
             if let HeaderValue::DateTime(dt) = MessageStream::new(bytes).parse_date() {
                 if dt.is_valid() {
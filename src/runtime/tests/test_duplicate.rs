@@ -39,8 +39,7 @@ impl TestDuplicate {
                     ctx.find_headers(&[header_name], None, true, |header, _, _| {
                         if header.offset_end > 0 {
                             if let Some(bytes) = ctx
-                                .message
-                                .raw_message
+                                .raw_bytes(&ctx.message)
                                 .get(header.offset_start..header.offset_end)
                             {
                                 if let HeaderValue::Text(id) = MessageStream::new(bytes).parse_id()
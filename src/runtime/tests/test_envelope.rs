@@ -32,19 +32,30 @@ use super::TestResult;
 
 impl TestEnvelope {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
-        let key_list = ctx.eval_strings(&self.key_list);
+        let comparator = ctx.comparator(&self.comparator);
+        let key_set = self
+            .key_set
+            .get(&self.match_type, &self.comparator, &self.key_list);
+        let key_list = if key_set.is_none() {
+            ctx.eval_strings(&self.key_list)
+        } else {
+            Vec::new()
+        };
 
         let result = match &self.match_type {
             MatchType::Is | MatchType::Contains => {
                 let is_is = matches!(&self.match_type, MatchType::Is);
 
                 ctx.find_envelopes(self, |value| {
+                    if let Some(key_set) = key_set {
+                        return key_set.matches(&self.comparator, value);
+                    }
                     for key in &key_list {
                         if is_is {
-                            if self.comparator.is(value, key.as_ref()) {
+                            if comparator.is(value, key.as_ref()) {
                                 return true;
                             }
-                        } else if self.comparator.contains(value, key.as_ref()) {
+                        } else if comparator.contains(value, key.as_ref()) {
                             return true;
                         }
                     }
@@ -54,7 +65,7 @@ impl TestEnvelope {
             }
             MatchType::Value(rel_match) => ctx.find_envelopes(self, |value| {
                 for key in &key_list {
-                    if self.comparator.relational(rel_match, value, key.as_ref()) {
+                    if comparator.relational(rel_match, value, key.as_ref()) {
                         return true;
                     }
                 }
@@ -68,7 +79,7 @@ impl TestEnvelope {
                 let result = ctx.find_envelopes(self, |value| {
                     for key in &key_list {
                         if is_matches {
-                            if self.comparator.matches(
+                            if comparator.matches(
                                 value,
                                 key.as_ref(),
                                 *capture_positions,
@@ -76,7 +87,7 @@ impl TestEnvelope {
                             ) {
                                 return true;
                             }
-                        } else if self.comparator.regex(
+                        } else if comparator.regex(
                             value,
                             key.as_ref(),
                             *capture_positions,
@@ -132,7 +143,7 @@ impl TestEnvelope {
                         event: Event::ListContains {
                             lists: ctx.eval_strings_owned(&self.key_list),
                             values,
-                            match_as: self.comparator.as_match(),
+                            match_as: comparator.as_match(),
                         },
                         is_not: self.is_not,
                     };
@@ -21,6 +21,8 @@
  * for more details.
 */
 
+use mail_parser::HeaderName;
+
 use crate::{compiler::grammar::tests::test_exists::TestExists, Context};
 
 use super::{mime::SubpartIterator, TestResult};
@@ -28,6 +30,10 @@ use super::{mime::SubpartIterator, TestResult};
 impl TestExists {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
         let header_names = ctx.parse_header_names(&self.header_names);
+        self.exec_with(ctx, &header_names)
+    }
+
+    pub(crate) fn exec_with(&self, ctx: &Context, header_names: &[HeaderName]) -> TestResult {
         let mut header_exists = vec![false; header_names.len()];
         let parts = [ctx.part];
         let mut part_iter = SubpartIterator::new(ctx, &parts, self.mime_anychild);
@@ -24,6 +24,7 @@
 use std::borrow::Cow;
 
 use mail_parser::{parsers::MessageStream, Header, HeaderName, HeaderValue, RfcHeader};
+use regex::Regex;
 
 use crate::{
     compiler::{
@@ -33,11 +34,20 @@ use crate::{
     Context, Event,
 };
 
-use super::{mime::SubpartIterator, TestResult};
+use super::{comparator::regex_match, mime::SubpartIterator, TestResult};
 
 impl TestHeader {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
-        let key_list = ctx.eval_strings(&self.key_list);
+        let mut captured_values = Vec::new();
+        let key_list = if self
+            .key_set
+            .get(&self.match_type, &self.comparator, &self.key_list)
+            .is_none()
+        {
+            ctx.eval_strings(&self.key_list)
+        } else {
+            Vec::new()
+        };
         let header_list = ctx.parse_header_names(&self.header_list);
         let mime_opts = match &self.mime_opts {
             MimeOpts::Type => MimeOpts::Type,
@@ -47,21 +57,53 @@ impl TestHeader {
             MimeOpts::None => MimeOpts::None,
         };
 
+        let result = self.exec_with(
+            ctx,
+            &header_list,
+            &key_list,
+            &[],
+            &mime_opts,
+            &mut captured_values,
+        );
+        if !captured_values.is_empty() {
+            ctx.set_match_variables(captured_values);
+        }
+        result
+    }
+
+    // Runs the test with its arguments already evaluated
+    pub(crate) fn exec_with(
+        &self,
+        ctx: &Context,
+        header_list: &[HeaderName],
+        key_list: &[Cow<str>],
+        regex_list: &[Regex],
+        mime_opts: &MimeOpts<Cow<str>>,
+        captured_values: &mut Vec<(usize, String)>,
+    ) -> TestResult {
+        let comparator = ctx.comparator(&self.comparator);
+        let key_set = self
+            .key_set
+            .get(&self.match_type, &self.comparator, &self.key_list);
+
         let result = match &self.match_type {
             MatchType::Is | MatchType::Contains => {
                 let is_is = matches!(&self.match_type, MatchType::Is);
                 ctx.find_headers(
-                    &header_list,
+                    header_list,
                     self.index,
                     self.mime_anychild,
                     |header, _, _| {
-                        ctx.find_header_values(header, &mime_opts, |value| {
-                            for key in &key_list {
+                        ctx.find_header_values(header, mime_opts, |value| {
+                            if let Some(key_set) = key_set {
+                                return key_set.matches(&self.comparator, value);
+                            }
+                            for key in key_list {
                                 if is_is {
-                                    if self.comparator.is(value, key.as_ref()) {
+                                    if comparator.is(value, key.as_ref()) {
                                         return true;
                                     }
-                                } else if self.comparator.contains(value, key.as_ref()) {
+                                } else if comparator.contains(value, key.as_ref()) {
                                     return true;
                                 }
                             }
@@ -71,13 +113,13 @@ impl TestHeader {
                 )
             }
             MatchType::Value(rel_match) => ctx.find_headers(
-                &header_list,
+                header_list,
                 self.index,
                 self.mime_anychild,
                 |header, _, _| {
-                    ctx.find_header_values(header, &mime_opts, |value| {
-                        for key in &key_list {
-                            if self.comparator.relational(rel_match, value, key.as_ref()) {
+                    ctx.find_header_values(header, mime_opts, |value| {
+                        for key in key_list {
+                            if comparator.relational(rel_match, value, key.as_ref()) {
                                 return true;
                             }
                         }
@@ -86,29 +128,33 @@ impl TestHeader {
                 },
             ),
             MatchType::Matches(capture_positions) | MatchType::Regex(capture_positions) => {
-                let mut captured_values = Vec::new();
                 let is_matches = matches!(&self.match_type, MatchType::Matches(_));
-                let result = ctx.find_headers(
-                    &header_list,
+                ctx.find_headers(
+                    header_list,
                     self.index,
                     self.mime_anychild,
                     |header, _, _| {
-                        ctx.find_header_values(header, &mime_opts, |value| {
-                            for key in &key_list {
+                        ctx.find_header_values(header, mime_opts, |value| {
+                            if regex_list.iter().any(|re| {
+                                regex_match(re, value, *capture_positions, captured_values)
+                            }) {
+                                return true;
+                            }
+                            for key in key_list {
                                 if is_matches {
-                                    if self.comparator.matches(
+                                    if comparator.matches(
                                         value,
                                         key.as_ref(),
                                         *capture_positions,
-                                        &mut captured_values,
+                                        captured_values,
                                     ) {
                                         return true;
                                     }
-                                } else if self.comparator.regex(
+                                } else if comparator.regex(
                                     value,
                                     key.as_ref(),
                                     *capture_positions,
-                                    &mut captured_values,
+                                    captured_values,
                                 ) {
                                     return true;
                                 }
@@ -116,20 +162,16 @@ impl TestHeader {
                             false
                         })
                     },
-                );
-                if !captured_values.is_empty() {
-                    ctx.set_match_variables(captured_values);
-                }
-                result
+                )
             }
             MatchType::Count(rel_match) => {
                 let mut count = 0;
                 ctx.find_headers(
-                    &header_list,
+                    header_list,
                     self.index,
                     self.mime_anychild,
                     |header, _, _| {
-                        match &mime_opts {
+                        match mime_opts {
                             MimeOpts::None => {
                                 count += 1;
                             }
@@ -159,7 +201,7 @@ impl TestHeader {
                 );
 
                 let mut result = false;
-                for key in &key_list {
+                for key in key_list {
                     if rel_match.cmp_num(count as f64, key.as_ref()) {
                         result = true;
                         break;
@@ -170,11 +212,11 @@ impl TestHeader {
             MatchType::List => {
                 let mut values: Vec<String> = Vec::new();
                 ctx.find_headers(
-                    &header_list,
+                    header_list,
                     self.index,
                     self.mime_anychild,
                     |header, _, _| {
-                        ctx.find_header_values(header, &mime_opts, |value| {
+                        ctx.find_header_values(header, mime_opts, |value| {
                             if !value.is_empty() && !values.iter().any(|v| v.eq(value)) {
                                 values.push(value.to_string());
                             }
@@ -188,7 +230,7 @@ impl TestHeader {
                         event: Event::ListContains {
                             lists: ctx.eval_strings_owned(&self.key_list),
                             values,
-                            match_as: self.comparator.as_match(),
+                            match_as: comparator.as_match(),
                         },
                         is_not: self.is_not,
                     };
@@ -233,52 +275,45 @@ impl<'x> Context<'x> {
     ) -> bool {
         let parts = [self.part];
         let mut part_iter = SubpartIterator::new(self, &parts, any_child);
+        let keys = header_names
+            .iter()
+            .map(|header_name| header_name.as_str().to_ascii_lowercase())
+            .collect::<Vec<_>>();
 
         while let Some((part_id, message_part)) = part_iter.next() {
-            'outer: for header_name in header_names {
-                match index {
-                    None => {
-                        for (pos, header) in message_part
+            let header_index = self.header_index(part_id, message_part);
+
+            for (header_name, key) in header_names.iter().zip(keys.iter()) {
+                let mut positions = if let Some(positions) = header_index.get(key) {
+                    positions.iter().copied().filter(|pos| {
+                        message_part
                             .headers
-                            .iter()
-                            .enumerate()
-                            .filter(|(_, h)| &h.name == header_name)
-                        {
-                            if visitor_fnc(header, part_id, pos) {
+                            .get(*pos)
+                            .map_or(false, |header| &header.name == header_name)
+                    })
+                } else {
+                    continue;
+                };
+
+                let pos = match index {
+                    None => {
+                        for pos in positions {
+                            if visitor_fnc(&message_part.headers[pos], part_id, pos) {
                                 return true;
                             }
                         }
+                        continue;
                     }
-                    Some(index) if index >= 0 => {
-                        let mut header_count = 0;
-
-                        for (pos, header) in message_part.headers.iter().enumerate() {
-                            if &header.name == header_name {
-                                header_count += 1;
-                                if header_count == index {
-                                    if visitor_fnc(header, part_id, pos) {
-                                        return true;
-                                    }
-                                    continue 'outer;
-                                }
-                            }
-                        }
+                    Some(index) if index > 0 => positions.nth(index as usize - 1),
+                    Some(index) if index < 0 => {
+                        positions.rev().nth(index.unsigned_abs() as usize - 1)
                     }
-                    Some(index) => {
-                        let index = -index;
-                        let mut header_count = 0;
+                    _ => None,
+                };
 
-                        for (pos, header) in message_part.headers.iter().enumerate().rev() {
-                            if &header.name == header_name {
-                                header_count += 1;
-                                if header_count == index {
-                                    if visitor_fnc(header, part_id, pos) {
-                                        return true;
-                                    }
-                                    break;
-                                }
-                            }
-                        }
+                if let Some(pos) = pos {
+                    if visitor_fnc(&message_part.headers[pos], part_id, pos) {
+                        return true;
                     }
                 }
             }
@@ -334,8 +369,7 @@ impl<'x> Context<'x> {
             }
             (MimeOpts::None, _) => {
                 if let HeaderValue::Text(text) = MessageStream::new(
-                    self.message
-                        .raw_message
+                    self.raw_bytes(&self.message)
                         .get(header.offset_start..header.offset_end)
                         .unwrap_or(b""),
                 )
@@ -33,6 +33,7 @@ use super::TestResult;
 
 impl TestMetadata {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
+        let comparator = ctx.comparator(&self.comparator);
         let metadata = match &self.medatata {
             Metadata::Server { annotation } => Metadata::Server {
                 annotation: ctx.eval_string(annotation),
@@ -81,18 +82,18 @@ impl TestMetadata {
             for key in &self.key_list {
                 let key = ctx.eval_string(key);
                 result = match &self.match_type {
-                    MatchType::Is => self.comparator.is(value, key.as_ref()),
-                    MatchType::Contains => self.comparator.contains(value, key.as_ref()),
+                    MatchType::Is => comparator.is(value, key.as_ref()),
+                    MatchType::Contains => comparator.contains(value, key.as_ref()),
                     MatchType::Value(relation) => {
-                        self.comparator.relational(relation, value, key.as_ref())
+                        comparator.relational(relation, value, key.as_ref())
                     }
-                    MatchType::Matches(capture_positions) => self.comparator.matches(
+                    MatchType::Matches(capture_positions) => comparator.matches(
                         value,
                         key.as_ref(),
                         *capture_positions,
                         &mut captured_values,
                     ),
-                    MatchType::Regex(capture_positions) => self.comparator.regex(
+                    MatchType::Regex(capture_positions) => comparator.regex(
                         value,
                         key.as_ref(),
                         *capture_positions,
@@ -28,7 +28,6 @@ use crate::{
         tests::test_notify::{TestNotifyMethodCapability, TestValidNotifyMethod},
         MatchType,
     },
-    runtime::actions::action_notify::validate_uri,
     Context,
 };
 
@@ -40,7 +39,7 @@ impl TestValidNotifyMethod {
 
         for uri in &self.notification_uris {
             let uri = ctx.eval_string(uri);
-            if let Some(scheme) = validate_uri(uri.as_ref()) {
+            if let Some(scheme) = ctx.runtime.notification_methods.validate_uri(uri.as_ref()) {
                 if ctx
                     .runtime
                     .valid_notification_uris
@@ -58,19 +57,31 @@ impl TestValidNotifyMethod {
 
 impl TestNotifyMethodCapability {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
+        let comparator = ctx.comparator(&self.comparator);
         let uri = ctx.eval_string(&self.notification_uri);
-        if !ctx
-            .eval_string(&self.notification_capability)
-            .eq_ignore_ascii_case("online")
-            || !validate_uri(uri.as_ref()).map_or(false, |scheme| {
+        let capability = ctx.eval_string(&self.notification_capability);
+        let value = if let Some(scheme) = ctx
+            .runtime
+            .notification_methods
+            .validate_uri(uri.as_ref())
+            .filter(|scheme| {
                 ctx.runtime
                     .valid_notification_uris
-                    .contains(&Cow::from(scheme))
+                    .contains(&Cow::from(*scheme))
                     || ctx.runtime.valid_notification_uris.contains(&uri)
-            })
-        {
+            }) {
+            ctx.runtime
+                .notification_methods
+                .get(scheme)
+                .and_then(|method| method.capability(uri.as_ref(), capability.as_ref()))
+        } else {
+            None
+        };
+        let value = if let Some(value) = value {
+            value
+        } else {
             return TestResult::Bool(false ^ self.is_not);
-        }
+        };
 
         if let MatchType::Count(rel_match) = &self.match_type {
             for key in &self.key_list {
@@ -82,18 +93,16 @@ impl TestNotifyMethodCapability {
             for key in &self.key_list {
                 let key = ctx.eval_string(key);
                 if match &self.match_type {
-                    MatchType::Is => self.comparator.is("maybe", key.as_ref()),
-                    MatchType::Contains => self.comparator.contains("maybe", key.as_ref()),
+                    MatchType::Is => comparator.is(value.as_str(), key.as_ref()),
+                    MatchType::Contains => comparator.contains(value.as_str(), key.as_ref()),
                     MatchType::Value(relation) => {
-                        self.comparator.relational(relation, "maybe", key.as_ref())
+                        comparator.relational(relation, value.as_str(), key.as_ref())
                     }
                     MatchType::Matches(_) => {
-                        self.comparator
-                            .matches("maybe", key.as_ref(), 0, &mut Vec::new())
+                        comparator.matches(value.as_str(), key.as_ref(), 0, &mut Vec::new())
                     }
                     MatchType::Regex(_) => {
-                        self.comparator
-                            .regex("maybe", key.as_ref(), 0, &mut Vec::new())
+                        comparator.regex(value.as_str(), key.as_ref(), 0, &mut Vec::new())
                     }
                     _ => false,
                 } {
@@ -35,6 +35,7 @@ use super::TestResult;
 
 impl TestSpamTest {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
+        let comparator = ctx.comparator(&self.comparator);
         let status = if self.percent {
             ctx.spam_status.as_percentage()
         } else {
@@ -44,19 +45,18 @@ impl TestSpamTest {
         let mut captured_values = Vec::new();
 
         let result = match &self.match_type {
-            MatchType::Is => self.comparator.is(status.as_ref(), value.as_ref()),
-            MatchType::Contains => self.comparator.contains(status.as_ref(), value.as_ref()),
+            MatchType::Is => comparator.is(status.as_ref(), value.as_ref()),
+            MatchType::Contains => comparator.contains(status.as_ref(), value.as_ref()),
             MatchType::Value(rel_match) => {
-                self.comparator
-                    .relational(rel_match, status.as_ref(), value.as_ref())
+                comparator.relational(rel_match, status.as_ref(), value.as_ref())
             }
-            MatchType::Matches(capture_positions) => self.comparator.matches(
+            MatchType::Matches(capture_positions) => comparator.matches(
                 status.as_ref(),
                 value.as_ref(),
                 *capture_positions,
                 &mut captured_values,
             ),
-            MatchType::Regex(capture_positions) => self.comparator.regex(
+            MatchType::Regex(capture_positions) => comparator.regex(
                 status.as_ref(),
                 value.as_ref(),
                 *capture_positions,
@@ -83,24 +83,24 @@ impl TestSpamTest {
 
 impl TestVirusTest {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
+        let comparator = ctx.comparator(&self.comparator);
         let status = ctx.virus_status.as_number();
         let value = ctx.eval_string(&self.value);
         let mut captured_values = Vec::new();
 
         let result = match &self.match_type {
-            MatchType::Is => self.comparator.is(status.as_ref(), value.as_ref()),
-            MatchType::Contains => self.comparator.contains(status.as_ref(), value.as_ref()),
+            MatchType::Is => comparator.is(status.as_ref(), value.as_ref()),
+            MatchType::Contains => comparator.contains(status.as_ref(), value.as_ref()),
             MatchType::Value(rel_match) => {
-                self.comparator
-                    .relational(rel_match, status.as_ref(), value.as_ref())
+                comparator.relational(rel_match, status.as_ref(), value.as_ref())
             }
-            MatchType::Matches(capture_positions) => self.comparator.regex(
+            MatchType::Matches(capture_positions) => comparator.regex(
                 status.as_ref(),
                 value.as_ref(),
                 *capture_positions,
                 &mut captured_values,
             ),
-            MatchType::Regex(capture_positions) => self.comparator.regex(
+            MatchType::Regex(capture_positions) => comparator.regex(
                 status.as_ref(),
                 value.as_ref(),
                 *capture_positions,
@@ -30,6 +30,7 @@ use super::TestResult;
 
 impl TestString {
     pub(crate) fn exec(&self, ctx: &mut Context, empty_is_null: bool) -> TestResult {
+        let comparator = ctx.comparator(&self.comparator);
         let mut result = false;
 
         match &self.match_type {
@@ -61,7 +62,7 @@ impl TestString {
                         event: Event::ListContains {
                             lists: ctx.eval_strings_owned(&self.key_list),
                             values,
-                            match_as: self.comparator.as_match(),
+                            match_as: comparator.as_match(),
                         },
                         is_not: self.is_not,
                     };
@@ -71,27 +72,37 @@ impl TestString {
                 let mut captured_values = Vec::new();
                 let sources = ctx.eval_strings(&self.source);
 
+                if let Some(key_set) =
+                    self.key_set
+                        .get(&self.match_type, &self.comparator, &self.key_list)
+                {
+                    return TestResult::Bool(
+                        sources.iter().any(|source| {
+                            (!empty_is_null || !source.is_empty())
+                                && key_set.matches(&self.comparator, source.as_ref())
+                        }) ^ self.is_not,
+                    );
+                }
+
                 for key in &self.key_list {
                     let key = ctx.eval_string(key);
                     for source in &sources {
                         if !empty_is_null || !source.is_empty() {
                             result = match &self.match_type {
-                                MatchType::Is => self.comparator.is(source.as_ref(), key.as_ref()),
+                                MatchType::Is => comparator.is(source.as_ref(), key.as_ref()),
                                 MatchType::Contains => {
-                                    self.comparator.contains(source.as_ref(), key.as_ref())
+                                    comparator.contains(source.as_ref(), key.as_ref())
                                 }
-                                MatchType::Value(relation) => self.comparator.relational(
-                                    relation,
-                                    source.as_ref(),
-                                    key.as_ref(),
-                                ),
-                                MatchType::Matches(capture_positions) => self.comparator.matches(
+                                MatchType::Value(relation) => {
+                                    comparator.relational(relation, source.as_ref(), key.as_ref())
+                                }
+                                MatchType::Matches(capture_positions) => comparator.matches(
                                     source.as_ref(),
                                     key.as_ref(),
                                     *capture_positions,
                                     &mut captured_values,
                                 ),
-                                MatchType::Regex(capture_positions) => self.comparator.regex(
+                                MatchType::Regex(capture_positions) => comparator.regex(
                                     source.as_ref(),
                                     key.as_ref(),
                                     *capture_positions,